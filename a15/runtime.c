@@ -14,13 +14,24 @@
 
 #ifdef __APPLE__
 #define SCHEME_ENTRY scheme_entry
+#define SCHEME_ERROR scheme_error
+#define SCHEME_RAISE scheme_raise
+#define SCHEME_LETREC_ERROR scheme_letrec_error
 #endif
 #ifdef __linux__
 #define SCHEME_ENTRY _scheme_entry
+#define SCHEME_ERROR _scheme_error
+#define SCHEME_RAISE _scheme_raise
+#define SCHEME_LETREC_ERROR _scheme_letrec_error
 #endif 
 
 extern long SCHEME_ENTRY(char *, char *); 
 
+/* called by the Scheme program, they never return */
+void SCHEME_ERROR(long msg, long irritants);
+void SCHEME_RAISE(long obj, long returned);
+void SCHEME_LETREC_ERROR(void);
+
 /* locally defined functions */
 static char *guarded_area(long n);
 #ifdef __APPLE__
@@ -203,6 +214,12 @@ static void usage_error(char *who) {
 #define tag_procedure 2
 #define disp_procedure_code 0
 #define disp_procedure_data 8
+#define mask_box 7
+#define tag_box 5
+#define disp_box 0
+#define mask_record 7
+#define tag_record 7
+#define disp_record_rtd 0
 #define mask_boolean 247
 #define tag_boolean 6
 #define _false 6
@@ -219,73 +236,114 @@ typedef long ptr;
 #define CDR(x) (*(ptr *)(UNTAG(x,tag_pair) + disp_cdr))
 #define VECTORLENGTH(x) (*(ptr *)(UNTAG(x,tag_vector) + disp_vector_length))
 #define VECTORDATA(x) ((ptr *)(UNTAG(x,tag_vector) + disp_vector_data))
+#define UNBOX(x) (*(ptr *)(UNTAG(x,tag_box) + disp_box))
+#define RECORDRTD(x) (*(ptr *)(UNTAG(x,tag_record) + disp_record_rtd))
 
 #define MAXDEPTH 100
 #define MAXLENGTH 1000
 
-static void print1(ptr x, int d) {
+static void print1(FILE *out, ptr x, int d) {
   if (TAG(x, mask_fixnum) == tag_fixnum) {
-    printf("%ld", (long)UNFIX(x));
+    fprintf(out, "%ld", (long)UNFIX(x));
   } else if (TAG(x, mask_pair) == tag_pair) {
     int len = 0;
     ptr y;
     
     if (d > MAXDEPTH) {
-      printf("(...)");
+      fprintf(out, "(...)");
       return;
     }
-    printf("(");
-    print1(CAR(x), d+1);
+    fprintf(out, "(");
+    print1(out, CAR(x), d+1);
     y = CDR(x);
     while (TAG(y, mask_pair) == tag_pair && (len < MAXLENGTH-1)) {
-      printf(" ");
-      print1(CAR(y), d+1);
+      fprintf(out, " ");
+      print1(out, CAR(y), d+1);
       y = CDR(y);
       len++;
     }
     if (y != _nil)
       if (len == MAXLENGTH-1)
-        printf(" ...");
+        fprintf(out, " ...");
       else {
-        printf(" . ");
-        print1(y, d+1);
+        fprintf(out, " . ");
+        print1(out, y, d+1);
       }
-    printf(")");
+    fprintf(out, ")");
   } else if (TAG(x, mask_vector) == tag_vector) {
     long i, n;
     ptr *p;
     if (d > MAXDEPTH) {
-      printf("#(...)");
+      fprintf(out, "#(...)");
       return;
     }
-    printf("#(");
+    fprintf(out, "#(");
     n = UNFIX(VECTORLENGTH(x));
     p = VECTORDATA(x);
     i = n > MAXLENGTH ? MAXLENGTH : n;
     if (i != 0) {
-      print1(*p, d+1);
+      print1(out, *p, d+1);
       while (--i) {
-        printf(" ");
-        print1(*++p, d+1);
+        fprintf(out, " ");
+        print1(out, *++p, d+1);
       }
     }
-    if (n > MAXLENGTH) printf(" ..."); 
-    printf(")");
+    if (n > MAXLENGTH) fprintf(out, " ..."); 
+    fprintf(out, ")");
+  } else if (TAG(x, mask_record) == tag_record) {
+    /* the descriptor holds the number of fields, then the name in bytes */
+    ptr rtd = RECORDRTD(x);
+    long i, n = UNFIX(VECTORLENGTH(rtd));
+    fprintf(out, "#<record ");
+    for (i = 1; i < n; i++)
+      fputc((int)UNFIX(VECTORDATA(rtd)[i]), out);
+    fprintf(out, ">");
+  } else if (TAG(x, mask_box) == tag_box) {
+    if (d > MAXDEPTH) {
+      fprintf(out, "#&...");
+      return;
+    }
+    fprintf(out, "#&");
+    print1(out, UNBOX(x), d+1);
   } else if (TAG(x, mask_procedure) == tag_procedure) {
-    printf("#<procedure>");
+    fprintf(out, "#<procedure>");
   } else if (x == _false) {
-    printf("#f");
+    fprintf(out, "#f");
   } else if (x == _true) {
-    printf("#t");
+    fprintf(out, "#t");
   } else if (x == _nil) {
-    printf("()");
+    fprintf(out, "()");
   } else if (x == _void) {
-    printf("#<void>");
+    fprintf(out, "#<void>");
   }
 }
 
 static void print(ptr x) {
-  print1(x, 0);
+  print1(stdout, x, 0);
+}
+
+void SCHEME_ERROR(ptr msg, ptr irritants) {
+  fflush(stdout);
+  fprintf(stderr, "Error: ");
+  print1(stderr, msg, 0);
+  while (TAG(irritants, mask_pair) == tag_pair) {
+    fprintf(stderr, " ");
+    print1(stderr, CAR(irritants), 0);
+    irritants = CDR(irritants);
+  }
+  fprintf(stderr, "\n");
+  exit(1);
+}
+
+void SCHEME_RAISE(ptr obj, long returned) {
+  fflush(stdout);
+  if (returned)
+    fprintf(stderr, "Exception: handler returned from non-continuable raise of ");
+  else
+    fprintf(stderr, "Exception: uncaught ");
+  print1(stderr, obj, 0);
+  fprintf(stderr, "\n");
+  exit(1);
 }
 
 #else /* SCHEME_PRINTER */
@@ -294,4 +352,22 @@ static void print(long x) {
     printf("%ld", x);
 } 
 
+void SCHEME_ERROR(long msg, long irritants) {
+  fflush(stdout);
+  fprintf(stderr, "Error: %ld\n", msg);
+  exit(1);
+}
+
+void SCHEME_RAISE(long obj, long returned) {
+  fflush(stdout);
+  fprintf(stderr, "Exception: %s %ld\n", returned ? "handler returned from" : "uncaught", obj);
+  exit(1);
+}
+
 #endif /* SCHEME_PRINTER */
+
+void SCHEME_LETREC_ERROR(void) {
+  fflush(stdout);
+  fprintf(stderr, "Error: invalid reference to a letrec variable before its initialization\n");
+  exit(1);
+}
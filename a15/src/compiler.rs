@@ -1,12 +1,15 @@
 use std::io::Write;
 use std::fs::File;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::vec::IntoIter;
 use std::rc::Rc;
+use std::fmt;
 
-use crate::syntax::{Scheme, Expr, Asm, ConflictGraph, Frame};
-use crate::parser::{Scanner, Parser};
+use crate::syntax::{Scheme, Expr, Asm, ConflictGraph, Frame, HashMap, HashSet};
+use crate::parser::{Reader, Parser, IlParser, Token, Datum, read, is_number};
+use crate::interp::{Answer, SchemeInterpreter, WordInterpreter, MachineInterpreter};
+use crate::verify::verify;
+use crate::validate::{ALLOCATORS, validate_allocation};
+use crate::encoder::Encoder;
+use crate::elf::elf_object;
 
 
 use Expr::*;
@@ -18,36 +21,48 @@ use Asm::*;
 //
 // ---------------------------------------------------------------------
 
-const MASK_FIXNUM  :i64 = 0b111;
+pub const MASK_FIXNUM  :i64 = 0b111;
 const FIXNUM_BITS  :i64 = 61;
-const SHIFT_FIXNUM :i64 = 3;
-const TAG_FIXNUM   :i64 = 0b000;
+pub const SHIFT_FIXNUM :i64 = 3;
+pub const TAG_FIXNUM   :i64 = 0b000;
 
 const MASK_PAIR  :i64 = 0b111;
-const TAG_PAIR   :i64 = 0b001;
-const SIZE_PAIR  :i64 = 16;
-const CAR_OFFSET :i64 = 0 - TAG_PAIR;
-const CDR_OFFSET :i64 = 8 - TAG_PAIR;
+pub const TAG_PAIR   :i64 = 0b001;
+pub const SIZE_PAIR  :i64 = 16;
+pub const CAR_OFFSET :i64 = 0 - TAG_PAIR;
+pub const CDR_OFFSET :i64 = 8 - TAG_PAIR;
 
 const MASK_VECTOR  :i64 = 0b111;
-const TAG_VECTOR   :i64 = 0b011;
-const VLEN_OFFSET  :i64 = 0 - TAG_VECTOR;
-const VDATA_OFFSET :i64 = 8 - TAG_VECTOR;
+pub const TAG_VECTOR   :i64 = 0b011;
+pub const VLEN_OFFSET  :i64 = 0 - TAG_VECTOR;
+pub const VDATA_OFFSET :i64 = 8 - TAG_VECTOR;
 const DISP_VDATA   :i64 = 8;
 
 const MASK_PROC        :i64 = 0b111;
-const TAG_PROC         :i64 = 0b010;
-const PROC_CODE_OFFSET :i64 = 0 - TAG_PROC;
-const PROC_DATA_OFFSET :i64 = 8 - TAG_PROC;
+pub const TAG_PROC         :i64 = 0b010;
+pub const PROC_CODE_OFFSET :i64 = 0 - TAG_PROC;
+pub const PROC_DATA_OFFSET :i64 = 8 - TAG_PROC;
 const DISP_PDATA       :i64 = 8;
 
+const MASK_BOX   :i64 = 0b111;
+pub const TAG_BOX    :i64 = 0b101;
+const SIZE_BOX   :i64 = 8;
+pub const BOX_OFFSET :i64 = 0 - TAG_BOX;
+
+// a record has a header pointing to its record-type descriptor, followed by the fields
+const MASK_RECORD        :i64 = 0b111;
+pub const TAG_RECORD         :i64 = 0b111;
+pub const RECORD_RTD_OFFSET  :i64 = 0 - TAG_RECORD;
+const RECORD_DATA_OFFSET :i64 = 8 - TAG_RECORD;
+const DISP_RDATA         :i64 = 8;
+
 const MASK_BOOL :i64 = 0b11110111;
 const TAG_BOOL  :i64 = 0b00000110;
 
-const FALSE :i64 = 0b0000_0110;
-const TRUE  :i64 = 0b0000_1110;
-const NIL   :i64 = 0b0001_0110;
-const VOID  :i64 = 0b0001_1110;
+pub const FALSE :i64 = 0b0000_0110;
+pub const TRUE  :i64 = 0b0000_1110;
+pub const NIL   :i64 = 0b0001_0110;
+pub const VOID  :i64 = 0b0001_1110;
 
 fn prim1_scm(op: String, v1: Scheme) -> Scheme {
     Scheme::Prim1 (op, Box::new(v1))
@@ -97,29 +112,71 @@ fn quote_scm(scm: Scheme) -> Scheme {
     Scheme::Quote (Box::new(scm))
 }
 
+// x.5 becomes x$5, the suffix is unique so the label is too
 fn uvar_to_label(var: &str) -> String {
-    var.replace(".", "$")
+    match var.rsplit_once('.') {
+        Some((name, suffix)) => format!("{}${}", name, suffix),
+        None => panic!("Invalid uvar {}", var),
+    }
 }
 
-fn is_value_prim(s: &str) -> bool {
+pub fn is_value_prim(s: &str) -> bool {
     ["+", "-", "*", "car", "cdr", "cons", "make-vector", "vector-length", "vector-ref", "void", 
-    "make-procedure", "procedure-code", "procedure-ref"].contains(&s)
+    "make-procedure", "procedure-code", "procedure-ref", "box", "unbox", "make-record", "record-ref"].contains(&s)
 }
 
-fn is_pred_prim(s: &str) -> bool {
-    ["<=", "<", "=", ">=", ">", "boolean?", "eq?", "fixnum?", "null?", "pair?", "vector?", "procedure?"].contains(&s)
+pub fn is_pred_prim(s: &str) -> bool {
+    ["<=", "<", "=", ">=", ">", "boolean?", "eq?", "fixnum?", "null?", "pair?", "vector?", "procedure?", "box?", "record-of?"].contains(&s)
 }
 
-fn is_effect_prim(s: &str) -> bool {
-    ["set-car!", "set-cdr!", "vector-set!", "procedure-set!"].contains(&s)
+pub fn is_effect_prim(s: &str) -> bool {
+    ["set-car!", "set-cdr!", "vector-set!", "procedure-set!", "set-box!", "record-set!"].contains(&s)
+}
+
+// primitives which are calls to the runtime procedures emitted by CompileToAsm
+pub fn runtime_label(op: &str) -> Option<&'static str> {
+    match op {
+        "apply" => Some(APPLY_LABEL),
+        "call/cc" | "call-with-current-continuation" => Some(CALLCC_LABEL),
+        "with-exception-handler" => Some(HANDLER_LABEL),
+        "raise" => Some(RAISE_LABEL),
+        "raise-continuable" => Some(RAISE_CONTINUABLE_LABEL),
+        "error" => Some(ERROR_LABEL),
+        "values" => Some(VALUES_LABEL),
+        "apply-values" => Some(APPLY_VALUES_LABEL),
+        "call-with-values" => Some(CALL_WITH_VALUES_LABEL),
+        "letrec-error" => Some(LETREC_ERROR_LABEL),
+        _ => None,
+    }
 }
 
 fn gen_anon() -> String {
     gensym("anon.")
 }
 
+// a rest argument is the last argument of its lambda. The parser marks it with "." before it,
+// UniquifyVariable gives it a fresh uvar which is remembered here, not told by its name.
+fn gen_rest() -> String {
+    let rest = gensym("rest.");
+    mark_rest(&rest);
+    rest
+}
+
+pub fn mark_rest(uvar: &str) {
+    RESTS.with(|r| r.borrow_mut().insert(uvar.to_string()));
+}
+
+pub fn is_rest(sym: &str) -> bool {
+    RESTS.with(|r| r.borrow().contains(sym))
+}
+
+// the name in the source of a letrec variable, a dump only has its uvar
+fn source_name(uvar: &str) -> String {
+    NAMES.with(|n| n.borrow().get(uvar).cloned()).unwrap_or(uvar.to_string())
+}
+
 fn union_set(sets: Vec<HashSet<String>>) -> HashSet<String> {
-    let mut new_set = HashSet::new();
+    let mut new_set = HashSet::default();
     for mut set in sets {
         for e in set.drain() {
             new_set.insert(e);
@@ -148,19 +205,339 @@ fn make_nopless_begin(exprs: Vec<Scheme>) -> Scheme {
     Scheme::Begin (new_exprs)
 }
 
+const KEYWORDS: [&str; 11] = ["if", "begin", "set!", "lambda", "let", "letrec", "let-values", "define-record-type", "and", "or", "void"];
+
+pub struct VerifyScheme {}
+impl VerifyScheme {
+    // checks the source before it is parsed, and reports all errors at once
+    pub fn run(&self, scm: &str) {
+        let mut errors = vec![];
+        match Reader::new(scm).read_all() {
+            Ok(data) if data.is_empty() => errors.push("line 1, col 1: empty program".to_string()),
+            Ok(data) => {
+                if let Some(d) = data.get(1) {
+                    errors.push(self.error(d.token(), "unexpected expression after the program".to_string()));
+                }
+                let prelude: Vec<String> = Parser::new().parse_definitions(&read(PRELUDE)).into_iter().map(|(name, _)| name).collect();
+                self.verify(&data[0], &prelude, &mut errors);
+            }
+            Err(e) => errors.push(e),
+        }
+        if !errors.is_empty() {
+            panic!("Invalid program\n{}", errors.join("\n"));
+        }
+    }
+
+    fn error(&self, t: &Token, msg: String) -> String {
+        format!("line {}, col {}: {}", t.line, t.col, msg)
+    }
+
+    fn verify(&self, datum: &Datum, bound: &Vec<String>, errors: &mut Vec<String>) {
+        match datum {
+            Datum::Quote (..) | Datum::Vector (..) => (),
+            Datum::Atom (t) => {
+                let s = t.token.as_str();
+                if s == "#t" || s == "#f" {
+                    // a boolean
+                } else if is_number(s) {
+                    if s.parse::<i64>().is_err() {
+                        errors.push(self.error(t, format!("invalid number {}", s)));
+                    }
+                } else if bound.iter().any(|v| v.as_str() == s) || primitive_arity(s).is_some() {
+                    // a variable, or a primitive as a procedure
+                } else if KEYWORDS.contains(&s) || self.prim_arity(s).is_some() {
+                    errors.push(self.error(t, format!("invalid use of keyword {}", s)));
+                } else {
+                    errors.push(self.error(t, format!("variable {} unbound", s)));
+                }
+            }
+            Datum::List (_, _, Some(tail)) => errors.push(self.error(tail.token(), "unexpected dotted list".to_string())),
+            Datum::List (_, elements, None) if elements.is_empty() => (),
+            Datum::List (t, elements, None) => {
+                let head = elements[0].symbol().filter(|h| !bound.iter().any(|v| v.as_str() == *h));
+                let operands = &elements[1..];
+                match head {
+                    Some("if") => {
+                        if operands.len() != 2 && operands.len() != 3 {
+                            errors.push(self.error(t, format!("if expects 2 or 3 operands, but got {}", operands.len())));
+                        }
+                        operands.iter().for_each(|e| self.verify(e, bound, errors));
+                    }
+                    Some("begin") => self.verify_body(t, "begin", operands, bound, errors),
+                    Some("set!") => {
+                        if operands.len() != 2 {
+                            errors.push(self.error(t, format!("set! expects 2 operands, but got {}", operands.len())));
+                        }
+                        match operands.first() {
+                            Some(var) if var.symbol().is_some() => self.verify(var, bound, errors),
+                            Some(other) => errors.push(self.error(other.token(), "set! expects a variable".to_string())),
+                            None => (),
+                        }
+                        operands.iter().skip(1).for_each(|e| self.verify(e, bound, errors));
+                    }
+                    Some("lambda") => {
+                        if operands.is_empty() {
+                            errors.push(self.error(t, "lambda expects formals and a body".to_string()));
+                            return;
+                        }
+                        let mut new_bound = bound.clone();
+                        new_bound.extend(self.verify_formals(&operands[0], errors).into_iter().map(|t| t.token));
+                        self.verify_body(t, "lambda", &operands[1..], &new_bound, errors);
+                    }
+                    Some(form) if form == "let" || form == "letrec" || form == "let-values" => {
+                        let bindings = match operands.first() {
+                            Some(Datum::List (_, bindings, None)) => bindings,
+                            _ => {
+                                errors.push(self.error(t, format!("{} expects a list of bindings", form)));
+                                return;
+                            }
+                        };
+                        let mut names = vec![];
+                        for b in bindings {
+                            match b {
+                                Datum::List (_, pair, None) if pair.len() == 2 && form == "let-values" => names.extend(self.verify_formals(&pair[0], errors)),
+                                Datum::List (_, pair, None) if pair.len() == 2 && pair[0].symbol().is_some() => names.push(pair[0].token().clone()),
+                                other => errors.push(self.error(other.token(), format!("invalid binding in {}", form))),
+                            }
+                        }
+                        self.check_duplicates(&names, form, errors);
+                        let mut new_bound = bound.clone();
+                        new_bound.extend(names.iter().map(|t| t.token.clone()));
+                        let value_bound = if form == "letrec" { &new_bound } else { bound };
+                        for b in bindings {
+                            if let Datum::List (_, pair, None) = b {
+                                pair.iter().skip(1).for_each(|e| self.verify(e, value_bound, errors));
+                            }
+                        }
+                        self.verify_body(t, form, &operands[1..], &new_bound, errors);
+                    }
+                    Some("define-record-type") => {
+                        errors.push(self.error(t, "define-record-type is only allowed in a body".to_string()));
+                    }
+                    Some(op) if self.prim_arity(op).is_some() => {
+                        let (min, max) = self.prim_arity(op).unwrap();
+                        if operands.len() < min || operands.len() > max {
+                            let expect = if min == max { format!("{}", min) } else if max == usize::MAX { format!("at least {}", min) } else { format!("{} to {}", min, max) };
+                            errors.push(self.error(t, format!("{} expects {} operands, but got {}", op, expect, operands.len())));
+                        }
+                        operands.iter().for_each(|e| self.verify(e, bound, errors));
+                    }
+                    _ => elements.iter().for_each(|e| self.verify(e, bound, errors)),
+                }
+            }
+        }
+    }
+
+    // the number of operands of the special forms and primitives which the parser dispatches on
+    fn prim_arity(&self, op: &str) -> Option<(usize, usize)> {
+        match op {
+            "void" => Some((0, 0)),
+            "and" | "or" | "values" => Some((0, usize::MAX)),
+            "error" => Some((1, usize::MAX)),
+            "apply" => Some((2, usize::MAX)),
+            other => primitive_arity(other).map(|n| (n, n)),
+        }
+    }
+
+    // a body is a nonempty sequence, where a record definition scopes over the rest
+    fn verify_body(&self, t: &Token, form: &str, body: &[Datum], bound: &Vec<String>, errors: &mut Vec<String>) {
+        if body.is_empty() {
+            errors.push(self.error(t, format!("{} expects a body", form)));
+            return;
+        }
+        let mut bound = bound.clone();
+        for (i, e) in body.iter().enumerate() {
+            match e {
+                Datum::List (t, elements, None) if elements.first().and_then(|h| h.symbol()) == Some("define-record-type") 
+                    && !bound.iter().any(|v| v.as_str() == "define-record-type") => {
+                    let names = self.verify_record(t, &elements[1..], errors);
+                    bound.extend(names);
+                    if i + 1 == body.len() {
+                        errors.push(self.error(t, "expect an expression after define-record-type".to_string()));
+                    }
+                }
+                e => self.verify(e, &bound, errors),
+            }
+        }
+    }
+
+    // (define-record-type name (ctor field ...) pred (field accessor [modifier]) ...)
+    fn verify_record(&self, t: &Token, operands: &[Datum], errors: &mut Vec<String>) -> Vec<String> {
+        let mut names = vec![];
+        let symbols = |e: &Datum| -> Option<Vec<String>> {
+            match e {
+                Datum::List (_, es, None) => es.iter().map(|e| e.symbol().map(|s| s.to_string())).collect(),
+                _ => None,
+            }
+        };
+        if operands.len() < 3 {
+            errors.push(self.error(t, "define-record-type expects a name, a constructor and a predicate".to_string()));
+            return names;
+        }
+        let mut fields = vec![];
+        for f in &operands[3..] {
+            match symbols(f) {
+                Some(spec) if spec.len() == 2 || spec.len() == 3 => {
+                    fields.push(spec[0].clone());
+                    names.extend(spec[1..].iter().cloned());
+                }
+                _ => errors.push(self.error(f.token(), "invalid field in define-record-type".to_string())),
+            }
+        }
+        match (operands[0].symbol(), symbols(&operands[1]), operands[2].symbol()) {
+            (Some(name), Some(ctor), Some(pred)) if ctor.len() > 0 => {
+                for f in &ctor[1..] {
+                    if !fields.contains(f) {
+                        errors.push(self.error(operands[1].token(), format!("unknown field {} of record {}", f, name)));
+                    }
+                }
+                names.extend(vec![name.to_string(), ctor[0].clone(), pred.to_string()]);
+            }
+            _ => errors.push(self.error(t, "invalid define-record-type".to_string())),
+        }
+        return names;
+    }
+
+    // formals are a list of distinct variables, a dotted list or a variable
+    fn verify_formals(&self, formals: &Datum, errors: &mut Vec<String>) -> Vec<Token> {
+        let mut vars = vec![];
+        match formals {
+            Datum::Atom (t) if formals.symbol().is_some() => vars.push(t.clone()),
+            Datum::List (_, elements, rest) => {
+                for e in elements.iter().chain(rest.iter().map(|r| r.as_ref())) {
+                    match e {
+                        e if e.symbol().is_some() => vars.push(e.token().clone()),
+                        other => errors.push(self.error(other.token(), "formals should be variables".to_string())),
+                    }
+                }
+            }
+            other => errors.push(self.error(other.token(), "invalid formals".to_string())),
+        }
+        self.check_duplicates(&vars, "formals", errors);
+        return vars;
+    }
+
+    fn check_duplicates(&self, names: &Vec<Token>, form: &str, errors: &mut Vec<String>) {
+        for (i, t) in names.iter().enumerate() {
+            if names[..i].iter().any(|n| n.token == t.token) {
+                errors.push(self.error(t, format!("duplicate variable {} in {}", t.token, form)));
+            }
+        }
+    }
+}
+
 pub struct ParseScheme {}
 impl ParseScheme {
     pub fn run(&self, scm: &str) -> Scheme {
-        let scanner = Scanner::new(scm);
-        let tokens = scanner.scan();
-        let parser = Parser::new(tokens);
-        let scm = parser.parse();
+        // the rest arguments of the last program are forgotten, a name of this one may be the same
+        RESTS.with(|r| r.borrow_mut().clear());
+        let data = read(scm);
+        assert!(data.len() > 0, "Empty program");
+        let scm = Parser::new().parse(&data[0]);
         return scm;
     }
 }
 
+// the prelude is linked into every program
+const PRELUDE: &str = include_str!("../prelude.ss");
+
+pub struct IntroduceLibrary {}
+impl IntroduceLibrary {
+    pub fn run(&self, scm: Scheme) -> Scheme {
+        let mut prelude: HashMap<String, Scheme> = Parser::new().parse_definitions(&read(PRELUDE)).into_iter().collect();
+        let mut free = HashSet::default();
+        self.free_variables(&scm, &HashSet::default(), &mut free);
+        // definitions may refer to each other, the rest are pruned
+        let mut bindings = HashMap::default();
+        let mut worklist: Vec<String> = free.into_iter().collect();
+        while let Some(name) = worklist.pop() {
+            if let Some(def) = prelude.remove(&name) {
+                let mut free = HashSet::default();
+                self.free_variables(&def, &HashSet::default(), &mut free);
+                worklist.extend(free);
+                bindings.insert(name, def);
+            }
+        }
+        if bindings.is_empty() { return scm; }
+        // the outermost letrec, LiftLetrec splices its lambdas into the top-level letrec. 
+        // The program is inside, so its bindings shadow the prelude.
+        return letrec_scm(bindings, scm);
+    }
+
+    fn free_variables(&self, scm: &Scheme, bound: &HashSet<String>, free: &mut HashSet<String>) {
+        use Scheme::*;
+        let extend = |vars: Vec<&String>| {
+            let mut new_bound = bound.clone();
+            new_bound.extend(vars.into_iter().cloned());
+            new_bound
+        };
+        match scm {
+            Letrec (bindings, box body) => {
+                let bound = extend(bindings.keys().collect());
+                bindings.values().for_each(|e| self.free_variables(e, &bound, free));
+                self.free_variables(body, &bound, free);
+            }
+            Let (bindings, box body) => {
+                bindings.values().for_each(|e| self.free_variables(e, bound, free));
+                self.free_variables(body, &extend(bindings.keys().collect()), free);
+            }
+            Lambda (args, box body) => self.free_variables(body, &extend(args.iter().collect()), free),
+            Begin (exprs) | PrimN (_, exprs) => exprs.iter().for_each(|e| self.free_variables(e, bound, free)),
+            Prim1 (_, box e) => self.free_variables(e, bound, free),
+            Prim2 (_, box e1, box e2) | Set (box e1, box e2) => {
+                self.free_variables(e1, bound, free);
+                self.free_variables(e2, bound, free);
+            }
+            Prim3 (_, box e1, box e2, box e3) | If (box e1, box e2, box e3) => {
+                self.free_variables(e1, bound, free);
+                self.free_variables(e2, bound, free);
+                self.free_variables(e3, bound, free);
+            }
+            Funcall (box func, args) => {
+                self.free_variables(func, bound, free);
+                args.iter().for_each(|e| self.free_variables(e, bound, free));
+            }
+            Symbol (s) if !bound.contains(s) => { free.insert(s.to_string()); }
+            _ => (),
+        }
+    }
+}
+
 pub struct UniquifyVariable {}
 
+// primitives which are also procedures, with their arity. 
+// error takes a message and a rest argument for the irritants, values takes a rest argument.
+pub fn primitive_arity(op: &str) -> Option<usize> {
+    match op {
+        "void" => Some(0),
+        "values" => Some(1),
+        "car" | "cdr" | "make-vector" | "vector-length" | "procedure?" | "boolean?" | "fixnum?" | 
+        "null?" | "pair?" | "vector?" | "not" | "box" | "unbox" | "box?" |
+        "call/cc" | "call-with-current-continuation" | "raise" | "raise-continuable" => Some(1),
+        "+" | "-" | "*" | "logor" | "logand" | "sra" | "=" | ">" | "<" | ">=" | "<=" | "eq?" |
+        "cons" | "vector-ref" | "set-car!" | "set-cdr!" | "set-box!" | 
+        "with-exception-handler" | "call-with-values" | "apply" | "error" => Some(2),
+        "vector-set!" => Some(3),
+        _ => None,
+    }
+}
+
+// (lambda (x y) (op x y)), and (lambda ls (apply-values ls)) for values
+fn eta_expand(op: &str) -> Scheme {
+    use Scheme::*;
+    let arity = primitive_arity(op).unwrap();
+    let args: Vec<String> = (0..arity).map(|i| if (op == "error" && i == 1) || op == "values" { gen_rest() } else { gen_uvar() }).collect();
+    let op = if op == "values" { "apply-values" } else { op };
+    let mut vals: Vec<Scheme> = args.iter().map(|a| Symbol (a.clone())).collect();
+    let body = match arity {
+        0 => Void,
+        1 => prim1_scm(op.to_string(), vals.remove(0)),
+        2 => prim2_scm(op.to_string(), vals.remove(0), vals.remove(0)),
+        _ => prim3_scm(op.to_string(), vals.remove(0), vals.remove(0), vals.remove(0)),
+    };
+    return lambda_scm(args, body);
+}
+
 pub struct SymTable {
     pub map: HashMap<String, String>,
     env: Option<Rc<SymTable>>,
@@ -169,24 +546,27 @@ pub struct SymTable {
 impl SymTable {
     pub fn new() -> Self {
         SymTable {
-            map: HashMap::new(),
+            map: HashMap::default(),
             env: None
         }
     }
     pub fn lookup(&self, x: &str) -> &str {
+        match self.try_lookup(x) {
+            Some(h) => h,
+            None => panic!("variable {} unbound", x),
+        }
+    }
+
+    pub fn try_lookup(&self, x: &str) -> Option<&str> {
         if let Some(h) = self.map.get(x) {
-            return h;
+            return Some(h);
         } else if let Some(env) = &self.env {
-            return env.lookup(x);
+            return env.try_lookup(x);
         } else {
-            panic!("variable {} unbound", x);
+            return None;
         }
     }
 
-    pub fn bind(&mut self, var: String, val: String) -> Option<String> {
-        return self.map.insert(var, val); 
-    }
-
     pub fn extend(map: HashMap<String, String>, table: &Rc<SymTable>) -> Self {
         SymTable { map, env: Some(Rc::clone(&table)) }
     }
@@ -195,6 +575,7 @@ impl SymTable {
 
 impl UniquifyVariable {
     pub fn run(&self, scm: Scheme) -> Scheme {
+        NAMES.with(|n| n.borrow_mut().clear());
         let symtable = Rc::new(SymTable::new());
         return self.uniquify(scm, symtable);
     }
@@ -215,8 +596,8 @@ impl UniquifyVariable {
                 values.into_iter().map(|e| self.uniquify(e, Rc::clone(&symtable))).collect()
             ),
             Let (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
-                let mut mapping = HashMap::new();
+                let mut new_bindings = HashMap::default();
+                let mut mapping = HashMap::default();
                 for (k, v) in bindings.drain() {
                     let new_v = self.uniquify(v, Rc::clone(&symtable));
                     let new_k = gen_uvar();
@@ -227,11 +608,13 @@ impl UniquifyVariable {
                 return let_scm(new_bindings, self.uniquify(body, symtable));
             }
             Letrec (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 // update symtable firstly
-                let mut mapping = HashMap::new();
+                let mut mapping = HashMap::default();
                 for k in bindings.keys() {
-                    mapping.insert(k.clone(), gen_uvar());
+                    let uvar = gen_uvar();
+                    NAMES.with(|n| n.borrow_mut().insert(uvar.clone(), k.clone()));
+                    mapping.insert(k.clone(), uvar);
                 }
                 symtable = Rc::new(SymTable::extend(mapping, &symtable));
                 // update bindings later
@@ -242,10 +625,12 @@ impl UniquifyVariable {
                 return letrec_scm(new_bindings, self.uniquify(body, symtable));
             }
             Lambda (args, box body) => {
-                let mut mapping = HashMap::new();
+                let mut mapping = HashMap::default();
                 let mut new_args = vec![];
+                let mut rest = false;
                 for a in args {
-                    let new_a = gen_uvar();
+                    if a.as_str() == "." { rest = true; continue; }
+                    let new_a = if rest || is_rest(&a) { gen_rest() } else { gen_uvar() };
                     new_args.push(new_a.clone());
                     mapping.insert(a, new_a);
                 }
@@ -257,6 +642,8 @@ impl UniquifyVariable {
             Prim3 (op, box e1, box e2, box e3) => 
                 prim3_scm(op, self.uniquify(e1, Rc::clone(&symtable)), self.uniquify(e2, Rc::clone(&symtable)), self.uniquify(e3, symtable)),
             Set (box e1, box e2) => set1_scm(self.uniquify(e1, Rc::clone(&symtable)), self.uniquify(e2, symtable)),
+            // a free primitive is a procedure value
+            Symbol (s) if symtable.try_lookup(&s).is_none() && primitive_arity(&s).is_some() => eta_expand(&s),
             Symbol (s) => Symbol (symtable.lookup(&s).to_string()),
             Quote (box imm) => quote_scm(imm),
             Void => Void,
@@ -310,14 +697,14 @@ impl ConvertComplexDatum {
                 values.into_iter().map(|e| self.convert(e, literals)).collect()
             ),
             Let (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.convert(v, literals));
                 }
                 return let_scm(new_bindings, self.convert(body, literals));
             }
             Letrec (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.convert(v, literals));
                 }
@@ -377,13 +764,34 @@ impl ConvertComplexDatum {
                         if exprs.len() == 1 { return exprs.pop().unwrap(); }
                         let mut b2 = exprs.pop().unwrap();
                         while let Some(b1) = exprs.pop() {
-                            let mut bindings = HashMap::new();
+                            let mut bindings = HashMap::default();
                             let tmp = gen_uvar();
                             bindings.insert(tmp.clone(), b1);
                             b2 = let_scm(bindings, if2_scm(Symbol (tmp.clone()), Symbol (tmp), b2));
                         }
                         return b2;
                     }
+                    // (error msg a b) => (error msg (cons a (cons b '())))
+                    "error" => {
+                        assert!(exprs.len() >= 1, "error expects a message");
+                        let msg = exprs.remove(0);
+                        let mut ls = quote_scm(EmptyList);
+                        while let Some(e) = exprs.pop() {
+                            ls = prim2_scm("cons".to_string(), e, ls);
+                        }
+                        return prim2_scm(op, msg, ls);
+                    }
+                    "values" => return PrimN (op, exprs),
+                    // (apply f a b ls) => (apply f (cons a (cons b ls)))
+                    "apply" => {
+                        assert!(exprs.len() >= 2, "apply expects a procedure and a list");
+                        let func = exprs.remove(0);
+                        let mut ls = exprs.pop().unwrap();
+                        while let Some(e) = exprs.pop() {
+                            ls = prim2_scm("cons".to_string(), e, ls);
+                        }
+                        return prim2_scm(op, func, ls);
+                    }
                     other => panic!("Unexpected op {} in PrimN", other),
                 }
             }
@@ -396,14 +804,14 @@ impl ConvertComplexDatum {
         while let Some(scm) = list.pop() {
             cons = prim2_scm("cons".to_string(), scm, cons);
         }
-        let mut bindings = HashMap::new();
+        let mut bindings = HashMap::default();
         bindings.insert(tmp, cons);
         return let_scm(bindings, scm);
     }
 
     fn construct_vector(&self, tmp: String, mut elements: Vec<Scheme>, scm: Scheme) -> Scheme {
         use Scheme::*;
-        let mut bindings = HashMap::new();
+        let mut bindings = HashMap::default();
         let alloc = prim1_scm("make-vector".to_string(), quote_scm(Int64 (elements.len() as i64)));
         let mut exprs = vec![];
         for (i, v) in elements.into_iter().enumerate() {
@@ -455,7 +863,7 @@ impl UncoverAssigned {
             }
             Let (mut bindings, box body) => {
                 let (body, mut bset) = self.uncover(body);
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 let mut sets = vec![bset];
                 for (k, v) in bindings.drain() {
                     let (e, set) = self.uncover(v);
@@ -468,7 +876,7 @@ impl UncoverAssigned {
             }
             Letrec (mut bindings, box body) => {
                 let (body, mut bset) = self.uncover(body);
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 let mut sets = vec![bset];
                 for (k, v) in bindings.drain() {
                     let (e, set) = self.uncover(v);
@@ -501,14 +909,24 @@ impl UncoverAssigned {
                 let new_set = union_set(vec![e1_set, e2_set, e3_set]);
                 return (prim3_scm(op, e1, e2, e3), new_set);
             }
+            PrimN (op, mut exprs) => {
+                let mut sets = vec![];
+                exprs = exprs.into_iter().map(|e| {
+                    let (e, set) = self.uncover(e);
+                    sets.push(set);
+                    e
+                }).collect();
+                let new_set = union_set(sets);
+                return (PrimN (op, exprs), new_set);
+            }
             Set (box Symbol (sym), box e) => {
                 let (e, mut new_set) = self.uncover(e);
                 new_set.insert(sym.clone());
                 return (set1_scm(Symbol (sym), e), new_set);
             }
-            Symbol (s) => (Symbol (s), HashSet::new()),
-            Quote (box imm) => (quote_scm(imm), HashSet::new()),
-            Void => (Void, HashSet::new()),
+            Symbol (s) => (Symbol (s), HashSet::default()),
+            Quote (box imm) => (quote_scm(imm), HashSet::default()),
+            Void => (Void, HashSet::default()),
             other => panic!("Invalid Program {}", other),
         }
     }
@@ -537,48 +955,98 @@ impl PurifyLetrec {
                 values.into_iter().map(|e| self.purify(e)).collect()
             ),
             Let (mut bindings, box Assigned (assigned, box body)) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.purify(v));
                 }
                 return let_scm(new_bindings, Assigned (assigned, Box::new(self.purify(body))));
             }
-            Letrec (mut bindings, box Assigned (assigned, box body)) if assigned.len() == 0 => {
-                let mut new_bindings = HashMap::new();
-                for (k, v) in bindings.drain() {
-                    new_bindings.insert(k, self.purify(v));
-                }
-                return letrec_scm(new_bindings, self.purify(body));
-            }
+            // (letrec ([x e] ...) body) is split into simple bindings, which refer to none of the x,
+            // lambdas and complex bindings. The lambdas come first, an assigned one is set first,
+            // then the complex bindings are evaluated and assigned.
+            //   (let ([xs e] ...) (let ([xc (void)] ...) (letrec ([xl (lambda ...)] ...) 
+            //     (begin (set! xa (lambda ...)) ... (let ([t e] ...) (set! xc t) ...) body))))
+            // An x which is not bound to a lambda is unsafe before the letrec is done, and so is a 
+            // lambda which may reach one. A reference to an unsafe x outside any lambda in an e is 
+            // an error. Inside a lambda in a complex e, the lambda may be called before the letrec
+            // is done, such a reference checks a flag at runtime.
             Letrec (mut bindings, box Assigned (assigned, box body)) => {
-                let mut void_bindings = HashMap::new();
-                let mut val_bindings = HashMap::new();
-                let mut exprs = vec![];
-                for (k, mut val) in bindings.drain() {
-                    if assigned.contains(&k) {
-                        void_bindings.insert(k.clone(), Void);
-                        val = self.purify(val);
-                        let tmp = gen_uvar();
-                        val_bindings.insert(tmp.clone(), val);
-                        exprs.push(set1_scm(Symbol (k), Symbol (tmp)));
-                    } else {
-                        val_bindings.insert(k, self.purify(val));
+                let vars: HashSet<String> = bindings.keys().cloned().collect();
+                let unsafe_vars = letrec_unsafe(&bindings);
+                let mut keys: Vec<String> = bindings.keys().cloned().collect();
+                keys.sort();
+                let mut simple = HashMap::default();
+                let mut lambdas = HashMap::default();
+                let mut early = vec![];
+                let mut complex = vec![];
+                for k in keys {
+                    let val = bindings.remove(&k).unwrap();
+                    let mut refs = vec![];
+                    self.references(&val, &vars, false, &mut refs);
+                    if let Some ((x, _)) = refs.iter().find(|(x, protected)| !protected && unsafe_vars.contains(x)) {
+                        panic!("Invalid letrec: variable {} referenced before its initialization in the binding of {}",
+                            source_name(x), source_name(&k));
                     }
+                    let guarded = refs.iter().any(|(x, _)| unsafe_vars.contains(x));
+                    let val = self.purify(val);
+                    match val {
+                        Lambda (..) if !assigned.contains(&k) => { lambdas.insert(k, val); }
+                        Lambda (..) => early.push((k, val)),
+                        _ if refs.is_empty() && !assigned.contains(&k) => { simple.insert(k, val); }
+                        _ => complex.push((k, val, guarded)),
+                    }
+                }
+                let body = self.purify(body);
+                let mut new_assigned = assigned;
+                let mut void_bindings = HashMap::default();
+                let mut val_bindings = HashMap::default();
+                let mut exprs = vec![];
+                let need_flag = complex.iter().any(|(_, _, guarded)| *guarded);
+                let valid = gen_uvar();
+                if need_flag {
+                    void_bindings.insert(valid.clone(), quote_scm(Bool (false)));
+                    new_assigned.insert(valid.clone());
+                }
+                let mut sets = vec![];
+                for (k, val) in early {
+                    sets.push(set1_scm(Symbol (k.clone()), val));
+                    void_bindings.insert(k, Void);
+                }
+                for (k, val, guarded) in complex {
+                    let val = if guarded { self.guard(val, &unsafe_vars, &valid) } else { val };
+                    let tmp = gen_uvar();
+                    val_bindings.insert(tmp.clone(), val);
+                    exprs.push(set1_scm(Symbol (k.clone()), Symbol (tmp)));
+                    void_bindings.insert(k.clone(), Void);
+                    new_assigned.insert(k);
+                }
+                if need_flag {
+                    exprs.push(set1_scm(Symbol (valid), quote_scm(Bool (true))));
+                }
+                if !exprs.is_empty() {
+                    sets.push(let_scm(val_bindings, Assigned (HashSet::default(), Box::new(Begin (exprs)))));
+                }
+                let mut scm = body;
+                if !sets.is_empty() {
+                    sets.push(scm);
+                    scm = Begin (sets);
+                }
+                if !lambdas.is_empty() {
+                    scm = letrec_scm(lambdas, scm);
+                }
+                if !void_bindings.is_empty() {
+                    scm = let_scm(void_bindings, Assigned (new_assigned, Box::new(scm)));
+                }
+                if !simple.is_empty() {
+                    scm = let_scm(simple, Assigned (HashSet::default(), Box::new(scm)));
                 }
-                let_scm(void_bindings, 
-                    Assigned (assigned, Box::new(
-                        Begin (vec![
-                            let_scm(val_bindings, 
-                                Assigned (HashSet::new(), Box::new(
-                                    Begin (exprs)))),
-                            self.purify(body)
-                        ])
-                    )))
+                return scm;
             }
             Lambda (args, box Assigned (assigned, box body)) => lambda_scm(args, Assigned (assigned, Box::new(self.purify(body)))),
             Prim1 (op, box e) => prim1_scm(op, self.purify(e)),
             Prim2 (op, box e1, box e2) => prim2_scm(op, self.purify(e1), self.purify(e2)),
             Prim3 (op, box e1, box e2, box e3) => prim3_scm(op, self.purify(e1), self.purify(e2), self.purify(e3)),
+            PrimN (op, exprs) => PrimN (op, exprs.into_iter().map(|e| self.purify(e)).collect()),
             Set (box sym, box e) => set1_scm(sym, self.purify(e)),
             Symbol (s) => Symbol (s),
             Quote (box imm) => quote_scm(imm),
@@ -586,12 +1054,133 @@ impl PurifyLetrec {
             other => panic!("Invalid Program {}", other),
         }
     }
+
+    // collect the references to vars in scm, and whether each one is inside a lambda.
+    // A variable bound inside scm hides the one of vars.
+    fn references(&self, scm: &Scheme, vars: &HashSet<String>, protected: bool, refs: &mut Vec<(String, bool)>) {
+        use Scheme::*;
+        let hide = |bound: Vec<&String>| -> HashSet<String> {
+            vars.iter().filter(|v| !bound.contains(v)).cloned().collect()
+        };
+        match scm {
+            If (box pred, box b1, box b2) => {
+                self.references(pred, vars, protected, refs);
+                self.references(b1, vars, protected, refs);
+                self.references(b2, vars, protected, refs);
+            }
+            Begin (exprs) | PrimN (_, exprs) => exprs.iter().for_each(|e| self.references(e, vars, protected, refs)),
+            Funcall (box func, values) => {
+                self.references(func, vars, protected, refs);
+                values.iter().for_each(|e| self.references(e, vars, protected, refs));
+            }
+            Let (bindings, box body) => {
+                bindings.values().for_each(|e| self.references(e, vars, protected, refs));
+                self.references(body, &hide(bindings.keys().collect()), protected, refs);
+            }
+            Letrec (bindings, box body) => {
+                let vars = hide(bindings.keys().collect());
+                bindings.values().for_each(|e| self.references(e, &vars, protected, refs));
+                self.references(body, &vars, protected, refs);
+            }
+            Lambda (args, box body) => self.references(body, &hide(args.iter().collect()), true, refs),
+            Assigned (_, box e) | Prim1 (_, box e) => self.references(e, vars, protected, refs),
+            Prim2 (_, box e1, box e2) => {
+                self.references(e1, vars, protected, refs);
+                self.references(e2, vars, protected, refs);
+            }
+            Prim3 (_, box e1, box e2, box e3) => {
+                self.references(e1, vars, protected, refs);
+                self.references(e2, vars, protected, refs);
+                self.references(e3, vars, protected, refs);
+            }
+            Set (box sym, box e) => {
+                self.references(sym, vars, protected, refs);
+                self.references(e, vars, protected, refs);
+            }
+            Symbol (s) if vars.contains(s) => refs.push((s.to_string(), protected)),
+            _ => {}
+        }
+    }
+
+    // a reference to one of vars becomes (begin (if valid (void) (letrec-error)) x),
+    // and so does an assignment to one
+    fn guard(&self, scm: Scheme, vars: &HashSet<String>, valid: &str) -> Scheme {
+        use Scheme::*;
+        match scm {
+            If (box pred, box b1, box b2) => if2_scm(
+                self.guard(pred, vars, valid),
+                self.guard(b1, vars, valid),
+                self.guard(b2, vars, valid),
+            ),
+            Begin (exprs) => Begin (exprs.into_iter().map(|e| self.guard(e, vars, valid)).collect()),
+            Funcall (box func, values) => funcall_scm(
+                self.guard(func, vars, valid),
+                values.into_iter().map(|e| self.guard(e, vars, valid)).collect()
+            ),
+            Let (mut bindings, box body) => {
+                let mut new_bindings = HashMap::default();
+                for (k, v) in bindings.drain() {
+                    new_bindings.insert(k, self.guard(v, vars, valid));
+                }
+                return let_scm(new_bindings, self.guard(body, vars, valid));
+            }
+            Letrec (mut bindings, box body) => {
+                let mut new_bindings = HashMap::default();
+                for (k, v) in bindings.drain() {
+                    new_bindings.insert(k, self.guard(v, vars, valid));
+                }
+                return letrec_scm(new_bindings, self.guard(body, vars, valid));
+            }
+            Lambda (args, box body) => lambda_scm(args, self.guard(body, vars, valid)),
+            Assigned (assigned, box e) => Assigned (assigned, Box::new(self.guard(e, vars, valid))),
+            Prim1 (op, box e) => prim1_scm(op, self.guard(e, vars, valid)),
+            Prim2 (op, box e1, box e2) => prim2_scm(op, self.guard(e1, vars, valid), self.guard(e2, vars, valid)),
+            Prim3 (op, box e1, box e2, box e3) => prim3_scm(op, self.guard(e1, vars, valid), self.guard(e2, vars, valid), self.guard(e3, vars, valid)),
+            PrimN (op, exprs) => PrimN (op, exprs.into_iter().map(|e| self.guard(e, vars, valid)).collect()),
+            Set (box Symbol (s), box e) if vars.contains(&s) => Begin (vec![
+                if2_scm(Symbol (valid.to_string()), Void, PrimN ("letrec-error".to_string(), vec![])),
+                set1_scm(Symbol (s), self.guard(e, vars, valid)),
+            ]),
+            Set (box sym, box e) => set1_scm(sym, self.guard(e, vars, valid)),
+            Symbol (s) if vars.contains(&s) => Begin (vec![
+                if2_scm(Symbol (valid.to_string()), Void, PrimN ("letrec-error".to_string(), vec![])),
+                Symbol (s),
+            ]),
+            other => other,
+        }
+    }
+}
+
+// the variables of a letrec which are unsafe before it is done, those not bound to a lambda 
+// and the lambdas which may reach one of them. The interpreters follow PurifyLetrec with it.
+pub fn letrec_unsafe(bindings: &HashMap<String, Scheme>) -> HashSet<String> {
+    let vars: HashSet<String> = bindings.keys().cloned().collect();
+    let mut unsafe_vars: HashSet<String> = bindings.iter()
+        .filter(|(_, e)| !matches!(e, Scheme::Lambda (..)))
+        .map(|(k, _)| k.clone())
+        .collect();
+    let mut reach = HashMap::default();
+    for (k, e) in bindings {
+        let mut refs = vec![];
+        PurifyLetrec{}.references(e, &vars, false, &mut refs);
+        reach.insert(k.clone(), refs);
+    }
+    loop {
+        let more: Vec<String> = reach.iter()
+            .filter(|(k, refs)| !unsafe_vars.contains(*k) && refs.iter().any(|(x, _)| unsafe_vars.contains(x)))
+            .map(|(k, _)| k.clone())
+            .collect();
+        if more.is_empty() {
+            return unsafe_vars;
+        }
+        unsafe_vars.extend(more);
+    }
 }
 
 pub struct ConvertAssignment {}
 impl ConvertAssignment {
     pub fn run(&self, scm: Scheme) -> Scheme {
-        let mut assigned_sets = HashSet::new();
+        let mut assigned_sets = HashSet::default();
         self.convert(scm, &mut assigned_sets)
     }
 
@@ -612,14 +1201,14 @@ impl ConvertAssignment {
             ),
             Let (mut bindings, box Assigned (assigned, box body)) => {
                 if assigned.is_empty() { 
-                    let mut new_bindings = HashMap::new();
+                    let mut new_bindings = HashMap::default();
                     for (k, val) in bindings.drain() {
                         new_bindings.insert(k, self.convert(val, assigned_sets));
                     }
                     return let_scm(new_bindings, self.convert(body, assigned_sets)); 
                 }
-                let mut rename_bindings = HashMap::new();
-                let mut assign_bindings = HashMap::new();
+                let mut rename_bindings = HashMap::default();
+                let mut assign_bindings = HashMap::default();
                 for (k, mut val) in bindings.drain() {
                     // val should not see the assigned variables in this form
                     val = self.convert(val, assigned_sets);
@@ -629,7 +1218,7 @@ impl ConvertAssignment {
                         assigned_sets.insert(k.clone());
                         // rename assigned variable
                         let tmp = gen_uvar();
-                        assign_bindings.insert(k, prim2_scm("cons".to_string(), Symbol (tmp.clone()), quote_scm(EmptyList)));
+                        assign_bindings.insert(k, prim1_scm("box".to_string(), Symbol (tmp.clone())));
                         tmp
                     } else { k };
                     rename_bindings.insert(new_k, val);
@@ -637,7 +1226,7 @@ impl ConvertAssignment {
                 return let_scm(rename_bindings, let_scm(assign_bindings, self.convert(body, assigned_sets)));
             }
             Letrec (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.convert(val, assigned_sets));
                 }
@@ -646,12 +1235,12 @@ impl ConvertAssignment {
             Lambda (args, box Assigned (assigned, box body)) => {
                 if assigned.is_empty() { return lambda_scm(args, self.convert(body, assigned_sets)); }
                 let mut new_args = vec![];
-                let mut assigned_bindings = HashMap::new();
+                let mut assigned_bindings = HashMap::default();
                 for a in args {
                     if assigned.contains(&a) {
                         assigned_sets.insert(a.clone());
-                        let tmp = gen_uvar();
-                        assigned_bindings.insert(a, prim2_scm("cons".to_string(), Symbol (tmp.clone()), quote_scm(EmptyList)));
+                        let tmp = if is_rest(&a) { gen_rest() } else { gen_uvar() };
+                        assigned_bindings.insert(a, prim1_scm("box".to_string(), Symbol (tmp.clone())));
                         new_args.push(tmp);
                     } else {
                         new_args.push(a);
@@ -663,10 +1252,11 @@ impl ConvertAssignment {
             Prim2 (op, box e1, box e2) => prim2_scm(op, self.convert(e1, assigned_sets), self.convert(e2, assigned_sets)),
             Prim3 (op, box e1, box e2, box e3) 
                 => prim3_scm(op, self.convert(e1, assigned_sets), self.convert(e2, assigned_sets), self.convert(e3, assigned_sets)),
-            Set (box sym, box e) => prim2_scm("set-car!".to_string(), sym, self.convert(e, assigned_sets)),
+            PrimN (op, exprs) => PrimN (op, exprs.into_iter().map(|e| self.convert(e, assigned_sets)).collect()),
+            Set (box sym, box e) => prim2_scm("set-box!".to_string(), sym, self.convert(e, assigned_sets)),
             Symbol (s) => {
                 if assigned_sets.contains(&s) {
-                    return prim1_scm("car".to_string(), Symbol (s));
+                    return prim1_scm("unbox".to_string(), Symbol (s));
                 } else {
                     return Symbol (s);
                 }
@@ -695,8 +1285,8 @@ impl OptimizeDirectCall {
             Begin (mut exprs) => Begin (
                 exprs.into_iter().map(|e| self.optimize(e)).collect()
             ),
-            Funcall (box Lambda (args, box body), values) if args.len() == values.len() => {
-                let mut bindings = HashMap::new();
+            Funcall (box Lambda (args, box body), values) if args.len() == values.len() && !args.iter().any(|a| is_rest(a)) => {
+                let mut bindings = HashMap::default();
                 for (arg, val) in args.into_iter().zip(values) {
                     bindings.insert(arg, self.optimize(val));
                 }
@@ -707,14 +1297,14 @@ impl OptimizeDirectCall {
                 values.into_iter().map(|e| self.optimize(e)).collect()
             ),
             Let (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.optimize(v));
                 }
                 return let_scm(new_bindings, self.optimize(body));
             }
             Letrec (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.optimize(v));
                 }
@@ -724,6 +1314,7 @@ impl OptimizeDirectCall {
             Prim1 (op, box e) => prim1_scm(op, self.optimize(e)),
             Prim2 (op, box e1, box e2) => prim2_scm(op, self.optimize(e1), self.optimize(e2)),
             Prim3 (op, box e1, box e2, box e3) => prim3_scm(op, self.optimize(e1), self.optimize(e2), self.optimize(e3)),
+            PrimN (op, exprs) => PrimN (op, exprs.into_iter().map(|e| self.optimize(e)).collect()),
             Symbol (s) => Symbol (s),
             Quote (box imm) => quote_scm(imm),
             Void => Void,
@@ -754,14 +1345,14 @@ impl RemoveAnonymousLambda {
                 values.into_iter().map(|e| self.remove(e, true)).collect()
             ),
             Let (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.remove(v, false));
                 }
                 return let_scm(new_bindings, self.remove(body, true));
             }
             Letrec (mut bindings, box body) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     new_bindings.insert(k, self.remove(v, false));
                 }
@@ -771,7 +1362,7 @@ impl RemoveAnonymousLambda {
                 let scm = lambda_scm(args, self.remove(body, true));
                 if anonymous {
                     let tmp = gen_anon();
-                    let mut new_bindings = HashMap::new();
+                    let mut new_bindings = HashMap::default();
                     new_bindings.insert(tmp.clone(), scm);
                     return letrec_scm(new_bindings, Symbol (tmp));
                 }
@@ -781,6 +1372,7 @@ impl RemoveAnonymousLambda {
             Prim2 (op, box e1, box e2) => prim2_scm(op, self.remove(e1, true), self.remove(e2, true)),
             Prim3 (op, box e1, box e2, box e3) => 
                 prim3_scm(op, self.remove(e1, true), self.remove(e2, true), self.remove(e3, true)),
+            PrimN (op, exprs) => PrimN (op, exprs.into_iter().map(|e| self.remove(e, true)).collect()),
             Symbol (s) => Symbol (s),
             Quote (box imm) => quote_scm(imm),
             Void => Void,
@@ -811,8 +1403,8 @@ impl SanitizeBindingForms {
                 values.into_iter().map(|e| self.sanitize(e)).collect()
             ),
             Let (mut bindings, box body) => {
-                let mut let_bindings = HashMap::new();
-                let mut letrec_bindings = HashMap::new();
+                let mut let_bindings = HashMap::default();
+                let mut letrec_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     let v = self.sanitize(v);
                     if let Lambda (_args, _body) = &v {
@@ -828,8 +1420,8 @@ impl SanitizeBindingForms {
                 return let_scm(let_bindings, letrec_scm(letrec_bindings, body));
             }
             Letrec (mut bindings, box body) => {
-                let mut let_bindings = HashMap::new();
-                let mut letrec_bindings = HashMap::new();
+                let mut let_bindings = HashMap::default();
+                let mut letrec_bindings = HashMap::default();
                 for (k, v) in bindings.drain() {
                     let v = self.sanitize(v);
                     if let Lambda (_args, _body) = &v {
@@ -848,6 +1440,7 @@ impl SanitizeBindingForms {
             Prim1 (op, box e) => prim1_scm(op, self.sanitize(e)),
             Prim2 (op, box e1, box e2) => prim2_scm(op, self.sanitize(e1), self.sanitize(e2)),
             Prim3 (op, box e1, box e2, box e3) => prim3_scm(op, self.sanitize(e1), self.sanitize(e2), self.sanitize(e3)),
+            PrimN (op, exprs) => PrimN (op, exprs.into_iter().map(|e| self.sanitize(e)).collect()),
             Symbol (s) => Symbol (s),
             Quote (box imm) => quote_scm(imm),
             Void => Void,
@@ -867,12 +1460,12 @@ impl UncoverFree {
         use Scheme::*;
         match scm {
             Symbol (s) => {
-                let mut free = HashSet::new();
+                let mut free = HashSet::default();
                 free.insert(s.clone());
                 return (free, Symbol (s));
             }
-            Quote (box imm) => (HashSet::new(), quote_scm(imm)),
-            Void => (HashSet::new(), Void),
+            Quote (box imm) => (HashSet::default(), quote_scm(imm)),
+            Void => (HashSet::default(), Void),
             If (box pred, box b1, box b2) => {
                 let (pf, pred) = self.uncover_free(pred);
                 let (bf1, b1) = self.uncover_free(b1);
@@ -892,7 +1485,7 @@ impl UncoverFree {
                 return (new_set, Begin (new_exprs));
             }
             Let (mut bindings, box e) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 let mut sets = vec![];
                 for (k, v) in bindings.drain() {
                     let (fset, v) = self.uncover_free(v);
@@ -908,7 +1501,7 @@ impl UncoverFree {
                 return (new_set, let_scm(new_bindings, e));
             }
             Letrec (mut lambdas, box e) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 let mut sets = vec![];
                 for (k, v) in lambdas.drain() {
                     let (fset, v) = self.uncover_free(v);
@@ -940,6 +1533,16 @@ impl UncoverFree {
                 let new_set = self.union_freeset(vec![fset1, fset2, fset3]);
                 return (new_set, prim3_scm(op, e1, e2, e3));
             }
+            PrimN (op, mut exprs) => {
+                let mut sets = vec![];
+                exprs = exprs.into_iter().map(|e| {
+                    let (fset, e) = self.uncover_free(e);
+                    sets.push(fset);
+                    e
+                }).collect();
+                let new_set = self.union_freeset(sets);
+                return (new_set, PrimN (op, exprs));
+            }
             Funcall (box func, mut args) => {
                 let (fset1, func) = self.uncover_free(func);
                 let mut sets = vec![fset1];
@@ -993,21 +1596,21 @@ impl ConvertClosure {
                 return Begin (exprs);
             }
             Let (mut bindings, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in bindings.into_iter() {
                     new_bindings.insert(k, self.convert_closure(v));
                 }
                 return let_scm(new_bindings, self.convert_closure(value));
             }
             Letrec (mut bindings, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 let mut clos = vec![];
                 for (k, v) in bindings.drain() {
                     if let Lambda (mut args, box Free (mut fvars, box body)) = v {
                         let label = uvar_to_label(&k);
                         clos.push((k.clone(), label.clone(), fvars.clone()));   // prepare closures
                         let new_body = self.convert_closure(body);
-                        args.push(k.clone());                                   // cp as argument
+                        args.insert(0, k.clone());                              // cp as argument
                         fvars.push(k);                                          // cp into bind-free form
                         let new_lambda = lambda_scm(args, Bindfree (fvars, Box::new(new_body)));
                         new_bindings.insert(label, new_lambda);
@@ -1020,10 +1623,20 @@ impl ConvertClosure {
                 let closures = Closures (clos, Box::new(new_value));
                 return letrec_scm(new_bindings, closures);
             }
+            // these primitives become calls to the runtime, and the runtime takes no cp
+            Prim1 (op, box e) if runtime_label(&op).is_some() => {
+                let e = self.convert_closure(e);
+                return funcall_scm(Symbol (runtime_label(&op).unwrap().to_string()), vec![e]);
+            }
             Prim1 (op, box e) => {
                 let e = self.convert_closure(e);
                 return prim1_scm(op, e);
             }
+            Prim2 (op, box e1, box e2) if runtime_label(&op).is_some() => {
+                let e1 = self.convert_closure(e1);
+                let e2 = self.convert_closure(e2);
+                return funcall_scm(Symbol (runtime_label(&op).unwrap().to_string()), vec![e1, e2]);
+            }
             Prim2 (op, box e1, box e2) => {
                 let e1 = self.convert_closure(e1);
                 let e2 = self.convert_closure(e2);
@@ -1035,18 +1648,22 @@ impl ConvertClosure {
                 let e3 = self.convert_closure(e3);
                 return prim3_scm(op, e1, e2, e3);
             }
+            PrimN (op, exprs) if runtime_label(&op).is_some() => {
+                let exprs = exprs.into_iter().map(|e| self.convert_closure(e)).collect();
+                return funcall_scm(Symbol (runtime_label(&op).unwrap().to_string()), exprs);
+            }
             Funcall (box func, mut args) => {
                 args = args.into_iter().map(|x| self.convert_closure(x)).collect();
-                // I choose to add cp as the last argument
+                // cp is the first argument, so it stays in place when a rest argument follows
                 if let Symbol (s) = &func {
-                    args.push(Symbol (s.to_string())); 
+                    args.insert(0, Symbol (s.to_string())); 
                     return funcall_scm(func, args);
                 } 
                 // func is a complex expression
                 let tmp = gen_uvar();
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 new_bindings.insert(tmp.clone(), self.convert_closure(func));
-                args.push(Symbol (tmp.clone()));
+                args.insert(0, Symbol (tmp.clone()));
                 return let_scm(new_bindings, funcall_scm(Symbol (tmp), args));
             }
             e => panic!("Invalid Program {}", e),
@@ -1057,7 +1674,7 @@ impl ConvertClosure {
 pub struct OptimizeKnownCall {}
 impl OptimizeKnownCall {
     pub fn run(&self, scm: Scheme) -> Scheme {
-        let mut mapping = HashMap::new();
+        let mut mapping = HashMap::default();
         self.optimize(scm, &mut mapping)
     }
 
@@ -1078,7 +1695,7 @@ impl OptimizeKnownCall {
                 return Begin (exprs);
             }
             Let (mut bindings, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.optimize(val, mapping));
                 }
@@ -1087,7 +1704,7 @@ impl OptimizeKnownCall {
             Letrec (mut bindings, box clos) => {
                 // here, we should collects closures firstly. or we will lost some optimization.
                 let clos = self.optimize(clos, mapping);
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.optimize(val, mapping));
                 }
@@ -1150,7 +1767,7 @@ impl IntroduceProceduraPrimitives {
                 return Begin (exprs);
             }
             Let (mut bindings, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.intro(val, cp, fvars));
                 }
@@ -1158,7 +1775,7 @@ impl IntroduceProceduraPrimitives {
             }
             // letrec deconstruct into Lambda and Closures as follow
             Letrec (mut bindings, box clos) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.intro(val, cp, fvars));
                 }
@@ -1173,7 +1790,7 @@ impl IntroduceProceduraPrimitives {
             // separate it from letrec to show its self-contained.
             // BE CAREFUL: there are two cp, fvars. 
             Closures (clos, box body) => {
-                let mut bindings = HashMap::new();
+                let mut bindings = HashMap::default();
                 let mut exprs = vec![];
                 for (clos_cp, clos_code, clos_fvars) in clos {
                     let length = clos_fvars.len();
@@ -1229,7 +1846,7 @@ pub struct LiftLetrec {}
 impl LiftLetrec {
     pub fn run(&self, scm: Scheme) -> Scheme {
         use Scheme::*;
-        let mut lambdas = HashMap::new();
+        let mut lambdas = HashMap::default();
         let body = self.lift_letrec(scm, &mut lambdas);
         return Letrec (lambdas, Box::new(body));
     }
@@ -1248,7 +1865,7 @@ impl LiftLetrec {
                 return Begin (exprs);
             }
             Let (mut bindings, box tail) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.lift_letrec(val, lambdas));
                 }
@@ -1288,7 +1905,7 @@ impl NormalizeContext {
         use Scheme::*;
         match scm {
             Letrec (mut lambdas, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in lambdas.drain() {
                     new_bindings.insert(k, self.value_helper(v));
                 }
@@ -1314,7 +1931,7 @@ impl NormalizeContext {
                 return make_nopless_begin(exprs);
             }
             Let (mut bindings, box tail) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.value_helper(val));
                 }
@@ -1379,7 +1996,7 @@ impl NormalizeContext {
                 return make_nopless_begin(exprs);
             }
             Let (mut bindings, box pred) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.value_helper(val));
                 }
@@ -1450,7 +2067,7 @@ impl NormalizeContext {
                 return make_nopless_begin(exprs);
             }
             Let (mut bindings, box tail) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, val) in bindings.drain() {
                     new_bindings.insert(k, self.value_helper(val));
                 }
@@ -1496,7 +2113,7 @@ impl SpecifyRepresentation {
         use Scheme::*;
         match scm {
             Letrec (mut lambdas, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in lambdas.drain() {
                     new_bindings.insert(k, self.value_helper(v));
                 }
@@ -1522,7 +2139,7 @@ impl SpecifyRepresentation {
                 return Begin (exprs);
             }
             Let (mut bindings, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (sym, val) in bindings.drain() {
                     new_bindings.insert(sym, self.value_helper(val));
                 }
@@ -1538,7 +2155,7 @@ impl SpecifyRepresentation {
                 let tmp = gen_uvar();
                 let vsize = (i << ALIGN_SHIFT) + DISP_VDATA;
                 let ptr = prim2_scm("+".to_string(), Alloc (Box::new(Int64 (vsize))), Int64 (TAG_VECTOR));
-                let mut bindings = HashMap::new();
+                let mut bindings = HashMap::default();
                 bindings.insert(tmp.clone(), ptr);
                 let exprs = vec![
                     mset_scm(Symbol (tmp.clone()), Int64 (VLEN_OFFSET), Int64 (i << SHIFT_FIXNUM)),
@@ -1553,14 +2170,29 @@ impl SpecifyRepresentation {
                     "cdr" => mref_scm(new_value, Int64 (CDR_OFFSET)),
                     "vector-length" => mref_scm(new_value, Int64 (VLEN_OFFSET)),
                     "procedure-code" => mref_scm(new_value, Int64 (PROC_CODE_OFFSET)),
+                    "unbox" => mref_scm(new_value, Int64 (BOX_OFFSET)),
+                    "box" => {
+                        let tmp_val = gen_uvar();
+                        let mut bindings = HashMap::default();
+                        bindings.insert(tmp_val.clone(), new_value);
+                        let tmp = gen_uvar();
+                        let ptr = prim2_scm("+".to_string(), Alloc (Box::new(Int64 (SIZE_BOX))), Int64 (TAG_BOX));
+                        let mut bindings_ptr = HashMap::default();
+                        bindings_ptr.insert(tmp.clone(), ptr);
+                        let exprs = vec![
+                            mset_scm(Symbol (tmp.clone()), Int64 (BOX_OFFSET), Symbol (tmp_val)),
+                            Symbol (tmp),
+                        ];
+                        return let_scm(bindings, let_scm(bindings_ptr, Begin (exprs)));
+                    }
                     "make-vector" => {
                         let tmp1 = gen_uvar();                            
-                        let mut bindings1 = HashMap::new();
+                        let mut bindings1 = HashMap::default();
                         bindings1.insert(tmp1.clone(), new_value);
                         let tmp2 = gen_uvar();
                         let vsize = prim2_scm("+".to_string(), Int64 (DISP_VDATA), Symbol (tmp1.clone()));
                         let ptr = prim2_scm("+".to_string(), Alloc (Box::new(vsize)), Int64 (TAG_VECTOR));
-                        let mut bindings2 = HashMap::new();
+                        let mut bindings2 = HashMap::default();
                         bindings2.insert(tmp2.clone(), ptr);
                         let exprs = vec![
                             mset_scm(Symbol (tmp2.clone()), Int64 (VLEN_OFFSET), Symbol (tmp1)),
@@ -1580,7 +2212,7 @@ impl SpecifyRepresentation {
                 let tmp = gen_uvar();
                 let vsize = (i << ALIGN_SHIFT) + DISP_PDATA;
                 let ptr = prim2_scm("+".to_string(), Alloc (Box::new(Int64 (vsize))), Int64 (TAG_PROC));
-                let mut bindings = HashMap::new();
+                let mut bindings = HashMap::default();
                 bindings.insert(tmp.clone(), ptr);
                 let exprs = vec![
                     mset_scm(Symbol (tmp.clone()), Int64 (PROC_CODE_OFFSET), labl),
@@ -1588,10 +2220,26 @@ impl SpecifyRepresentation {
                 ];
                 return let_scm(bindings, Begin (exprs));
             } 
-            Prim2 (op, box e, box Quote (box Int64 (i))) if op.as_str() == "vector-ref" || op.as_str() == "procedure-ref" => {
+            Prim2 (op, box rtd, box Quote (box Int64 (i))) if op.as_str() == "make-record" => {
+                let tmp_rtd = gen_uvar();
+                let mut bindings_rtd = HashMap::default();
+                bindings_rtd.insert(tmp_rtd.clone(), self.value_helper(rtd));
+                let tmp = gen_uvar();
+                let vsize = (i << ALIGN_SHIFT) + DISP_RDATA;
+                let ptr = prim2_scm("+".to_string(), Alloc (Box::new(Int64 (vsize))), Int64 (TAG_RECORD));
+                let mut bindings = HashMap::default();
+                bindings.insert(tmp.clone(), ptr);
+                let exprs = vec![
+                    mset_scm(Symbol (tmp.clone()), Int64 (RECORD_RTD_OFFSET), Symbol (tmp_rtd)),
+                    Symbol (tmp),
+                ];
+                return let_scm(bindings_rtd, let_scm(bindings, Begin (exprs)));
+            } 
+            Prim2 (op, box e, box Quote (box Int64 (i))) if op.as_str() == "vector-ref" || op.as_str() == "procedure-ref" || op.as_str() == "record-ref" => {
                 let offset = match op.as_str() {
                     "vector-ref" => VDATA_OFFSET,
                     "procedure-ref" => PROC_DATA_OFFSET,
+                    "record-ref" => RECORD_DATA_OFFSET,
                     other => panic!("Invalid prim2 {}", other),
                 };
                 let new_e = self.value_helper(e); 
@@ -1613,10 +2261,10 @@ impl SpecifyRepresentation {
                     "cons" => {
                         let tmp_car = gen_uvar();
                         let tmp_cdr = gen_uvar();
-                        let mut bindings = HashMap::new();
+                        let mut bindings = HashMap::default();
                         bindings.insert(tmp_car.clone(), new_v1);
                         bindings.insert(tmp_cdr.clone(), new_v2);
-                        let mut bindings_ptr = HashMap::new();
+                        let mut bindings_ptr = HashMap::default();
                         let tmp = gen_uvar();
                         let ptr = prim2_scm("+".to_string(), Alloc (Box::new(Int64 (SIZE_PAIR))), Int64 (TAG_PAIR));
                         bindings_ptr.insert(tmp.clone(), ptr);
@@ -1652,7 +2300,7 @@ impl SpecifyRepresentation {
                 return Begin(exprs);
             }
             Let (mut bindings, box effect) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (sym, val) in bindings.drain() {
                     new_bindings.insert(sym, self.value_helper(val));
                 }
@@ -1664,13 +2312,15 @@ impl SpecifyRepresentation {
                 match op.as_str() {
                     "set-car!" => mset_scm(new_v1, Int64 (CAR_OFFSET), new_v2),
                     "set-cdr!" => mset_scm(new_v1, Int64 (CDR_OFFSET), new_v2),
+                    "set-box!" => mset_scm(new_v1, Int64 (BOX_OFFSET), new_v2),
                     other => prim2_scm(op, new_v1, new_v2),
                 }
             }
-            Prim3 (op, box v1, box Quote (box Int64 (i)), box v3)  if op.as_str() == "vector-set!" || op.as_str() == "procedure-set!" => {
+            Prim3 (op, box v1, box Quote (box Int64 (i)), box v3)  if op.as_str() == "vector-set!" || op.as_str() == "procedure-set!" || op.as_str() == "record-set!" => {
                 let offset = match op.as_str() {
                     "vector-set!" => VDATA_OFFSET,
                     "procedure-set!" => PROC_DATA_OFFSET,
+                    "record-set!" => RECORD_DATA_OFFSET,
                     other => panic!("Invalid prim2 op {}", other),
                 };
                 let new_v1 = self.value_helper(v1);
@@ -1716,7 +2366,7 @@ impl SpecifyRepresentation {
                 return Begin(exprs);
             }
             Let (mut bindings, box pred) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (sym, val) in bindings.drain() {
                     new_bindings.insert(sym, self.value_helper(val));
                 }
@@ -1740,6 +2390,9 @@ impl SpecifyRepresentation {
                     "null?" => {
                         prim2_scm("=".to_string(), new_e1, Int64 (NIL))
                     }
+                    "box?" => {
+                        prim2_scm("=".to_string(), prim2_scm("logand".to_string(), new_e1, Int64 (MASK_BOX)), Int64 (TAG_BOX))
+                    }
                     "procedure?" => {
                         prim2_scm("=".to_string(), prim2_scm("logand".to_string(), new_e1, Int64 (MASK_PROC)), Int64 (TAG_PROC))
                     }
@@ -1751,6 +2404,15 @@ impl SpecifyRepresentation {
                 let new_e2 = self.value_helper(e2);
                 match op.as_str() {
                     "eq?" => prim2_scm("=".to_string(), new_e1, new_e2),
+                    // a record of the type, not any object with the record tag
+                    "record-of?" => {
+                        let tmp = gen_uvar();
+                        let mut bindings = HashMap::default();
+                        bindings.insert(tmp.clone(), new_e1);
+                        let is_record = prim2_scm("=".to_string(), prim2_scm("logand".to_string(), Symbol (tmp.clone()), Int64 (MASK_RECORD)), Int64 (TAG_RECORD));
+                        let is_type = prim2_scm("=".to_string(), mref_scm(Symbol (tmp), Int64 (RECORD_RTD_OFFSET)), new_e2);
+                        return let_scm(bindings, if2_scm(is_record, is_type, Bool (false)));
+                    }
                     other => prim2_scm(op, new_e1, new_e2),
                 }
             }
@@ -1777,7 +2439,7 @@ impl UncoverLocals {
         use Scheme::*;
         match scm {
             Letrec (mut lambdas, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in lambdas.drain() {
                     new_bindings.insert(k, self.helper(v));
                 }
@@ -1795,7 +2457,7 @@ impl UncoverLocals {
                 Lambda (args, Box::new(body))
             }
             tail => {
-                let mut locals = HashSet::new();
+                let mut locals = HashSet::default();
                 self.tail_helper(&tail, &mut locals);
                 Locals (locals, Box::new(tail))
             }
@@ -1954,7 +2616,7 @@ impl RemoveLet {
         use Scheme::*;
         match scm {
             Letrec (mut lambdas, box value) => {
-                let mut new_bindings = HashMap::new();
+                let mut new_bindings = HashMap::default();
                 for (k, v) in lambdas.drain() {
                     new_bindings.insert(k, self.helper(v));
                 }
@@ -2325,6 +2987,37 @@ const FRAME_POINTER_REGISTER :&str = "rbp";
 const RETURN_VALUE_REGISTER :&str = "rax";
 const RETRUN_ADDRESS_REGISTER :&str = "r15";
 const ALLOCATION_REGISTER :&str = "rdx";
+const ARGUMENT_COUNT_REGISTER :&str = "rcx";
+
+// runtime procedures emitted by CompileToAsm
+pub const APPLY_LABEL :&str = "apply$0";
+pub const COLLECT_REST_LABEL :&str = "collect-rest$0";
+pub const CALLCC_LABEL :&str = "callcc$0";
+pub const CONTINUATION_LABEL :&str = "continuation$0";
+pub const HANDLER_LABEL :&str = "with-exception-handler$0";
+pub const RAISE_LABEL :&str = "raise$0";
+pub const RAISE_CONTINUABLE_LABEL :&str = "raise-continuable$0";
+pub const ERROR_LABEL :&str = "error$0";
+pub const VALUES_LABEL :&str = "values$0";
+pub const APPLY_VALUES_LABEL :&str = "apply-values$0";
+pub const CALL_WITH_VALUES_LABEL :&str = "call-with-values$0";
+pub const LETREC_ERROR_LABEL :&str = "letrec-error$0";
+
+// C functions in runtime.c, they report to stderr and exit 
+const C_ERROR_LABEL :&str = "_scheme_error";
+const C_RAISE_LABEL :&str = "_scheme_raise";
+const C_LETREC_ERROR_LABEL :&str = "_scheme_letrec_error";
+
+// _scheme_entry keeps the stack base and the handler stack on top of the C stack
+const STACK_BASE_OFFSET :i64 = 0;
+const HANDLERS_OFFSET   :i64 = 8;
+
+// a continuation is a procedure whose data is the saved return address, the saved
+// handler stack, the size of the saved stack in bytes and the stack itself.
+pub const CONT_RP_OFFSET       :i64 = PROC_DATA_OFFSET;
+pub const CONT_HANDLERS_OFFSET :i64 = PROC_DATA_OFFSET + 8;
+pub const CONT_SIZE_OFFSET     :i64 = PROC_DATA_OFFSET + 16;
+pub const CONT_STACK_OFFSET    :i64 = PROC_DATA_OFFSET + 24;
 
 const FRAME_VARS :[&str; 101] = [
     "fv0", "fv1", "fv2", "fv3", "fv4", "fv5", "fv6", "fv7", "fv8", "fv9", "fv10", 
@@ -2341,7 +3034,7 @@ const FRAME_VARS :[&str; 101] = [
 
 const ALIGN_SHIFT: i64 = 3;
 // ---------------------- general utils --------------------------------
-fn is_reg(reg: &str) -> bool {
+pub fn is_reg(reg: &str) -> bool {
     REGISTERS.contains(&reg)
 }
 
@@ -2349,18 +3042,14 @@ fn is_fv(s: &str) -> bool {
     s.starts_with("fv")
 }
 
-fn is_nfv(s: &str) -> bool {
-    s.starts_with("nfv")
-}
-
-fn is_uvar(sym: &str) -> bool {
+pub fn is_uvar(sym: &str) -> bool {
     match sym.rfind('.') {
         Some(index) => index > 0 && index < sym.len() - 1,
         None => false,
     }
 }
 
-fn is_label(sym: &str) -> bool {
+pub fn is_label(sym: &str) -> bool {
     match sym.rfind('$') {
         Some(index) => index > 0 && index < sym.len() - 1,
         None => false,
@@ -2371,15 +3060,26 @@ fn fv_to_index(fv: &str) -> i64 {
     fv[2..].parse().unwrap()
 }
 
+const GENSYM_START: usize = 5000;
+
+thread_local! {
+    // one counter for each thread, so that tests running at the same time do not share it
+    static COUNTER: std::cell::Cell<usize> = std::cell::Cell::new(GENSYM_START);
+    // the uvars which are rest arguments
+    static RESTS: std::cell::RefCell<HashSet<String>> = std::cell::RefCell::new(HashSet::default());
+    // the names in the source of the letrec variables, for the errors of PurifyLetrec
+    static NAMES: std::cell::RefCell<HashMap<String, String>> = std::cell::RefCell::new(HashMap::default());
+}
+
 fn gensym(prefix: &str) -> String {
-    // When running test, data race happens
-    static mut counter :usize = 5000;
-    let mut s = String::from(prefix);
-    unsafe {
-        s.push_str(&counter.to_string());
-        counter += 1;
-    }
-    return s;
+    let n = COUNTER.with(|c| c.replace(c.get() + 1));
+    format!("{}{}", prefix, n)
+}
+
+// restarts the names, so that the same program compiles to the same text
+#[cfg(test)]
+pub fn reset_gensym() {
+    COUNTER.with(|c| c.set(GENSYM_START));
 }
 
 fn gen_label() -> String {
@@ -2837,7 +3537,7 @@ impl ImposeCallingConvention {
         match expr {
             Letrec (lambdas, box body) => {
                 let new_lambdas: Vec<Expr> = lambdas.into_iter()
-                                                .map(|e| self.lambda_helper(e))
+                                                .flat_map(|e| self.lambda_helper(e))
                                                 .collect();
                 let new_body = self.body_helper(body, vec![], "letrec");
                 return Letrec (new_lambdas, Box::new(new_body));
@@ -2846,14 +3546,41 @@ impl ImposeCallingConvention {
         }
     } 
 
-    fn lambda_helper(&self, expr: Expr) -> Expr {
+    fn lambda_helper(&self, expr: Expr) -> Vec<Expr> {
         if let Lambda (labl, args, box body) = expr {
+            if args.last().map_or(false, |a| is_rest(a)) {
+                // a variadic lambda is entered at labl, which conses the surplus arguments
+                // and then jumps to the body with the list as its last argument.
+                let body_labl = format!("{}$body", labl);
+                let entry = self.rest_entry(args.len() - 1, &body_labl);
+                let new_body = self.body_helper(body, args, &body_labl);
+                return vec![Lambda (labl, vec![], Box::new(entry)), Lambda (body_labl, vec![], Box::new(new_body))];
+            }
             let new_body = self.body_helper(body, args, &labl);
-            return Lambda (labl, vec![], Box::new(new_body));
+            return vec![Lambda (labl, vec![], Box::new(new_body))];
         }
         unreachable!()
     }
 
+    // collect-rest takes the index of the rest argument in rax and the body in r11
+    fn rest_entry(&self, index: usize, body_labl: &str) -> Expr {
+        let exprs = vec![
+            set1(Symbol ("rax".to_string()), Int64 (index as i64)),
+            set1(Symbol ("r11".to_string()), Symbol (body_labl.to_string())),
+            Funcall (Box::new(Symbol (COLLECT_REST_LABEL.to_string())), vec![
+                Symbol (FRAME_POINTER_REGISTER.to_string()),
+                Symbol (RETRUN_ADDRESS_REGISTER.to_string()),
+                Symbol (ALLOCATION_REGISTER.to_string()),
+                Symbol (ARGUMENT_COUNT_REGISTER.to_string()),
+                Symbol (PARAMETER_REGISTERS[0].to_string()),
+                Symbol (PARAMETER_REGISTERS[1].to_string()),
+                Symbol ("rax".to_string()),
+                Symbol ("r11".to_string()),
+            ]),
+        ];
+        return Locals (HashSet::default(), Box::new(NewFrames (Frame::default(), Box::new(Begin (exprs)))));
+    }
+
     fn body_helper(&self, expr: Expr, mut args: Vec<String>, rp: &str) -> Expr {
         if let Locals (mut uvars, box tail) = expr {
             uvars.insert(get_rp(rp));
@@ -2878,7 +3605,7 @@ impl ImposeCallingConvention {
             // assign frame var later if any
             exprs.append(&mut fv_assign);
 
-            let mut new_frame = Frame::new();
+            let mut new_frame = Frame::default();
             let new_tail = self.tail_helper(tail, rp, &mut new_frame);
             exprs.push(new_tail);
             for lst in new_frame.iter() {
@@ -2900,7 +3627,9 @@ impl ImposeCallingConvention {
                     Symbol (FRAME_POINTER_REGISTER.to_string()),
                     Symbol (RETRUN_ADDRESS_REGISTER.to_string()),
                     Symbol (ALLOCATION_REGISTER.to_string()),
+                    Symbol (ARGUMENT_COUNT_REGISTER.to_string()),
                 ];
+                let argc = args.len() as i64;
                 if args.len() > PARAMETER_REGISTERS.len() {
                     let fv_args = args.drain(PARAMETER_REGISTERS.len()..);
                     for (i, arg) in fv_args.into_iter().enumerate() {
@@ -2912,6 +3641,8 @@ impl ImposeCallingConvention {
                     exprs.push(set1(Symbol (reg.to_string()), arg));
                     liveset.push(Symbol (reg.to_string()));
                 }
+                // the callee learns how many arguments it got, see collect-rest
+                exprs.push(set1(Symbol (ARGUMENT_COUNT_REGISTER.to_string()), Int64 (argc)));
                 exprs.push(set1(Symbol (RETRUN_ADDRESS_REGISTER.to_string()), Symbol (get_rp(rp))));
                 let new_call = Funcall (labl, liveset);
                 exprs.push(new_call);
@@ -3007,7 +3738,9 @@ impl ImposeCallingConvention {
                     Symbol (FRAME_POINTER_REGISTER.to_string()),
                     Symbol (RETRUN_ADDRESS_REGISTER.to_string()),
                     Symbol (ALLOCATION_REGISTER.to_string()),
+                    Symbol (ARGUMENT_COUNT_REGISTER.to_string()),
                 ];
+                let argc = args.len() as i64;
                 if args.len() > PARAMETER_REGISTERS.len() {
                     let mut fvs = vec![];
                     for a in args.drain(PARAMETER_REGISTERS.len()..) {
@@ -3023,6 +3756,7 @@ impl ImposeCallingConvention {
                     liveset.push(Symbol (reg.to_string()));
                     exprs.push(set1(Symbol (reg.to_string()), val));
                 }
+                exprs.push(set1(Symbol (ARGUMENT_COUNT_REGISTER.to_string()), Int64 (argc)));
                 exprs.push(set1(Symbol (RETRUN_ADDRESS_REGISTER.to_string()), Symbol (rp_label.clone())));
                 let new_call = Funcall (Box::new(Symbol (labl)), liveset);
                 exprs.push(new_call);
@@ -3241,9 +3975,9 @@ impl UncoverFrameConflict {
         match expr {
             Lambda (labl, args, box body) => Lambda (labl, args, Box::new(self.helper(body))),
             Locals (uvars, box NewFrames (frames, box tail)) => {
-                let mut conflict_graph = ConflictGraph::new();
+                let mut conflict_graph = ConflictGraph::default();
                 for uvar in uvars.iter() {
-                    conflict_graph.insert(uvar.to_string(), HashSet::new());
+                    conflict_graph.insert(uvar.to_string(), HashSet::default());
                 }
                 let new_tail = self.uncover_conflict(conflict_graph, tail);
                 return Locals (uvars, Box::new(NewFrames (frames, Box::new(new_tail))));
@@ -3259,9 +3993,9 @@ impl UncoverConflict for UncoverFrameConflict {
     }
 
     fn uncover_conflict(&self, mut conflict_graph: ConflictGraph, tail: Expr) -> Expr {
-        let mut call_live = HashSet::new();
-        let mut spills = HashSet::new();
-        let _liveset = self.tail_liveset(&tail, HashSet::new(), &mut conflict_graph, &mut call_live);
+        let mut call_live = HashSet::default();
+        let mut spills = HashSet::default();
+        let _liveset = self.tail_liveset(&tail, HashSet::default(), &mut conflict_graph, &mut call_live);
         // spills are variables that going to be spilled into frame locations
         for var in call_live.iter() { if is_uvar(var) { spills.insert(var.to_string()); } }
         Spills (spills, Box::new(FrameConflict (conflict_graph, Box::new(CallLive (call_live, Box::new(tail))))))
@@ -3284,7 +4018,7 @@ impl PreAssignFrame {
         match expr {
             Lambda (label, args, box body) => Lambda (label, args, Box::new(self.helper(body))),
             Locals (mut uvars, box NewFrames (frames, box Spills (spills, box FrameConflict (fc_graph, box CallLive (call_live, box tail))))) => {
-                let mut bindings = HashMap::new();
+                let mut bindings = HashMap::default();
                 self.assign_frame(spills, &mut bindings, &fc_graph);
                 Locals (uvars, Box::new(
                     NewFrames (frames, Box::new(
@@ -3305,7 +4039,7 @@ impl PreAssignFrame {
     }
 
     fn find_compatible(&self, var: &String, bindings: &mut HashMap<String, String>, fc_graph: &ConflictGraph) -> String {
-        let mut uncompat: HashSet<&str> = HashSet::new();
+        let mut uncompat: HashSet<&str> = HashSet::default();
         let conflicts = fc_graph.get(var).unwrap();
         for (v, fv) in bindings {
             if conflicts.contains(v) {
@@ -3342,7 +4076,7 @@ impl AssignNewFrame {
                                     FrameConflict (fc_graph, box CallLive (call_live, box tail))))) => {
                 let frame_size = self.decide_frame_size(call_live, &bindings);
                 self.assign_new_frame(frame_size, frames, &mut bindings, &mut uvars);
-                Locals (uvars, Box::new(Ulocals (HashSet::new(), Box::new(
+                Locals (uvars, Box::new(Ulocals (HashSet::default(), Box::new(
                     Locate (bindings, Box::new(FrameConflict (fc_graph, Box::new(self.tail_helper(tail, frame_size)))))))))
             }
             _ => panic!("Invalid Program {}", expr),
@@ -3763,12 +4497,12 @@ impl UncoverRegisterConflict {
         match expr {
             Lambda (labl, args, box body) => Lambda (labl, args, Box::new(self.helper(body))),
             Locals (uvars, box Ulocals (unspills, box Locate (bindings, box FrameConflict (f_conflict_graph, box tail)))) => {
-                let mut r_conflict_graph = ConflictGraph::new();
+                let mut r_conflict_graph = ConflictGraph::default();
                 for u in uvars.iter() {
-                    r_conflict_graph.insert(u.to_string(), HashSet::new());
+                    r_conflict_graph.insert(u.to_string(), HashSet::default());
                 }
                 for u in unspills.iter() {
-                    r_conflict_graph.insert(u.to_string(), HashSet::new());
+                    r_conflict_graph.insert(u.to_string(), HashSet::default());
                 }
                 let new_tail = self.uncover_conflict(r_conflict_graph, tail);
                 Locals (uvars, Box::new(Ulocals (unspills, Box::new(Locate (bindings, Box::new(FrameConflict (f_conflict_graph, Box::new(new_tail))))))))
@@ -3784,8 +4518,8 @@ impl UncoverConflict for UncoverRegisterConflict {
     }
 
     fn uncover_conflict(&self, mut conflict_graph: ConflictGraph, tail: Expr) -> Expr {
-        let mut _callset = HashSet::new();
-        let _liveset = self.tail_liveset(&tail, HashSet::new(), &mut conflict_graph, &mut _callset);
+        let mut _callset = HashSet::default();
+        let _liveset = self.tail_liveset(&tail, HashSet::default(), &mut conflict_graph, &mut _callset);
         return RegisterConflict (conflict_graph, Box::new(tail));
     }
 }
@@ -3808,8 +4542,8 @@ impl AssignRegister {
         match expr {
             Lambda (label, args, box body) => Lambda (label, args, Box::new(self.helper(body))),
             Locals (mut uvars, box Ulocals (mut unspills, box Locate (bindings, box FrameConflict (fc_graph, box RegisterConflict (mut rc_graph, box tail))))) => {
-                let mut assigned = HashMap::new();
-                let mut spills = HashSet::new();
+                let mut assigned = HashMap::default();
+                let mut spills = HashSet::default();
                 let mut uvars_backup = uvars.clone();
                 let unspills_backup = unspills.clone();
                 self.assign_registers(&mut uvars, &mut unspills, rc_graph, &mut assigned, &mut spills);
@@ -3882,7 +4616,7 @@ impl AssignRegister {
     }
 
     fn find_available(&self, conflict: HashSet<String>, assigned: &HashMap<String, String>) -> Option<String> {
-        let mut unavailable: HashSet<&str> = HashSet::new();
+        let mut unavailable: HashSet<&str> = HashSet::default();
         // record the register that its conflicting variables already in use.
         for (var, reg) in assigned {
             if conflict.contains(var) {
@@ -3931,7 +4665,7 @@ impl AssignFrame {
     }
 
     fn find_compatible(&self, var: &String, bindings: &mut HashMap<String, String>, fc_graph: &ConflictGraph) -> String {
-        let mut uncompat: HashSet<&str> = HashSet::new();
+        let mut uncompat: HashSet<&str> = HashSet::default();
         let conflicts = fc_graph.get(var).unwrap();
         for (v, fv) in bindings {
             if conflicts.contains(v) {
@@ -4542,6 +5276,9 @@ impl CompileToAsm {
                     Push (Box::new(R13)),
                     Push (Box::new(R14)),
                     Push (Box::new(R15)),
+                    // the handler stack starts empty, and call/cc copies the stack from the stack base 
+                    Push (Box::new(Imm (NIL))),
+                    Push (Box::new(RDI)),
                     self.op2("movq", RDI, self.string_to_reg(FRAME_POINTER_REGISTER)),
                     self.op2("movq", RSI, self.string_to_reg(ALLOCATION_REGISTER)),
                     self.op2("leaq", DerefLabel(Box::new(RIP), Box::new(Label ("_scheme_exit".to_string()))), self.string_to_reg(RETRUN_ADDRESS_REGISTER)),
@@ -4560,9 +5297,22 @@ impl CompileToAsm {
                         e => panic!("Expect Lambda, found {}", e),
                     };
                 }
+                // runtime procedures
+                blocks.append(&mut self.apply_blocks());
+                blocks.append(&mut self.collect_rest_blocks());
+                blocks.append(&mut self.callcc_blocks());
+                blocks.append(&mut self.continuation_blocks());
+                blocks.append(&mut self.handler_blocks());
+                blocks.append(&mut self.raise_blocks());
+                blocks.append(&mut self.error_blocks());
+                blocks.append(&mut self.letrec_error_blocks());
+                blocks.append(&mut self.values_blocks());
+                blocks.append(&mut self.apply_values_blocks());
+                blocks.append(&mut self.call_with_values_blocks());
                 // the exit code
                 let label = String::from("_scheme_exit");
                 let codes = vec![
+                    self.op2("addq", Imm (16), RSP),
                     Pop (Box::new(R15)),
                     Pop (Box::new(R14)),
                     Pop (Box::new(R13)),
@@ -4579,6 +5329,448 @@ impl CompileToAsm {
         return Prog (blocks);
     }
 
+    // apply receives a procedure in r8 and a list in r9. It spreads the list 
+    // across r9 and the frame, just like a call site, then jumps to the procedure.
+    fn apply_blocks(&self) -> Vec<Asm> {
+        let spread = "apply$spread".to_string();
+        let call = "apply$call".to_string();
+        let entry = vec![
+            self.op2("movq", R9, RAX),
+            self.op2("movq", Imm (1), RCX),
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RAX), CAR_OFFSET), R9),
+            self.op2("movq", Deref (Box::new(RAX), CDR_OFFSET), RAX),
+            self.op2("movq", Imm (2), RCX),
+            self.op2("movq", RBP, RSI),
+        ];
+        let spread_codes = vec![
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RAX), CAR_OFFSET), RDI),
+            self.op2("movq", RDI, Deref (Box::new(RSI), 0)),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RSI),
+            self.op2("movq", Deref (Box::new(RAX), CDR_OFFSET), RAX),
+            self.op2("addq", Imm (1), RCX),
+            Jmp (Box::new(Label (spread.clone()))),
+        ];
+        let call_codes = vec![
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        return vec![Cfg (APPLY_LABEL.to_string(), entry), Cfg (spread, spread_codes), Cfg (call, call_codes)];
+    }
+
+    // collect-rest receives the argument count in rcx, the index of the rest argument 
+    // in rax and the body in r11. It conses the arguments from rax on into a list, 
+    // stores the list at index rax, then jumps to the body.
+    fn collect_rest_blocks(&self) -> Vec<Asm> {
+        let next = "collect_rest$next".to_string();
+        let register = "collect_rest$register".to_string();
+        let cons = "collect_rest$cons".to_string();
+        let store = "collect_rest$store".to_string();
+        let store_register = "collect_rest$store_register".to_string();
+        let entry = vec![
+            self.op2("movq", Imm (NIL), RBX),
+        ];
+        let next_codes = vec![
+            self.op2("cmpq", RAX, RCX),
+            Jmpif ("le".to_string(), Box::new(Label (store.clone()))),
+            self.op2("subq", Imm (1), RCX),
+            self.op2("cmpq", Imm (1), RCX),
+            Jmpif ("e".to_string(), Box::new(Label (register.clone()))),
+            // the i-th argument lives in fv(i-2)
+            self.op2("movq", RCX, RSI),
+            self.op2("imulq", Imm (1 << ALIGN_SHIFT), RSI),
+            self.op2("addq", RBP, RSI),
+            self.op2("movq", Deref (Box::new(RSI), -2 << ALIGN_SHIFT), RDI),
+            Jmp (Box::new(Label (cons.clone()))),
+        ];
+        let register_codes = vec![
+            self.op2("movq", R9, RDI),
+        ];
+        let cons_codes = vec![
+            self.op2("movq", RDX, RSI),
+            self.op2("addq", Imm (SIZE_PAIR), RDX),
+            self.op2("movq", RDI, Deref (Box::new(RSI), CAR_OFFSET + TAG_PAIR)),
+            self.op2("movq", RBX, Deref (Box::new(RSI), CDR_OFFSET + TAG_PAIR)),
+            self.op2("leaq", Deref (Box::new(RSI), TAG_PAIR), RBX),
+            Jmp (Box::new(Label (next.clone()))),
+        ];
+        let store_codes = vec![
+            self.op2("cmpq", Imm (1), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (store_register.clone()))),
+            self.op2("imulq", Imm (1 << ALIGN_SHIFT), RAX),
+            self.op2("addq", RBP, RAX),
+            self.op2("movq", RBX, Deref (Box::new(RAX), -2 << ALIGN_SHIFT)),
+            Jmp (Box::new(R11)),
+        ];
+        let store_register_codes = vec![
+            self.op2("movq", RBX, R9),
+            Jmp (Box::new(R11)),
+        ];
+        return vec![
+            Cfg (COLLECT_REST_LABEL.to_string(), entry), 
+            Cfg (next, next_codes),
+            Cfg (register, register_codes),
+            Cfg (cons, cons_codes),
+            Cfg (store, store_codes),
+            Cfg (store_register, store_register_codes),
+        ];
+    }
+
+    // call/cc receives a procedure in r8. It copies the stack between the stack base
+    // and rbp into a continuation, then calls the procedure with the continuation.
+    // The return address in r15 is left alone, so the procedure returns to the caller of call/cc.
+    fn callcc_blocks(&self) -> Vec<Asm> {
+        let copy = "callcc$copy".to_string();
+        let call = "callcc$call".to_string();
+        let entry = vec![
+            self.op2("movq", Deref (Box::new(RSP), STACK_BASE_OFFSET), RSI),
+            self.op2("movq", RBP, RCX),
+            self.op2("subq", RSI, RCX),
+            self.op2("movq", RDX, R9),
+            self.op2("leaq", DerefLabel (Box::new(RIP), Box::new(Label (CONTINUATION_LABEL.to_string()))), RAX),
+            self.op2("movq", RAX, Deref (Box::new(R9), PROC_CODE_OFFSET + TAG_PROC)),
+            self.op2("movq", R15, Deref (Box::new(R9), CONT_RP_OFFSET + TAG_PROC)),
+            self.op2("movq", Deref (Box::new(RSP), HANDLERS_OFFSET), RAX),
+            self.op2("movq", RAX, Deref (Box::new(R9), CONT_HANDLERS_OFFSET + TAG_PROC)),
+            self.op2("movq", RCX, Deref (Box::new(R9), CONT_SIZE_OFFSET + TAG_PROC)),
+            self.op2("leaq", Deref (Box::new(R9), CONT_STACK_OFFSET + TAG_PROC), RDI),
+            self.op2("addq", Imm (CONT_STACK_OFFSET + TAG_PROC), RDX),
+            self.op2("addq", RCX, RDX),
+        ];
+        let copy_codes = vec![
+            self.op2("cmpq", Imm (0), RCX),
+            Jmpif ("e".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RSI), 0), RAX),
+            self.op2("movq", RAX, Deref (Box::new(RDI), 0)),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RSI),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RDI),
+            self.op2("subq", Imm (1 << ALIGN_SHIFT), RCX),
+            Jmp (Box::new(Label (copy.clone()))),
+        ];
+        let call_codes = vec![
+            self.op2("addq", Imm (TAG_PROC), R9),
+            self.op2("movq", Imm (2), RCX),
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        return vec![Cfg (CALLCC_LABEL.to_string(), entry), Cfg (copy, copy_codes), Cfg (call, call_codes)];
+    }
+
+    // the code of every continuation. It copies the saved stack back to the stack base and 
+    // restores the handler stack, then returns the argument in r9 to the saved return address.
+    fn continuation_blocks(&self) -> Vec<Asm> {
+        let copy = "continuation$copy".to_string();
+        let resume = "continuation$resume".to_string();
+        let entry = vec![
+            self.op2("movq", Deref (Box::new(R8), CONT_HANDLERS_OFFSET), RAX),
+            self.op2("movq", RAX, Deref (Box::new(RSP), HANDLERS_OFFSET)),
+            self.op2("movq", Deref (Box::new(RSP), STACK_BASE_OFFSET), RDI),
+            self.op2("movq", Deref (Box::new(R8), CONT_SIZE_OFFSET), RCX),
+            self.op2("leaq", Deref (Box::new(R8), CONT_STACK_OFFSET), RSI),
+        ];
+        let copy_codes = vec![
+            self.op2("cmpq", Imm (0), RCX),
+            Jmpif ("e".to_string(), Box::new(Label (resume.clone()))),
+            self.op2("movq", Deref (Box::new(RSI), 0), RAX),
+            self.op2("movq", RAX, Deref (Box::new(RDI), 0)),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RSI),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RDI),
+            self.op2("subq", Imm (1 << ALIGN_SHIFT), RCX),
+            Jmp (Box::new(Label (copy.clone()))),
+        ];
+        let resume_codes = vec![
+            self.op2("movq", RDI, RBP),
+            self.op2("movq", R9, RAX),
+            Jmp (Box::new(Deref (Box::new(R8), CONT_RP_OFFSET))),
+        ];
+        return vec![Cfg (CONTINUATION_LABEL.to_string(), entry), Cfg (copy, copy_codes), Cfg (resume, resume_codes)];
+    }
+
+    // with-exception-handler receives a handler in r8 and a thunk in r9. It pushes the handler,
+    // calls the thunk with a frame holding the return address and the old handler stack,
+    // and restores the old handler stack when the thunk returns.
+    fn handler_blocks(&self) -> Vec<Asm> {
+        let ret = "with_exception_handler$return".to_string();
+        let entry = vec![
+            self.op2("movq", R15, Deref (Box::new(RBP), 0)),
+            self.op2("movq", Deref (Box::new(RSP), HANDLERS_OFFSET), RAX),
+            self.op2("movq", RAX, Deref (Box::new(RBP), 8)),
+            self.op2("movq", RDX, RSI),
+            self.op2("addq", Imm (SIZE_PAIR), RDX),
+            self.op2("movq", R8, Deref (Box::new(RSI), CAR_OFFSET + TAG_PAIR)),
+            self.op2("movq", RAX, Deref (Box::new(RSI), CDR_OFFSET + TAG_PAIR)),
+            self.op2("leaq", Deref (Box::new(RSI), TAG_PAIR), RAX),
+            self.op2("movq", RAX, Deref (Box::new(RSP), HANDLERS_OFFSET)),
+            self.op2("addq", Imm (16), RBP),
+            self.op2("movq", R9, R8),
+            self.op2("movq", Imm (1), RCX),
+            self.op2("leaq", DerefLabel (Box::new(RIP), Box::new(Label (ret.clone()))), R15),
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        let return_codes = vec![
+            self.op2("subq", Imm (16), RBP),
+            self.op2("movq", Deref (Box::new(RBP), 8), RSI),
+            self.op2("movq", RSI, Deref (Box::new(RSP), HANDLERS_OFFSET)),
+            Jmp (Box::new(Deref (Box::new(RBP), 0))),
+        ];
+        return vec![Cfg (HANDLER_LABEL.to_string(), entry), Cfg (ret, return_codes)];
+    }
+
+    // raise receives an object in r8. It calls the current handler with the object, while the
+    // outer handlers are installed. The frame holds the return address, the handler stack,
+    // the object and whether the raise is continuable. When the handler returns from a
+    // continuable raise, its value is returned to the caller of raise-continuable.
+    fn raise_blocks(&self) -> Vec<Asm> {
+        let common = "raise$common".to_string();
+        let ret = "raise$return".to_string();
+        let returned = "raise$returned".to_string();
+        let uncaught = "raise$uncaught".to_string();
+        let report = "raise$report".to_string();
+        let non_continuable = vec![
+            self.op2("movq", Imm (0), RBX),
+            Jmp (Box::new(Label (common.clone()))),
+        ];
+        let continuable = vec![
+            self.op2("movq", Imm (1), RBX),
+        ];
+        let common_codes = vec![
+            self.op2("movq", Deref (Box::new(RSP), HANDLERS_OFFSET), RAX),
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (uncaught.clone()))),
+            self.op2("movq", R15, Deref (Box::new(RBP), 0)),
+            self.op2("movq", RAX, Deref (Box::new(RBP), 8)),
+            self.op2("movq", R8, Deref (Box::new(RBP), 16)),
+            self.op2("movq", RBX, Deref (Box::new(RBP), 24)),
+            self.op2("movq", Deref (Box::new(RAX), CDR_OFFSET), RSI),
+            self.op2("movq", RSI, Deref (Box::new(RSP), HANDLERS_OFFSET)),
+            self.op2("addq", Imm (32), RBP),
+            self.op2("movq", R8, R9),
+            self.op2("movq", Deref (Box::new(RAX), CAR_OFFSET), R8),
+            self.op2("movq", Imm (2), RCX),
+            self.op2("leaq", DerefLabel (Box::new(RIP), Box::new(Label (ret.clone()))), R15),
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        let return_codes = vec![
+            self.op2("subq", Imm (32), RBP),
+            self.op2("movq", Deref (Box::new(RBP), 8), RSI),
+            self.op2("movq", RSI, Deref (Box::new(RSP), HANDLERS_OFFSET)),
+            self.op2("movq", Deref (Box::new(RBP), 16), R8),
+            self.op2("movq", Deref (Box::new(RBP), 24), RBX),
+            self.op2("cmpq", Imm (0), RBX),
+            Jmpif ("e".to_string(), Box::new(Label (returned.clone()))),
+            Jmp (Box::new(Deref (Box::new(RBP), 0))),
+        ];
+        // rsi tells _scheme_raise whether a handler returned
+        let returned_codes = vec![
+            self.op2("movq", Imm (1), RSI),
+            Jmp (Box::new(Label (report.clone()))),
+        ];
+        let uncaught_codes = vec![
+            self.op2("movq", Imm (0), RSI),
+        ];
+        let report_codes = vec![
+            self.op2("movq", R8, RDI),
+            self.op2("andq", Imm (-16), RSP),
+            Callq (Box::new(Label (C_RAISE_LABEL.to_string()))),
+        ];
+        return vec![
+            Cfg (RAISE_LABEL.to_string(), non_continuable),
+            Cfg (RAISE_CONTINUABLE_LABEL.to_string(), continuable),
+            Cfg (common, common_codes),
+            Cfg (ret, return_codes),
+            Cfg (returned, returned_codes),
+            Cfg (uncaught, uncaught_codes),
+            Cfg (report, report_codes),
+        ];
+    }
+
+    // error receives a message in r8 and a list of irritants in r9. With a handler, it raises
+    // the error object, the pair of the message and the irritants. Otherwise the error is
+    // reported by _scheme_error. It never returns.
+    fn error_blocks(&self) -> Vec<Asm> {
+        let uncaught = "error$uncaught".to_string();
+        let entry = vec![
+            self.op2("movq", Deref (Box::new(RSP), HANDLERS_OFFSET), RAX),
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (uncaught.clone()))),
+            self.op2("movq", RDX, RSI),
+            self.op2("addq", Imm (SIZE_PAIR), RDX),
+            self.op2("movq", R8, Deref (Box::new(RSI), CAR_OFFSET + TAG_PAIR)),
+            self.op2("movq", R9, Deref (Box::new(RSI), CDR_OFFSET + TAG_PAIR)),
+            self.op2("leaq", Deref (Box::new(RSI), TAG_PAIR), R8),
+            Jmp (Box::new(Label (RAISE_LABEL.to_string()))),
+        ];
+        let uncaught_codes = vec![
+            self.op2("movq", R8, RDI),
+            self.op2("movq", R9, RSI),
+            self.op2("andq", Imm (-16), RSP),
+            Callq (Box::new(Label (C_ERROR_LABEL.to_string()))),
+        ];
+        return vec![Cfg (ERROR_LABEL.to_string(), entry), Cfg (uncaught, uncaught_codes)];
+    }
+
+    // reached when a letrec variable is referenced before the letrec is initialized
+    fn letrec_error_blocks(&self) -> Vec<Asm> {
+        let codes = vec![
+            self.op2("andq", Imm (-16), RSP),
+            Callq (Box::new(Label (C_LETREC_ERROR_LABEL.to_string()))),
+        ];
+        return vec![Cfg (LETREC_ERROR_LABEL.to_string(), codes)];
+    }
+
+    // values receives its values like arguments, in r8, r9 and then the frame slots, with the 
+    // count in rcx. When returning to call-with-values, they are passed on as they are. 
+    // Otherwise a single value is returned in rax like any other procedure. Any other count
+    // calls error with the list of the values, collect-rest conses all but the first.
+    fn values_blocks(&self) -> Vec<Asm> {
+        let single = "values$single".to_string();
+        let wrong = "values$wrong".to_string();
+        let list = "values$list".to_string();
+        let none = "values$none".to_string();
+        let ret = "values$return".to_string();
+        let entry = vec![
+            self.op2("leaq", DerefLabel (Box::new(RIP), Box::new(Label ("call_with_values$return".to_string()))), RAX),
+            self.op2("cmpq", RAX, R15),
+            Jmpif ("e".to_string(), Box::new(Label ("call_with_values$multiple".to_string()))),
+        ];
+        let single_codes = vec![
+            self.op2("movq", R8, RAX),
+            self.op2("cmpq", Imm (1), RCX),
+            Jmpif ("e".to_string(), Box::new(Label (ret.clone()))),
+        ];
+        let wrong_codes = vec![
+            self.op2("cmpq", Imm (0), RCX),
+            Jmpif ("e".to_string(), Box::new(Label (none.clone()))),
+            self.op2("movq", Imm (1), RAX),
+            self.op2("leaq", DerefLabel (Box::new(RIP), Box::new(Label (list.clone()))), R11),
+            Jmp (Box::new(Label (COLLECT_REST_LABEL.to_string()))),
+        ];
+        let list_codes = vec![
+            self.op2("movq", RDX, RSI),
+            self.op2("addq", Imm (SIZE_PAIR), RDX),
+            self.op2("movq", R8, Deref (Box::new(RSI), CAR_OFFSET + TAG_PAIR)),
+            self.op2("movq", R9, Deref (Box::new(RSI), CDR_OFFSET + TAG_PAIR)),
+            self.op2("leaq", Deref (Box::new(RSI), TAG_PAIR), R8),
+            self.op2("movq", Imm (NIL), R9),
+            Jmp (Box::new(Label (ERROR_LABEL.to_string()))),
+        ];
+        let none_codes = vec![
+            self.op2("movq", Imm (NIL), R8),
+            self.op2("movq", Imm (NIL), R9),
+            Jmp (Box::new(Label (ERROR_LABEL.to_string()))),
+        ];
+        let return_codes = vec![
+            Jmp (Box::new(R15)),
+        ];
+        return vec![
+            Cfg (VALUES_LABEL.to_string(), entry),
+            Cfg (single, single_codes),
+            Cfg (wrong, wrong_codes),
+            Cfg (list, list_codes),
+            Cfg (none, none_codes),
+            Cfg (ret, return_codes),
+        ];
+    }
+
+    // apply-values receives a list in r8. It spreads the list across r8, r9 and the frame, 
+    // like the values of a call to values, then jumps to values.
+    fn apply_values_blocks(&self) -> Vec<Asm> {
+        let spread = "apply_values$spread".to_string();
+        let call = "apply_values$call".to_string();
+        let entry = vec![
+            self.op2("movq", R8, RAX),
+            self.op2("movq", Imm (0), RCX),
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RAX), CAR_OFFSET), R8),
+            self.op2("movq", Deref (Box::new(RAX), CDR_OFFSET), RAX),
+            self.op2("movq", Imm (1), RCX),
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RAX), CAR_OFFSET), R9),
+            self.op2("movq", Deref (Box::new(RAX), CDR_OFFSET), RAX),
+            self.op2("movq", Imm (2), RCX),
+            self.op2("movq", RBP, RSI),
+        ];
+        let spread_codes = vec![
+            self.op2("cmpq", Imm (NIL), RAX),
+            Jmpif ("e".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RAX), CAR_OFFSET), RDI),
+            self.op2("movq", RDI, Deref (Box::new(RSI), 0)),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RSI),
+            self.op2("movq", Deref (Box::new(RAX), CDR_OFFSET), RAX),
+            self.op2("addq", Imm (1), RCX),
+            Jmp (Box::new(Label (spread.clone()))),
+        ];
+        let call_codes = vec![
+            Jmp (Box::new(Label (VALUES_LABEL.to_string()))),
+        ];
+        return vec![Cfg (APPLY_VALUES_LABEL.to_string(), entry), Cfg (spread, spread_codes), Cfg (call, call_codes)];
+    }
+
+    // call-with-values receives a producer in r8 and a consumer in r9. It calls the producer 
+    // with a frame holding the return address and the consumer. A producer returning normally
+    // comes back with one value in rax, while values comes back to call_with_values$multiple 
+    // with the values still in place. They are shifted by one for cp of the consumer, then the 
+    // consumer is tail called.
+    fn call_with_values_blocks(&self) -> Vec<Asm> {
+        let ret = "call_with_values$return".to_string();
+        let multiple = "call_with_values$multiple".to_string();
+        let shift = "call_with_values$shift".to_string();
+        let call = "call_with_values$call".to_string();
+        let entry = vec![
+            self.op2("movq", R15, Deref (Box::new(RBP), 0)),
+            self.op2("movq", R9, Deref (Box::new(RBP), 8)),
+            self.op2("addq", Imm (16), RBP),
+            self.op2("movq", Imm (1), RCX),
+            self.op2("leaq", DerefLabel (Box::new(RIP), Box::new(Label (ret.clone()))), R15),
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        let return_codes = vec![
+            self.op2("subq", Imm (16), RBP),
+            self.op2("movq", Deref (Box::new(RBP), 0), R15),
+            self.op2("movq", Deref (Box::new(RBP), 8), R8),
+            self.op2("movq", RAX, R9),
+            self.op2("movq", Imm (2), RCX),
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        // the values after the second are at 16(%rbp), one slot above where the consumer wants them
+        let multiple_codes = vec![
+            self.op2("subq", Imm (16), RBP),
+            self.op2("movq", Deref (Box::new(RBP), 0), R15),
+            self.op2("movq", Deref (Box::new(RBP), 8), R11),
+            self.op2("movq", RCX, RAX),
+            self.op2("leaq", Deref (Box::new(RBP), 8), RDI),
+            self.op2("movq", RCX, RSI),
+            self.op2("subq", Imm (2), RSI),
+        ];
+        let shift_codes = vec![
+            self.op2("cmpq", Imm (0), RSI),
+            Jmpif ("le".to_string(), Box::new(Label (call.clone()))),
+            self.op2("movq", Deref (Box::new(RDI), 8), R10),
+            self.op2("movq", R10, Deref (Box::new(RDI), 0)),
+            self.op2("addq", Imm (1 << ALIGN_SHIFT), RDI),
+            self.op2("subq", Imm (1), RSI),
+            Jmp (Box::new(Label (shift.clone()))),
+        ];
+        let call_codes = vec![
+            self.op2("movq", R9, Deref (Box::new(RBP), 0)),
+            self.op2("movq", R8, R9),
+            self.op2("movq", R11, R8),
+            self.op2("movq", RAX, RCX),
+            self.op2("addq", Imm (1), RCX),
+            Jmp (Box::new(Deref (Box::new(R8), PROC_CODE_OFFSET))),
+        ];
+        return vec![
+            Cfg (CALL_WITH_VALUES_LABEL.to_string(), entry),
+            Cfg (ret, return_codes),
+            Cfg (multiple, multiple_codes),
+            Cfg (shift, shift_codes),
+            Cfg (call, call_codes),
+        ];
+    }
+
     fn tail_to_asm(&self, expr: Expr) -> Vec<Asm> {
         match expr {
             Begin (exprs) => exprs.into_iter().map(|e| self.expr_to_asm(e)).collect(),
@@ -4730,6 +5922,18 @@ impl GenerateAsm {
 }
 
 
+// the object file of the encoder, without an assembler
+pub struct GenerateObject {}
+impl GenerateObject {
+    pub fn run(&self, code: Asm, filename: &str) -> std::io::Result<()> {
+        let text = Encoder{}.run(&code);
+        let mut file = File::create(filename)?;
+        file.write_all(&elf_object(&text))?;
+        return Ok(());
+    }
+}
+
+
 pub fn compile_formatter<T: std::fmt::Display>(s: &str, expr: &T) {
     println!(">>> {}", s);
     println!("----------------------------");
@@ -4757,91 +5961,220 @@ pub fn everybody_home(expr: &Expr) -> bool {
     panic!("Invalid Program {}", expr);
 }
 
-pub fn compile(s: &str, filename: &str) -> std::io::Result<()>  {
-    let expr = ParseScheme{}.run(s);
-    compile_formatter("ParseScheme", &expr);
-    let expr = UniquifyVariable{}.run(expr);
-    compile_formatter("UniquifyVariable", &expr);
-    let expr = ConvertComplexDatum{}.run(expr);
-    compile_formatter("ConvertComplexDatum", &expr);
-    let expr = UncoverAssigned{}.run(expr);
-    compile_formatter("UncoverAssigned", &expr);
-    let expr = PurifyLetrec{}.run(expr);
-    compile_formatter("PurifyLetrec", &expr);
-    let expr = ConvertAssignment{}.run(expr);
-    compile_formatter("ConvertAssignment", &expr);
-    let expr = OptimizeDirectCall{}.run(expr);
-    compile_formatter("OptimizeDirectCall", &expr);
-    let expr = RemoveAnonymousLambda{}.run(expr);
-    compile_formatter("RemoveAnonymousLambda", &expr);
-    let expr = SanitizeBindingForms{}.run(expr);
-    compile_formatter("SanitizeBindingForms", &expr);
-    let expr = UncoverFree{}.run(expr);
-    compile_formatter("UncoverFree", &expr);
-    let expr = ConvertClosure{}.run(expr);
-    compile_formatter("ConvertClosure", &expr);
-    let expr = OptimizeKnownCall{}.run(expr);
-    compile_formatter("OptimizeKnownCall", &expr);
-    let expr = IntroduceProceduraPrimitives{}.run(expr);
-    compile_formatter("IntroduceProceduraPrimitives", &expr);
-    let expr = LiftLetrec{}.run(expr);
-    compile_formatter("LiftLetrec", &expr);
-    let expr = NormalizeContext{}.run(expr);
-    compile_formatter("NormalizeContext", &expr);
-    let expr = SpecifyRepresentation{}.run(expr);
-    compile_formatter("SpecifyRepresentation", &expr);
-    let expr = UncoverLocals{}.run(expr);
-    compile_formatter("UncoverLocals", &expr);
-    let expr = RemoveLet{}.run(expr);
-    compile_formatter("RemoveLet", &expr);
-    let expr = CompileToExpr{}.run(expr);
-    compile_formatter("CompileToExpr", &expr);
-    let expr = RemoveComplexOpera{}.run(expr);
-    compile_formatter("RemoveComplexOpera", &expr);
-    let expr = FlattenSet{}.run(expr);
-    compile_formatter("FlattenSet", &expr);
-    let expr = ImposeCallingConvention{}.run(expr);
-    compile_formatter("ImposeCallingConvention", &expr);
-    let expr = UncoverFrameConflict{}.run(expr);
-    compile_formatter("UncoverFrameConflict", &expr);
-    let expr = PreAssignFrame{}.run(expr);
-    compile_formatter("PreAssignFrame", &expr);
-    let mut expr = AssignNewFrame{}.run(expr);
-    compile_formatter("AssignNewFrame", &expr);
+// the program between two passes
+#[derive(Debug)]
+pub enum Program {
+    Scheme (Scheme),
+    Expr (Expr),
+    Asm (Asm),
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Program::Scheme (scm) => fmt::Display::fmt(scm, f),
+            Program::Expr (expr) => fmt::Display::fmt(expr, f),
+            Program::Asm (asm) => fmt::Display::fmt(asm, f),
+        }
+    }
+}
+
+// the passes after ParseScheme, FinalizeFrameLocations through AssignFrame repeat until everybody is home
+pub const PASSES: [&str; 37] = [
+    "IntroduceLibrary", "UniquifyVariable", "ConvertComplexDatum", "UncoverAssigned", "PurifyLetrec",
+    "ConvertAssignment", "OptimizeDirectCall", "RemoveAnonymousLambda", "SanitizeBindingForms", "UncoverFree",
+    "ConvertClosure", "OptimizeKnownCall", "IntroduceProceduraPrimitives", "LiftLetrec", "NormalizeContext",
+    "SpecifyRepresentation", "UncoverLocals", "RemoveLet", "CompileToExpr", "RemoveComplexOpera",
+    "FlattenSet", "ImposeCallingConvention", "UncoverFrameConflict", "PreAssignFrame", "AssignNewFrame",
+    "FinalizeFrameLocations", "SelectInstructions", "UncoverRegisterConflict", "AssignRegister", "AssignFrame",
+    "DiscardCallLive", "FinalizeLocations", "UpdateFrameLocations", "ExposeBasicBlocks", "OptimizeJump",
+    "FlattenProgram", "CompileToAsm",
+];
+
+pub fn pass_index(pass: &str) -> usize {
+    match PASSES.iter().position(|p| *p == pass) {
+        Some(i) => i,
+        None => panic!("Unknown pass {}", pass),
+    }
+}
+
+pub fn run_pass(pass: &str, program: Program) -> Program {
+    use Program as P;
+    match (pass, program) {
+        ("IntroduceLibrary", P::Scheme (scm)) => P::Scheme (IntroduceLibrary{}.run(scm)),
+        ("UniquifyVariable", P::Scheme (scm)) => P::Scheme (UniquifyVariable{}.run(scm)),
+        ("ConvertComplexDatum", P::Scheme (scm)) => P::Scheme (ConvertComplexDatum{}.run(scm)),
+        ("UncoverAssigned", P::Scheme (scm)) => P::Scheme (UncoverAssigned{}.run(scm)),
+        ("PurifyLetrec", P::Scheme (scm)) => P::Scheme (PurifyLetrec{}.run(scm)),
+        ("ConvertAssignment", P::Scheme (scm)) => P::Scheme (ConvertAssignment{}.run(scm)),
+        ("OptimizeDirectCall", P::Scheme (scm)) => P::Scheme (OptimizeDirectCall{}.run(scm)),
+        ("RemoveAnonymousLambda", P::Scheme (scm)) => P::Scheme (RemoveAnonymousLambda{}.run(scm)),
+        ("SanitizeBindingForms", P::Scheme (scm)) => P::Scheme (SanitizeBindingForms{}.run(scm)),
+        ("UncoverFree", P::Scheme (scm)) => P::Scheme (UncoverFree{}.run(scm)),
+        ("ConvertClosure", P::Scheme (scm)) => P::Scheme (ConvertClosure{}.run(scm)),
+        ("OptimizeKnownCall", P::Scheme (scm)) => P::Scheme (OptimizeKnownCall{}.run(scm)),
+        ("IntroduceProceduraPrimitives", P::Scheme (scm)) => P::Scheme (IntroduceProceduraPrimitives{}.run(scm)),
+        ("LiftLetrec", P::Scheme (scm)) => P::Scheme (LiftLetrec{}.run(scm)),
+        ("NormalizeContext", P::Scheme (scm)) => P::Scheme (NormalizeContext{}.run(scm)),
+        ("SpecifyRepresentation", P::Scheme (scm)) => P::Scheme (SpecifyRepresentation{}.run(scm)),
+        ("UncoverLocals", P::Scheme (scm)) => P::Scheme (UncoverLocals{}.run(scm)),
+        ("RemoveLet", P::Scheme (scm)) => P::Scheme (RemoveLet{}.run(scm)),
+        ("CompileToExpr", P::Scheme (scm)) => P::Expr (CompileToExpr{}.run(scm)),
+        ("RemoveComplexOpera", P::Expr (expr)) => P::Expr (RemoveComplexOpera{}.run(expr)),
+        ("FlattenSet", P::Expr (expr)) => P::Expr (FlattenSet{}.run(expr)),
+        ("ImposeCallingConvention", P::Expr (expr)) => P::Expr (ImposeCallingConvention{}.run(expr)),
+        ("UncoverFrameConflict", P::Expr (expr)) => P::Expr (UncoverFrameConflict{}.run(expr)),
+        ("PreAssignFrame", P::Expr (expr)) => P::Expr (PreAssignFrame{}.run(expr)),
+        ("AssignNewFrame", P::Expr (expr)) => P::Expr (AssignNewFrame{}.run(expr)),
+        ("FinalizeFrameLocations", P::Expr (expr)) => P::Expr (FinalizeFrameLocations{}.run(expr)),
+        ("SelectInstructions", P::Expr (expr)) => P::Expr (SelectInstructions{}.run(expr)),
+        ("UncoverRegisterConflict", P::Expr (expr)) => P::Expr (UncoverRegisterConflict{}.run(expr)),
+        ("AssignRegister", P::Expr (expr)) => P::Expr (AssignRegister{}.run(expr)),
+        ("AssignFrame", P::Expr (expr)) => P::Expr (AssignFrame{}.run(expr)),
+        ("DiscardCallLive", P::Expr (expr)) => P::Expr (DiscardCallLive{}.run(expr)),
+        ("FinalizeLocations", P::Expr (expr)) => P::Expr (FinalizeLocations{}.run(expr)),
+        ("UpdateFrameLocations", P::Expr (expr)) => P::Expr (UpdateFrameLocations{}.run(expr)),
+        ("ExposeBasicBlocks", P::Expr (expr)) => P::Expr (ExposeBasicBlocks{}.run(expr)),
+        ("OptimizeJump", P::Expr (expr)) => P::Expr (OptimizeJump{}.run(expr)),
+        ("FlattenProgram", P::Expr (expr)) => P::Expr (FlattenProgram{}.run(expr)),
+        ("CompileToAsm", P::Expr (expr)) => P::Asm (CompileToAsm{}.run(expr)),
+        (pass, program) => panic!("{} can not run on {}", pass, program),
+    }
+}
+
+// the answer of a pass output, by the interpreter of its language. Assembly is not run.
+pub fn interpret(pass: &str, program: &Program) -> Option<Answer> {
+    let i = pass_index(pass);
+    match program {
+        Program::Scheme (scm) if i < pass_index("SpecifyRepresentation") => {
+            Some (SchemeInterpreter { converted: i >= pass_index("ConvertClosure") }.run(scm))
+        }
+        Program::Scheme (scm) => Some (WordInterpreter{}.scheme(scm)),
+        Program::Expr (expr) if i < pass_index("ImposeCallingConvention") => Some (WordInterpreter{}.expr(expr)),
+        Program::Expr (expr) => Some (MachineInterpreter {
+            new_frames: i >= pass_index("AssignNewFrame"),
+            updated_frames: i >= pass_index("UpdateFrameLocations"),
+            flattened: i >= pass_index("OptimizeJump"),
+        }.run(expr)),
+        Program::Asm (_) => None,
+    }
+}
+
+// a pass output must give the answer of the program it was given
+pub fn check_pass(pass: &str, program: &Program, expected: &Answer) {
+    if let Some (answer) = interpret(pass, program) {
+        assert!(&answer == expected, "{} changed the answer from {} to {}", pass, expected, answer);
+    }
+}
+
+// the output of a pass must be in the language of the next pass, and the locations
+// it gives must hold one value at a time
+pub fn verify_pass(pass: &str, program: &Program) {
+    if let Err (msg) = verify(pass, program) {
+        panic!("{} produced an invalid program: {}", pass, msg);
+    }
+    if let (true, Program::Expr (expr)) = (ALLOCATORS.contains(&pass), program) {
+        if let Err (msg) = validate_allocation(expr) {
+            panic!("{} produced an unsound allocation: {}", pass, msg);
+        }
+    }
+}
+
+// A15_CHECK=1 checks every pass
+fn check_enabled() -> bool {
+    std::env::var_os("A15_CHECK").is_some()
+}
+
+// runs the passes from the named one to CompileToAsm, and shows every output to visit.
+// With check, the first pass output gives the answer that every later one must give.
+pub fn run_passes<F: FnMut(&str, &Program)>(pass: &str, program: Program, check: bool, mut visit: F) -> Program {
+    let mut i = pass_index(pass);
+    let mut program = program;
+    let mut expected: Option<Answer> = None;
+    while i < PASSES.len() {
+        let pass = PASSES[i];
+        program = run_pass(pass, program);
+        visit(pass, &program);
+        if cfg!(debug_assertions) {
+            verify_pass(pass, &program);
+        }
+        if check {
+            match &expected {
+                // a stuck program has no answer to keep
+                Some (Answer::Stuck (_)) => (),
+                Some (answer) => check_pass(pass, &program, answer),
+                None => expected = interpret(pass, &program),
+            }
+        }
+        i = match (pass, &program) {
+            ("AssignRegister", Program::Expr (expr)) if everybody_home(expr) => i + 2,
+            ("AssignFrame", _) => pass_index("FinalizeFrameLocations"),
+            _ => i + 1,
+        };
+    }
+    program
+}
+
+// runs the passes and writes the assembly, or the object for a .o file
+fn compile_passes(pass: &str, program: Program, filename: &str, check: bool, dump: bool) -> std::io::Result<()> {
     let mut loop_id = 1;
-    loop {
-        println!("The {}-th iteration", loop_id);
-        loop_id += 1;
-
-        expr = FinalizeFrameLocations{}.run(expr);
-        compile_formatter("FinalizeFrameLocations", &expr);
-        expr = SelectInstructions{}.run(expr);
-        compile_formatter("SelectInstructions", &expr);
-        expr = UncoverRegisterConflict{}.run(expr);
-        compile_formatter("UncoverRegisterConflict", &expr);
-        expr = AssignRegister{}.run(expr);
-        compile_formatter("AssignRegister", &expr);
-
-        if everybody_home(&expr) {
-            break;
-        }
-
-        expr = AssignFrame{}.run(expr);
-        compile_formatter("AssignFrame", &expr);
-    }
-    let expr = DiscardCallLive{}.run(expr);
-    compile_formatter("DiscardCallLive", &expr);
-    let expr = FinalizeLocations{}.run(expr);
-    compile_formatter("Finalizelocations", &expr);
-    let expr = UpdateFrameLocations{}.run(expr);
-    compile_formatter("UpdateFrameLocations", &expr);
-    let expr = ExposeBasicBlocks{}.run(expr);
-    compile_formatter("ExposeBasicBlocks", &expr);
-    let expr = OptimizeJump{}.run(expr);
-    compile_formatter("OptimizeJump", &expr);
-    let expr = FlattenProgram{}.run(expr);
-    compile_formatter("FlattenProgram", &expr);
-    let expr = CompileToAsm{}.run(expr);
-    compile_formatter("CompileToAsm", &expr);
-    return GenerateAsm{}.run(expr, filename)
-}
\ No newline at end of file
+    let program = run_passes(pass, program, check, |pass, program| {
+        if !dump {
+            return;
+        }
+        if pass == "FinalizeFrameLocations" {
+            println!("The {}-th iteration", loop_id);
+            loop_id += 1;
+        }
+        compile_formatter(pass, program);
+    });
+    match program {
+        Program::Asm (asm) if filename.ends_with(".o") => GenerateObject{}.run(asm, filename),
+        Program::Asm (asm) => GenerateAsm{}.run(asm, filename),
+        program => panic!("Compilation stops before assembly {}", program),
+    }
+}
+
+fn compile_with(s: &str, filename: &str, check: bool, dump: bool) -> std::io::Result<()>  {
+    VerifyScheme{}.run(s);
+    let expr = ParseScheme{}.run(s);
+    if dump {
+        compile_formatter("ParseScheme", &expr);
+    }
+    compile_passes("IntroduceLibrary", Program::Scheme (expr), filename, check, dump)
+}
+
+pub fn compile(s: &str, filename: &str) -> std::io::Result<()>  {
+    compile_with(s, filename, check_enabled(), true)
+}
+
+// compiles without dumping the passes, for the fuzzer
+pub fn compile_quiet(s: &str, filename: &str) -> std::io::Result<()>  {
+    compile_with(s, filename, check_enabled(), false)
+}
+
+// compiles and checks that every pass keeps the answer of the program
+#[cfg(test)]
+pub fn compile_checked(s: &str, filename: &str) -> std::io::Result<()>  {
+    compile_with(s, filename, true, true)
+}
+
+// starts at the named pass with a program dumped by the pass before it,
+// Scheme before CompileToExpr and Expr up to CompileToAsm
+pub fn compile_from(pass: &str, s: &str, filename: &str) -> std::io::Result<()> {
+    if pass == "ParseScheme" {
+        return compile(s, filename);
+    }
+    let data = read(s);
+    assert!(data.len() == 1, "Expect one program to start {} with", pass);
+    let program = if pass_index(pass) <= pass_index("CompileToExpr") {
+        Program::Scheme (IlParser{}.scheme(&data[0]))
+    } else {
+        Program::Expr (IlParser{}.expr(&data[0]))
+    };
+    // the program stands for the output of the pass before
+    if cfg!(debug_assertions) && pass_index(pass) > 0 {
+        verify_pass(PASSES[pass_index(pass) - 1], &program);
+    }
+    compile_passes(pass, program, filename, check_enabled(), true)
+}
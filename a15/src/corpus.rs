@@ -0,0 +1,259 @@
+// A runner for the programs of a corpus directory. Each .ss file starts with comments which
+// give what it should do, and the rest is the program:
+//
+//   ;; expect: (1 2 3)              what it prints, one line of the output each
+//   ;; error: Error in car: ...     what it reports on stderr when it fails at run time
+//   ;; compile-error: line 1, ...   a line of the message it fails to compile with,
+//                                  or nothing after the colon for any message
+//
+// Every program compiles with the answer checked after each pass, in a directory of its own,
+// so programs run at the same time share no files, and a failure names the pass to blame.
+// The assembly runs natively, assembled by gcc or encoded into an object, or in the emulator.
+use std::fs;
+use std::io::Read;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::compiler::{interpret, run_passes, GenerateAsm, GenerateObject, ParseScheme, Program, VerifyScheme};
+use crate::interp::Answer;
+use crate::emulator::Emulator;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::jit::Jit;
+
+#[cfg(test)]
+pub const CORPUS: &str = "corpus";
+
+const RUNTIME: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/runtime.c");
+const TIMEOUT: Duration = Duration::from_secs(10);
+const WORKERS: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum Expect {
+    Output (String),
+    Error (String),
+    CompileError (String),
+}
+
+// how the assembly runs
+#[derive(Clone, Copy, Debug)]
+pub enum Backend {
+    Native,
+    Object,
+    Emulator,
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    Jit,
+}
+
+pub struct Case {
+    pub name: String,
+    pub expect: Expect,
+    pub program: String,
+}
+
+// splits the header comments from the program, which keeps its own line numbers
+pub fn parse_case(name: &str, text: &str) -> Result<Case, String> {
+    let mut expect: Option<(&str, Vec<&str>)> = None;
+    let mut rest = text;
+    while rest.starts_with(";;") {
+        let end = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        let line = rest[2..end].trim_end_matches(|c| c == '\n' || c == '\r');
+        rest = &rest[end..];
+        let (key, value) = match line.find(':') {
+            Some (i) => (line[..i].trim(), line[i + 1..].strip_prefix(' ').unwrap_or(&line[i + 1..])),
+            None => continue,
+        };
+        if !["expect", "error", "compile-error"].contains(&key) {
+            continue;
+        }
+        match &mut expect {
+            Some ((k, lines)) if *k == key => lines.push(value),
+            Some ((k, _)) => return Err (format!("both {} and {} in the header", k, key)),
+            None => expect = Some ((key, vec![value])),
+        }
+    }
+    let expect = match expect {
+        Some (("expect", lines)) => Expect::Output (lines.join("\n")),
+        Some (("error", lines)) => Expect::Error (lines.join("\n")),
+        Some ((_, lines)) => Expect::CompileError (lines.join("\n")),
+        None => return Err ("no expect, error or compile-error in the header".to_string()),
+    };
+    Ok (Case { name: name.to_string(), expect, program: rest.to_string() })
+}
+
+pub fn panic_message(e: Box<dyn std::any::Any + Send>) -> String {
+    match e.downcast::<String>() {
+        Ok (s) => *s,
+        Err (e) => e.downcast::<&str>().map(|s| s.to_string()).unwrap_or_else(|_| "a panic".to_string()),
+    }
+}
+
+// links the assembly or the object with the runtime
+pub fn build(code: &Path, exe: &Path) -> Result<(), String> {
+    let output = Command::new("gcc").arg("-m64").arg("-o").arg(exe).arg(code).arg(RUNTIME)
+                    .output().expect("failed to execute process");
+    if output.status.success() {
+        Ok (())
+    } else {
+        Err (format!("gcc failed: {}", String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+// reads a pipe to the end on a thread of its own
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut bytes = vec![];
+        if let Some (mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        bytes
+    })
+}
+
+// runs the executable, and gives up on it after the timeout. The output is read while it
+// runs, a program would block on a full pipe otherwise.
+pub fn execute(exe: &Path) -> Result<Output, String> {
+    let mut child = Command::new(exe).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().expect("failed to execute process");
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let start = Instant::now();
+    let status = loop {
+        if let Some (status) = child.try_wait().expect("failed to wait for the process") {
+            break status;
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err (format!("the program ran for more than {:?}", TIMEOUT));
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    Ok (Output { status, stdout: stdout.join().unwrap(), stderr: stderr.join().unwrap() })
+}
+
+// compiles and runs the case in dir, and says what went wrong
+pub fn run_case(case: &Case, dir: &Path, backend: Backend) -> Result<(), String> {
+    // the answer of the first pass, and the last pass to finish
+    let mut answer: Option<Answer> = None;
+    let mut last = "ParseScheme".to_string();
+    let compiled = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        VerifyScheme{}.run(&case.program);
+        let scm = ParseScheme{}.run(&case.program);
+        run_passes("IntroduceLibrary", Program::Scheme (scm), true, |pass, program| {
+            if answer.is_none() {
+                answer = interpret(pass, program);
+            }
+            last = pass.to_string();
+        })
+    }));
+    let asm = match (compiled, &case.expect) {
+        (Err (e), Expect::CompileError (expected)) => {
+            let msg = panic_message(e);
+            if msg.contains(expected.as_str()) {
+                return Ok (());
+            }
+            return Err (format!("it fails to compile with\n{}\nbut not with\n{}", msg, expected));
+        }
+        (Err (e), _) => return Err (format!("the pass after {} panicked: {}", last, panic_message(e))),
+        (Ok (_), Expect::CompileError (expected)) => return Err (format!("it compiles, but it should fail with\n{}", expected)),
+        (Ok (Program::Asm (asm)), _) => asm,
+        (Ok (program), _) => return Err (format!("compilation stops before assembly {}", program)),
+    };
+    let (success, stdout, stderr) = match backend {
+        Backend::Native | Backend::Object => {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            let exe = dir.join(&case.name);
+            let file = match backend {
+                Backend::Object => dir.join(format!("{}.o", case.name)),
+                _ => dir.join(format!("{}.s", case.name)),
+            };
+            let written = match backend {
+                Backend::Object => GenerateObject{}.run(asm, file.to_str().unwrap()),
+                _ => GenerateAsm{}.run(asm, file.to_str().unwrap()),
+            };
+            written.map_err(|e| e.to_string())?;
+            build(&file, &exe)?;
+            let output = execute(&exe)?;
+            let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            (output.status.success(), stdout, stderr)
+        }
+        Backend::Emulator => match (Emulator{}).run(&asm) {
+            Answer::Value (s) => (true, s, String::new()),
+            Answer::Error (s) => (false, String::new(), s),
+            Answer::Stuck (s) => return Err (format!("the emulator can not run it: {}", s)),
+        },
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        Backend::Jit => match (Jit{}).run(asm) {
+            Ok (value) => (true, value.to_string(), String::new()),
+            Err (s) => (false, String::new(), s),
+        },
+    };
+    let (actual, expected) = match &case.expect {
+        Expect::Output (expected) if success => (stdout, expected),
+        Expect::Error (expected) if !success => (stderr, expected),
+        Expect::Output (expected) => return Err (format!("it fails with {}, but it should print {}", stderr, expected)),
+        Expect::Error (expected) => return Err (format!("it prints {}, but it should fail with {}", stdout, expected)),
+        Expect::CompileError (_) => unreachable!(),
+    };
+    if &actual == expected {
+        return Ok (());
+    }
+    // every pass gave the same answer, so either the source means something else, or the
+    // fault is after the last pass
+    let blame = match answer {
+        Some (Answer::Stuck (_)) | None => String::new(),
+        Some (answer) if &answer.to_string() == expected => format!(", and every pass gives {}, so the assembly or the runtime is wrong", expected),
+        Some (answer) => format!(", and the answer is {} from IntroduceLibrary on", answer),
+    };
+    Err (format!("it gives {}, but it should give {}{}", actual, expected, blame))
+}
+
+// the .ss files of a directory, or the file itself
+pub fn corpus_files(path: &str) -> Vec<PathBuf> {
+    let path = Path::new(path);
+    if path.is_file() {
+        return vec![path.to_path_buf()];
+    }
+    let mut paths: Vec<PathBuf> = fs::read_dir(path).expect("failed to read the corpus")
+                                    .filter_map(|e| e.ok().map(|e| e.path()))
+                                    .filter(|p| p.extension().map_or(false, |ext| ext == "ss"))
+                                    .collect();
+    paths.sort();
+    paths
+}
+
+// runs every program of the corpus on a few threads, and reports the failures in order.
+// A failure keeps its directory under the temporary directory to look into.
+pub fn run_corpus(path: &str, backend: Backend) -> Vec<String> {
+    let root = std::env::temp_dir().join(format!("a15-corpus-{}", std::process::id()));
+    let files = Arc::new(Mutex::new(corpus_files(path).into_iter().enumerate().collect::<Vec<_>>()));
+    let failures = Arc::new(Mutex::new(vec![]));
+    let workers: Vec<_> = (0..WORKERS).map(|_| {
+        let (files, failures, root) = (files.clone(), failures.clone(), root.clone());
+        std::thread::spawn(move || loop {
+            let (i, file) = match files.lock().unwrap().pop() {
+                Some (next) => next,
+                None => return,
+            };
+            let name = file.file_stem().unwrap().to_string_lossy().to_string();
+            let text = fs::read_to_string(&file).expect("failed to read the program");
+            let dir = root.join(&name);
+            let result = parse_case(&name, &text).and_then(|case| run_case(&case, &dir, backend));
+            match result {
+                Ok (()) => { let _ = fs::remove_dir_all(&dir); }
+                Err (msg) => failures.lock().unwrap().push((i, format!("{}: {}", file.display(), msg))),
+            }
+        })
+    }).collect();
+    for worker in workers {
+        worker.join().expect("a corpus worker panicked");
+    }
+    let _ = fs::remove_dir(&root);
+    let mut failures = std::mem::take(&mut *failures.lock().unwrap());
+    failures.sort();
+    failures.into_iter().map(|(_, msg)| msg).collect()
+}
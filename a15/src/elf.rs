@@ -0,0 +1,177 @@
+// An ELF64 relocatable object for the code of the encoder, like `as` writes for the assembly:
+// a .text section, the labels as local symbols, _scheme_entry exported, and a relocation for
+// every call of a function of runtime.c.
+use crate::encoder::Text;
+use crate::syntax::mangle;
+
+const ENTRY: &str = "_scheme_entry";
+
+// the section types and flags
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_SECTION: u8 = 3;
+const R_X86_64_PLT32: u64 = 4;
+
+// the indices of the sections
+const TEXT: u16 = 1;
+const SYMTAB: u32 = 3;
+const STRTAB: u32 = 4;
+
+struct Section {
+    name: &'static str,
+    kind: u32,
+    flags: u64,
+    data: Vec<u8>,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+// a string table, which starts with the empty string
+struct Strings (Vec<u8>);
+
+impl Strings {
+    fn add(&mut self, s: &str) -> u32 {
+        let at = self.0.len() as u32;
+        self.0.extend_from_slice(s.as_bytes());
+        self.0.push(0);
+        at
+    }
+}
+
+fn symbol(out: &mut Vec<u8>, name: u32, bind: u8, kind: u8, section: u16, value: u64) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.push(bind << 4 | kind);
+    out.push(0);
+    out.extend_from_slice(&section.to_le_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+}
+
+fn align(out: &mut Vec<u8>, n: usize) {
+    out.resize(out.len().next_multiple_of(n), 0);
+}
+
+pub fn elf_object(text: &Text) -> Vec<u8> {
+    // the null symbol, the section, the labels, then the globals
+    let mut strtab = Strings (vec![0]);
+    let mut symtab = vec![0; 24];
+    symbol(&mut symtab, 0, STB_LOCAL, STT_SECTION, TEXT, 0);
+    for (label, offset) in text.labels.iter().filter(|(label, _)| label != ENTRY) {
+        let name = strtab.add(&mangle(label));
+        symbol(&mut symtab, name, STB_LOCAL, STT_NOTYPE, TEXT, *offset as u64);
+    }
+    let first_global = (symtab.len() / 24) as u32;
+    if let Some ((_, offset)) = text.labels.iter().find(|(label, _)| label == ENTRY) {
+        let name = strtab.add(ENTRY);
+        symbol(&mut symtab, name, STB_GLOBAL, STT_NOTYPE, TEXT, *offset as u64);
+    }
+    // a function of runtime.c is an undefined symbol
+    let first_function = symtab.len() / 24;
+    let mut functions: Vec<&str> = vec![];
+    let mut rela = vec![];
+    for (offset, function) in &text.calls {
+        let index = match functions.iter().position(|f| f == function) {
+            Some (k) => k,
+            None => {
+                functions.push(function);
+                let name = strtab.add(function);
+                symbol(&mut symtab, name, STB_GLOBAL, STT_NOTYPE, 0, 0);
+                functions.len() - 1
+            }
+        };
+        let sym = (first_function + index) as u64;
+        rela.extend_from_slice(&(*offset as u64).to_le_bytes());
+        rela.extend_from_slice(&(sym << 32 | R_X86_64_PLT32).to_le_bytes());
+        rela.extend_from_slice(&(-4i64).to_le_bytes());
+    }
+    let sections = vec![
+        Section { name: ".text", kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, data: text.bytes.clone(), link: 0, info: 0, align: 1, entsize: 0 },
+        Section { name: ".rela.text", kind: SHT_RELA, flags: SHF_INFO_LINK, data: rela, link: SYMTAB, info: TEXT as u32, align: 8, entsize: 24 },
+        Section { name: ".symtab", kind: SHT_SYMTAB, flags: 0, data: symtab, link: STRTAB, info: first_global, align: 8, entsize: 24 },
+        Section { name: ".strtab", kind: SHT_STRTAB, flags: 0, data: strtab.0, link: 0, info: 0, align: 1, entsize: 0 },
+        // the stack need not be executable
+        Section { name: ".note.GNU-stack", kind: SHT_PROGBITS, flags: 0, data: vec![], link: 0, info: 0, align: 1, entsize: 0 },
+    ];
+    let mut shstrtab = Strings (vec![0]);
+    let names: Vec<u32> = sections.iter().map(|s| shstrtab.add(s.name)).collect();
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    // the header, the sections, then the section headers
+    let mut out = vec![0; 64];
+    let mut offsets = vec![];
+    for section in &sections {
+        align(&mut out, section.align as usize);
+        offsets.push(out.len() as u64);
+        out.extend_from_slice(&section.data);
+    }
+    let shstrtab_offset = out.len() as u64;
+    out.extend_from_slice(&shstrtab.0);
+    align(&mut out, 8);
+    let shoff = out.len() as u64;
+    out.extend_from_slice(&[0; 64]);
+    let mut header = |name: u32, kind: u32, flags: u64, offset: u64, size: u64, link: u32, info: u32, align: u64, entsize: u64| {
+        out.extend_from_slice(&name.to_le_bytes());
+        out.extend_from_slice(&kind.to_le_bytes());
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&0u64.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&link.to_le_bytes());
+        out.extend_from_slice(&info.to_le_bytes());
+        out.extend_from_slice(&align.to_le_bytes());
+        out.extend_from_slice(&entsize.to_le_bytes());
+    };
+    for (k, s) in sections.iter().enumerate() {
+        header(names[k], s.kind, s.flags, offsets[k], s.data.len() as u64, s.link, s.info, s.align, s.entsize);
+    }
+    header(shstrtab_name, SHT_STRTAB, 0, shstrtab_offset, shstrtab.0.len() as u64, 0, 0, 1, 0);
+    // the ELF header, of a relocatable x86-64 object
+    let shnum = sections.len() as u16 + 2;
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0];
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&1u16.to_le_bytes());
+    elf.extend_from_slice(&62u16.to_le_bytes());
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes());
+    elf.extend_from_slice(&shoff.to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes());
+    elf.extend_from_slice(&64u16.to_le_bytes());
+    elf.extend_from_slice(&0u16.to_le_bytes());
+    elf.extend_from_slice(&0u16.to_le_bytes());
+    elf.extend_from_slice(&64u16.to_le_bytes());
+    elf.extend_from_slice(&shnum.to_le_bytes());
+    elf.extend_from_slice(&(shnum - 1).to_le_bytes());
+    out[..64].copy_from_slice(&elf);
+    out
+}
+
+// the named section of an object, to compare the code with what `as` gives
+#[cfg(test)]
+pub fn elf_section<'a>(object: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let word = |at: usize, size: usize| {
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(&object[at..at + size]);
+        u64::from_le_bytes(bytes) as usize
+    };
+    let (shoff, shnum, shstrndx) = (word(0x28, 8), word(0x3c, 2), word(0x3e, 2));
+    let section = |k: usize| {
+        let at = shoff + 64 * k;
+        (word(at, 4), word(at + 0x18, 8), word(at + 0x20, 8))
+    };
+    let (_, strings, _) = section(shstrndx);
+    (0..shnum).map(section).find(|&(n, _, _)| {
+        let s = &object[strings + n..];
+        &s[..s.iter().position(|&b| b == 0).unwrap_or(0)] == name.as_bytes()
+    }).map(|(_, offset, size)| &object[offset..offset + size])
+}
@@ -0,0 +1,513 @@
+// An emulator for the x86-64 subset that CompileToAsm emits, so a program runs without an
+// assembler or the runtime. Like main of runtime.c, it calls _scheme_entry with a stack and a
+// heap of the same sizes, and prints the answer with the same printer. A native program only
+// gets a signal for a bad access, here the access is reported with the instruction making it.
+use crate::syntax::{Asm, HashMap};
+use crate::interp::{Answer, show_word, show_irritants, error, uncaught, returned, LETREC_ERROR};
+
+const MAX_STEPS: usize = 200_000_000;
+
+// the sizes of runtime.c in words, which it rounds up to pages
+const PAGE_SIZE: i64 = 4096;
+const STACK_SIZE: i64 = 100000;
+const HEAP_SIZE: i64 = 100000;
+const C_STACK_SIZE: i64 = 8192;
+
+// every instruction takes 8 bytes of the code
+const CODE_BASE: i64 = 0x40_0000;
+const STACK_BASE: i64 = 0x1000_0000;
+const HEAP_BASE: i64 = 0x2000_0000;
+const C_STACK_BASE: i64 = 0x7ff0_0000;
+
+// _scheme_entry returns to main here
+const EXIT: i64 = CODE_BASE - 8;
+
+// an access this close to a region is described from it
+const NEAR: i64 = 1 << 20;
+
+// the registers in the order of their encoding, the callee-saved ones hold these until returned
+const REGISTERS: [&str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+                               "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const CALLEE_SAVED: [usize; 6] = [3, 5, 12, 13, 14, 15];
+const SAVED: i64 = 0x5a5a_0000;
+const RSP: usize = 4;
+const RAX: usize = 0;
+const RSI: usize = 6;
+const RDI: usize = 7;
+
+fn register(asm: &Asm) -> Option<usize> {
+    use Asm::*;
+    let r = match asm {
+        RAX => 0, RCX => 1, RDX => 2, RBX => 3, RSP => 4, RBP => 5, RSI => 6, RDI => 7,
+        R8 => 8, R9 => 9, R10 => 10, R11 => 11, R12 => 12, R13 => 13, R14 => 14, R15 => 15,
+        _ => return None,
+    };
+    Some (r)
+}
+
+fn imm32(n: i64) -> bool {
+    n as i32 as i64 == n
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    Reg (usize),
+    Imm (i64),
+    // base, index and displacement
+    Mem (usize, Option<usize>, i64),
+    // the address of a label
+    Code (i64),
+}
+
+#[derive(Clone, Copy)]
+enum Binop { Add, Sub, Imul, And, Or, Sar }
+
+#[derive(Clone, Copy)]
+enum Cc { E, Ne, L, Le, G, Ge, B, Be, A, Ae, S, Ns }
+
+// the functions of runtime.c the program calls, they never return
+#[derive(Clone, Copy)]
+enum Runtime { Error, Raise, LetrecError }
+
+#[derive(Clone, Copy)]
+enum Instr {
+    Mov (Operand, Operand),
+    Lea (Operand, Operand),
+    Binop (Binop, Operand, Operand),
+    Cmp (Operand, Operand),
+    Jmp (Operand),
+    Jcc (Cc, Operand),
+    Call (Operand),
+    Runtime (Runtime),
+    Push (Operand),
+    Pop (Operand),
+    Ret,
+}
+
+#[derive(Default)]
+struct Flags {
+    zf: bool,
+    sf: bool,
+    of: bool,
+    cf: bool,
+}
+
+struct Region {
+    name: &'static str,
+    base: i64,
+    words: Vec<i64>,
+}
+
+impl Region {
+    fn new(name: &'static str, base: i64, size: i64) -> Self {
+        let bytes = (size * 8 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+        Region { name, base, words: vec![0; (bytes / 8) as usize] }
+    }
+
+    fn end(&self) -> i64 {
+        self.base + 8 * self.words.len() as i64
+    }
+}
+
+
+pub struct Emulator {}
+impl Emulator {
+    pub fn run(&self, asm: &Asm) -> Answer {
+        match Machine::new(asm) {
+            Ok (mut machine) => machine.run(),
+            Err (msg) => Answer::Stuck (msg),
+        }
+    }
+}
+
+
+struct Machine<'a> {
+    code: Vec<Instr>,
+    // the instruction as written, and the block it is in
+    source: Vec<(&'a Asm, usize)>,
+    // the label and the first instruction of each block
+    blocks: Vec<(&'a str, usize)>,
+    regs: [i64; 16],
+    flags: Flags,
+    pc: usize,
+    regions: [Region; 3],
+}
+
+impl<'a> Machine<'a> {
+    fn new(prog: &'a Asm) -> Result<Self, String> {
+        let cfgs = match prog {
+            Asm::Prog (cfgs) => cfgs,
+            _ => return Err ("the assembly is not a program".to_string()),
+        };
+        // the labels first, since a jump may go forward
+        let mut source = vec![];
+        let mut blocks = vec![];
+        for cfg in cfgs {
+            match cfg {
+                Asm::Cfg (label, codes) => {
+                    blocks.push((label.as_str(), source.len()));
+                    flatten(codes, blocks.len() - 1, &mut source);
+                }
+                other => return Err (format!("{} is not a block", other)),
+            }
+        }
+        let addresses: HashMap<&str, i64> = blocks.iter().map(|(label, i)| (*label, CODE_BASE + 8 * *i as i64)).collect();
+        let mut code = vec![];
+        for (asm, b) in &source {
+            let instr = decode(asm, &addresses)
+                            .map_err(|msg| format!("{} `{}` in {}", msg, asm.to_string().trim(), blocks[*b].0))?;
+            code.push(instr);
+        }
+        let pc = match blocks.iter().find(|(label, _)| *label == "_scheme_entry") {
+            Some ((_, i)) => *i,
+            None => return Err ("there is no _scheme_entry".to_string()),
+        };
+        let regions = [
+            Region::new("stack", STACK_BASE, STACK_SIZE),
+            Region::new("heap", HEAP_BASE, HEAP_SIZE),
+            Region::new("C stack", C_STACK_BASE, C_STACK_SIZE),
+        ];
+        let mut regs = [0; 16];
+        for r in CALLEE_SAVED {
+            regs[r] = SAVED + r as i64;
+        }
+        let mut machine = Machine { code, source, blocks, regs, flags: Flags::default(), pc, regions };
+        // the call of main
+        machine.regs[RDI] = STACK_BASE;
+        machine.regs[RSI] = HEAP_BASE;
+        machine.regs[RSP] = machine.regions[2].end() - 8;
+        machine.store(machine.regs[RSP], EXIT).unwrap();
+        Ok (machine)
+    }
+
+    fn run(&mut self) -> Answer {
+        for _ in 0..MAX_STEPS {
+            if self.pc >= self.code.len() {
+                return Answer::Error ("the program runs past the end of the code".to_string());
+            }
+            let at = self.pc;
+            match self.step() {
+                Ok (None) => (),
+                Ok (Some (answer)) => return answer,
+                Err (msg) => return Answer::Error (self.fault(at, msg)),
+            }
+        }
+        Answer::Stuck (format!("the program runs for more than {} instructions", MAX_STEPS))
+    }
+
+    // the message with the instruction which failed
+    fn fault(&self, at: usize, msg: String) -> String {
+        let (asm, b) = self.source[at];
+        let (label, start) = self.blocks[b];
+        format!("{}, by `{}` at {}+{}", msg, asm.to_string().trim(), label, at - start)
+    }
+
+    fn step(&mut self) -> Result<Option<Answer>, String> {
+        let instr = self.code[self.pc];
+        self.pc += 1;
+        match instr {
+            Instr::Mov (src, dst) => {
+                let v = self.read(src)?;
+                self.write(dst, v)?;
+            }
+            Instr::Lea (src, dst) => {
+                let v = self.address(src);
+                self.write(dst, v)?;
+            }
+            Instr::Binop (op, src, dst) => {
+                let v = self.arith(op, self.read(src)?, self.read(dst)?);
+                self.write(dst, v)?;
+            }
+            Instr::Cmp (src, dst) => {
+                self.arith(Binop::Sub, self.read(src)?, self.read(dst)?);
+            }
+            Instr::Jmp (target) => return self.jump(self.read(target)?),
+            Instr::Jcc (cc, target) => {
+                if self.holds(cc) {
+                    return self.jump(self.read(target)?);
+                }
+            }
+            Instr::Call (target) => {
+                let target = self.read(target)?;
+                self.push(CODE_BASE + 8 * self.pc as i64)?;
+                return self.jump(target);
+            }
+            Instr::Runtime (f) => return self.call_runtime(f).map(Some),
+            Instr::Push (src) => {
+                let v = self.read(src)?;
+                self.push(v)?;
+            }
+            Instr::Pop (dst) => {
+                let v = self.load(self.regs[RSP])?;
+                self.regs[RSP] += 8;
+                self.write(dst, v)?;
+            }
+            Instr::Ret => {
+                let v = self.load(self.regs[RSP])?;
+                self.regs[RSP] += 8;
+                return self.jump(v);
+            }
+        }
+        Ok (None)
+    }
+
+    fn push(&mut self, v: i64) -> Result<(), String> {
+        let rsp = self.regs[RSP] - 8;
+        self.store(rsp, v)?;
+        self.regs[RSP] = rsp;
+        Ok (())
+    }
+
+    fn jump(&mut self, addr: i64) -> Result<Option<Answer>, String> {
+        if addr == EXIT {
+            return self.exit().map(Some);
+        }
+        let i = (addr - CODE_BASE) / 8;
+        if addr < CODE_BASE || addr % 8 != 0 || i >= self.code.len() as i64 {
+            return Err (format!("a jump to {:#x}, which is not code", addr));
+        }
+        self.pc = i as usize;
+        Ok (None)
+    }
+
+    // _scheme_entry returned to main, which prints the answer
+    fn exit(&self) -> Result<Answer, String> {
+        for r in CALLEE_SAVED {
+            if self.regs[r] != SAVED + r as i64 {
+                return Err (format!("a return to main without restoring %{}", REGISTERS[r]));
+            }
+        }
+        self.show(self.regs[RAX]).map(Answer::Value)
+    }
+
+    fn call_runtime(&self, f: Runtime) -> Result<Answer, String> {
+        if self.regs[RSP] % 16 != 0 {
+            return Err (format!("a call with %rsp at {:#x}, which is not aligned to 16 bytes", self.regs[RSP]));
+        }
+        let (rdi, rsi) = (self.regs[RDI], self.regs[RSI]);
+        let answer = match f {
+            Runtime::Error => {
+                let irritants = show_irritants(&|addr| self.load(addr), rsi)?;
+                error(self.show(rdi)?, irritants)
+            }
+            Runtime::Raise if rsi != 0 => returned(self.show(rdi)?),
+            Runtime::Raise => uncaught(self.show(rdi)?),
+            Runtime::LetrecError => Answer::Error (LETREC_ERROR.to_string()),
+        };
+        Ok (answer)
+    }
+
+    fn show(&self, x: i64) -> Result<String, String> {
+        show_word(&|addr| self.load(addr), x, 0)
+    }
+
+    fn read(&self, op: Operand) -> Result<i64, String> {
+        match op {
+            Operand::Reg (r) => Ok (self.regs[r]),
+            Operand::Imm (n) | Operand::Code (n) => Ok (n),
+            Operand::Mem (..) => self.load(self.address(op)),
+        }
+    }
+
+    fn write(&mut self, op: Operand, v: i64) -> Result<(), String> {
+        match op {
+            Operand::Reg (r) => self.regs[r] = v,
+            Operand::Mem (..) => self.store(self.address(op), v)?,
+            _ => unreachable!("decode only writes to registers and memory"),
+        }
+        Ok (())
+    }
+
+    fn address(&self, op: Operand) -> i64 {
+        match op {
+            Operand::Mem (base, index, disp) => {
+                let i = index.map_or(0, |r| self.regs[r]);
+                self.regs[base].wrapping_add(i).wrapping_add(disp)
+            }
+            Operand::Code (addr) => addr,
+            _ => unreachable!("decode only takes the address of memory and labels"),
+        }
+    }
+
+    // the region and the word of an address
+    fn locate(&self, addr: i64) -> Result<(usize, usize), String> {
+        for (r, region) in self.regions.iter().enumerate() {
+            if region.base <= addr && addr < region.end() {
+                if addr % 8 != 0 {
+                    return Err (format!("a misaligned access at {:#x} in the {}", addr, region.name));
+                }
+                return Ok ((r, ((addr - region.base) / 8) as usize));
+            }
+        }
+        if CODE_BASE <= addr && addr < CODE_BASE + 8 * self.code.len() as i64 {
+            return Err (format!("an invalid access to the code at {:#x}", addr));
+        }
+        // a word below a region is as many bytes below as above it ends past it
+        let nearest = self.regions.iter().map(|region| {
+            if addr < region.base {
+                (region.base - addr, "below", region.name)
+            } else {
+                (addr + 8 - region.end(), "above", region.name)
+            }
+        }).min();
+        match nearest {
+            Some ((d, side, name)) if d <= NEAR => Err (format!("an invalid access {} bytes {} the {} at {:#x}", d, side, name, addr)),
+            _ => Err (format!("an invalid access at {:#x}", addr)),
+        }
+    }
+
+    fn load(&self, addr: i64) -> Result<i64, String> {
+        let (r, i) = self.locate(addr)?;
+        Ok (self.regions[r].words[i])
+    }
+
+    fn store(&mut self, addr: i64, v: i64) -> Result<(), String> {
+        let (r, i) = self.locate(addr)?;
+        self.regions[r].words[i] = v;
+        Ok (())
+    }
+
+    // the result of the op on dst and src, setting the flags like the hardware
+    fn arith(&mut self, op: Binop, src: i64, dst: i64) -> i64 {
+        let (v, of, cf) = match op {
+            Binop::Add => {
+                let (v, of) = dst.overflowing_add(src);
+                (v, of, (dst as u64).overflowing_add(src as u64).1)
+            }
+            Binop::Sub => {
+                let (v, of) = dst.overflowing_sub(src);
+                (v, of, (dst as u64) < (src as u64))
+            }
+            Binop::Imul => {
+                let (v, of) = dst.overflowing_mul(src);
+                (v, of, of)
+            }
+            Binop::And => (dst & src, false, false),
+            Binop::Or => (dst | src, false, false),
+            Binop::Sar => {
+                // a count of zero leaves the flags alone
+                let n = src & 63;
+                if n == 0 {
+                    return dst;
+                }
+                (dst >> n, false, (dst >> (n - 1)) & 1 == 1)
+            }
+        };
+        self.flags = Flags { zf: v == 0, sf: v < 0, of, cf };
+        v
+    }
+
+    fn holds(&self, cc: Cc) -> bool {
+        let Flags { zf, sf, of, cf } = self.flags;
+        match cc {
+            Cc::E => zf, Cc::Ne => !zf,
+            Cc::L => sf != of, Cc::Ge => sf == of,
+            Cc::Le => zf || sf != of, Cc::G => !zf && sf == of,
+            Cc::B => cf, Cc::Ae => !cf,
+            Cc::Be => cf || zf, Cc::A => !cf && !zf,
+            Cc::S => sf, Cc::Ns => !sf,
+        }
+    }
+}
+
+
+fn flatten<'a>(codes: &'a [Asm], b: usize, source: &mut Vec<(&'a Asm, usize)>) {
+    for code in codes {
+        match code {
+            Asm::Code (codes) => flatten(codes, b, source),
+            other => source.push((other, b)),
+        }
+    }
+}
+
+fn operand(asm: &Asm, addresses: &HashMap<&str, i64>) -> Result<Operand, String> {
+    if let Some (r) = register(asm) {
+        return Ok (Operand::Reg (r));
+    }
+    match asm {
+        Asm::Imm (n) => Ok (Operand::Imm (*n)),
+        Asm::Deref (box base, disp) => match register(base) {
+            Some (r) => Ok (Operand::Mem (r, None, *disp)),
+            None => Err ("a bad base in".to_string()),
+        },
+        Asm::DerefRegister (box base, box index) => match (register(base), register(index)) {
+            (Some (r1), Some (r2)) => Ok (Operand::Mem (r1, Some (r2), 0)),
+            _ => Err ("a bad base or index in".to_string()),
+        },
+        Asm::DerefLabel (box Asm::RIP, box Asm::Label (s)) | Asm::Label (s) => match addresses.get(s.as_str()) {
+            Some (addr) => Ok (Operand::Code (*addr)),
+            None => Err (format!("the undefined label {} in", s)),
+        },
+        _ => Err ("an unknown operand in".to_string()),
+    }
+}
+
+// the instruction, if the assembler takes it
+fn decode(asm: &Asm, addresses: &HashMap<&str, i64>) -> Result<Instr, String> {
+    use Operand::*;
+    let rejected = || Err ("the assembler rejects".to_string());
+    let instr = match asm {
+        Asm::Op2 (op, box src, box dst) => {
+            // a label is only an operand of leaq, which takes its address
+            let (src_asm, dst_asm) = (src, dst);
+            let (src, dst) = (operand(src, addresses)?, operand(dst, addresses)?);
+            let code = |asm: &Asm| matches!(asm, Asm::Label (_) | Asm::DerefLabel (..));
+            if code(dst_asm) || (code(src_asm) && op != "leaq") {
+                return Err ("the emulator only takes the address of a label, in".to_string());
+            }
+            match (op.as_str(), src, dst) {
+                (_, _, Imm (_)) | (_, Mem (..), Mem (..)) => return rejected(),
+                ("movq", Imm (n), Mem (..)) if !imm32(n) => return rejected(),
+                ("movq", src, dst) => Instr::Mov (src, dst),
+                ("leaq", Mem (..) | Code (_), Reg (_)) => Instr::Lea (src, dst),
+                ("leaq", _, _) => return rejected(),
+                (_, Imm (n), _) if !imm32(n) => return rejected(),
+                ("cmpq", src, dst) => Instr::Cmp (src, dst),
+                ("imulq", _, Mem (..)) => return rejected(),
+                ("sarq", Imm (n), _) if !(0..256).contains(&n) => return rejected(),
+                ("sarq", Reg (_) | Mem (..), _) => return rejected(),
+                (op, src, dst) => {
+                    let op = match op {
+                        "addq" => Binop::Add, "subq" => Binop::Sub, "imulq" => Binop::Imul,
+                        "andq" => Binop::And, "orq" => Binop::Or, "sarq" => Binop::Sar,
+                        _ => return Err (format!("the unknown instruction {} in", op)),
+                    };
+                    Instr::Binop (op, src, dst)
+                }
+            }
+        }
+        Asm::Jmp (box target) => match operand(target, addresses)? {
+            Imm (_) => return rejected(),
+            target => Instr::Jmp (target),
+        },
+        Asm::Jmpif (cc, box target @ Asm::Label (_)) => {
+            let cc = match cc.as_str() {
+                "e" | "z" => Cc::E, "ne" | "nz" => Cc::Ne, "l" => Cc::L, "le" => Cc::Le,
+                "g" => Cc::G, "ge" => Cc::Ge, "b" => Cc::B, "be" => Cc::Be, "a" => Cc::A,
+                "ae" => Cc::Ae, "s" => Cc::S, "ns" => Cc::Ns,
+                _ => return Err (format!("the unknown condition {} in", cc)),
+            };
+            Instr::Jcc (cc, operand(target, addresses)?)
+        }
+        Asm::Jmpif (..) => return rejected(),
+        Asm::Callq (box Asm::Label (s)) if s == "_scheme_error" => Instr::Runtime (Runtime::Error),
+        Asm::Callq (box Asm::Label (s)) if s == "_scheme_raise" => Instr::Runtime (Runtime::Raise),
+        Asm::Callq (box Asm::Label (s)) if s == "_scheme_letrec_error" => Instr::Runtime (Runtime::LetrecError),
+        Asm::Callq (box target) => match operand(target, addresses)? {
+            Imm (_) => return rejected(),
+            target => Instr::Call (target),
+        },
+        Asm::Push (box src) => match operand(src, addresses)? {
+            Imm (n) if !imm32(n) => return rejected(),
+            Code (_) => return rejected(),
+            src => Instr::Push (src),
+        },
+        Asm::Pop (box dst) => match operand(dst, addresses)? {
+            Imm (_) | Code (_) => return rejected(),
+            dst => Instr::Pop (dst),
+        },
+        Asm::Retq => Instr::Ret,
+        _ => return Err ("the emulator does not know".to_string()),
+    };
+    Ok (instr)
+}
@@ -0,0 +1,357 @@
+// An encoder for the x86-64 instructions of CompileToAsm. It picks the encodings the GNU
+// assembler picks, down to the short jumps, so the code is byte for byte what `as` gives for
+// the assembly of GenerateAsm.
+use crate::syntax::{Asm, HashMap};
+
+// the code of a program, with the labels and the calls of functions outside of it
+pub struct Text {
+    pub bytes: Vec<u8>,
+    pub labels: Vec<(String, usize)>,
+    // the offset of the rel32 field of each call, and the function
+    pub calls: Vec<(usize, String)>,
+}
+
+fn register(asm: &Asm) -> Option<u8> {
+    use Asm::*;
+    let r = match asm {
+        RAX => 0, RCX => 1, RDX => 2, RBX => 3, RSP => 4, RBP => 5, RSI => 6, RDI => 7,
+        R8 => 8, R9 => 9, R10 => 10, R11 => 11, R12 => 12, R13 => 13, R14 => 14, R15 => 15,
+        _ => return None,
+    };
+    Some (r)
+}
+
+fn imm8(n: i64) -> bool {
+    n as i8 as i64 == n
+}
+
+fn imm32(n: i64) -> bool {
+    n as i32 as i64 == n
+}
+
+fn condition(cc: &str) -> u8 {
+    match cc {
+        "o" => 0x0, "no" => 0x1, "b" => 0x2, "ae" => 0x3, "e" | "z" => 0x4, "ne" | "nz" => 0x5,
+        "be" => 0x6, "a" => 0x7, "s" => 0x8, "ns" => 0x9, "p" => 0xa, "np" => 0xb,
+        "l" => 0xc, "ge" => 0xd, "le" => 0xe, "g" => 0xf,
+        _ => panic!("Invalid condition {}", cc),
+    }
+}
+
+// the group, the opcodes of op r/m, r and of op r, r/m, and the opcode with rax
+fn alu(op: &str) -> Option<(u8, u8, u8, u8)> {
+    match op {
+        "addq" => Some ((0, 0x01, 0x03, 0x05)),
+        "orq" => Some ((1, 0x09, 0x0b, 0x0d)),
+        "andq" => Some ((4, 0x21, 0x23, 0x25)),
+        "subq" => Some ((5, 0x29, 0x2b, 0x2d)),
+        "cmpq" => Some ((7, 0x39, 0x3b, 0x3d)),
+        _ => None,
+    }
+}
+
+enum Operand<'a> {
+    Reg (u8),
+    Imm (i64),
+    // base and displacement, base and index, or a label relative to rip
+    Base (u8, i64),
+    Index (u8, u8),
+    Rip (&'a str),
+}
+
+fn operand(asm: &Asm) -> Operand<'_> {
+    if let Some (r) = register(asm) {
+        return Operand::Reg (r);
+    }
+    match asm {
+        Asm::Imm (n) => Operand::Imm (*n),
+        Asm::Deref (box base, disp) => Operand::Base (register(base).expect("Invalid base"), *disp),
+        Asm::DerefRegister (box base, box index) => match (register(base), register(index)) {
+            (Some (b), Some (i)) if i != 4 => Operand::Index (b, i),
+            _ => panic!("Invalid base or index {}", asm),
+        },
+        Asm::DerefLabel (box Asm::RIP, box Asm::Label (s)) => Operand::Rip (s),
+        _ => panic!("Can not encode the operand {}", asm),
+    }
+}
+
+// one instruction. A rip relative operand is fixed up once the length is known.
+struct Instr<'a> {
+    bytes: Vec<u8>,
+    rip: Option<(usize, &'a str)>,
+}
+
+impl<'a> Instr<'a> {
+    fn new() -> Self {
+        Instr { bytes: vec![], rip: None }
+    }
+
+    fn imm(&mut self, n: i64, size: usize) {
+        self.bytes.extend_from_slice(&n.to_le_bytes()[..size]);
+    }
+
+    // the prefix, the opcode and the operand in r/m, with the register or the group in reg
+    fn op(&mut self, w: bool, opcode: &[u8], reg: u8, rm: &Operand<'a>) {
+        let (x, b) = match rm {
+            Operand::Reg (r) | Operand::Base (r, _) => (0, r >> 3),
+            Operand::Index (base, index) => (index >> 3, base >> 3),
+            _ => (0, 0),
+        };
+        let rex = (w as u8) << 3 | (reg >> 3) << 2 | x << 1 | b;
+        if rex != 0 {
+            self.bytes.push(0x40 | rex);
+        }
+        self.bytes.extend_from_slice(opcode);
+        let reg = (reg & 7) << 3;
+        match *rm {
+            Operand::Reg (r) => self.bytes.push(0xc0 | reg | (r & 7)),
+            Operand::Base (base, disp) => {
+                // rbp and r13 need a displacement, rsp and r12 a sib byte
+                let mode = if disp == 0 && base & 7 != 5 { 0x00 } else if imm8(disp) { 0x40 } else { 0x80 };
+                self.bytes.push(mode | reg | (base & 7));
+                if base & 7 == 4 {
+                    self.bytes.push(0x24);
+                }
+                match mode {
+                    0x40 => self.imm(disp, 1),
+                    0x80 => self.imm(disp, 4),
+                    _ => (),
+                }
+            }
+            Operand::Index (base, index) => {
+                let mode = if base & 7 == 5 { 0x40 } else { 0x00 };
+                self.bytes.push(mode | reg | 4);
+                self.bytes.push((index & 7) << 3 | (base & 7));
+                if mode == 0x40 {
+                    self.bytes.push(0);
+                }
+            }
+            Operand::Rip (label) => {
+                self.bytes.push(reg | 5);
+                self.rip = Some ((self.bytes.len(), label));
+                self.imm(0, 4);
+            }
+            Operand::Imm (_) => panic!("An immediate is not a r/m operand"),
+        }
+    }
+}
+
+fn memory(op: &Operand) -> bool {
+    matches!(op, Operand::Base (..) | Operand::Index (..) | Operand::Rip (_))
+}
+
+// every instruction but a jump or a call to a label
+fn encode(asm: &Asm) -> Instr<'_> {
+    use Operand::*;
+    let mut i = Instr::new();
+    match asm {
+        Asm::Op2 (op, box src, box dst) => {
+            let (src, dst) = (operand(src), operand(dst));
+            if memory(&src) && memory(&dst) {
+                panic!("Two memory operands in {}", asm);
+            }
+            match (op.as_str(), &src, &dst) {
+                ("movq", Reg (r), _) => i.op(true, &[0x89], *r, &dst),
+                ("movq", _, Reg (r)) if memory(&src) => i.op(true, &[0x8b], *r, &src),
+                ("movq", Imm (n), Reg (r)) if !imm32(*n) => {
+                    i.bytes.push(0x48 | r >> 3);
+                    i.bytes.push(0xb8 + (r & 7));
+                    i.imm(*n, 8);
+                }
+                ("movq", Imm (n), _) if imm32(*n) => {
+                    i.op(true, &[0xc7], 0, &dst);
+                    i.imm(*n, 4);
+                }
+                ("leaq", _, Reg (r)) if memory(&src) => i.op(true, &[0x8d], *r, &src),
+                ("imulq", Imm (n), Reg (r)) if imm8(*n) => {
+                    i.op(true, &[0x6b], *r, &dst);
+                    i.imm(*n, 1);
+                }
+                ("imulq", Imm (n), Reg (r)) if imm32(*n) => {
+                    i.op(true, &[0x69], *r, &dst);
+                    i.imm(*n, 4);
+                }
+                ("imulq", _, Reg (r)) => i.op(true, &[0x0f, 0xaf], *r, &src),
+                ("sarq", Imm (1), _) => i.op(true, &[0xd1], 7, &dst),
+                ("sarq", Imm (n), _) if (0..256).contains(n) => {
+                    i.op(true, &[0xc1], 7, &dst);
+                    i.imm(*n, 1);
+                }
+                (op, Imm (n), _) if alu(op).is_some() && imm8(*n) => {
+                    i.op(true, &[0x83], alu(op).unwrap().0, &dst);
+                    i.imm(*n, 1);
+                }
+                (op, Imm (n), Reg (0)) if alu(op).is_some() && imm32(*n) => {
+                    i.bytes.extend_from_slice(&[0x48, alu(op).unwrap().3]);
+                    i.imm(*n, 4);
+                }
+                (op, Imm (n), _) if alu(op).is_some() && imm32(*n) => {
+                    i.op(true, &[0x81], alu(op).unwrap().0, &dst);
+                    i.imm(*n, 4);
+                }
+                (op, Reg (r), _) if alu(op).is_some() => i.op(true, &[alu(op).unwrap().1], *r, &dst),
+                (op, _, Reg (r)) if alu(op).is_some() => i.op(true, &[alu(op).unwrap().2], *r, &src),
+                _ => panic!("Can not encode {}", asm),
+            }
+        }
+        Asm::Push (box src) => match operand(src) {
+            Reg (r) => {
+                if r >= 8 {
+                    i.bytes.push(0x41);
+                }
+                i.bytes.push(0x50 + (r & 7));
+            }
+            Imm (n) if imm8(n) => {
+                i.bytes.push(0x6a);
+                i.imm(n, 1);
+            }
+            Imm (n) if imm32(n) => {
+                i.bytes.push(0x68);
+                i.imm(n, 4);
+            }
+            Imm (_) => panic!("Can not encode {}", asm),
+            src => i.op(false, &[0xff], 6, &src),
+        },
+        Asm::Pop (box dst) => match operand(dst) {
+            Reg (r) => {
+                if r >= 8 {
+                    i.bytes.push(0x41);
+                }
+                i.bytes.push(0x58 + (r & 7));
+            }
+            Imm (_) => panic!("Can not encode {}", asm),
+            dst => i.op(false, &[0x8f], 0, &dst),
+        },
+        Asm::Jmp (box target) => i.op(false, &[0xff], 4, &operand(target)),
+        Asm::Callq (box target) => i.op(false, &[0xff], 2, &operand(target)),
+        Asm::Retq => i.bytes.push(0xc3),
+        _ => panic!("Can not encode {}", asm),
+    }
+    i
+}
+
+enum Item<'a> {
+    Label (&'a str),
+    Instr (&'a Asm),
+}
+
+fn flatten<'a>(codes: &'a [Asm], items: &mut Vec<Item<'a>>) {
+    for code in codes {
+        match code {
+            Asm::Code (codes) => flatten(codes, items),
+            other => items.push(Item::Instr (other)),
+        }
+    }
+}
+
+pub struct Encoder {}
+impl Encoder {
+    pub fn run(&self, code: &Asm) -> Text {
+        let mut items = vec![];
+        match code {
+            Asm::Prog (cfgs) => for cfg in cfgs {
+                match cfg {
+                    Asm::Cfg (label, codes) => {
+                        items.push(Item::Label (label));
+                        flatten(codes, &mut items);
+                    }
+                    e => panic!("Expect Cfg, found {}", e),
+                }
+            },
+            e => panic!("Invalid Program {}", e),
+        }
+        let defined: HashMap<&str, usize> = items.iter().enumerate()
+                                                .filter_map(|(k, item)| match item { Item::Label (s) => Some ((*s, k)), _ => None })
+                                                .collect();
+        // a jump to a label starts short, and grows until every jump reaches
+        let mut long: Vec<bool> = items.iter().map(|_| false).collect();
+        let sizes: Vec<usize> = items.iter().map(|item| match item {
+            Item::Instr (Asm::Jmp (box Asm::Label (_)) | Asm::Jmpif (_, box Asm::Label (_))) | Item::Label (_) => 0,
+            Item::Instr (Asm::Callq (box Asm::Label (_))) => 5,
+            Item::Instr (asm) => encode(asm).bytes.len(),
+        }).collect();
+        let offsets = loop {
+            let mut offsets = Vec::with_capacity(items.len() + 1);
+            let mut offset = 0;
+            for (k, item) in items.iter().enumerate() {
+                offsets.push(offset);
+                offset += match item {
+                    Item::Instr (Asm::Jmp (box Asm::Label (_))) => if long[k] { 5 } else { 2 },
+                    Item::Instr (Asm::Jmpif (_, box Asm::Label (_))) => if long[k] { 6 } else { 2 },
+                    _ => sizes[k],
+                };
+            }
+            offsets.push(offset);
+            let mut grown = false;
+            for (k, item) in items.iter().enumerate() {
+                if let Item::Instr (Asm::Jmp (box Asm::Label (s)) | Asm::Jmpif (_, box Asm::Label (s))) = item {
+                    let target = *defined.get(s.as_str()).unwrap_or_else(|| panic!("Undefined label {}", s));
+                    if !long[k] && !imm8(offsets[target] as i64 - offsets[k + 1] as i64) {
+                        long[k] = true;
+                        grown = true;
+                    }
+                }
+            }
+            if !grown {
+                break offsets;
+            }
+        };
+        let address = |s: &str| defined.get(s).map(|&k| offsets[k] as i64);
+        let mut text = Text { bytes: vec![], labels: vec![], calls: vec![] };
+        for (k, item) in items.iter().enumerate() {
+            let end = offsets[k + 1] as i64;
+            let i = match item {
+                Item::Label (s) => {
+                    text.labels.push((s.to_string(), offsets[k]));
+                    continue;
+                }
+                Item::Instr (Asm::Jmp (box Asm::Label (s))) => {
+                    let mut i = Instr::new();
+                    let rel = address(s).unwrap() - end;
+                    if long[k] {
+                        i.bytes.push(0xe9);
+                        i.imm(rel, 4);
+                    } else {
+                        i.bytes.push(0xeb);
+                        i.imm(rel, 1);
+                    }
+                    i
+                }
+                Item::Instr (Asm::Jmpif (cc, box Asm::Label (s))) => {
+                    let mut i = Instr::new();
+                    let rel = address(s).unwrap() - end;
+                    if long[k] {
+                        i.bytes.extend_from_slice(&[0x0f, 0x80 + condition(cc)]);
+                        i.imm(rel, 4);
+                    } else {
+                        i.bytes.push(0x70 + condition(cc));
+                        i.imm(rel, 1);
+                    }
+                    i
+                }
+                Item::Instr (Asm::Callq (box Asm::Label (s))) => {
+                    let mut i = Instr::new();
+                    i.bytes.push(0xe8);
+                    match address(s) {
+                        Some (target) => i.imm(target - end, 4),
+                        None => {
+                            text.calls.push((offsets[k] + 1, s.to_string()));
+                            i.imm(0, 4);
+                        }
+                    }
+                    i
+                }
+                Item::Instr (asm) => {
+                    let mut i = encode(asm);
+                    if let Some ((at, s)) = i.rip {
+                        let rel = address(s).unwrap_or_else(|| panic!("Undefined label {}", s)) - end;
+                        i.bytes[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+                    }
+                    i
+                }
+            };
+            text.bytes.extend_from_slice(&i.bytes);
+        }
+        text
+    }
+}
@@ -0,0 +1,594 @@
+// A differential fuzzer. It generates well-typed programs from a seed, compiles each one with
+// gcc and runtime.c, compares what it prints with the answer of the interpreter, and shrinks
+// a program they disagree on to a minimal one. The programs never fail at run time: vector
+// indices are constants in bounds, and the only recursion counts down from a small literal.
+use std::fmt;
+use std::panic;
+use std::path::Path;
+
+use crate::compiler::{compile_quiet, interpret, run_pass, ParseScheme, Program};
+use crate::corpus::{build, execute, panic_message};
+use crate::interp::Answer;
+
+// splitmix64, so that a seed gives the same programs everywhere
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + self.below((hi - lo + 1) as usize) as i64
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Features {
+    // lambdas as values, which capture the variables around them
+    pub closures: bool,
+    // set! on variables, some of them captured
+    pub assignments: bool,
+    pub vectors: bool,
+    // calls in non-tail position, including recursion which is not a loop
+    pub calls: bool,
+    // procedures defined in the outermost letrec
+    pub functions: usize,
+    // more than the parameter registers, so that the rest go to the frame
+    pub max_args: usize,
+    pub depth: usize,
+}
+
+impl Features {
+    pub fn all() -> Features {
+        Features { closures: true, assignments: true, vectors: true, calls: true, functions: 3, max_args: 5, depth: 4 }
+    }
+}
+
+// every procedure returns an integer
+#[derive(Clone, Debug, PartialEq)]
+enum Type {
+    Int,
+    Bool,
+    Vector (usize),
+    Proc (Vec<Type>),
+    Void,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Int (i64),
+    Bool (bool),
+    Var (String),
+    Prim (&'static str, Vec<Term>),
+    If (Box<Term>, Box<Term>, Box<Term>),
+    Let (Vec<(String, Term)>, Box<Term>),
+    Letrec (Vec<(String, Term)>, Box<Term>),
+    Lambda (Vec<String>, Box<Term>),
+    Call (Box<Term>, Vec<Term>),
+    Begin (Vec<Term>, Box<Term>),
+    Set (String, Box<Term>),
+    // a vector made and filled in place, with the name it is bound to meanwhile
+    Vector (String, Vec<Term>),
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    ty: Type,
+    node: Node,
+}
+
+fn term(ty: Type, node: Node) -> Term {
+    Term { ty, node }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn join(terms: &[Term]) -> String {
+            terms.iter().map(|t| format!(" {}", t)).collect()
+        }
+        fn bindings(bindings: &[(String, Term)]) -> String {
+            bindings.iter().map(|(x, t)| format!("[{} {}]", x, t)).collect::<Vec<_>>().join(" ")
+        }
+        match &self.node {
+            Node::Int (n) => write!(f, "{}", n),
+            Node::Bool (b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
+            Node::Var (x) => write!(f, "{}", x),
+            Node::Prim (op, args) => write!(f, "({}{})", op, join(args)),
+            Node::If (pred, b1, b2) => write!(f, "(if {} {} {})", pred, b1, b2),
+            Node::Let (bs, body) => write!(f, "(let ({}) {})", bindings(bs), body),
+            Node::Letrec (bs, body) => write!(f, "(letrec ({}) {})", bindings(bs), body),
+            Node::Lambda (params, body) => write!(f, "(lambda ({}) {})", params.join(" "), body),
+            Node::Call (proc, args) => write!(f, "({}{})", proc, join(args)),
+            Node::Begin (effects, value) => write!(f, "(begin{} {})", join(effects), value),
+            Node::Set (x, value) => write!(f, "(set! {} {})", x, value),
+            Node::Vector (v, elements) => {
+                write!(f, "(let ([{} (make-vector {})]) (begin", v, elements.len())?;
+                for (i, e) in elements.iter().enumerate() {
+                    write!(f, " (vector-set! {} {} {})", v, i, e)?;
+                }
+                write!(f, " {}))", v)
+            }
+        }
+    }
+}
+
+impl Term {
+    fn children_mut(&mut self) -> Vec<&mut Term> {
+        match &mut self.node {
+            Node::Int (_) | Node::Bool (_) | Node::Var (_) => vec![],
+            Node::Prim (_, args) | Node::Vector (_, args) => args.iter_mut().collect(),
+            Node::If (pred, b1, b2) => vec![pred, b1, b2],
+            Node::Let (bs, body) | Node::Letrec (bs, body) => bs.iter_mut().map(|(_, t)| t).chain(std::iter::once(&mut **body)).collect(),
+            Node::Lambda (_, body) | Node::Set (_, body) => vec![body],
+            Node::Call (proc, args) => std::iter::once(&mut **proc).chain(args.iter_mut()).collect(),
+            Node::Begin (effects, value) => effects.iter_mut().chain(std::iter::once(&mut **value)).collect(),
+        }
+    }
+
+    fn children(&self) -> Vec<&Term> {
+        match &self.node {
+            Node::Int (_) | Node::Bool (_) | Node::Var (_) => vec![],
+            Node::Prim (_, args) | Node::Vector (_, args) => args.iter().collect(),
+            Node::If (pred, b1, b2) => vec![pred, b1, b2],
+            Node::Let (bs, body) | Node::Letrec (bs, body) => bs.iter().map(|(_, t)| t).chain(std::iter::once(&**body)).collect(),
+            Node::Lambda (_, body) | Node::Set (_, body) => vec![body],
+            Node::Call (proc, args) => std::iter::once(&**proc).chain(args.iter()).collect(),
+            Node::Begin (effects, value) => effects.iter().chain(std::iter::once(&**value)).collect(),
+        }
+    }
+
+    // names are never reused, so a name mentioned anywhere inside refers to the same variable
+    fn mentions(&self, x: &str) -> bool {
+        match &self.node {
+            Node::Var (y) | Node::Set (y, _) if y == x => true,
+            _ => self.children().iter().any(|t| t.mentions(x)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        1 + self.children().iter().map(|t| t.size()).sum::<usize>()
+    }
+}
+
+// the simplest term of a type
+fn default(ty: &Type, names: &mut usize) -> Term {
+    let node = match ty {
+        Type::Int => Node::Int (0),
+        Type::Bool => Node::Bool (false),
+        Type::Void => Node::Prim ("void", vec![]),
+        Type::Vector (n) => Node::Vector (fresh("v", names), vec![term(Type::Int, Node::Int (0)); *n]),
+        Type::Proc (params) => {
+            let params = params.iter().map(|_| fresh("p", names)).collect();
+            Node::Lambda (params, Box::new(term(Type::Int, Node::Int (0))))
+        }
+    };
+    term(ty.clone(), node)
+}
+
+fn fresh(prefix: &str, names: &mut usize) -> String {
+    *names += 1;
+    format!("{}{}", prefix, names)
+}
+
+#[derive(Clone)]
+struct Scope {
+    // the variables in scope, their types, and whether they may be assigned
+    vars: Vec<(String, Type, bool)>,
+    // the procedures of the outermost letrec which may be called
+    callable: usize,
+}
+
+impl Scope {
+    fn bind(&self, x: &str, ty: &Type, mutable: bool) -> Scope {
+        let mut scope = self.clone();
+        scope.vars.push((x.to_string(), ty.clone(), mutable));
+        scope
+    }
+}
+
+struct Generator {
+    rng: Rng,
+    features: Features,
+    names: usize,
+    // the parameter types after the count of each procedure of the outermost letrec
+    functions: Vec<(String, Vec<Type>)>,
+}
+
+impl Generator {
+    fn new(seed: u64, features: &Features) -> Generator {
+        Generator { rng: Rng::new(seed), features: features.clone(), names: 0, functions: vec![] }
+    }
+
+    fn fresh(&mut self, prefix: &str) -> String {
+        fresh(prefix, &mut self.names)
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> Option<T> {
+        match items.len() {
+            0 => None,
+            n => Some (items[self.rng.below(n)].clone()),
+        }
+    }
+
+    fn random_type(&mut self) -> Type {
+        let mut types = vec![Type::Int, Type::Int, Type::Int, Type::Bool];
+        if self.features.vectors {
+            types.push(Type::Vector (self.rng.range(1, 3) as usize));
+        }
+        if self.features.closures {
+            let n = self.rng.range(1, 2) as usize;
+            types.push(Type::Proc (vec![Type::Int; n]));
+        }
+        self.pick(&types).unwrap()
+    }
+
+    fn program(&mut self) -> Term {
+        let mut bindings = vec![];
+        let depth = self.features.depth;
+        for i in 0..self.features.functions {
+            let name = self.fresh("f");
+            let params: Vec<Type> = (1..self.rng.range(1, self.features.max_args.max(1) as i64)).map(|_| self.random_type()).collect();
+            self.functions.push((name.clone(), params.clone()));
+            let count = self.fresh("n");
+            let mut scope = Scope { vars: vec![], callable: i };
+            scope = scope.bind(&count, &Type::Int, false);
+            let names: Vec<String> = params.iter().map(|_| self.fresh("x")).collect();
+            for (x, ty) in names.iter().zip(params.iter()) {
+                scope = scope.bind(x, ty, *ty == Type::Int);
+            }
+            // (if (<= n 0) base (f (- n 1) ...)), where the call may be an operand
+            let base = self.term(&Type::Int, &scope, depth.saturating_sub(1), true);
+            let decrement = term(Type::Int, Node::Prim ("-", vec![term(Type::Int, Node::Var (count.clone())), term(Type::Int, Node::Int (1))]));
+            let mut args = vec![decrement];
+            args.extend(params.iter().map(|ty| self.term(ty, &scope, depth.saturating_sub(2), false)));
+            let ty = Type::Proc (std::iter::once(Type::Int).chain(params.iter().cloned()).collect());
+            let mut recur = term(Type::Int, Node::Call (Box::new(term(ty.clone(), Node::Var (name.clone()))), args));
+            if self.features.calls && self.rng.chance(50) {
+                let other = self.term(&Type::Int, &scope, depth.saturating_sub(2), false);
+                recur = term(Type::Int, Node::Prim ("+", vec![other, recur]));
+            }
+            let pred = term(Type::Bool, Node::Prim ("<=", vec![term(Type::Int, Node::Var (count.clone())), term(Type::Int, Node::Int (0))]));
+            let body = term(Type::Int, Node::If (Box::new(pred), Box::new(base), Box::new(recur)));
+            let params = std::iter::once(count).chain(names.into_iter()).collect();
+            bindings.push((name, term(ty, Node::Lambda (params, Box::new(body)))));
+        }
+        let scope = Scope { vars: vec![], callable: self.functions.len() };
+        let ty = if self.features.vectors && self.rng.chance(20) {
+            Type::Vector (self.rng.range(1, 3) as usize)
+        } else if self.rng.chance(20) {
+            Type::Bool
+        } else {
+            Type::Int
+        };
+        let body = self.term(&ty, &scope, depth, true);
+        if bindings.is_empty() {
+            return body;
+        }
+        term(ty, Node::Letrec (bindings, Box::new(body)))
+    }
+
+    fn leaf(&mut self, ty: &Type, scope: &Scope) -> Term {
+        let vars: Vec<String> = scope.vars.iter().filter(|(_, t, _)| t == ty).map(|(x, _, _)| x.clone()).collect();
+        if !vars.is_empty() && (self.rng.chance(60) || !matches!(ty, Type::Int | Type::Bool)) {
+            let x = self.pick(&vars).unwrap();
+            return term(ty.clone(), Node::Var (x));
+        }
+        match ty {
+            Type::Int => term(Type::Int, Node::Int (self.rng.range(-10, 50))),
+            Type::Bool => term(Type::Bool, Node::Bool (self.rng.chance(50))),
+            Type::Vector (n) => {
+                let v = self.fresh("v");
+                let elements = (0..*n).map(|_| self.leaf(&Type::Int, scope)).collect();
+                term(ty.clone(), Node::Vector (v, elements))
+            }
+            Type::Proc (params) => {
+                let names: Vec<String> = params.iter().map(|_| self.fresh("p")).collect();
+                let body = self.leaf(&Type::Int, scope);
+                term(ty.clone(), Node::Lambda (names, Box::new(body)))
+            }
+            Type::Void => term(Type::Void, Node::Prim ("void", vec![])),
+        }
+    }
+
+    fn term(&mut self, ty: &Type, scope: &Scope, depth: usize, tail: bool) -> Term {
+        if depth == 0 || self.rng.chance(15) {
+            return self.leaf(ty, scope);
+        }
+        let d = depth - 1;
+        let vectors: Vec<(String, usize)> = scope.vars.iter().filter_map(|(x, t, _)| match t {
+            Type::Vector (n) => Some ((x.clone(), *n)),
+            _ => None,
+        }).collect();
+        let procs: Vec<(String, Vec<Type>)> = scope.vars.iter().filter_map(|(x, t, _)| match t {
+            Type::Proc (params) => Some ((x.clone(), params.clone())),
+            _ => None,
+        }).collect();
+        let calls = tail || self.features.calls;
+        let mut choices = vec!["if", "let"];
+        match ty {
+            Type::Int => {
+                choices.extend(&["+", "-", "*", "+", "-"]);
+                if calls && scope.callable > 0 {
+                    choices.extend(&["function", "function"]);
+                }
+                if calls && self.features.closures {
+                    choices.push("lambda");
+                    if !procs.is_empty() {
+                        choices.extend(&["closure", "closure"]);
+                    }
+                }
+                if self.features.vectors {
+                    choices.extend(&["vector-ref", "vector-length"]);
+                }
+                if self.features.assignments || self.features.vectors {
+                    choices.push("begin");
+                }
+            }
+            Type::Bool => {
+                choices.extend(&["<", "=", "<=", "<", "predicate"]);
+            }
+            Type::Vector (_) => choices.extend(&["make", "make", "begin"]),
+            Type::Proc (_) => choices.extend(&["lambda", "lambda"]),
+            Type::Void => {
+                choices = vec!["if"];
+                if self.features.assignments && scope.vars.iter().any(|(_, _, mutable)| *mutable) {
+                    choices.extend(&["set!", "set!"]);
+                }
+                if !vectors.is_empty() {
+                    choices.extend(&["vector-set!", "vector-set!"]);
+                }
+            }
+        }
+        let choice = self.pick(&choices).unwrap();
+        let int = Type::Int;
+        let node = match choice {
+            "if" => {
+                let pred = self.term(&Type::Bool, scope, d, false);
+                let b1 = self.term(ty, scope, d, tail);
+                let b2 = self.term(ty, scope, d, tail);
+                Node::If (Box::new(pred), Box::new(b1), Box::new(b2))
+            }
+            "let" => {
+                // several bindings, which stay live across what the body calls
+                let mut bindings = vec![];
+                let mut inner = scope.clone();
+                for _ in 0..self.rng.range(1, 4) {
+                    let t = self.random_type();
+                    let rhs = self.term(&t, scope, d, false);
+                    let x = self.fresh("x");
+                    inner = inner.bind(&x, &t, t == Type::Int && self.features.assignments);
+                    bindings.push((x, rhs));
+                }
+                Node::Let (bindings, Box::new(self.term(ty, &inner, d, tail)))
+            }
+            "begin" => {
+                let effects = (0..self.rng.range(1, 2)).map(|_| self.term(&Type::Void, scope, d, false)).collect();
+                Node::Begin (effects, Box::new(self.term(ty, scope, d, tail)))
+            }
+            "+" | "-" => Node::Prim (if choice == "+" { "+" } else { "-" }, vec![self.term(&int, scope, d, false), self.term(&int, scope, d, false)]),
+            "*" => Node::Prim ("*", vec![self.term(&int, scope, d, false), term(Type::Int, Node::Int (self.rng.range(-3, 3)))]),
+            "<" | "=" | "<=" => {
+                let op = match choice { "<" => "<", "=" => "=", _ => "<=" };
+                Node::Prim (op, vec![self.term(&int, scope, d, false), self.term(&int, scope, d, false)])
+            }
+            "predicate" => {
+                let t = self.random_type();
+                let op = self.pick(&["fixnum?", "boolean?", "vector?", "procedure?"]).unwrap();
+                Node::Prim (op, vec![self.term(&t, scope, d, false)])
+            }
+            "function" => {
+                // a small count keeps the recursion short
+                let (name, params) = self.functions[self.rng.below(scope.callable)].clone();
+                let mut args = vec![term(Type::Int, Node::Int (self.rng.range(0, 3)))];
+                args.extend(params.iter().map(|t| self.term(t, scope, d, false)));
+                let ty = Type::Proc (std::iter::once(Type::Int).chain(params.into_iter()).collect());
+                Node::Call (Box::new(term(ty, Node::Var (name))), args)
+            }
+            "closure" => {
+                let (name, params) = self.pick(&procs).unwrap();
+                let args = params.iter().map(|t| self.term(t, scope, d, false)).collect();
+                Node::Call (Box::new(term(Type::Proc (params), Node::Var (name))), args)
+            }
+            "lambda" if *ty == Type::Int => {
+                // ((lambda (x ...) body) arg ...)
+                let n = self.rng.range(1, 2) as usize;
+                let t = Type::Proc (vec![Type::Int; n]);
+                let proc = self.term(&t, scope, d, false);
+                let args = (0..n).map(|_| self.term(&int, scope, d, false)).collect();
+                Node::Call (Box::new(proc), args)
+            }
+            "lambda" => {
+                let params = match ty { Type::Proc (params) => params.clone(), _ => unreachable!() };
+                let names: Vec<String> = params.iter().map(|_| self.fresh("p")).collect();
+                let mut inner = scope.clone();
+                for (x, t) in names.iter().zip(params.iter()) {
+                    inner = inner.bind(x, t, *t == Type::Int && self.features.assignments);
+                }
+                Node::Lambda (names, Box::new(self.term(&int, &inner, d, true)))
+            }
+            "vector-ref" | "vector-length" => {
+                let n = self.rng.range(1, 3) as usize;
+                let v = self.term(&Type::Vector (n), scope, d, false);
+                if choice == "vector-length" {
+                    Node::Prim ("vector-length", vec![v])
+                } else {
+                    Node::Prim ("vector-ref", vec![v, term(Type::Int, Node::Int (self.rng.below(n) as i64))])
+                }
+            }
+            "make" => {
+                let n = match ty { Type::Vector (n) => *n, _ => unreachable!() };
+                let v = self.fresh("v");
+                Node::Vector (v, (0..n).map(|_| self.term(&int, scope, d, false)).collect())
+            }
+            "set!" => {
+                let vars: Vec<String> = scope.vars.iter().filter(|(_, _, mutable)| *mutable).map(|(x, _, _)| x.clone()).collect();
+                let x = self.pick(&vars).unwrap();
+                Node::Set (x, Box::new(self.term(&int, scope, d, false)))
+            }
+            "vector-set!" => {
+                let (v, n) = self.pick(&vectors).unwrap();
+                let i = term(Type::Int, Node::Int (self.rng.below(n) as i64));
+                Node::Prim ("vector-set!", vec![term(Type::Vector (n), Node::Var (v)), i, self.term(&int, scope, d, false)])
+            }
+            _ => unreachable!(),
+        };
+        term(ty.clone(), node)
+    }
+}
+
+// the programs one step smaller than t
+fn shrinks(t: &Term, names: &mut usize) -> Vec<Term> {
+    let mut candidates = vec![];
+    match &t.node {
+        Node::Int (0) | Node::Bool (false) | Node::Var (_) => (),
+        Node::Prim ("void", _) => (),
+        _ => candidates.push(default(&t.ty, names)),
+    }
+    if let Node::Int (n) = t.node {
+        if n.abs() > 1 {
+            candidates.push(term(Type::Int, Node::Int (n / 2)));
+        }
+    }
+    match &t.node {
+        Node::Let (bindings, body) | Node::Letrec (bindings, body) => {
+            let letrec = matches!(t.node, Node::Letrec (..));
+            if !bindings.iter().any(|(x, _)| body.mentions(x)) {
+                candidates.push((**body).clone());
+            }
+            for (i, (x, _)) in bindings.iter().enumerate() {
+                let used = body.mentions(x) || (letrec && bindings.iter().any(|(_, rhs)| rhs.mentions(x)));
+                if !used {
+                    let mut rest = bindings.clone();
+                    rest.remove(i);
+                    let node = if letrec { Node::Letrec (rest, body.clone()) } else { Node::Let (rest, body.clone()) };
+                    candidates.push(term(t.ty.clone(), node));
+                }
+            }
+            if !letrec {
+                candidates.extend(bindings.iter().map(|(_, rhs)| rhs).filter(|rhs| rhs.ty == t.ty).cloned());
+            }
+        }
+        Node::Begin (effects, value) => {
+            candidates.push((**value).clone());
+            for i in 0..effects.len() {
+                let mut rest = effects.clone();
+                rest.remove(i);
+                candidates.push(term(t.ty.clone(), Node::Begin (rest, value.clone())));
+            }
+        }
+        Node::Lambda (..) | Node::Vector (..) => (),
+        _ => candidates.extend(t.children().into_iter().filter(|c| c.ty == t.ty).cloned()),
+    }
+    let n = t.children().len();
+    for i in 0..n {
+        let child = t.children()[i].clone();
+        for s in shrinks(&child, names) {
+            let mut copy = t.clone();
+            *copy.children_mut()[i] = s;
+            candidates.push(copy);
+        }
+    }
+    candidates
+}
+
+enum Outcome {
+    Agree,
+    // the interpreter gave no answer
+    Unknown,
+    Disagree (String),
+}
+
+pub struct Fuzzer {
+    pub features: Features,
+    // the assembly goes to <stem>.s and the executable to test_<stem>
+    pub stem: String,
+}
+
+impl Fuzzer {
+    fn test(&self, program: &str) -> Outcome {
+        let expected = panic::catch_unwind(|| {
+            let scm = ParseScheme{}.run(program);
+            interpret("IntroduceLibrary", &run_pass("IntroduceLibrary", Program::Scheme (scm)))
+        });
+        let expected = match expected {
+            Ok (Some (Answer::Stuck (_))) | Ok (None) => return Outcome::Unknown,
+            Ok (Some (answer)) => answer,
+            Err (e) => return Outcome::Disagree (format!("the interpreter panicked: {}", panic_message(e))),
+        };
+        let filename = format!("{}.s", self.stem);
+        if let Err (e) = panic::catch_unwind(|| compile_quiet(program, &filename).expect("failed to write the assembly")) {
+            return Outcome::Disagree (format!("the compiler panicked: {}", panic_message(e)));
+        }
+        let exe = format!("./test_{}", self.stem);
+        if let Err (msg) = build(Path::new(&filename), Path::new(&exe)) {
+            return Outcome::Disagree (msg);
+        }
+        let output = match execute(Path::new(&exe)) {
+            Ok (output) => output,
+            Err (msg) => return Outcome::Disagree (msg),
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let actual = if output.status.success() { Answer::Value (stdout) } else { Answer::Error (stderr) };
+        match (&expected, &actual) {
+            (Answer::Value (a), Answer::Value (b)) | (Answer::Error (a), Answer::Error (b)) if a == b => Outcome::Agree,
+            _ => Outcome::Disagree (format!("the interpreter says {}, but the program says {}", expected, actual)),
+        }
+    }
+
+    // keeps taking the first smaller program which still fails, until none does
+    fn shrink(&self, mut program: Term, mut reason: String, names: &mut usize) -> (Term, String) {
+        'outer: loop {
+            let mut candidates = shrinks(&program, names);
+            candidates.sort_by_key(|t| t.size());
+            for candidate in candidates {
+                if candidate.size() >= program.size() {
+                    continue;
+                }
+                if let Outcome::Disagree (r) = self.test(&candidate.to_string()) {
+                    program = candidate;
+                    reason = r;
+                    continue 'outer;
+                }
+            }
+            return (program, reason);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn generate(&self, seed: u64) -> String {
+        Generator::new(seed, &self.features).program().to_string()
+    }
+
+    // tests count programs from the seed on, and reports the first failure shrunk
+    pub fn run(&self, seed: u64, count: usize) -> Option<String> {
+        for seed in seed..seed + count as u64 {
+            let mut generator = Generator::new(seed, &self.features);
+            let program = generator.program();
+            if let Outcome::Disagree (reason) = self.test(&program.to_string()) {
+                let (program, reason) = self.shrink(program, reason, &mut generator.names);
+                return Some (format!("seed {}: {}\n{}", seed, reason, program));
+            }
+        }
+        None
+    }
+}
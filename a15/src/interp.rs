@@ -0,0 +1,2199 @@
+// Interpreters for the languages between the passes. Every pass output runs to an answer,
+// and a pass that changes the answer has miscompiled the program.
+use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::convert::Infallible;
+
+use crate::syntax::{Scheme, Expr, HashMap};
+use crate::compiler::{
+    SHIFT_FIXNUM, MASK_FIXNUM, TAG_FIXNUM, TAG_PAIR, TAG_VECTOR, TAG_PROC, TAG_BOX, TAG_RECORD,
+    SIZE_PAIR, CAR_OFFSET, CDR_OFFSET, VLEN_OFFSET, VDATA_OFFSET, PROC_CODE_OFFSET, PROC_DATA_OFFSET,
+    BOX_OFFSET, RECORD_RTD_OFFSET, FALSE, TRUE, NIL, VOID,
+    APPLY_LABEL, COLLECT_REST_LABEL, CALLCC_LABEL, CONTINUATION_LABEL, HANDLER_LABEL, RAISE_LABEL,
+    RAISE_CONTINUABLE_LABEL, ERROR_LABEL, VALUES_LABEL, APPLY_VALUES_LABEL, CALL_WITH_VALUES_LABEL, LETREC_ERROR_LABEL,
+    CONT_RP_OFFSET, CONT_HANDLERS_OFFSET, CONT_SIZE_OFFSET, CONT_STACK_OFFSET,
+    runtime_label, primitive_arity, letrec_unsafe, is_reg, is_uvar, is_label, is_rest,
+};
+
+const MAX_STEPS: usize = 50_000_000;
+
+// the same limits as print1 in runtime.c
+const MAX_DEPTH: usize = 100;
+const MAX_LENGTH: usize = 1000;
+
+pub const LETREC_ERROR: &str = "Error: invalid reference to a letrec variable before its initialization";
+
+const CODE_BASE: i64 = 0x100;
+const HEAP_BASE: i64 = 0x1000_0000;
+const STACK_BASE: i64 = 0x2000_0000;
+
+// what the program prints, a value on stdout or an error on stderr.
+// A program is stuck when the interpreter can not go on, the answer is unknown then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Answer {
+    Value (String),
+    Error (String),
+    Stuck (String),
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Answer::Value (s) | Answer::Error (s) => write!(f, "{}", s),
+            Answer::Stuck (s) => write!(f, "stuck, {}", s),
+        }
+    }
+}
+
+fn stuck<T>(msg: String) -> Result<T, Answer> {
+    Err (Answer::Stuck (msg))
+}
+
+pub fn error(msg: String, irritants: Vec<String>) -> Answer {
+    let mut s = format!("Error: {}", msg);
+    for irritant in irritants {
+        s.push(' ');
+        s.push_str(&irritant);
+    }
+    Answer::Error (s)
+}
+
+pub fn uncaught(obj: String) -> Answer {
+    Answer::Error (format!("Exception: uncaught {}", obj))
+}
+
+pub fn returned(obj: String) -> Answer {
+    Answer::Error (format!("Exception: handler returned from non-continuable raise of {}", obj))
+}
+
+// a fixnum keeps 61 bits, like its tagged word
+fn fixnum(n: i64) -> i64 {
+    n.wrapping_shl(SHIFT_FIXNUM as u32) >> SHIFT_FIXNUM
+}
+
+fn binop(op: &str, a: i64, b: i64) -> Option<i64> {
+    match op {
+        "+" => Some (a.wrapping_add(b)),
+        "-" => Some (a.wrapping_sub(b)),
+        "*" => Some (a.wrapping_mul(b)),
+        "logand" => Some (a & b),
+        "logor" => Some (a | b),
+        "sra" => Some (a >> (b & 63)),
+        _ => None,
+    }
+}
+
+fn relop(op: &str, a: i64, b: i64) -> Option<bool> {
+    match op {
+        "=" => Some (a == b),
+        "<" => Some (a < b),
+        ">" => Some (a > b),
+        "<=" => Some (a <= b),
+        ">=" => Some (a >= b),
+        _ => None,
+    }
+}
+
+// the primitive a runtime label stands for
+fn runtime_op(label: &str) -> Option<&'static str> {
+    if label == CONTINUATION_LABEL {
+        return Some ("continuation");
+    }
+    ["apply", "call/cc", "with-exception-handler", "raise", "raise-continuable", "error", "values",
+    "apply-values", "call-with-values", "letrec-error"].iter().copied().find(|op| runtime_label(op) == Some (label))
+}
+
+fn in_range(i: i64, len: usize) -> bool {
+    i >= 0 && (i as usize) < len
+}
+
+
+// ---------------------------------------------------------------------
+//
+// Scheme, from IntroduceLibrary to NormalizeContext
+//
+// ---------------------------------------------------------------------
+
+#[derive(Clone)]
+enum Value<'a> {
+    Int (i64),
+    Bool (bool),
+    Nil,
+    Void,
+    Pair (Rc<RefCell<(Value<'a>, Value<'a>)>>),
+    Vector (Rc<RefCell<Vec<Value<'a>>>>),
+    Boxed (Rc<RefCell<Value<'a>>>),
+    Record (Rc<Record<'a>>),
+    Proc (Rc<Procedure<'a>>),
+    Label (String),
+    Cont (Rc<Captured<'a>>),
+    Unbound,
+}
+
+// the last reference to a long list would drop its cells recursively, one frame per cdr,
+// so the contents of a pair, vector or box dropped here are moved to a worklist first
+impl<'a> Drop for Value<'a> {
+    fn drop(&mut self) {
+        let mut pending = vec![];
+        unlink(self, &mut pending);
+        while let Some (mut v) = pending.pop() {
+            unlink(&mut v, &mut pending);
+        }
+    }
+}
+
+fn unlink<'a>(v: &mut Value<'a>, pending: &mut Vec<Value<'a>>) {
+    match v {
+        Value::Pair (p) => if let Some (cell) = Rc::get_mut(p) {
+            let (a, b) = std::mem::replace(cell.get_mut(), (Value::Nil, Value::Nil));
+            pending.push(a);
+            pending.push(b);
+        },
+        Value::Vector (items) => if let Some (cell) = Rc::get_mut(items) {
+            pending.append(cell.get_mut());
+        },
+        Value::Boxed (b) => if let Some (cell) = Rc::get_mut(b) {
+            pending.push(std::mem::replace(cell.get_mut(), Value::Nil));
+        },
+        _ => (),
+    }
+}
+
+struct Record<'a> {
+    rtd: Value<'a>,
+    fields: RefCell<Vec<Value<'a>>>,
+}
+
+struct Procedure<'a> {
+    code: Code<'a>,
+    data: RefCell<Vec<Value<'a>>>,
+}
+
+enum Code<'a> {
+    Lambda (&'a [String], &'a Scheme, Env<'a>),
+    Label (String),
+    Prim (String),
+    Cont (Rc<Captured<'a>>),
+}
+
+struct Captured<'a> {
+    kont: Kont<'a>,
+    handlers: Vec<Value<'a>>,
+}
+
+type Env<'a> = Option<Rc<Binding<'a>>>;
+
+struct Binding<'a> {
+    name: &'a str,
+    value: RefCell<Value<'a>>,
+    next: Env<'a>,
+}
+
+fn extend<'a>(env: Env<'a>, name: &'a str, value: Value<'a>) -> Env<'a> {
+    Some (Rc::new(Binding { name, value: RefCell::new(value), next: env }))
+}
+
+fn binding<'a>(env: &Env<'a>, name: &str) -> Option<Rc<Binding<'a>>> {
+    let mut env = env;
+    while let Some (b) = env {
+        if b.name == name {
+            return Some (b.clone());
+        }
+        env = &b.next;
+    }
+    None
+}
+
+fn procedure<'a>(code: Code<'a>, n: usize) -> Value<'a> {
+    Value::Proc (Rc::new(Procedure { code, data: RefCell::new(vec![Value::Int (0); n]) }))
+}
+
+fn cons<'a>(a: Value<'a>, b: Value<'a>) -> Value<'a> {
+    Value::Pair (Rc::new(RefCell::new((a, b))))
+}
+
+fn truthy(v: &Value) -> bool {
+    !matches!(v, Value::Bool (false))
+}
+
+fn eq<'a>(a: &Value<'a>, b: &Value<'a>) -> bool {
+    use Value as V;
+    match (a, b) {
+        (V::Int (a), V::Int (b)) => a == b,
+        (V::Bool (a), V::Bool (b)) => a == b,
+        (V::Nil, V::Nil) | (V::Void, V::Void) => true,
+        (V::Pair (a), V::Pair (b)) => Rc::ptr_eq(a, b),
+        (V::Vector (a), V::Vector (b)) => Rc::ptr_eq(a, b),
+        (V::Boxed (a), V::Boxed (b)) => Rc::ptr_eq(a, b),
+        (V::Record (a), V::Record (b)) => Rc::ptr_eq(a, b),
+        (V::Proc (a), V::Proc (b)) => Rc::ptr_eq(a, b),
+        (V::Label (a), V::Label (b)) => a == b,
+        _ => false,
+    }
+}
+
+// print1 of runtime.c
+fn show(v: &Value, depth: usize) -> String {
+    use Value as V;
+    match v {
+        V::Int (i) => i.to_string(),
+        V::Pair (_) if depth > MAX_DEPTH => "(...)".to_string(),
+        V::Pair (p) => {
+            let mut s = format!("({}", show(&p.borrow().0, depth + 1));
+            let mut rest = p.borrow().1.clone();
+            let mut len = 0;
+            while let V::Pair (q) = &rest.clone() {
+                if len == MAX_LENGTH - 1 {
+                    break;
+                }
+                s.push(' ');
+                s.push_str(&show(&q.borrow().0, depth + 1));
+                rest = q.borrow().1.clone();
+                len += 1;
+            }
+            match rest {
+                V::Nil => (),
+                _ if len == MAX_LENGTH - 1 => s.push_str(" ..."),
+                rest => s.push_str(&format!(" . {}", show(&rest, depth + 1))),
+            }
+            s.push(')');
+            s
+        }
+        V::Vector (_) if depth > MAX_DEPTH => "#(...)".to_string(),
+        V::Vector (v) => {
+            let v = v.borrow();
+            let items: Vec<String> = v.iter().take(MAX_LENGTH).map(|e| show(e, depth + 1)).collect();
+            let more = if v.len() > MAX_LENGTH { " ..." } else { "" };
+            format!("#({}{})", items.join(" "), more)
+        }
+        V::Record (r) => {
+            let name = match &r.rtd {
+                V::Vector (rtd) => rtd.borrow().iter().skip(1).map(|c| match c {
+                    V::Int (c) => *c as u8,
+                    _ => b'?',
+                }).collect(),
+                _ => vec![],
+            };
+            format!("#<record {}>", String::from_utf8_lossy(&name))
+        }
+        V::Boxed (_) if depth > MAX_DEPTH => "#&...".to_string(),
+        V::Boxed (b) => format!("#&{}", show(&b.borrow(), depth + 1)),
+        V::Proc (_) | V::Label (_) | V::Cont (_) => "#<procedure>".to_string(),
+        V::Bool (false) => "#f".to_string(),
+        V::Bool (true) => "#t".to_string(),
+        V::Nil => "()".to_string(),
+        V::Void => "#<void>".to_string(),
+        V::Unbound => "".to_string(),
+    }
+}
+
+type Kont<'a> = Option<Rc<Frame<'a>>>;
+
+struct Frame<'a> {
+    kind: FrameKind<'a>,
+    next: Kont<'a>,
+}
+
+// a primitive of PrimN, error and apply take their arguments spread
+#[derive(Clone, Copy)]
+enum Head<'a> {
+    Prim (&'a str),
+    Spread (&'a str),
+    Call,
+}
+
+enum FrameKind<'a> {
+    Operands (Head<'a>, Rc<Vec<&'a Scheme>>, Vec<Value<'a>>, Env<'a>),
+    Seq (&'a [Scheme], Env<'a>),
+    Test (&'a Scheme, &'a Scheme, Env<'a>),
+    Bind (Rc<Vec<(&'a str, &'a Scheme)>>, Vec<Value<'a>>, &'a Scheme, Env<'a>),
+    Init (Rc<Vec<(Rc<Binding<'a>>, &'a Scheme, bool)>>, usize, Vec<Value<'a>>, &'a Scheme, Env<'a>),
+    Assign (Rc<Binding<'a>>),
+    And (&'a [Scheme], Env<'a>),
+    Or (&'a [Scheme], Env<'a>),
+    Handler (Vec<Value<'a>>),
+    Raise (Value<'a>, bool, Vec<Value<'a>>),
+    Consumer (Value<'a>),
+}
+
+enum State<'a> {
+    Eval (&'a Scheme, Env<'a>),
+    Return (Value<'a>),
+    Apply (Value<'a>, Vec<Value<'a>>),
+}
+
+// Runs Scheme up to NormalizeContext. After ConvertClosure the lambdas bound to labels are
+// code, which is called with its closure as the first argument.
+pub struct SchemeInterpreter {
+    pub converted: bool,
+}
+
+impl SchemeInterpreter {
+    pub fn run(&self, scm: &Scheme) -> Answer {
+        let mut machine = SchemeMachine {
+            converted: self.converted,
+            codes: HashMap::default(),
+            literals: HashMap::default(),
+            kont: None,
+            handlers: vec![],
+        };
+        if self.converted {
+            machine.scan(scm);
+        }
+        machine.run(scm)
+    }
+}
+
+struct SchemeMachine<'a> {
+    converted: bool,
+    codes: HashMap<&'a str, &'a Scheme>,
+    literals: HashMap<String, Value<'a>>,
+    kont: Kont<'a>,
+    handlers: Vec<Value<'a>>,
+}
+
+impl<'a> SchemeMachine<'a> {
+    fn run(&mut self, scm: &'a Scheme) -> Answer {
+        let mut state = State::Eval (scm, None);
+        for _ in 0..MAX_STEPS {
+            let next = match state {
+                State::Return (v) if self.kont.is_none() => return Answer::Value (show(&v, 0)),
+                State::Eval (e, env) => self.eval(e, env),
+                State::Return (v) => self.ret(v),
+                State::Apply (f, args) => self.apply(f, args),
+            };
+            state = match next {
+                Ok (state) => state,
+                Err (answer) => return answer,
+            };
+        }
+        Answer::Stuck (format!("no answer after {} steps", MAX_STEPS))
+    }
+
+    // the lambdas bound to labels are closed, they are known before the program runs
+    fn scan(&mut self, scm: &'a Scheme) {
+        use Scheme::*;
+        match scm {
+            Letrec (bindings, box body) => {
+                for (k, v) in bindings {
+                    if is_label(k) && !is_uvar(k) {
+                        self.codes.insert(k, v);
+                    }
+                    self.scan(v);
+                }
+                self.scan(body);
+            }
+            Let (bindings, box body) => {
+                bindings.values().for_each(|v| self.scan(v));
+                self.scan(body);
+            }
+            Locals (_, box e) | Assigned (_, box e) | Lambda (_, box e) | Free (_, box e) |
+            Bindfree (_, box e) | Closures (_, box e) | Prim1 (_, box e) | Alloc (box e) => self.scan(e),
+            Begin (es) | PrimN (_, es) => es.iter().for_each(|e| self.scan(e)),
+            Prim2 (_, box a, box b) | Set (box a, box b) | Mref (box a, box b) => {
+                self.scan(a);
+                self.scan(b);
+            }
+            Prim3 (_, box a, box b, box c) | If (box a, box b, box c) | Mset (box a, box b, box c) => {
+                self.scan(a);
+                self.scan(b);
+                self.scan(c);
+            }
+            Funcall (box f, args) => {
+                self.scan(f);
+                args.iter().for_each(|e| self.scan(e));
+            }
+            _ => (),
+        }
+    }
+
+    fn push(&mut self, kind: FrameKind<'a>) {
+        let next = self.kont.take();
+        self.kont = Some (Rc::new(Frame { kind, next }));
+    }
+
+    fn eval(&mut self, scm: &'a Scheme, env: Env<'a>) -> Result<State<'a>, Answer> {
+        use Scheme::*;
+        let value = match scm {
+            Int64 (_) | Bool (_) | EmptyList | Quote (_) | LiteralList (_) | LiteralVector (_) => self.literal(scm)?,
+            Void | Nop => Value::Void,
+            Symbol (s) => self.lookup(s, &env)?,
+            Lambda (args, box body) => procedure(Code::Lambda (args, body, env), 0),
+            Locals (_, box e) | Assigned (_, box e) | Free (_, box e) => return Ok (State::Eval (e, env)),
+            Begin (exprs) => return Ok (self.sequence(exprs, env)),
+            If (box pred, box b1, box b2) => {
+                self.push(FrameKind::Test (b1, b2, env.clone()));
+                return Ok (State::Eval (pred, env));
+            }
+            Let (bindings, box body) => {
+                let mut bindings: Vec<(&'a str, &'a Scheme)> = bindings.iter().map(|(k, v)| (k.as_str(), v)).collect();
+                bindings.sort_by_key(|(k, _)| *k);
+                if bindings.is_empty() {
+                    return Ok (State::Eval (body, env));
+                }
+                let first = bindings[0].1;
+                self.push(FrameKind::Bind (Rc::new(bindings), vec![], body, env.clone()));
+                return Ok (State::Eval (first, env));
+            }
+            Letrec (bindings, box body) => {
+                if self.converted && bindings.keys().all(|k| self.codes.contains_key(k.as_str())) {
+                    return Ok (State::Eval (body, env));
+                }
+                // the lambdas come first, like PurifyLetrec binds them before the rest.
+                // The others go by name without the suffix of UniquifyVariable.
+                // An unsafe variable is bound once all are evaluated, like PurifyLetrec assigns it
+                // then, before it is unbound and a reference to it is an error.
+                let unsafe_vars = letrec_unsafe(bindings);
+                let mut keys: Vec<&'a String> = bindings.keys().collect();
+                keys.sort_by_key(|k| {
+                    let name = k.rsplit_once('.').map_or(k.as_str(), |(name, _)| name);
+                    (!matches!(bindings[*k], Lambda (..)), name, *k)
+                });
+                let mut env = env;
+                let mut inits = vec![];
+                for k in keys {
+                    env = extend(env, k, Value::Unbound);
+                    inits.push((env.clone().unwrap(), &bindings[k], unsafe_vars.contains(k)));
+                }
+                if inits.is_empty() {
+                    return Ok (State::Eval (body, env));
+                }
+                let first = inits[0].1;
+                self.push(FrameKind::Init (Rc::new(inits), 0, vec![], body, env.clone()));
+                return Ok (State::Eval (first, env));
+            }
+            // cp is the last variable, the free variables are its data
+            Bindfree (vars, box body) => {
+                let (cp, free) = match vars.split_last() {
+                    Some (split) => split,
+                    None => return stuck(format!("bind-free without a closure")),
+                };
+                let data = match &self.lookup(cp, &env)? {
+                    Value::Proc (p) => p.data.borrow().clone(),
+                    _ => return stuck(format!("{} is not a closure", cp)),
+                };
+                if data.len() < free.len() {
+                    return stuck(format!("{} has {} free variables", cp, data.len()));
+                }
+                let mut env = env;
+                for (v, d) in free.iter().zip(data) {
+                    env = extend(env, v, d);
+                }
+                return Ok (State::Eval (body, env));
+            }
+            Closures (closures, box body) => {
+                let mut env = env;
+                let mut procs = vec![];
+                for (name, label, free) in closures {
+                    let p = Rc::new(Procedure { code: Code::Label (label.clone()), data: RefCell::new(vec![]) });
+                    env = extend(env, name, Value::Proc (p.clone()));
+                    procs.push((p, free));
+                }
+                for (p, free) in procs {
+                    let data = free.iter().map(|v| self.lookup(v, &env)).collect::<Result<Vec<_>, _>>()?;
+                    *p.data.borrow_mut() = data;
+                }
+                return Ok (State::Eval (body, env));
+            }
+            Set (box Symbol (s), box e) => {
+                let b = match binding(&env, s) {
+                    Some (b) => b,
+                    None => return stuck(format!("set! of unbound {}", s)),
+                };
+                if matches!(&*b.value.borrow(), Value::Unbound) {
+                    return Err (Answer::Error (LETREC_ERROR.to_string()));
+                }
+                self.push(FrameKind::Assign (b));
+                return Ok (State::Eval (e, env));
+            }
+            Prim1 (op, box a) => return self.operands(Head::Prim (op), vec![a], env),
+            Prim2 (op, box a, box b) => return self.operands(Head::Prim (op), vec![a, b], env),
+            Prim3 (op, box a, box b, box c) => return self.operands(Head::Prim (op), vec![a, b, c], env),
+            PrimN (op, exprs) if op == "and" => return Ok (self.and(exprs, env)),
+            PrimN (op, exprs) if op == "or" => return Ok (self.or(exprs, env)),
+            PrimN (op, exprs) => return self.operands(Head::Spread (op), exprs.iter().collect(), env),
+            Funcall (box f, args) => {
+                let mut exprs = vec![f];
+                exprs.extend(args.iter());
+                return self.operands(Head::Call, exprs, env);
+            }
+            other => return stuck(format!("{:?} is not a Scheme form", other)),
+        };
+        Ok (State::Return (value))
+    }
+
+    fn sequence(&mut self, exprs: &'a [Scheme], env: Env<'a>) -> State<'a> {
+        match exprs {
+            [] => State::Return (Value::Void),
+            [e] => State::Eval (e, env),
+            [e, rest @ ..] => {
+                self.push(FrameKind::Seq (rest, env.clone()));
+                State::Eval (e, env)
+            }
+        }
+    }
+
+    fn and(&mut self, exprs: &'a [Scheme], env: Env<'a>) -> State<'a> {
+        match exprs {
+            [] => State::Return (Value::Bool (true)),
+            [e] => State::Eval (e, env),
+            [e, rest @ ..] => {
+                self.push(FrameKind::And (rest, env.clone()));
+                State::Eval (e, env)
+            }
+        }
+    }
+
+    fn or(&mut self, exprs: &'a [Scheme], env: Env<'a>) -> State<'a> {
+        match exprs {
+            [] => State::Return (Value::Bool (false)),
+            [e] => State::Eval (e, env),
+            [e, rest @ ..] => {
+                self.push(FrameKind::Or (rest, env.clone()));
+                State::Eval (e, env)
+            }
+        }
+    }
+
+    // operands are evaluated from left to right
+    fn operands(&mut self, head: Head<'a>, todo: Vec<&'a Scheme>, env: Env<'a>) -> Result<State<'a>, Answer> {
+        if todo.is_empty() {
+            return self.finish(head, vec![]);
+        }
+        let first = todo[0];
+        self.push(FrameKind::Operands (head, Rc::new(todo), vec![], env.clone()));
+        Ok (State::Eval (first, env))
+    }
+
+    fn ret(&mut self, v: Value<'a>) -> Result<State<'a>, Answer> {
+        use FrameKind::*;
+        let frame = self.kont.take().unwrap();
+        self.kont = frame.next.clone();
+        let state = match &frame.kind {
+            Operands (head, todo, done, env) => {
+                let mut done = done.clone();
+                done.push(v);
+                if done.len() == todo.len() {
+                    return self.finish(*head, done);
+                }
+                let next = todo[done.len()];
+                self.push(Operands (*head, todo.clone(), done, env.clone()));
+                State::Eval (next, env.clone())
+            }
+            Seq (rest, env) => self.sequence(rest, env.clone()),
+            Test (b1, b2, env) => State::Eval (if truthy(&v) { b1 } else { b2 }, env.clone()),
+            Bind (bindings, done, body, env) => {
+                let mut done = done.clone();
+                done.push(v);
+                if done.len() < bindings.len() {
+                    let next = bindings[done.len()].1;
+                    self.push(Bind (bindings.clone(), done, body, env.clone()));
+                    return Ok (State::Eval (next, env.clone()));
+                }
+                let mut env = env.clone();
+                for ((k, _), v) in bindings.iter().zip(done) {
+                    env = extend(env, k, v);
+                }
+                State::Eval (body, env)
+            }
+            Init (inits, i, pending, body, env) => {
+                let mut pending = pending.clone();
+                if inits[*i].2 {
+                    pending.push(v);
+                } else {
+                    *inits[*i].0.value.borrow_mut() = v;
+                }
+                if i + 1 == inits.len() {
+                    for ((b, _, _), v) in inits.iter().filter(|(_, _, unsafe_var)| *unsafe_var).zip(pending) {
+                        *b.value.borrow_mut() = v;
+                    }
+                    return Ok (State::Eval (body, env.clone()));
+                }
+                let next = inits[i + 1].1;
+                self.push(Init (inits.clone(), i + 1, pending, body, env.clone()));
+                State::Eval (next, env.clone())
+            }
+            Assign (b) => {
+                *b.value.borrow_mut() = v;
+                State::Return (Value::Void)
+            }
+            And (rest, env) if truthy(&v) => self.and(rest, env.clone()),
+            Or (rest, env) if !truthy(&v) => self.or(rest, env.clone()),
+            And (..) | Or (..) => State::Return (v),
+            Handler (saved) => {
+                self.handlers = saved.clone();
+                State::Return (v)
+            }
+            Raise (obj, continuable, saved) => {
+                self.handlers = saved.clone();
+                if !continuable {
+                    return Err (returned(show(obj, 0)));
+                }
+                State::Return (v)
+            }
+            Consumer (c) => State::Apply (c.clone(), vec![v]),
+        };
+        Ok (state)
+    }
+
+    fn finish(&mut self, head: Head<'a>, vals: Vec<Value<'a>>) -> Result<State<'a>, Answer> {
+        match head {
+            Head::Prim (op) => self.prim(op, vals),
+            Head::Spread (op) => self.spread(op, vals),
+            Head::Call => {
+                let mut vals = vals;
+                let f = vals.remove(0);
+                // a closure called through its label already has itself as the first argument
+                if let Value::Proc (p) = &f {
+                    if let Code::Label (l) = &p.code {
+                        return self.call_label(l.clone(), vals);
+                    }
+                }
+                Ok (State::Apply (f, vals))
+            }
+        }
+    }
+
+    fn apply(&mut self, f: Value<'a>, args: Vec<Value<'a>>) -> Result<State<'a>, Answer> {
+        match &f {
+            Value::Label (l) => self.call_label(l.clone(), args),
+            Value::Proc (p) => match &p.code {
+                Code::Lambda (params, body, env) => {
+                    let env = self.bind(params, args, env.clone())?;
+                    Ok (State::Eval (body, env))
+                }
+                Code::Label (l) => {
+                    let mut args = args;
+                    args.insert(0, Value::Proc (p.clone()));
+                    self.call_label(l.clone(), args)
+                }
+                Code::Prim (op) => {
+                    let op = op.clone();
+                    self.spread(&op, args)
+                }
+                Code::Cont (c) => self.resume(c.clone(), args),
+            },
+            other => stuck(format!("{} is not a procedure", show(other, 0))),
+        }
+    }
+
+    fn call_label(&mut self, label: String, args: Vec<Value<'a>>) -> Result<State<'a>, Answer> {
+        if let Some (op) = runtime_op(&label) {
+            return self.native(op, args, true);
+        }
+        match self.codes.get(label.as_str()).copied() {
+            Some (Scheme::Lambda (params, box body)) => {
+                let env = self.bind(params, args, None)?;
+                Ok (State::Eval (body, env))
+            }
+            _ => stuck(format!("no code at {}", label)),
+        }
+    }
+
+    // the parser marks a rest parameter with ".", later passes name it rest.N
+    fn bind(&self, params: &'a [String], args: Vec<Value<'a>>, env: Env<'a>) -> Result<Env<'a>, Answer> {
+        let (fixed, rest) = match params {
+            [fixed @ .., dot, rest] if dot == "." => (fixed, Some (rest)),
+            [fixed @ .., rest] if is_rest(rest) => (fixed, Some (rest)),
+            fixed => (fixed, None),
+        };
+        let enough = match rest {
+            Some (_) => args.len() >= fixed.len(),
+            None => args.len() == fixed.len(),
+        };
+        if !enough {
+            return stuck(format!("{} arguments for {} parameters", args.len(), params.len()));
+        }
+        let mut env = env;
+        let mut args = args.into_iter();
+        for p in fixed {
+            env = extend(env, p, args.next().unwrap());
+        }
+        if let Some (rest) = rest {
+            let items: Vec<Value<'a>> = args.collect();
+            let list = items.into_iter().rev().fold(Value::Nil, |ls, v| cons(v, ls));
+            env = extend(env, rest, list);
+        }
+        Ok (env)
+    }
+
+    fn lookup(&self, s: &str, env: &Env<'a>) -> Result<Value<'a>, Answer> {
+        if let Some (b) = binding(env, s) {
+            return match &*b.value.borrow() {
+                Value::Unbound => Err (Answer::Error (LETREC_ERROR.to_string())),
+                v => Ok (v.clone()),
+            };
+        }
+        if self.converted && (self.codes.contains_key(s) || runtime_op(s).is_some()) {
+            return Ok (Value::Label (s.to_string()));
+        }
+        if !self.converted && (primitive_arity(s).is_some() || runtime_label(s).is_some()) {
+            return Ok (procedure(Code::Prim (s.to_string()), 0));
+        }
+        stuck(format!("{} is unbound", s))
+    }
+
+    // a literal is made once, like ConvertComplexDatum makes it at the start of the program
+    fn literal(&mut self, scm: &'a Scheme) -> Result<Value<'a>, Answer> {
+        use Scheme::*;
+        let value = match scm {
+            Quote (box e) => return self.literal(e),
+            Int64 (i) => Value::Int (fixnum(*i)),
+            Bool (b) => Value::Bool (*b),
+            EmptyList => Value::Nil,
+            Void => Value::Void,
+            LiteralList (elements) => {
+                let key = format!("{:?}", scm);
+                if let Some (v) = self.literals.get(&key) {
+                    return Ok (v.clone());
+                }
+                let mut v = Value::Nil;
+                if let Some ((last, init)) = elements.split_last() {
+                    v = self.literal(last)?;
+                    for e in init.iter().rev() {
+                        v = cons(self.literal(e)?, v);
+                    }
+                }
+                self.literals.insert(key, v.clone());
+                v
+            }
+            LiteralVector (elements) => {
+                let key = format!("{:?}", scm);
+                if let Some (v) = self.literals.get(&key) {
+                    return Ok (v.clone());
+                }
+                let items = elements.iter().map(|e| self.literal(e)).collect::<Result<Vec<_>, _>>()?;
+                let v = Value::Vector (Rc::new(RefCell::new(items)));
+                self.literals.insert(key, v.clone());
+                v
+            }
+            other => return stuck(format!("{:?} is not a literal", other)),
+        };
+        Ok (value)
+    }
+
+    fn items(&self, list: &Value<'a>) -> Result<Vec<Value<'a>>, Answer> {
+        let mut items = vec![];
+        let mut list = list.clone();
+        while let Value::Pair (p) = &list.clone() {
+            items.push(p.borrow().0.clone());
+            list = p.borrow().1.clone();
+        }
+        match list {
+            Value::Nil => Ok (items),
+            _ => stuck(format!("an improper list")),
+        }
+    }
+
+    // (error msg a b) is (error msg (list a b)) and (apply f a ls) is (apply f (cons a ls))
+    fn spread(&mut self, op: &str, args: Vec<Value<'a>>) -> Result<State<'a>, Answer> {
+        let mut args = args;
+        let (keep, tail) = match op {
+            "error" if !args.is_empty() => (1, Value::Nil),
+            "apply" if args.len() >= 2 => (1, args.pop().unwrap()),
+            _ => return self.prim(op, args),
+        };
+        let list = args.split_off(keep).into_iter().rev().fold(tail, |ls, v| cons(v, ls));
+        args.push(list);
+        self.prim(op, args)
+    }
+
+    fn prim(&mut self, op: &str, args: Vec<Value<'a>>) -> Result<State<'a>, Answer> {
+        use Value as V;
+        if runtime_label(op).is_some() {
+            let op = if op == "call-with-current-continuation" { "call/cc" } else { op };
+            return self.native(op, args, false);
+        }
+        let v = match (op, args.as_slice()) {
+            ("sra", [V::Int (a), V::Int (b)]) => {
+                let w = (a << SHIFT_FIXNUM) >> ((b << SHIFT_FIXNUM) & 63);
+                if w & MASK_FIXNUM != TAG_FIXNUM {
+                    return stuck(format!("sra leaves a tagged word"));
+                }
+                V::Int (w >> SHIFT_FIXNUM)
+            }
+            (op, [V::Int (a), V::Int (b)]) if binop(op, 0, 0).is_some() => V::Int (fixnum(binop(op, *a, *b).unwrap())),
+            (op, [V::Int (a), V::Int (b)]) if relop(op, 0, 0).is_some() => V::Bool (relop(op, *a, *b).unwrap()),
+            ("eq?", [a, b]) => V::Bool (eq(a, b)),
+            ("not", [a]) => V::Bool (!truthy(a)),
+            ("boolean?", [a]) => V::Bool (matches!(a, V::Bool (_))),
+            ("fixnum?", [a]) => V::Bool (matches!(a, V::Int (_))),
+            ("null?", [a]) => V::Bool (matches!(a, V::Nil)),
+            ("pair?", [a]) => V::Bool (matches!(a, V::Pair (_))),
+            ("vector?", [a]) => V::Bool (matches!(a, V::Vector (_))),
+            ("box?", [a]) => V::Bool (matches!(a, V::Boxed (_))),
+            ("procedure?", [a]) => V::Bool (matches!(a, V::Proc (_) | V::Label (_))),
+            ("cons", [a, b]) => cons(a.clone(), b.clone()),
+            ("car", [V::Pair (p)]) => p.borrow().0.clone(),
+            ("cdr", [V::Pair (p)]) => p.borrow().1.clone(),
+            ("set-car!", [V::Pair (p), v]) => {
+                p.borrow_mut().0 = v.clone();
+                V::Void
+            }
+            ("set-cdr!", [V::Pair (p), v]) => {
+                p.borrow_mut().1 = v.clone();
+                V::Void
+            }
+            ("make-vector", [V::Int (n)]) if *n >= 0 => V::Vector (Rc::new(RefCell::new(vec![V::Int (0); *n as usize]))),
+            ("vector-length", [V::Vector (v)]) => V::Int (v.borrow().len() as i64),
+            ("vector-ref", [V::Vector (v), V::Int (i)]) if in_range(*i, v.borrow().len()) => v.borrow()[*i as usize].clone(),
+            ("vector-set!", [V::Vector (v), V::Int (i), x]) if in_range(*i, v.borrow().len()) => {
+                v.borrow_mut()[*i as usize] = x.clone();
+                V::Void
+            }
+            ("box", [v]) => V::Boxed (Rc::new(RefCell::new(v.clone()))),
+            ("unbox", [V::Boxed (b)]) => b.borrow().clone(),
+            ("set-box!", [V::Boxed (b), v]) => {
+                *b.borrow_mut() = v.clone();
+                V::Void
+            }
+            ("void", []) => V::Void,
+            ("make-procedure", [V::Label (l), V::Int (n)]) if *n >= 0 => procedure(Code::Label (l.clone()), *n as usize),
+            ("procedure-code", [V::Proc (p)]) => match &p.code {
+                Code::Label (l) => V::Label (l.clone()),
+                _ => return stuck(format!("procedure-code of a closure without a label")),
+            },
+            ("procedure-ref", [V::Proc (p), V::Int (i)]) if in_range(*i, p.data.borrow().len()) => p.data.borrow()[*i as usize].clone(),
+            ("procedure-set!", [V::Proc (p), V::Int (i), v]) if in_range(*i, p.data.borrow().len()) => {
+                p.data.borrow_mut()[*i as usize] = v.clone();
+                V::Void
+            }
+            ("make-record", [rtd, V::Int (n)]) if *n >= 0 => {
+                V::Record (Rc::new(Record { rtd: rtd.clone(), fields: RefCell::new(vec![V::Int (0); *n as usize]) }))
+            }
+            ("record-ref", [V::Record (r), V::Int (i)]) if in_range(*i, r.fields.borrow().len()) => r.fields.borrow()[*i as usize].clone(),
+            ("record-set!", [V::Record (r), V::Int (i), v]) if in_range(*i, r.fields.borrow().len()) => {
+                r.fields.borrow_mut()[*i as usize] = v.clone();
+                V::Void
+            }
+            ("record-of?", [r, rtd]) => V::Bool (matches!(r, V::Record (r) if eq(&r.rtd, rtd))),
+            (op, args) => {
+                let args: Vec<String> = args.iter().map(|a| show(a, 0)).collect();
+                return stuck(format!("({} {})", op, args.join(" ")));
+            }
+        };
+        Ok (State::Return (v))
+    }
+
+    // the runtime procedures, called as primitives or through their labels after ConvertClosure
+    fn native(&mut self, op: &str, args: Vec<Value<'a>>, labelled: bool) -> Result<State<'a>, Answer> {
+        match (op, args.as_slice()) {
+            ("apply", [f, ls]) => {
+                let items = self.items(ls)?;
+                Ok (State::Apply (f.clone(), items))
+            }
+            ("call/cc", [f]) => {
+                let captured = Rc::new(Captured { kont: self.kont.clone(), handlers: self.handlers.clone() });
+                let k = if labelled {
+                    let k = procedure(Code::Label (CONTINUATION_LABEL.to_string()), 0);
+                    if let Value::Proc (p) = &k {
+                        *p.data.borrow_mut() = vec![Value::Cont (captured)];
+                    }
+                    k
+                } else {
+                    procedure(Code::Cont (captured), 0)
+                };
+                Ok (State::Apply (f.clone(), vec![k]))
+            }
+            ("continuation", [Value::Proc (p), args @ ..]) => {
+                let captured = match p.data.borrow().first() {
+                    Some (Value::Cont (c)) => c.clone(),
+                    _ => return stuck(format!("not a continuation")),
+                };
+                self.resume(captured, args.to_vec())
+            }
+            ("with-exception-handler", [handler, thunk]) => {
+                let saved = self.handlers.clone();
+                self.push(FrameKind::Handler (saved));
+                self.handlers.push(handler.clone());
+                Ok (State::Apply (thunk.clone(), vec![]))
+            }
+            ("raise", [obj]) | ("raise-continuable", [obj]) => {
+                let saved = self.handlers.clone();
+                match self.handlers.pop() {
+                    None => Err (uncaught(show(obj, 0))),
+                    Some (handler) => {
+                        self.push(FrameKind::Raise (obj.clone(), op == "raise-continuable", saved));
+                        Ok (State::Apply (handler, vec![obj.clone()]))
+                    }
+                }
+            }
+            // with a handler, error raises the error object, the pair of the message and the irritants
+            ("error", [msg, irritants]) if !self.handlers.is_empty() => {
+                self.native("raise", vec![cons(msg.clone(), irritants.clone())], labelled)
+            }
+            ("error", [msg, irritants]) => {
+                let mut items = vec![];
+                let mut ls = irritants.clone();
+                while let Value::Pair (p) = &ls.clone() {
+                    items.push(show(&p.borrow().0, 0));
+                    ls = p.borrow().1.clone();
+                }
+                Err (error(show(msg, 0), items))
+            }
+            ("letrec-error", []) => Err (Answer::Error (LETREC_ERROR.to_string())),
+            // many values go to a consumer, otherwise there must be one, the others are an error
+            // whose message is the list of the values
+            ("values", _) => {
+                if let Some (frame) = self.kont.clone() {
+                    if let FrameKind::Consumer (c) = &frame.kind {
+                        self.kont = frame.next.clone();
+                        return Ok (State::Apply (c.clone(), args));
+                    }
+                }
+                if args.len() == 1 {
+                    return Ok (State::Return (args[0].clone()));
+                }
+                let values = args.into_iter().rev().fold(Value::Nil, |ls, v| cons(v, ls));
+                self.native("error", vec![values, Value::Nil], labelled)
+            }
+            ("apply-values", [ls]) => {
+                let items = self.items(ls)?;
+                self.native("values", items, labelled)
+            }
+            ("call-with-values", [producer, consumer]) => {
+                self.push(FrameKind::Consumer (consumer.clone()));
+                Ok (State::Apply (producer.clone(), vec![]))
+            }
+            (op, args) => stuck(format!("{} can not take {} arguments", op, args.len())),
+        }
+    }
+
+    fn resume(&mut self, captured: Rc<Captured<'a>>, args: Vec<Value<'a>>) -> Result<State<'a>, Answer> {
+        self.kont = captured.kont.clone();
+        self.handlers = captured.handlers.clone();
+        match args.into_iter().next() {
+            Some (v) => Ok (State::Return (v)),
+            None => stuck(format!("a continuation called without a value")),
+        }
+    }
+}
+
+
+// ---------------------------------------------------------------------
+//
+// memory of the word level and machine level
+//
+// ---------------------------------------------------------------------
+
+// words are zero until written like fresh pages, a label has an address in the code
+struct Memory {
+    words: HashMap<i64, i64>,
+    labels: Vec<String>,
+    addresses: HashMap<String, i64>,
+}
+
+impl Memory {
+    fn new() -> Self {
+        Memory { words: HashMap::default(), labels: vec![], addresses: HashMap::default() }
+    }
+
+    fn load(&self, addr: i64) -> i64 {
+        *self.words.get(&addr).unwrap_or(&0)
+    }
+
+    fn store(&mut self, addr: i64, value: i64) {
+        self.words.insert(addr, value);
+    }
+
+    fn address(&mut self, label: &str) -> i64 {
+        if let Some (addr) = self.addresses.get(label) {
+            return *addr;
+        }
+        let addr = CODE_BASE + 16 * self.labels.len() as i64;
+        self.labels.push(label.to_string());
+        self.addresses.insert(label.to_string(), addr);
+        addr
+    }
+
+    fn label(&self, addr: i64) -> Option<String> {
+        if addr < CODE_BASE || (addr - CODE_BASE) % 16 != 0 {
+            return None;
+        }
+        self.labels.get(((addr - CODE_BASE) / 16) as usize).cloned()
+    }
+
+    fn items(&self, list: i64) -> Result<Vec<i64>, Answer> {
+        let mut items = vec![];
+        let mut list = list;
+        while list & MASK_FIXNUM == TAG_PAIR {
+            items.push(self.load(list + CAR_OFFSET));
+            list = self.load(list + CDR_OFFSET);
+        }
+        match list {
+            NIL => Ok (items),
+            _ => stuck(format!("an improper list")),
+        }
+    }
+
+    fn show(&self, x: i64, depth: usize) -> String {
+        show_word(&|addr| Ok (self.load(addr)), x, depth).unwrap_or_else(|e: Infallible| match e {})
+    }
+
+    fn irritants(&self, list: i64) -> Vec<String> {
+        show_irritants(&|addr| Ok (self.load(addr)), list).unwrap_or_else(|e: Infallible| match e {})
+    }
+}
+
+// print1 of runtime.c, over any memory of words. A load may fail, when the memory
+// is bounded like that of the emulator.
+pub fn show_word<E>(load: &dyn Fn(i64) -> Result<i64, E>, x: i64, depth: usize) -> Result<String, E> {
+    let s = match x & MASK_FIXNUM {
+        TAG_FIXNUM => (x >> SHIFT_FIXNUM).to_string(),
+        TAG_PAIR if depth > MAX_DEPTH => "(...)".to_string(),
+        TAG_PAIR => {
+            let mut s = format!("({}", show_word(load, load(x + CAR_OFFSET)?, depth + 1)?);
+            let mut rest = load(x + CDR_OFFSET)?;
+            let mut len = 0;
+            while rest & MASK_FIXNUM == TAG_PAIR && len < MAX_LENGTH - 1 {
+                s.push(' ');
+                s.push_str(&show_word(load, load(rest + CAR_OFFSET)?, depth + 1)?);
+                rest = load(rest + CDR_OFFSET)?;
+                len += 1;
+            }
+            if rest != NIL {
+                if len == MAX_LENGTH - 1 {
+                    s.push_str(" ...");
+                } else {
+                    s.push_str(&format!(" . {}", show_word(load, rest, depth + 1)?));
+                }
+            }
+            s.push(')');
+            s
+        }
+        TAG_VECTOR if depth > MAX_DEPTH => "#(...)".to_string(),
+        TAG_VECTOR => {
+            let n = load(x + VLEN_OFFSET)? >> SHIFT_FIXNUM;
+            let mut items = vec![];
+            for i in 0..n.min(MAX_LENGTH as i64) {
+                items.push(show_word(load, load(x + VDATA_OFFSET + 8 * i)?, depth + 1)?);
+            }
+            let more = if n > MAX_LENGTH as i64 { " ..." } else { "" };
+            format!("#({}{})", items.join(" "), more)
+        }
+        TAG_RECORD => {
+            let rtd = load(x + RECORD_RTD_OFFSET)?;
+            let n = load(rtd + VLEN_OFFSET)? >> SHIFT_FIXNUM;
+            let mut name = vec![];
+            for i in 1..n {
+                name.push((load(rtd + VDATA_OFFSET + 8 * i)? >> SHIFT_FIXNUM) as u8);
+            }
+            format!("#<record {}>", String::from_utf8_lossy(&name))
+        }
+        TAG_BOX if depth > MAX_DEPTH => "#&...".to_string(),
+        TAG_BOX => format!("#&{}", show_word(load, load(x + BOX_OFFSET)?, depth + 1)?),
+        TAG_PROC => "#<procedure>".to_string(),
+        _ => match x {
+            FALSE => "#f".to_string(),
+            TRUE => "#t".to_string(),
+            NIL => "()".to_string(),
+            VOID => "#<void>".to_string(),
+            _ => "".to_string(),
+        },
+    };
+    Ok (s)
+}
+
+// the irritants _scheme_error prints after the message
+pub fn show_irritants<E>(load: &dyn Fn(i64) -> Result<i64, E>, list: i64) -> Result<Vec<String>, E> {
+    let mut items = vec![];
+    let mut list = list;
+    while list & MASK_FIXNUM == TAG_PAIR {
+        items.push(show_word(load, load(list + CAR_OFFSET)?, 0)?);
+        list = load(list + CDR_OFFSET)?;
+    }
+    Ok (items)
+}
+
+
+// ---------------------------------------------------------------------
+//
+// words, from SpecifyRepresentation to FlattenSet
+//
+// ---------------------------------------------------------------------
+
+// Scheme after SpecifyRepresentation and Expr before ImposeCallingConvention are both
+// variables and words, they are lowered to the same nodes.
+enum Node {
+    Int (i64),
+    Var (String),
+    Let (Vec<(String, Node)>, Box<Node>),
+    Begin (Vec<Node>),
+    If (Box<Node>, Box<Node>, Box<Node>),
+    Set (String, Box<Node>),
+    Op (String, Vec<Node>),
+    Call (Vec<Node>),
+    Nop,
+}
+
+type Codes = HashMap<String, (Vec<String>, Node)>;
+
+fn lower_scheme(scm: &Scheme) -> Result<Node, Answer> {
+    use Scheme::*;
+    let all = |es: &[&Scheme]| es.iter().map(|e| lower_scheme(e)).collect::<Result<Vec<_>, _>>();
+    let node = match scm {
+        Locals (_, box e) => return lower_scheme(e),
+        Let (bindings, box body) => {
+            let mut bindings = bindings.iter()
+                .map(|(k, v)| Ok ((k.clone(), lower_scheme(v)?)))
+                .collect::<Result<Vec<_>, Answer>>()?;
+            bindings.sort_by(|a, b| a.0.cmp(&b.0));
+            Node::Let (bindings, Box::new(lower_scheme(body)?))
+        }
+        Begin (es) => Node::Begin (all(&es.iter().collect::<Vec<_>>())?),
+        If (box a, box b, box c) => Node::If (Box::new(lower_scheme(a)?), Box::new(lower_scheme(b)?), Box::new(lower_scheme(c)?)),
+        Set (box Symbol (s), box e) => Node::Set (s.clone(), Box::new(lower_scheme(e)?)),
+        Prim1 (op, box a) => Node::Op (op.clone(), all(&[a])?),
+        Prim2 (op, box a, box b) => Node::Op (op.clone(), all(&[a, b])?),
+        Prim3 (op, box a, box b, box c) => Node::Op (op.clone(), all(&[a, b, c])?),
+        Alloc (box a) => Node::Op ("alloc".to_string(), all(&[a])?),
+        Mref (box a, box b) => Node::Op ("mref".to_string(), all(&[a, b])?),
+        Mset (box a, box b, box c) => Node::Op ("mset!".to_string(), all(&[a, b, c])?),
+        Funcall (box f, args) => {
+            let mut es = vec![f];
+            es.extend(args.iter());
+            Node::Call (all(&es)?)
+        }
+        Symbol (s) => Node::Var (s.clone()),
+        Int64 (i) => Node::Int (*i),
+        Bool (true) => Node::Int (TRUE),
+        Bool (false) => Node::Int (FALSE),
+        Void => Node::Int (VOID),
+        Nop => Node::Nop,
+        other => return stuck(format!("{:?} is not a word", other)),
+    };
+    Ok (node)
+}
+
+fn lower_expr(expr: &Expr) -> Result<Node, Answer> {
+    use Expr::*;
+    let all = |es: &[&Expr]| es.iter().map(|e| lower_expr(e)).collect::<Result<Vec<_>, _>>();
+    let node = match expr {
+        Locals (_, box e) => return lower_expr(e),
+        Begin (es) => Node::Begin (all(&es.iter().collect::<Vec<_>>())?),
+        If (box a, box b, box c) => Node::If (Box::new(lower_expr(a)?), Box::new(lower_expr(b)?), Box::new(lower_expr(c)?)),
+        If1 (box a, box b) => Node::If (Box::new(lower_expr(a)?), Box::new(lower_expr(b)?), Box::new(Node::Nop)),
+        Set (box Symbol (s), box e) => Node::Set (s.clone(), Box::new(lower_expr(e)?)),
+        Prim1 (op, box a) => Node::Op (op.clone(), all(&[a])?),
+        Prim2 (op, box a, box b) => Node::Op (op.clone(), all(&[a, b])?),
+        Alloc (box a) => Node::Op ("alloc".to_string(), all(&[a])?),
+        Mref (box a, box b) => Node::Op ("mref".to_string(), all(&[a, b])?),
+        Mset (box a, box b, box c) => Node::Op ("mset!".to_string(), all(&[a, b, c])?),
+        Funcall (box f, args) => {
+            let mut es = vec![f];
+            es.extend(args.iter());
+            Node::Call (all(&es)?)
+        }
+        Symbol (s) => Node::Var (s.clone()),
+        Int64 (i) => Node::Int (*i),
+        Bool (true) => Node::Int (TRUE),
+        Bool (false) => Node::Int (FALSE),
+        Nop => Node::Nop,
+        other => return stuck(format!("{:?} is not a word", other)),
+    };
+    Ok (node)
+}
+
+fn load_scheme(scm: &Scheme) -> Result<(Codes, Node), Answer> {
+    let mut codes = HashMap::default();
+    match scm {
+        Scheme::Letrec (bindings, box body) => {
+            for (label, lambda) in bindings {
+                match lambda {
+                    Scheme::Lambda (args, box body) => codes.insert(label.clone(), (args.clone(), lower_scheme(body)?)),
+                    other => return stuck(format!("{:?} is not a lambda", other)),
+                };
+            }
+            Ok ((codes, lower_scheme(body)?))
+        }
+        other => Ok ((codes, lower_scheme(other)?)),
+    }
+}
+
+fn load_expr(expr: &Expr) -> Result<(Codes, Node), Answer> {
+    let mut codes = HashMap::default();
+    match expr {
+        Expr::Letrec (lambdas, box body) => {
+            for lambda in lambdas {
+                match lambda {
+                    Expr::Lambda (label, args, box body) => codes.insert(label.clone(), (args.clone(), lower_expr(body)?)),
+                    other => return stuck(format!("{:?} is not a lambda", other)),
+                };
+            }
+            Ok ((codes, lower_expr(body)?))
+        }
+        other => Ok ((codes, lower_expr(other)?)),
+    }
+}
+
+// Runs words with variables over a simulated heap, from SpecifyRepresentation to FlattenSet.
+pub struct WordInterpreter {}
+
+impl WordInterpreter {
+    pub fn scheme(&self, scm: &Scheme) -> Answer {
+        match load_scheme(scm) {
+            Ok ((codes, body)) => WordMachine::new(&codes).run(&body),
+            Err (answer) => answer,
+        }
+    }
+
+    pub fn expr(&self, expr: &Expr) -> Answer {
+        match load_expr(expr) {
+            Ok ((codes, body)) => WordMachine::new(&codes).run(&body),
+            Err (answer) => answer,
+        }
+    }
+}
+
+type Locals = Rc<RefCell<HashMap<String, i64>>>;
+
+enum WordFrame<'a> {
+    Operands (&'a Node, Vec<i64>, Locals),
+    Seq (&'a [Node], Locals),
+    Test (&'a Node, &'a Node, Locals),
+    Bind (&'a Node, Vec<i64>, Locals),
+    Assign (&'a str, Locals),
+    Handler (Vec<i64>),
+    Raise (i64, bool, Vec<i64>),
+    Consumer (i64),
+}
+
+type WordKont<'a> = Option<Rc<WordLink<'a>>>;
+
+struct WordLink<'a> (WordFrame<'a>, WordKont<'a>);
+
+enum WordState<'a> {
+    Eval (&'a Node, Locals),
+    Return (i64),
+}
+
+struct WordMachine<'a> {
+    codes: &'a Codes,
+    memory: Memory,
+    heap: i64,
+    kont: WordKont<'a>,
+    handlers: Vec<i64>,
+    // a continuation keeps the index of its captured frames in its data
+    captured: Vec<(WordKont<'a>, Vec<i64>)>,
+}
+
+impl<'a> WordMachine<'a> {
+    fn new(codes: &'a Codes) -> Self {
+        WordMachine { codes, memory: Memory::new(), heap: HEAP_BASE, kont: None, handlers: vec![], captured: vec![] }
+    }
+
+    fn run(&mut self, body: &'a Node) -> Answer {
+        let mut state = WordState::Eval (body, Rc::new(RefCell::new(HashMap::default())));
+        for _ in 0..MAX_STEPS {
+            let next = match state {
+                WordState::Return (v) if self.kont.is_none() => return Answer::Value (self.memory.show(v, 0)),
+                WordState::Eval (node, env) => self.eval(node, env),
+                WordState::Return (v) => self.ret(v),
+            };
+            state = match next {
+                Ok (state) => state,
+                Err (answer) => return answer,
+            };
+        }
+        Answer::Stuck (format!("no answer after {} steps", MAX_STEPS))
+    }
+
+    fn push(&mut self, frame: WordFrame<'a>) {
+        let next = self.kont.take();
+        self.kont = Some (Rc::new(WordLink (frame, next)));
+    }
+
+    fn eval(&mut self, node: &'a Node, env: Locals) -> Result<WordState<'a>, Answer> {
+        let state = match node {
+            Node::Int (i) => WordState::Return (*i),
+            Node::Var (s) => WordState::Return (self.var(s, &env)?),
+            Node::Nop => WordState::Return (VOID),
+            Node::Begin (nodes) => self.sequence(nodes, env),
+            Node::If (box pred, box b1, box b2) => {
+                self.push(WordFrame::Test (b1, b2, env.clone()));
+                WordState::Eval (pred, env)
+            }
+            Node::Let (bindings, box body) if bindings.is_empty() => WordState::Eval (body, env),
+            Node::Let (bindings, _) => {
+                self.push(WordFrame::Bind (node, vec![], env.clone()));
+                WordState::Eval (&bindings[0].1, env)
+            }
+            Node::Set (s, box e) => {
+                self.push(WordFrame::Assign (s, env.clone()));
+                WordState::Eval (e, env)
+            }
+            Node::Op (_, nodes) | Node::Call (nodes) => {
+                if nodes.is_empty() {
+                    return self.finish(node, vec![]);
+                }
+                self.push(WordFrame::Operands (node, vec![], env.clone()));
+                WordState::Eval (&nodes[0], env)
+            }
+        };
+        Ok (state)
+    }
+
+    fn sequence(&mut self, nodes: &'a [Node], env: Locals) -> WordState<'a> {
+        match nodes {
+            [] => WordState::Return (VOID),
+            [n] => WordState::Eval (n, env),
+            [n, rest @ ..] => {
+                self.push(WordFrame::Seq (rest, env.clone()));
+                WordState::Eval (n, env)
+            }
+        }
+    }
+
+    fn var(&mut self, s: &str, env: &Locals) -> Result<i64, Answer> {
+        if let Some (v) = env.borrow().get(s) {
+            return Ok (*v);
+        }
+        if is_label(s) {
+            return Ok (self.memory.address(s));
+        }
+        stuck(format!("{} is read before it is set", s))
+    }
+
+    fn ret(&mut self, v: i64) -> Result<WordState<'a>, Answer> {
+        use WordFrame::*;
+        let frame = self.kont.take().unwrap();
+        self.kont = frame.1.clone();
+        let state = match &frame.0 {
+            Operands (node, done, env) => {
+                let mut done = done.clone();
+                done.push(v);
+                let nodes = match node {
+                    Node::Op (_, nodes) | Node::Call (nodes) => nodes,
+                    _ => unreachable!(),
+                };
+                if done.len() == nodes.len() {
+                    return self.finish(node, done);
+                }
+                let next = &nodes[done.len()];
+                self.push(Operands (node, done, env.clone()));
+                WordState::Eval (next, env.clone())
+            }
+            Seq (rest, env) => self.sequence(rest, env.clone()),
+            Test (b1, b2, env) => WordState::Eval (if v != FALSE { b1 } else { b2 }, env.clone()),
+            Bind (node, done, env) => {
+                let (bindings, body) = match node {
+                    Node::Let (bindings, box body) => (bindings, body),
+                    _ => unreachable!(),
+                };
+                let mut done = done.clone();
+                done.push(v);
+                if done.len() < bindings.len() {
+                    let next = &bindings[done.len()].1;
+                    self.push(Bind (node, done, env.clone()));
+                    return Ok (WordState::Eval (next, env.clone()));
+                }
+                for ((k, _), v) in bindings.iter().zip(done) {
+                    env.borrow_mut().insert(k.clone(), v);
+                }
+                WordState::Eval (body, env.clone())
+            }
+            Assign (s, env) => {
+                env.borrow_mut().insert(s.to_string(), v);
+                WordState::Return (VOID)
+            }
+            Handler (saved) => {
+                self.handlers = saved.clone();
+                WordState::Return (v)
+            }
+            Raise (obj, continuable, saved) => {
+                self.handlers = saved.clone();
+                if !continuable {
+                    return Err (returned(self.memory.show(*obj, 0)));
+                }
+                WordState::Return (v)
+            }
+            Consumer (c) => return self.call_procedure(*c, vec![v]),
+        };
+        Ok (state)
+    }
+
+    fn finish(&mut self, node: &'a Node, vals: Vec<i64>) -> Result<WordState<'a>, Answer> {
+        match node {
+            Node::Op (op, _) => Ok (WordState::Return (self.op(op, &vals)?)),
+            _ => {
+                let label = match self.memory.label(vals[0]) {
+                    Some (label) => label,
+                    None => return stuck(format!("{} is not code", vals[0])),
+                };
+                self.call(label, vals[1..].to_vec())
+            }
+        }
+    }
+
+    fn op(&mut self, op: &str, vals: &[i64]) -> Result<i64, Answer> {
+        let v = match (op, vals) {
+            (op, [a, b]) if binop(op, 0, 0).is_some() => binop(op, *a, *b).unwrap(),
+            (op, [a, b]) if relop(op, 0, 0).is_some() => if relop(op, *a, *b).unwrap() { TRUE } else { FALSE },
+            ("not", [a]) => if *a == FALSE { TRUE } else { FALSE },
+            ("alloc", [n]) => {
+                let p = self.heap;
+                self.heap += n;
+                p
+            }
+            ("mref", [a, b]) => self.memory.load(a + b),
+            ("mset!", [a, b, c]) => {
+                self.memory.store(a + b, *c);
+                VOID
+            }
+            (op, vals) => return stuck(format!("({} {:?})", op, vals)),
+        };
+        Ok (v)
+    }
+
+    fn alloc_pair(&mut self, a: i64, b: i64) -> i64 {
+        let p = self.heap + TAG_PAIR;
+        self.heap += SIZE_PAIR;
+        self.memory.store(p + CAR_OFFSET, a);
+        self.memory.store(p + CDR_OFFSET, b);
+        p
+    }
+
+    fn call(&mut self, label: String, args: Vec<i64>) -> Result<WordState<'a>, Answer> {
+        if let Some (op) = runtime_op(&label) {
+            return self.native(op, args);
+        }
+        let (params, body) = match self.codes.get(&label) {
+            Some ((params, body)) => (params, body),
+            None => return stuck(format!("no code at {}", label)),
+        };
+        let (fixed, rest) = match params.split_last() {
+            Some ((rest, fixed)) if is_rest(rest) => (fixed, Some (rest)),
+            _ => (&params[..], None),
+        };
+        let enough = match rest {
+            Some (_) => args.len() >= fixed.len(),
+            None => args.len() == fixed.len(),
+        };
+        if !enough {
+            return stuck(format!("{} called with {} arguments", label, args.len()));
+        }
+        let mut env = HashMap::default();
+        for (p, a) in fixed.iter().zip(args.iter()) {
+            env.insert(p.clone(), *a);
+        }
+        if let Some (rest) = rest {
+            let list = args[fixed.len()..].iter().rev().fold(NIL, |ls, v| self.alloc_pair(*v, ls));
+            env.insert(rest.clone(), list);
+        }
+        Ok (WordState::Eval (body, Rc::new(RefCell::new(env))))
+    }
+
+    fn call_procedure(&mut self, p: i64, args: Vec<i64>) -> Result<WordState<'a>, Answer> {
+        if p & MASK_FIXNUM != TAG_PROC {
+            return stuck(format!("{} is not a procedure", self.memory.show(p, 0)));
+        }
+        let code = self.memory.load(p + PROC_CODE_OFFSET);
+        let label = match self.memory.label(code) {
+            Some (label) => label,
+            None => return stuck(format!("{} is not code", code)),
+        };
+        let mut args = args;
+        args.insert(0, p);
+        self.call(label, args)
+    }
+
+    fn native(&mut self, op: &str, args: Vec<i64>) -> Result<WordState<'a>, Answer> {
+        match (op, args.as_slice()) {
+            ("apply", [p, ls]) => {
+                let items = self.memory.items(*ls)?;
+                self.call_procedure(*p, items)
+            }
+            ("call/cc", [p]) => {
+                let id = self.captured.len() as i64;
+                self.captured.push((self.kont.clone(), self.handlers.clone()));
+                let k = self.heap + TAG_PROC;
+                self.heap += 16;
+                let code = self.memory.address(CONTINUATION_LABEL);
+                self.memory.store(k + PROC_CODE_OFFSET, code);
+                self.memory.store(k + PROC_DATA_OFFSET, id << SHIFT_FIXNUM);
+                self.call_procedure(*p, vec![k])
+            }
+            ("continuation", [k, v, ..]) => {
+                let id = (self.memory.load(k + PROC_DATA_OFFSET) >> SHIFT_FIXNUM) as usize;
+                let (kont, handlers) = match self.captured.get(id) {
+                    Some (captured) => captured.clone(),
+                    None => return stuck(format!("not a continuation")),
+                };
+                self.kont = kont;
+                self.handlers = handlers;
+                Ok (WordState::Return (*v))
+            }
+            ("with-exception-handler", [handler, thunk]) => {
+                self.push(WordFrame::Handler (self.handlers.clone()));
+                self.handlers.push(*handler);
+                self.call_procedure(*thunk, vec![])
+            }
+            ("raise", [obj]) | ("raise-continuable", [obj]) => {
+                let saved = self.handlers.clone();
+                match self.handlers.pop() {
+                    None => Err (uncaught(self.memory.show(*obj, 0))),
+                    Some (handler) => {
+                        self.push(WordFrame::Raise (*obj, op == "raise-continuable", saved));
+                        self.call_procedure(handler, vec![*obj])
+                    }
+                }
+            }
+            ("error", [msg, irritants]) if !self.handlers.is_empty() => {
+                let obj = self.alloc_pair(*msg, *irritants);
+                self.native("raise", vec![obj])
+            }
+            ("error", [msg, irritants]) => Err (error(self.memory.show(*msg, 0), self.memory.irritants(*irritants))),
+            ("letrec-error", []) => Err (Answer::Error (LETREC_ERROR.to_string())),
+            ("values", _) => {
+                if let Some (frame) = self.kont.clone() {
+                    if let WordFrame::Consumer (c) = &frame.0 {
+                        self.kont = frame.1.clone();
+                        return self.call_procedure(*c, args);
+                    }
+                }
+                if let [v] = args.as_slice() {
+                    return Ok (WordState::Return (*v));
+                }
+                let values = args.iter().rev().fold(NIL, |ls, v| self.alloc_pair(*v, ls));
+                self.native("error", vec![values, NIL])
+            }
+            ("apply-values", [ls]) => {
+                let items = self.memory.items(*ls)?;
+                self.native("values", items)
+            }
+            ("call-with-values", [producer, consumer]) => {
+                self.push(WordFrame::Consumer (*consumer));
+                self.call_procedure(*producer, vec![])
+            }
+            (op, args) => stuck(format!("{} can not take {} arguments", op, args.len())),
+        }
+    }
+}
+
+
+// ---------------------------------------------------------------------
+//
+// the machine, from ImposeCallingConvention to FlattenProgram
+//
+// ---------------------------------------------------------------------
+
+// Runs the code over registers, the stack and the heap, with the runtime procedures of
+// CompileToAsm. Before AssignNewFrame a call bumps rbp by the frame size itself, and
+// before UpdateFrameLocations a frame variable is relative to rbp at the start of its body.
+// From OptimizeJump on a body without a jump falls into the next one.
+pub struct MachineInterpreter {
+    pub new_frames: bool,
+    pub updated_frames: bool,
+    pub flattened: bool,
+}
+
+struct Block<'a> {
+    label: String,
+    body: &'a Expr,
+    locate: HashMap<&'a str, &'a str>,
+    nfvs: HashMap<&'a str, i64>,
+    size: i64,
+}
+
+#[derive(Clone)]
+enum MachineFrame<'a> {
+    Seq (&'a [Expr]),
+    Branch (&'a Expr, Option<&'a Expr>),
+    Not,
+}
+
+// where a return point resumes the body which made the call
+#[derive(Clone)]
+struct Resume<'a> {
+    block: usize,
+    locals: Rc<RefCell<HashMap<&'a str, i64>>>,
+    base: i64,
+    kont: Vec<MachineFrame<'a>>,
+    bump: i64,
+}
+
+type Resumes<'a> = HashMap<(String, i64), Resume<'a>>;
+
+fn copy_resumes<'a>(resumes: &Resumes<'a>) -> Resumes<'a> {
+    resumes.iter().map(|(k, r)| {
+        let locals = Rc::new(RefCell::new(r.locals.borrow().clone()));
+        (k.clone(), Resume { locals, ..r.clone() })
+    }).collect()
+}
+
+enum Step<'a> {
+    Eval (&'a Expr),
+    Done,
+    Test (bool),
+    Jump (i64),
+}
+
+fn frame_index(s: &str) -> Option<i64> {
+    s.strip_prefix("fv").and_then(|i| i.parse().ok())
+}
+
+impl MachineInterpreter {
+    pub fn run(&self, expr: &Expr) -> Answer {
+        let mut blocks = vec![];
+        match expr {
+            Expr::Letrec (lambdas, box body) => {
+                blocks.push(self.block("_scheme_entry".to_string(), body));
+                for lambda in lambdas {
+                    match lambda {
+                        Expr::Lambda (label, _, box body) => blocks.push(self.block(label.clone(), body)),
+                        other => return Answer::Stuck (format!("{:?} is not a lambda", other)),
+                    }
+                }
+            }
+            other => return Answer::Stuck (format!("{:?} is not a program", other)),
+        }
+        let mut machine = Machine {
+            config: self,
+            index: blocks.iter().enumerate().map(|(i, b)| (b.label.clone(), i)).collect(),
+            blocks,
+            memory: Memory::new(),
+            registers: HashMap::default(),
+            handlers: NIL,
+            block: 0,
+            locals: Rc::new(RefCell::new(HashMap::default())),
+            base: STACK_BASE,
+            kont: vec![],
+            resumes: HashMap::default(),
+            saved: HashMap::default(),
+        };
+        machine.run()
+    }
+
+    // the wrappers around a body, with the frame variables it uses
+    fn block<'a>(&self, label: String, body: &'a Expr) -> Block<'a> {
+        use Expr::*;
+        let mut block = Block { label, body, locate: HashMap::default(), nfvs: HashMap::default(), size: 0 };
+        let mut body = body;
+        loop {
+            body = match body {
+                Locate (bindings, box e) => {
+                    for (k, v) in bindings {
+                        block.locate.insert(k, v);
+                        if let Some (i) = frame_index(v) {
+                            block.size = block.size.max(i + 1);
+                        }
+                    }
+                    e
+                }
+                NewFrames (frames, box e) => {
+                    for frame in frames {
+                        for (j, nfv) in frame.iter().enumerate() {
+                            block.nfvs.insert(nfv, j as i64);
+                        }
+                    }
+                    e
+                }
+                Locals (_, box e) | Ulocals (_, box e) | Spills (_, box e) | RegisterConflict (_, box e) |
+                FrameConflict (_, box e) | CallLive (_, box e) => e,
+                _ => break,
+            };
+        }
+        block.body = body;
+        block.size = block.size.max(frame_size(body));
+        block
+    }
+}
+
+// one more than the largest frame variable in the body
+fn frame_size(expr: &Expr) -> i64 {
+    use Expr::*;
+    match expr {
+        Symbol (s) => frame_index(s).map_or(0, |i| i + 1),
+        Begin (es) => es.iter().map(frame_size).max().unwrap_or(0),
+        Funcall (box f, args) => args.iter().map(frame_size).max().unwrap_or(0).max(frame_size(f)),
+        Locals (_, box e) | Ulocals (_, box e) | Spills (_, box e) | Locate (_, box e) | RegisterConflict (_, box e) |
+        FrameConflict (_, box e) | NewFrames (_, box e) | CallLive (_, box e) | ReturnPoint (_, box e) |
+        Prim1 (_, box e) | Alloc (box e) => frame_size(e),
+        Prim2 (_, box a, box b) | If1 (box a, box b) | Set (box a, box b) | Mref (box a, box b) => frame_size(a).max(frame_size(b)),
+        If (box a, box b, box c) | Mset (box a, box b, box c) => frame_size(a).max(frame_size(b)).max(frame_size(c)),
+        _ => 0,
+    }
+}
+
+struct Machine<'a> {
+    config: &'a MachineInterpreter,
+    blocks: Vec<Block<'a>>,
+    index: HashMap<String, usize>,
+    memory: Memory,
+    registers: HashMap<&'a str, i64>,
+    handlers: i64,
+    // the running body
+    block: usize,
+    locals: Rc<RefCell<HashMap<&'a str, i64>>>,
+    base: i64,
+    kont: Vec<MachineFrame<'a>>,
+    // the return points waiting for their calls, and the ones saved by each continuation
+    resumes: Resumes<'a>,
+    saved: HashMap<i64, Resumes<'a>>,
+}
+
+impl<'a> Machine<'a> {
+    fn run(&mut self) -> Answer {
+        let exit = self.memory.address("_scheme_exit");
+        self.registers.insert("rbp", STACK_BASE);
+        self.registers.insert("rdx", HEAP_BASE);
+        self.registers.insert("r15", exit);
+        let mut step = Step::Eval (self.blocks[0].body);
+        for _ in 0..MAX_STEPS {
+            let next = match step {
+                Step::Eval (e) => self.eval(e),
+                Step::Done => match self.kont.pop() {
+                    Some (MachineFrame::Seq (rest)) => Ok (self.sequence(rest)),
+                    Some (_) => stuck(format!("an effect where a predicate is expected")),
+                    None => self.fall(),
+                },
+                Step::Test (b) => match self.kont.pop() {
+                    Some (MachineFrame::Branch (b1, Some (b2))) => Ok (Step::Eval (if b { b1 } else { b2 })),
+                    Some (MachineFrame::Branch (b1, None)) => Ok (if b { Step::Eval (b1) } else { Step::Done }),
+                    Some (MachineFrame::Not) => Ok (Step::Test (!b)),
+                    _ => stuck(format!("a predicate where an effect is expected")),
+                },
+                Step::Jump (addr) => self.jump(addr),
+            };
+            step = match next {
+                Ok (step) => step,
+                Err (answer) => return answer,
+            };
+        }
+        Answer::Stuck (format!("no answer after {} steps", MAX_STEPS))
+    }
+
+    fn fall(&mut self) -> Result<Step<'a>, Answer> {
+        if self.config.flattened && self.block + 1 < self.blocks.len() {
+            let next = self.blocks[self.block + 1].label.clone();
+            return Ok (Step::Jump (self.memory.address(&next)));
+        }
+        stuck(format!("{} ends without a jump", self.blocks[self.block].label))
+    }
+
+    fn sequence(&mut self, exprs: &'a [Expr]) -> Step<'a> {
+        match exprs {
+            [] => Step::Done,
+            [e] => Step::Eval (e),
+            [e, rest @ ..] => {
+                self.kont.push(MachineFrame::Seq (rest));
+                Step::Eval (e)
+            }
+        }
+    }
+
+    fn eval(&mut self, expr: &'a Expr) -> Result<Step<'a>, Answer> {
+        use Expr::*;
+        let step = match expr {
+            Begin (exprs) => self.sequence(exprs),
+            If (box pred, box b1, box b2) => {
+                self.kont.push(MachineFrame::Branch (b1, Some (b2)));
+                Step::Eval (pred)
+            }
+            If1 (box pred, box b1) => {
+                self.kont.push(MachineFrame::Branch (b1, None));
+                Step::Eval (pred)
+            }
+            Prim1 (op, box pred) if op == "not" => {
+                self.kont.push(MachineFrame::Not);
+                Step::Eval (pred)
+            }
+            Bool (b) => Step::Test (*b),
+            Prim2 (op, box a, box b) if relop(op, 0, 0).is_some() => {
+                let (a, b) = (self.value(a)?, self.value(b)?);
+                Step::Test (relop(op, a, b).unwrap())
+            }
+            Set (box Symbol (dst), box rhs) => {
+                let v = self.rhs(rhs)?;
+                self.store(dst, v)?;
+                Step::Done
+            }
+            Mset (box base, box offset, box v) => {
+                let addr = self.value(base)? + self.value(offset)?;
+                let v = self.value(v)?;
+                self.memory.store(addr, v);
+                Step::Done
+            }
+            Nop => Step::Done,
+            ReturnPoint (label, box body) => {
+                // one more word, so calls from the same body never share a frame
+                let bump = if self.config.new_frames { 0 } else { 8 * (self.blocks[self.block].size + 1) };
+                let rbp = self.register("rbp")? + bump;
+                self.registers.insert("rbp", rbp);
+                let resume = Resume {
+                    block: self.block,
+                    locals: self.locals.clone(),
+                    base: self.base,
+                    kont: self.kont.clone(),
+                    bump,
+                };
+                self.resumes.insert((label.clone(), rbp), resume);
+                Step::Eval (body)
+            }
+            Funcall (box f, _) => Step::Jump (self.value(f)?),
+            other => return stuck(format!("{} can not run", other)),
+        };
+        Ok (step)
+    }
+
+    fn rhs(&mut self, expr: &'a Expr) -> Result<i64, Answer> {
+        match expr {
+            Expr::Prim2 (op, box a, box b) => {
+                let (a, b) = (self.value(a)?, self.value(b)?);
+                match binop(op, a, b) {
+                    Some (v) => Ok (v),
+                    None => stuck(format!("{} is not an operator", op)),
+                }
+            }
+            Expr::Mref (box base, box offset) => {
+                let addr = self.value(base)? + self.value(offset)?;
+                Ok (self.memory.load(addr))
+            }
+            Expr::Alloc (box n) => {
+                let p = self.register("rdx")?;
+                let n = self.value(n)?;
+                self.registers.insert("rdx", p + n);
+                Ok (p)
+            }
+            e => self.value(e),
+        }
+    }
+
+    fn value(&mut self, expr: &'a Expr) -> Result<i64, Answer> {
+        match expr {
+            Expr::Int64 (i) => Ok (*i),
+            Expr::Symbol (s) => self.load(s),
+            other => stuck(format!("{} is not a value", other)),
+        }
+    }
+
+    fn register(&self, r: &str) -> Result<i64, Answer> {
+        match self.registers.get(r) {
+            Some (v) => Ok (*v),
+            None => stuck(format!("{} is read before it is set", r)),
+        }
+    }
+
+    fn frame_base(&self) -> Result<i64, Answer> {
+        if self.config.updated_frames {
+            self.register("rbp")
+        } else {
+            Ok (self.base)
+        }
+    }
+
+    // the home of a variable, in memory or in a register
+    fn home(&self, s: &'a str) -> Result<Result<i64, &'a str>, Answer> {
+        if is_reg(s) {
+            return Ok (Err (s));
+        }
+        if let Some (i) = frame_index(s) {
+            return Ok (Ok (self.frame_base()? + 8 * i));
+        }
+        let block = &self.blocks[self.block];
+        if let Some (loc) = block.locate.get(s).copied() {
+            return self.home(loc);
+        }
+        if !self.config.new_frames {
+            if let Some (j) = block.nfvs.get(s) {
+                return Ok (Ok (self.register("rbp")? + 8 * j));
+            }
+        }
+        Ok (Err (s))
+    }
+
+    fn load(&mut self, s: &'a str) -> Result<i64, Answer> {
+        match self.home(s)? {
+            Ok (addr) => Ok (self.memory.load(addr)),
+            Err (r) if is_reg(r) => self.register(r),
+            Err (v) if is_uvar(v) => match self.locals.borrow().get(v) {
+                Some (x) => Ok (*x),
+                None => stuck(format!("{} is read before it is set", v)),
+            },
+            Err (l) if is_label(l) => Ok (self.memory.address(l)),
+            Err (s) => stuck(format!("{} is not a location", s)),
+        }
+    }
+
+    fn store(&mut self, s: &'a str, v: i64) -> Result<(), Answer> {
+        match self.home(s)? {
+            Ok (addr) => self.memory.store(addr, v),
+            Err (r) if is_reg(r) => { self.registers.insert(r, v); }
+            Err (u) if is_uvar(u) => { self.locals.borrow_mut().insert(u, v); }
+            Err (s) => return stuck(format!("{} is not a location", s)),
+        }
+        Ok (())
+    }
+
+    fn jump(&mut self, addr: i64) -> Result<Step<'a>, Answer> {
+        let label = match self.memory.label(addr) {
+            Some (label) => label,
+            None => return stuck(format!("jump to {}", addr)),
+        };
+        if label == "_scheme_exit" {
+            return Err (Answer::Value (self.memory.show(self.register("rax")?, 0)));
+        }
+        if let Some (target) = self.native(&label)? {
+            return Ok (Step::Jump (target));
+        }
+        let rbp = self.register("rbp")?;
+        if let Some (resume) = self.resumes.get(&(label.clone(), rbp)).cloned() {
+            self.block = resume.block;
+            self.locals = resume.locals;
+            self.base = resume.base;
+            self.kont = resume.kont;
+            self.registers.insert("rbp", rbp - resume.bump);
+            return Ok (Step::Done);
+        }
+        if let Some (i) = self.index.get(&label).copied() {
+            self.block = i;
+            self.locals = Rc::new(RefCell::new(HashMap::default()));
+            self.base = rbp;
+            self.kont = vec![];
+            return Ok (Step::Eval (self.blocks[i].body));
+        }
+        stuck(format!("no code at {}", label))
+    }
+
+    fn code(&self, p: i64) -> i64 {
+        self.memory.load(p + PROC_CODE_OFFSET)
+    }
+
+    fn set(&mut self, r: &'static str, v: i64) {
+        self.registers.insert(r, v);
+    }
+
+    fn cons(&mut self, a: i64, b: i64) -> Result<i64, Answer> {
+        let p = self.register("rdx")?;
+        self.set("rdx", p + SIZE_PAIR);
+        self.memory.store(p, a);
+        self.memory.store(p + 8, b);
+        Ok (p + TAG_PAIR)
+    }
+
+    // the runtime procedures of CompileToAsm, the address they jump to
+    fn native(&mut self, label: &str) -> Result<Option<i64>, Answer> {
+        let target = match label {
+            APPLY_LABEL => {
+                let mut ls = self.register("r9")?;
+                self.set("rcx", 1);
+                if ls != NIL {
+                    let mut rsi = self.register("rbp")?;
+                    let mut rcx = 1;
+                    while ls != NIL {
+                        if ls & MASK_FIXNUM != TAG_PAIR {
+                            return stuck(format!("apply to an improper list"));
+                        }
+                        let item = self.memory.load(ls + CAR_OFFSET);
+                        if rcx == 1 {
+                            self.set("r9", item);
+                        } else {
+                            self.memory.store(rsi, item);
+                            rsi += 8;
+                        }
+                        ls = self.memory.load(ls + CDR_OFFSET);
+                        rcx += 1;
+                    }
+                    self.set("rcx", rcx);
+                }
+                self.set("rax", ls);
+                self.code(self.register("r8")?)
+            }
+            COLLECT_REST_LABEL => {
+                let (mut rcx, rax, rbp) = (self.register("rcx")?, self.register("rax")?, self.register("rbp")?);
+                let mut rest = NIL;
+                while rcx > rax {
+                    rcx -= 1;
+                    let item = if rcx == 1 { self.register("r9")? } else { self.memory.load(rbp + 8 * rcx - 16) };
+                    rest = self.cons(item, rest)?;
+                }
+                if rax == 1 {
+                    self.set("r9", rest);
+                } else {
+                    self.memory.store(rbp + 8 * rax - 16, rest);
+                }
+                self.set("rcx", rcx);
+                self.register("r11")?
+            }
+            CALLCC_LABEL => {
+                let rbp = self.register("rbp")?;
+                let size = rbp - STACK_BASE;
+                let k = self.register("rdx")? + TAG_PROC;
+                let code = self.memory.address(CONTINUATION_LABEL);
+                let rp = self.register("r15")?;
+                let m = &mut self.memory;
+                m.store(k + PROC_CODE_OFFSET, code);
+                m.store(k + CONT_RP_OFFSET, rp);
+                m.store(k + CONT_HANDLERS_OFFSET, self.handlers);
+                m.store(k + CONT_SIZE_OFFSET, size);
+                for i in 0..size / 8 {
+                    let w = m.load(STACK_BASE + 8 * i);
+                    m.store(k + CONT_STACK_OFFSET + 8 * i, w);
+                }
+                self.set("rdx", k + CONT_STACK_OFFSET + size);
+                self.saved.insert(k, copy_resumes(&self.resumes));
+                self.set("r9", k);
+                self.set("rcx", 2);
+                self.code(self.register("r8")?)
+            }
+            CONTINUATION_LABEL => {
+                let k = self.register("r8")?;
+                let m = &mut self.memory;
+                self.handlers = m.load(k + CONT_HANDLERS_OFFSET);
+                let size = m.load(k + CONT_SIZE_OFFSET);
+                for i in 0..size / 8 {
+                    let w = m.load(k + CONT_STACK_OFFSET + 8 * i);
+                    m.store(STACK_BASE + 8 * i, w);
+                }
+                if let Some (saved) = self.saved.get(&k) {
+                    self.resumes = copy_resumes(saved);
+                }
+                self.set("rbp", STACK_BASE + size);
+                self.set("rax", self.register("r9")?);
+                self.memory.load(k + CONT_RP_OFFSET)
+            }
+            HANDLER_LABEL => {
+                let rbp = self.register("rbp")?;
+                let rp = self.register("r15")?;
+                self.memory.store(rbp, rp);
+                self.memory.store(rbp + 8, self.handlers);
+                self.handlers = self.cons(self.register("r8")?, self.handlers)?;
+                self.set("rbp", rbp + 16);
+                self.set("r8", self.register("r9")?);
+                self.set("rcx", 1);
+                let ret = self.memory.address("with_exception_handler$return");
+                self.set("r15", ret);
+                self.code(self.register("r8")?)
+            }
+            "with_exception_handler$return" => {
+                let rbp = self.register("rbp")? - 16;
+                self.set("rbp", rbp);
+                self.handlers = self.memory.load(rbp + 8);
+                self.memory.load(rbp)
+            }
+            RAISE_LABEL | RAISE_CONTINUABLE_LABEL => {
+                let obj = self.register("r8")?;
+                if self.handlers == NIL {
+                    return Err (uncaught(self.memory.show(obj, 0)));
+                }
+                let rbp = self.register("rbp")?;
+                let rp = self.register("r15")?;
+                let m = &mut self.memory;
+                m.store(rbp, rp);
+                m.store(rbp + 8, self.handlers);
+                m.store(rbp + 16, obj);
+                m.store(rbp + 24, (label == RAISE_CONTINUABLE_LABEL) as i64);
+                let handler = m.load(self.handlers + CAR_OFFSET);
+                self.handlers = m.load(self.handlers + CDR_OFFSET);
+                self.set("rbp", rbp + 32);
+                self.set("r9", obj);
+                self.set("r8", handler);
+                self.set("rcx", 2);
+                let ret = self.memory.address("raise$return");
+                self.set("r15", ret);
+                self.code(handler)
+            }
+            "raise$return" => {
+                let rbp = self.register("rbp")? - 32;
+                self.set("rbp", rbp);
+                self.handlers = self.memory.load(rbp + 8);
+                let obj = self.memory.load(rbp + 16);
+                self.set("r8", obj);
+                if self.memory.load(rbp + 24) == 0 {
+                    return Err (returned(self.memory.show(obj, 0)));
+                }
+                self.memory.load(rbp)
+            }
+            ERROR_LABEL if self.handlers != NIL => {
+                let obj = self.cons(self.register("r8")?, self.register("r9")?)?;
+                self.set("r8", obj);
+                return self.native(RAISE_LABEL);
+            }
+            ERROR_LABEL => {
+                let msg = self.memory.show(self.register("r8")?, 0);
+                let irritants = self.memory.irritants(self.register("r9")?);
+                return Err (error(msg, irritants));
+            }
+            LETREC_ERROR_LABEL => return Err (Answer::Error (LETREC_ERROR.to_string())),
+            VALUES_LABEL => {
+                let consumer = self.memory.address("call_with_values$return");
+                let rcx = self.register("rcx")?;
+                if self.register("r15")? == consumer {
+                    // the values go to the consumer, which is under the frame of call-with-values
+                    let rbp = self.register("rbp")? - 16;
+                    self.set("rbp", rbp);
+                    self.set("r15", self.memory.load(rbp));
+                    let f = self.memory.load(rbp + 8);
+                    for i in 0..(rcx - 2).max(0) {
+                        let w = self.memory.load(rbp + 16 + 8 * i);
+                        self.memory.store(rbp + 8 + 8 * i, w);
+                    }
+                    if rcx > 1 {
+                        self.memory.store(rbp, self.register("r9")?);
+                    }
+                    if rcx > 0 {
+                        self.set("r9", self.register("r8")?);
+                    }
+                    self.set("r8", f);
+                    self.set("rcx", rcx + 1);
+                    self.code(f)
+                } else if rcx == 1 {
+                    self.set("rax", self.register("r8")?);
+                    self.register("r15")?
+                } else {
+                    let rbp = self.register("rbp")?;
+                    let mut values = NIL;
+                    for i in (0..rcx).rev() {
+                        let v = match i {
+                            0 => self.register("r8")?,
+                            1 => self.register("r9")?,
+                            i => self.memory.load(rbp + 8 * i - 16),
+                        };
+                        values = self.cons(v, values)?;
+                    }
+                    self.set("r8", values);
+                    self.set("r9", NIL);
+                    return self.native(ERROR_LABEL);
+                }
+            }
+            APPLY_VALUES_LABEL => {
+                let items = self.memory.items(self.register("r8")?)?;
+                let rbp = self.register("rbp")?;
+                for (i, item) in items.iter().enumerate() {
+                    match i {
+                        0 => self.set("r8", *item),
+                        1 => self.set("r9", *item),
+                        i => self.memory.store(rbp + 8 * (i as i64 - 2), *item),
+                    }
+                }
+                self.set("rcx", items.len() as i64);
+                return self.native(VALUES_LABEL);
+            }
+            CALL_WITH_VALUES_LABEL => {
+                let rbp = self.register("rbp")?;
+                let rp = self.register("r15")?;
+                let consumer = self.register("r9")?;
+                self.memory.store(rbp, rp);
+                self.memory.store(rbp + 8, consumer);
+                self.set("rbp", rbp + 16);
+                self.set("rcx", 1);
+                let ret = self.memory.address("call_with_values$return");
+                self.set("r15", ret);
+                self.code(self.register("r8")?)
+            }
+            "call_with_values$return" => {
+                let rbp = self.register("rbp")? - 16;
+                self.set("rbp", rbp);
+                self.set("r15", self.memory.load(rbp));
+                let f = self.memory.load(rbp + 8);
+                self.set("r8", f);
+                self.set("r9", self.register("rax")?);
+                self.set("rcx", 2);
+                self.code(f)
+            }
+            _ => return Ok (None),
+        };
+        Ok (Some (target))
+    }
+}
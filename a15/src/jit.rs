@@ -0,0 +1,424 @@
+// Runs a compiled program inside the process. The encoder puts the code into an executable
+// mapping, the stack and the heap are mapped with guard pages like guarded_area of runtime.c,
+// and _scheme_entry is called with them as main calls it. The answer is decoded into a Value.
+//
+// The functions of runtime.c are stubs in the same code, which call into Rust to record the
+// error, then go back to the caller of _scheme_entry with the stack pointer saved on entry.
+// A SIGSEGV in the code, on a guard page or elsewhere, goes back there too, with the message
+// of segv_handler of runtime.c.
+use std::fmt;
+use std::ffi::c_void;
+use std::cell::Cell;
+use std::sync::OnceLock;
+
+use crate::syntax::Asm;
+use crate::encoder::Encoder;
+use crate::interp::{Answer, show_word, show_irritants, error, uncaught, returned, LETREC_ERROR};
+use crate::compiler::{
+    SHIFT_FIXNUM, MASK_FIXNUM, TAG_FIXNUM, TAG_PAIR, TAG_VECTOR, TAG_PROC, TAG_BOX, TAG_RECORD,
+    CAR_OFFSET, CDR_OFFSET, VLEN_OFFSET, VDATA_OFFSET, BOX_OFFSET, RECORD_RTD_OFFSET,
+    FALSE, TRUE, NIL, VOID,
+};
+
+// the limits of print1
+const MAX_DEPTH: usize = 100;
+const MAX_LENGTH: usize = 1000;
+
+// the sizes of runtime.c in words
+const STACK_SIZE: usize = 100000;
+const HEAP_SIZE: usize = 100000;
+
+const PROT_NONE: i32 = 0;
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+const SC_PAGESIZE: i32 = 30;
+const SIGSEGV: i32 = 11;
+const SA_SIGINFO: i32 = 0x04;
+const SA_ONSTACK: i32 = 0x08000000;
+
+// the offset of si_addr in siginfo_t, and of rsp and rip in the gregs of ucontext_t
+const SI_ADDR: usize = 16;
+const UC_RSP: usize = 40 + 8 * 15;
+const UC_RIP: usize = 40 + 8 * 16;
+
+#[repr(C)]
+struct SigAction {
+    handler: usize,
+    mask: [u64; 16],
+    flags: i32,
+    restorer: usize,
+}
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+    fn sysconf(name: i32) -> i64;
+    fn sigaction(signum: i32, act: *const SigAction, old: *mut SigAction) -> i32;
+}
+
+// the handler before segv_handler, and the context of the program the thread runs
+static PREVIOUS: OnceLock<SigAction> = OnceLock::new();
+thread_local! {
+    static CURRENT: Cell<*mut Context> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+// the answer of a program, as print1 sees it. Elided stands for what print1 leaves out,
+// past MAX_DEPTH or MAX_LENGTH, so a circular list decodes too.
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Fixnum (i64),
+    Boolean (bool),
+    Nil,
+    Void,
+    Procedure,
+    Pair (Box<Value>, Box<Value>),
+    Vector (Vec<Value>),
+    Box (Box<Value>),
+    Record (String),
+    Elided,
+}
+
+impl Value {
+    fn show(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        match self {
+            Value::Fixnum (n) => write!(f, "{}", n),
+            Value::Boolean (true) => write!(f, "#t"),
+            Value::Boolean (false) => write!(f, "#f"),
+            Value::Nil => write!(f, "()"),
+            Value::Void => write!(f, "#<void>"),
+            Value::Procedure => write!(f, "#<procedure>"),
+            Value::Pair (..) if depth > MAX_DEPTH => write!(f, "(...)"),
+            Value::Pair (car, cdr) => {
+                write!(f, "(")?;
+                car.show(f, depth + 1)?;
+                let mut rest = cdr;
+                while let Value::Pair (car, cdr) = &**rest {
+                    write!(f, " ")?;
+                    car.show(f, depth + 1)?;
+                    rest = cdr;
+                }
+                match &**rest {
+                    Value::Nil => (),
+                    Value::Elided => write!(f, " ...")?,
+                    other => {
+                        write!(f, " . ")?;
+                        other.show(f, depth + 1)?;
+                    }
+                }
+                write!(f, ")")
+            }
+            Value::Vector (_) if depth > MAX_DEPTH => write!(f, "#(...)"),
+            Value::Vector (items) => {
+                write!(f, "#(")?;
+                for (i, item) in items.iter().enumerate() {
+                    match item {
+                        Value::Elided => write!(f, " ...")?,
+                        item => {
+                            if i > 0 {
+                                write!(f, " ")?;
+                            }
+                            item.show(f, depth + 1)?;
+                        }
+                    }
+                }
+                write!(f, ")")
+            }
+            Value::Box (_) if depth > MAX_DEPTH => write!(f, "#&..."),
+            Value::Box (v) => {
+                write!(f, "#&")?;
+                v.show(f, depth + 1)
+            }
+            Value::Record (name) => write!(f, "#<record {}>", name),
+            Value::Elided => write!(f, "..."),
+        }
+    }
+}
+
+// prints like print1
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.show(f, 0)
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { sysconf(SC_PAGESIZE) as usize }
+}
+
+// an anonymous mapping, unmapped when dropped
+struct Mapping {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(len: usize) -> Self {
+        let addr = unsafe { mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+        if addr as isize == -1 {
+            panic!("mmap failed: {}", std::io::Error::last_os_error());
+        }
+        Mapping { addr: addr as *mut u8, len }
+    }
+
+    fn protect(&self, offset: usize, len: usize, prot: i32) {
+        if unsafe { mprotect(self.addr.add(offset) as *mut c_void, len, prot) } != 0 {
+            panic!("mprotect failed: {}", std::io::Error::last_os_error());
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.addr as *mut c_void, self.len) };
+    }
+}
+
+// guarded_area of runtime.c, n words rounded up to pages between two guard pages
+fn guarded_area(n: usize) -> (Mapping, i64, i64) {
+    let page = page_size();
+    let size = (n * 8).next_multiple_of(page);
+    let area = Mapping::new(size + 2 * page);
+    area.protect(0, page, PROT_NONE);
+    area.protect(page + size, page, PROT_NONE);
+    let start = area.addr as i64 + page as i64;
+    (area, start, start + size as i64)
+}
+
+// what the stubs share with Rust. The saved stack pointer comes first, the stubs load it.
+#[repr(C)]
+struct Context {
+    rsp: u64,
+    error: Option<String>,
+    stack: (i64, i64),
+    heap: (i64, i64),
+    // the code, where jit$resume is, and the address of a fault in it
+    code: (u64, u64),
+    resume: u64,
+    fault: Option<i64>,
+}
+
+impl Context {
+    // the message of segv_handler of runtime.c
+    fn fault_message(&self, addr: i64) -> String {
+        let page = page_size() as i64;
+        let ((stack, stack_end), (heap, heap_end)) = (self.stack, self.heap);
+        if heap - page <= addr && addr < heap {
+            "invalid access just below the heap".to_string()
+        } else if heap_end <= addr && addr < heap_end + page {
+            "invalid access just above the heap".to_string()
+        } else if stack - page <= addr && addr < stack {
+            "invalid access just below the stack".to_string()
+        } else if stack_end <= addr && addr < stack_end + page {
+            "invalid access just above the stack".to_string()
+        } else {
+            "Segmentation violation".to_string()
+        }
+    }
+
+    // a word of the stack or the heap, as the runtime would read it without faulting
+    fn load(&self, addr: i64) -> Result<i64, String> {
+        let inside = |(start, end): (i64, i64)| start <= addr && addr < end;
+        if addr % 8 != 0 || !(inside(self.stack) || inside(self.heap)) {
+            return Err (format!("an invalid access at {:#x}", addr));
+        }
+        Ok (unsafe { *(addr as *const i64) })
+    }
+
+    fn show(&self, x: i64) -> Result<String, String> {
+        show_word(&|addr| self.load(addr), x, 0)
+    }
+
+    fn record(&mut self, answer: Result<Answer, String>) {
+        self.error = Some (match answer {
+            Ok (answer) => answer.to_string(),
+            Err (msg) => msg,
+        });
+    }
+
+    fn decode(&self, x: i64, depth: usize) -> Result<Value, String> {
+        let value = match x & MASK_FIXNUM {
+            TAG_FIXNUM => Value::Fixnum (x >> SHIFT_FIXNUM),
+            TAG_PAIR if depth > MAX_DEPTH => Value::Pair (Box::new(Value::Elided), Box::new(Value::Elided)),
+            TAG_PAIR => {
+                // the items, then the rest, which print1 leaves out past MAX_LENGTH
+                let mut items = vec![self.decode(self.load(x + CAR_OFFSET)?, depth + 1)?];
+                let mut rest = self.load(x + CDR_OFFSET)?;
+                while rest & MASK_FIXNUM == TAG_PAIR && items.len() < MAX_LENGTH {
+                    items.push(self.decode(self.load(rest + CAR_OFFSET)?, depth + 1)?);
+                    rest = self.load(rest + CDR_OFFSET)?;
+                }
+                let mut list = match rest {
+                    NIL => Value::Nil,
+                    _ if items.len() == MAX_LENGTH => Value::Elided,
+                    rest => self.decode(rest, depth + 1)?,
+                };
+                for item in items.into_iter().rev() {
+                    list = Value::Pair (Box::new(item), Box::new(list));
+                }
+                list
+            }
+            TAG_VECTOR if depth > MAX_DEPTH => Value::Vector (vec![]),
+            TAG_VECTOR => {
+                let n = (self.load(x + VLEN_OFFSET)? >> SHIFT_FIXNUM) as usize;
+                let mut items = vec![];
+                for i in 0..n.min(MAX_LENGTH) {
+                    items.push(self.decode(self.load(x + VDATA_OFFSET + 8 * i as i64)?, depth + 1)?);
+                }
+                if n > MAX_LENGTH {
+                    items.push(Value::Elided);
+                }
+                Value::Vector (items)
+            }
+            TAG_RECORD => {
+                let rtd = self.load(x + RECORD_RTD_OFFSET)?;
+                let n = self.load(rtd + VLEN_OFFSET)? >> SHIFT_FIXNUM;
+                let mut name = vec![];
+                for i in 1..n {
+                    name.push((self.load(rtd + VDATA_OFFSET + 8 * i)? >> SHIFT_FIXNUM) as u8);
+                }
+                Value::Record (String::from_utf8_lossy(&name).to_string())
+            }
+            TAG_BOX if depth > MAX_DEPTH => Value::Box (Box::new(Value::Elided)),
+            TAG_BOX => Value::Box (Box::new(self.decode(self.load(x + BOX_OFFSET)?, depth + 1)?)),
+            TAG_PROC => Value::Procedure,
+            _ => match x {
+                FALSE => Value::Boolean (false),
+                TRUE => Value::Boolean (true),
+                NIL => Value::Nil,
+                VOID => Value::Void,
+                _ => return Err (format!("{:#x} is not a value", x)),
+            },
+        };
+        Ok (value)
+    }
+}
+
+// the functions of runtime.c, the stubs pass the context in rdx
+extern "C" fn jit_error(msg: i64, irritants: i64, ctx: *mut Context) {
+    let ctx = unsafe { &mut *ctx };
+    let answer = ctx.show(msg).and_then(|msg| Ok (error(msg, show_irritants(&|addr| ctx.load(addr), irritants)?)));
+    ctx.record(answer);
+}
+
+extern "C" fn jit_raise(obj: i64, returns: i64, ctx: *mut Context) {
+    let ctx = unsafe { &mut *ctx };
+    let answer = ctx.show(obj).map(|obj| if returns != 0 { returned(obj) } else { uncaught(obj) });
+    ctx.record(answer);
+}
+
+extern "C" fn jit_letrec_error(_: i64, _: i64, ctx: *mut Context) {
+    let ctx = unsafe { &mut *ctx };
+    ctx.record(Ok (Answer::Error (LETREC_ERROR.to_string())));
+}
+
+// a fault in the code of the thread resumes at jit$resume, like a stub. Any other fault goes
+// to the handler before, which gets it again once this one returns.
+extern "C" fn segv_handler(_: i32, info: *mut u8, uc: *mut u8) {
+    let ctx = CURRENT.with(|current| current.get());
+    unsafe {
+        let rip = uc.add(UC_RIP) as *mut u64;
+        if ctx.is_null() || *rip < (*ctx).code.0 || (*ctx).code.1 <= *rip {
+            if let Some (previous) = PREVIOUS.get() {
+                sigaction(SIGSEGV, previous, std::ptr::null_mut());
+            }
+            return;
+        }
+        (*ctx).fault = Some (*(info.add(SI_ADDR) as *const i64));
+        *(uc.add(UC_RSP) as *mut u64) = (*ctx).rsp;
+        *rip = (*ctx).resume;
+    }
+}
+
+fn install_handler() {
+    PREVIOUS.get_or_init(|| {
+        let handler: extern "C" fn(i32, *mut u8, *mut u8) = segv_handler;
+        let action = SigAction { handler: handler as usize, mask: [0; 16], flags: SA_SIGINFO | SA_ONSTACK, restorer: 0 };
+        let mut previous = SigAction { handler: 0, mask: [0; 16], flags: 0, restorer: 0 };
+        if unsafe { sigaction(SIGSEGV, &action, &mut previous) } != 0 {
+            panic!("sigaction failed: {}", std::io::Error::last_os_error());
+        }
+        previous
+    });
+}
+
+// jit$entry saves the callee-saved registers and the stack pointer, then calls _scheme_entry.
+// A stub calls its function with the stack aligned, then resumes with the saved stack pointer.
+fn glue(ctx: *mut Context) -> Vec<Asm> {
+    use Asm::*;
+    let op2 = |op: &str, src, dst| Op2 (op.to_string(), Box::new(src), Box::new(dst));
+    let saved = || vec![RBX, RBP, R12, R13, R14, R15];
+    let mut entry: Vec<Asm> = saved().into_iter().map(|r| Push (Box::new(r))).collect();
+    entry.push(op2("subq", Imm (8), RSP));
+    entry.push(op2("movq", RSP, Deref (Box::new(RDX), 0)));
+    entry.push(Callq (Box::new(Label ("_scheme_entry".to_string()))));
+    let mut resume = vec![op2("addq", Imm (8), RSP)];
+    resume.extend(saved().into_iter().rev().map(|r| Pop (Box::new(r))));
+    resume.push(Retq);
+    let stub = |f: usize| vec![
+        op2("subq", Imm (8), RSP),
+        op2("movq", Imm (ctx as i64), RDX),
+        op2("movq", Imm (f as i64), RAX),
+        Callq (Box::new(RAX)),
+        op2("movq", Imm (ctx as i64), RAX),
+        op2("movq", Deref (Box::new(RAX), 0), RSP),
+        Jmp (Box::new(Label ("jit$resume".to_string()))),
+    ];
+    let f: extern "C" fn(i64, i64, *mut Context) = jit_error;
+    let g: extern "C" fn(i64, i64, *mut Context) = jit_raise;
+    let h: extern "C" fn(i64, i64, *mut Context) = jit_letrec_error;
+    vec![
+        Cfg ("jit$entry".to_string(), entry),
+        Cfg ("jit$resume".to_string(), resume),
+        Cfg ("_scheme_error".to_string(), stub(f as usize)),
+        Cfg ("_scheme_raise".to_string(), stub(g as usize)),
+        Cfg ("_scheme_letrec_error".to_string(), stub(h as usize)),
+    ]
+}
+
+pub struct Jit {}
+impl Jit {
+    // the answer of the program, or the error it reports
+    pub fn run(&self, code: Asm) -> Result<Value, String> {
+        install_handler();
+        let (stack_area, stack, stack_end) = guarded_area(STACK_SIZE);
+        let (heap_area, heap, heap_end) = guarded_area(HEAP_SIZE);
+        let mut ctx = Box::new(Context { rsp: 0, error: None, stack: (stack, stack_end), heap: (heap, heap_end),
+                                         code: (0, 0), resume: 0, fault: None });
+        let mut blocks = match code {
+            Asm::Prog (blocks) => blocks,
+            e => panic!("Invalid Program {}", e),
+        };
+        blocks.append(&mut glue(&mut *ctx));
+        let text = Encoder{}.run(&Asm::Prog (blocks));
+        if let Some ((_, f)) = text.calls.first() {
+            panic!("{} is not a function of the runtime", f);
+        }
+        let label = |name: &str| text.labels.iter().find(|(label, _)| label == name).unwrap().1;
+        let (entry, resume) = (label("jit$entry"), label("jit$resume"));
+        let page = page_size();
+        let len = text.bytes.len().next_multiple_of(page);
+        let code_area = Mapping::new(len);
+        unsafe { std::ptr::copy_nonoverlapping(text.bytes.as_ptr(), code_area.addr, text.bytes.len()) };
+        code_area.protect(0, len, PROT_READ | PROT_EXEC);
+        let code = code_area.addr as u64;
+        ctx.code = (code, code + len as u64);
+        ctx.resume = code + resume as u64;
+        CURRENT.with(|current| current.set(&mut *ctx));
+        let result = unsafe {
+            let f: extern "C" fn(i64, i64, *mut Context) -> i64 = std::mem::transmute(code_area.addr.add(entry));
+            f(stack, heap, &mut *ctx)
+        };
+        CURRENT.with(|current| current.set(std::ptr::null_mut()));
+        let answer = match (ctx.fault, ctx.error.take()) {
+            (Some (addr), _) => Err (ctx.fault_message(addr)),
+            (None, Some (msg)) => Err (msg),
+            (None, None) => ctx.decode(result, 0),
+        };
+        drop((code_area, stack_area, heap_area));
+        answer
+    }
+}
@@ -3,14 +3,76 @@
 
 mod syntax;
 mod parser;
+mod pretty;
 mod compiler;
+mod interp;
+mod verify;
+mod validate;
+mod emulator;
+mod encoder;
+mod elf;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod jit;
+mod corpus;
+mod fuzz;
+#[cfg(test)]
+mod snapshot;
+#[cfg(test)]
 mod test;
 
 
-use compiler::compile;
+use compiler::{compile, compile_from};
+use fuzz::{Features, Fuzzer};
+use corpus::{run_corpus, Backend};
 
 
 fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    // `a15 fuzz <seed> <count>` compiles count random programs and shrinks the first failure
+    if let [_, cmd, seed, count] = args.as_slice() {
+        if cmd == "fuzz" {
+            let fuzzer = Fuzzer { features: Features::all(), stem: "fuzz".to_string() };
+            match fuzzer.run(seed.parse().expect("invalid seed"), count.parse().expect("invalid count")) {
+                Some (report) => {
+                    println!("{}", report);
+                    std::process::exit(1);
+                }
+                None => println!("{} programs passed", count),
+            }
+            return Ok (());
+        }
+    }
+    // `a15 corpus <dir or file>` runs the programs against the expectations in their headers,
+    // `a15 object <dir or file>` links them from the objects of the encoder instead,
+    // `a15 emulate <dir or file>` runs them in the emulator, and `a15 jit <dir or file>` in the process
+    if let [_, cmd, path] = args.as_slice() {
+        let backend = match cmd.as_str() {
+            "corpus" => Some (Backend::Native),
+            "object" => Some (Backend::Object),
+            "emulate" => Some (Backend::Emulator),
+            #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+            "jit" => Some (Backend::Jit),
+            _ => None,
+        };
+        if let Some (backend) = backend {
+            // the failures carry the messages, so the panics need not be printed too
+            std::panic::set_hook(Box::new(|_| ()));
+            let failures = run_corpus(path, backend);
+            for failure in &failures {
+                println!("{}\n", failure);
+            }
+            println!("{} failed", failures.len());
+            if !failures.is_empty() {
+                std::process::exit(1);
+            }
+            return Ok (());
+        }
+    }
+    // `a15 <pass> <file>` starts at the pass with a program dumped before it
+    if let [_, pass, file] = args.as_slice() {
+        let s = std::fs::read_to_string(file)?;
+        return compile_from(pass, &s, "t.s");
+    }
     let s = "'#3(0)";
     compile(s, "t.s")
 }
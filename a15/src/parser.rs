@@ -1,8 +1,9 @@
-use std::vec::IntoIter;
-use std::collections::HashMap;
-use std::collections::HashSet;
 
-use crate::syntax::Scheme;
+use crate::syntax::{Scheme, Expr, HashMap, HashSet};
+#[cfg(test)]
+use crate::syntax::Asm;
+use crate::pretty::{PRIMN_OPS, is_prim};
+use crate::compiler::{is_uvar, mark_rest};
 use Scheme::*;
 
 #[derive(Debug, Clone)]
@@ -13,9 +14,49 @@ pub struct Token {
     pub col: usize,
 }
 
+// The reader turns the source into data, every datum keeps the token it starts with for locations.
+#[derive(Debug, Clone)]
+pub enum Datum {
+    // a symbol, a number, #t or #f
+    Atom (Token),
+    // a list, the token is its open paren, a dotted list keeps its tail
+    List (Token, Vec<Datum>, Option<Box<Datum>>),
+    // #(...) or #n(...), the token is "#(" or "#n("
+    Vector (Token, Vec<Datum>),
+    // 'datum
+    Quote (Token, Box<Datum>),
+}
+
+impl Datum {
+    pub fn token(&self) -> &Token {
+        match self {
+            Datum::Atom (t) | Datum::List (t, _, _) | Datum::Vector (t, _) | Datum::Quote (t, _) => t,
+        }
+    }
 
-pub struct Scanner {
+    pub fn symbol(&self) -> Option<&str> {
+        match self {
+            Datum::Atom (t) if !is_number(&t.token) && !t.token.starts_with('#') => Some(t.token.as_str()),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_number(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some('0'..='9') => true,
+        Some('-') => chars.next().is_some(),
+        _ => false,
+    }
+}
+
+
+pub struct Reader {
     expr: Vec<char>,
+    i: usize,
+    line: usize,
+    col: usize,
 }
 
 
@@ -24,97 +65,213 @@ fn is_delimiter(c: char) -> bool {
     match delimiter.find(c) {
         Some(_i) => true,
         None => false,
-    } 
+    }
 }
 
 
 fn is_sym_terminal(c: char) -> bool {
-    c == ';' || c == ' ' || c == '\n' || is_delimiter(c)
+    c == ';' || c == '"' || c == '\'' || c.is_whitespace() || is_delimiter(c)
+}
+
+
+fn close_paren(left: &str) -> char {
+    match left {
+        "[" => ']',
+        "{" => '}',
+        _ => ')',
+    }
 }
 
 
-impl Scanner {
+impl Reader {
     pub fn new(expr: &str) -> Self {
         let expr: Vec<char> = expr.chars().collect();
-        Self { expr }
+        Self { expr, i: 0, line: 1, col: 1 }
+    }
+
+    // all data in the source, or the first error as "line L, col C: msg"
+    pub fn read_all(mut self) -> Result<Vec<Datum>, String> {
+        let mut data = vec![];
+        loop {
+            self.skip_atmosphere()?;
+            match self.peek(0) {
+                None => return Ok(data),
+                Some(c) if c == ')' || c == ']' || c == '}' => return Err(self.error(self.line, self.col, "unexpected close paren")),
+                Some(_) => data.push(self.read()?),
+            }
+        }
     }
 
-    pub fn scan(self) -> Vec<Token> {
-        let mut res = vec![];
-        let mut i = 0;
-        let mut line = 1;
-        let mut col = 1;
-        while i < self.expr.len() {
-            i = self.scan_expr(i, &mut line, &mut col, &mut res);
-        }
-        return res
+    fn error(&self, line: usize, col: usize, msg: &str) -> String {
+        format!("line {}, col {}: {}", line, col, msg)
     }
 
-    pub fn scan_expr(&self, mut i: usize, line: &mut usize, col: &mut usize, tokens: &mut Vec<Token>) -> usize {
-        let c = self.expr[i];
+    fn peek(&self, k: usize) -> Option<char> {
+        self.expr.get(self.i + k).copied()
+    }
+
+    // a "\r\n" is a single newline, a tab is a single column
+    fn advance(&mut self) -> char {
+        let c = self.expr[self.i];
+        self.i += 1;
         match c {
-            cc if is_delimiter(cc) => {
-                let tok = Token { token: format!("{}", cc), i, line: *line, col: *col };
-                tokens.push(tok);
-                *col = *col + 1;
-                i + 1
+            '\r' if self.peek(0) == Some('\n') => (),
+            '\n' | '\r' => {
+                self.line += 1;
+                self.col = 1;
             }
-            ' ' => {
-                *col += 1;
-                i + 1
+            _ => self.col += 1,
+        }
+        return c;
+    }
+
+    fn token(&self, token: String, i: usize, line: usize, col: usize) -> Token {
+        Token { token, i, line, col }
+    }
+
+    // whitespace, ; line comments, nested #| |# block comments and #; datum comments
+    fn skip_atmosphere(&mut self) -> Result<(), String> {
+        while let Some(c) = self.peek(0) {
+            match c {
+                c if c.is_whitespace() => { self.advance(); }
+                ';' => {
+                    while self.peek(0).map_or(false, |c| c != '\n' && c != '\r') {
+                        self.advance();
+                    }
+                }
+                '#' if self.peek(1) == Some('|') => {
+                    let (line, col) = (self.line, self.col);
+                    self.advance();
+                    self.advance();
+                    let mut depth = 1;
+                    while depth > 0 {
+                        match (self.peek(0), self.peek(1)) {
+                            (None, _) => return Err(self.error(line, col, "unexpected eof, the block comment is not closed")),
+                            (Some('|'), Some('#')) => { self.advance(); self.advance(); depth -= 1; }
+                            (Some('#'), Some('|')) => { self.advance(); self.advance(); depth += 1; }
+                            _ => { self.advance(); }
+                        }
+                    }
+                }
+                '#' if self.peek(1) == Some(';') => {
+                    let (line, col) = (self.line, self.col);
+                    self.advance();
+                    self.advance();
+                    self.skip_atmosphere()?;
+                    match self.peek(0) {
+                        None | Some(')') | Some(']') | Some('}') => return Err(self.error(line, col, "expect a datum after #;")),
+                        Some(_) => { self.read()?; }
+                    }
+                }
+                _ => return Ok(()),
             }
-            '\n' => {
-                *col = 0;
-                *line += 1;
-                i + 1
+        }
+        return Ok(());
+    }
+
+    // a datum starts at the current char, which is not atmosphere
+    fn read(&mut self) -> Result<Datum, String> {
+        let (i, line, col) = (self.i, self.line, self.col);
+        match self.peek(0).unwrap() {
+            c if c == '(' || c == '[' || c == '{' => {
+                self.advance();
+                let left = self.token(c.to_string(), i, line, col);
+                let (elements, tail) = self.read_elements(&left)?;
+                return Ok(Datum::List (left, elements, tail));
             }
-            ';' => {
-                i = i + 1;  // skip ;
-                while i < self.expr.len() && self.expr[i] != '\n' {
-                    i = i + 1;
+            '\'' => {
+                self.advance();
+                self.skip_atmosphere()?;
+                match self.peek(0) {
+                    None | Some(')') | Some(']') | Some('}') => Err(self.error(line, col, "expect a datum after '")),
+                    Some(_) => Ok(Datum::Quote (self.token("'".to_string(), i, line, col), Box::new(self.read()?))),
                 }
-                *col = 0;
-                *line += 1;
-                return i;
             }
-            '\'' | '#' => {
-                let tok = Token { token: format!("{}", c), i, line: *line, col: *col };
-                tokens.push(tok);
-                *col = *col + 1;
-                i = i + 1;
-                assert!(self.expr[i] != ' ', "Invalid quote/hash in line {}, col {}", line, col);       // make sure no space following quote
-                return i;
+            '#' => self.read_hash(),
+            '"' => Err(self.error(line, col, "strings are not supported")),
+            _ => {
+                let atom = self.read_atom();
+                if atom.as_str() == "." {
+                    return Err(self.error(line, col, "unexpected ."));
+                }
+                return Ok(Datum::Atom (self.token(atom, i, line, col)));
             }
-            e => self.scan_sym(i, line, col, tokens),
         }
     }
 
-    fn scan_atom<F>(&self, i: usize, line: &mut usize, col: &mut usize, tokens: &mut Vec<Token>, mut terminal: F) -> usize 
-        where F: FnMut (char) -> bool  {
-        let mut sym = String::new();
-        let mut j = i;
-        while j < self.expr.len() && !terminal(self.expr[j]) {
-            sym.push(self.expr[j]);
-            j = j + 1;
+    // elements after the open paren, up to and including the matching close paren
+    fn read_elements(&mut self, left: &Token) -> Result<(Vec<Datum>, Option<Box<Datum>>), String> {
+        let close = close_paren(&left.token);
+        let mut elements = vec![];
+        let mut tail = None;
+        loop {
+            self.skip_atmosphere()?;
+            match self.peek(0) {
+                None => return Err(self.error(left.line, left.col, "unexpected eof, the list is not closed")),
+                Some(c) if c == ')' || c == ']' || c == '}' => {
+                    if c != close {
+                        let msg = format!("{} does not match {} at line {}, col {}", c, left.token, left.line, left.col);
+                        return Err(self.error(self.line, self.col, &msg));
+                    }
+                    self.advance();
+                    return Ok((elements, tail));
+                }
+                Some('.') if tail.is_none() && self.peek(1).map_or(true, is_sym_terminal) && left.token.len() == 1 => {
+                    let (line, col) = (self.line, self.col);
+                    self.advance();
+                    self.skip_atmosphere()?;
+                    match self.peek(0) {
+                        Some(c) if elements.len() > 0 && c != ')' && c != ']' && c != '}' => tail = Some(Box::new(self.read()?)),
+                        _ => return Err(self.error(line, col, "unexpected .")),
+                    }
+                }
+                Some(_) if tail.is_some() => return Err(self.error(self.line, self.col, "expect a close paren after the tail of a dotted list")),
+                Some(_) => elements.push(self.read()?),
+            }
         }
-        let tok = Token {token: sym, i, line: *line, col: *col};
-        *col += tok.token.len();
-        tokens.push(tok);
-        return j;
     }
 
-    fn scan_sym(&self, i: usize, line: &mut usize, col: &mut usize, tokens: &mut Vec<Token>) -> usize {
-        return self.scan_atom(i, line, col, tokens, is_sym_terminal);
+    // #t, #f, #true, #false, #(...) and #n(...)
+    fn read_hash(&mut self) -> Result<Datum, String> {
+        let (i, line, col) = (self.i, self.line, self.col);
+        self.advance();
+        let mut len = String::new();
+        while self.peek(0).map_or(false, |c| c.is_ascii_digit()) {
+            len.push(self.advance());
+        }
+        if self.peek(0) == Some('(') {
+            self.advance();
+            let left = self.token(format!("#{}(", len), i, line, col);
+            let (elements, _tail) = self.read_elements(&left)?;
+            return Ok(Datum::Vector (left, elements));
+        }
+        if len.is_empty() {
+            let atom = self.read_atom();
+            match atom.as_str() {
+                "t" | "true" => return Ok(Datum::Atom (self.token("#t".to_string(), i, line, col))),
+                "f" | "false" => return Ok(Datum::Atom (self.token("#f".to_string(), i, line, col))),
+                _ => (),
+            }
+        }
+        return Err(self.error(line, col, "invalid # literal"));
+    }
+
+    fn read_atom(&mut self) -> String {
+        let mut sym = String::new();
+        while self.peek(0).map_or(false, |c| !is_sym_terminal(c)) {
+            sym.push(self.advance());
+        }
+        return sym;
     }
 }
 
 
 
 
-use Scheme::*;
 pub struct Parser {
-    tokens: IntoIter<Token>,
-    top: Option<Token>,
+    temps: usize,
+    // variables in scope, a keyword bound by the program is a variable
+    bound: Vec<String>,
 }
 
 fn verify_symbol(sym: &str) -> bool {
@@ -127,401 +284,723 @@ fn verify_symbol(sym: &str) -> bool {
     return true;
 }
 
-fn is_pair(left: &str, right: &str) -> bool {
-    (left == "(" && right == ")") || 
-    (left == "[" && right == "]") || 
-    (left == "{" && right == "}")
+// reads a program, a reader error is a panic
+pub fn read(expr: &str) -> Vec<Datum> {
+    match Reader::new(expr).read_all() {
+        Ok(data) => data,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+// a record type as written in define-record-type, each field is its name, its accessor
+// and maybe its modifier
+struct RecordType {
+    name: String,
+    ctor: String,
+    ctor_fields: Vec<String>,
+    pred: String,
+    fields: Vec<Vec<String>>,
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        let mut tokens = tokens.into_iter();
-        let top = tokens.next();
-        Self { tokens, top }
+    pub fn new() -> Self {
+        Self { temps: 0, bound: vec![] }
+    }
+
+    pub fn parse(mut self, datum: &Datum) -> Scheme {
+        self.parse_expr(datum)
+    }
+
+    // a sequence of (define (f . formals) body ...), (define x e) or (define-record-type ...), as in
+    // the prelude. A record type defines its name, bound to the descriptor, and its procedures.
+    pub fn parse_definitions(mut self, data: &[Datum]) -> Vec<(String, Scheme)> {
+        let mut definitions = vec![];
+        for d in data {
+            let left = d.token();
+            let elements = self.code_list(d);
+            if elements.first().and_then(|h| h.symbol()) == Some("define-record-type") {
+                let record = self.parse_record_type(left, &elements[1..]);
+                let (descriptor, procs) = self.record_definitions(&record, &record.name);
+                definitions.push((record.name.clone(), descriptor));
+                definitions.extend(procs);
+                continue;
+            }
+            assert!(elements.len() >= 3 && elements[0].symbol() == Some("define"), "Expect a definition at line {}, col {}", left.line, left.col);
+            match &elements[1] {
+                Datum::List (formals_left, formals, rest) => {
+                    let name = self.variable(&formals[0]);
+                    let args = self.parse_formal_list(formals_left, &formals[1..], rest);
+                    definitions.push((name, self.parse_lambda_body(args, &elements[2..])));
+                }
+                var => {
+                    let name = self.variable(var);
+                    definitions.push((name, self.parse_expr(&elements[2])));
+                }
+            }
+        }
+        return definitions;
     }
 
-    pub fn parse(mut self) -> Scheme {
-        self.parse_expr()
+    pub fn parse_expr(&mut self, datum: &Datum) -> Scheme {
+        match datum {
+            Datum::List (t, elements, None) => self.parse_list(t, elements),
+            Datum::List (t, _, Some(_)) => panic!("Invalid dotted list at line {}, col {}", t.line, t.col),
+            Datum::Quote (_, d) => self.parse_quote(d),
+            Datum::Vector (..) => self.parse_literal(datum),
+            Datum::Atom (t) => self.parse_atom(t),
+        }
     }
 
-    pub fn parse_expr(&mut self) -> Scheme {
-        if let Some(ref t) = self.top() {
-            if t.token.as_str() == "(" || t.token.as_str() == "[" {
-                return self.parse_list();
-            }
-            return self.parse_atom();
+    // the elements of a list which is code
+    fn code_list<'a>(&self, datum: &'a Datum) -> &'a [Datum] {
+        match datum {
+            Datum::List (_, elements, None) => elements,
+            other => panic!("Expect a list at line {}, col {}", other.token().line, other.token().col),
         }
-        panic!("Unexpected Eof");
     }
 
-    fn parse_list(&mut self) -> Scheme {
-        let _left = self.remove_top();
-        let top = self.top().unwrap().token.as_str();
-        match top {
-            "letrec" => self.parse_letrec(),
-            "lambda" => self.parse_lambda(),
-            "begin" => self.parse_begin(),
-            "set!" => self.parse_set(),
-            "if" => self.parse_if(),
-            "let" => self.parse_let(),
+    fn variable(&self, datum: &Datum) -> String {
+        match datum.symbol() {
+            Some(s) if verify_symbol(s) => s.to_string(),
+            _ => panic!("Expect a variable at line {}, col {}", datum.token().line, datum.token().col),
+        }
+    }
+
+    fn parse_list(&mut self, left: &Token, elements: &[Datum]) -> Scheme {
+        if elements.is_empty() {
+            return self.parse_empty_list();
+        }
+        let head = match elements[0].symbol() {
+            Some(s) if !self.bound.iter().any(|v| v.as_str() == s) => s,
+            _ => return self.parse_funcall(elements),
+        };
+        let operands = &elements[1..];
+        match head {
+            "letrec" => self.parse_letrec(left, operands),
+            "lambda" => self.parse_lambda(left, operands),
+            "begin" => self.parse_begin(left, operands),
+            "set!" => self.parse_set(left, operands),
+            "if" => self.parse_if(left, operands),
+            "let" => self.parse_let(left, operands),
+            "let-values" => self.parse_let_values(left, operands),
+            "define-record-type" => panic!("define-record-type is only allowed in a body at line {}, col {}", left.line, left.col),
             "car" | "cdr" | "make-vector" | "vector-length" | "procedure?" |
-            "boolean?" | "fixnum?" | "null?" | "pair?" | "vector?" | "not"
-                => self.parse_prim1(),
+            "boolean?" | "fixnum?" | "null?" | "pair?" | "vector?" | "not" |
+            "call/cc" | "call-with-current-continuation" | "raise" | "raise-continuable" |
+            "box" | "unbox" | "box?"
+                => self.parse_prim1(left, head, operands),
             "+" | "-" | "*" | "logor" | "logand" | "sra" |
             "=" | ">" | "<" | ">=" | "<=" | "eq?" |
-            "cons" | "vector-ref" | "set-car!" | "set-cdr!"
-                => self.parse_prim2(),
-            "vector-set!" => self.parse_prim3(),
-            "and" | "or" => self.parse_primn(),
-            "nop" => self.parse_nop(),
-            "void" => self.parse_void(),
-            "true" | "false" => self.parse_bool(),
-            ")" => self.parse_empty_list(),
-            s_expr => self.parse_funcall(),
-        }
-    }
-
-    fn parse_letrec(&mut self) -> Scheme {
-        let _letrec = self.remove_top();
-        let _lambda_left = self.remove_top();
-        let mut bindings = HashMap::new();
-        while let Some(ref t) = self.top() {
-            if t.token.as_str() != ")" {
-                let (k, lambda) = self.parse_binding();
-                bindings.insert(k, lambda);
-            } else {
-                let _lambda_right = self.remove_top();
-                let mut exprs = vec![];
-                while let Some(ref t) = self.top() {
-                    if t.token.as_str() != ")" {
-                        exprs.push(self.parse_expr());
-                    } else {
-                        let _letrec_right = self.remove_top();
-                        return Scheme::Letrec (bindings, Box::new(Begin (exprs)));
-                    }
-                } 
-                panic!("Parse letrec, unexpected eof");
-            }
+            "cons" | "vector-ref" | "set-car!" | "set-cdr!" | "set-box!" | "with-exception-handler" |
+            "call-with-values"
+                => self.parse_prim2(left, head, operands),
+            "vector-set!" => self.parse_prim3(left, head, operands),
+            "and" | "or" | "apply" | "error" | "values" => self.parse_primn(head, operands),
+            "nop" => Scheme::Nop,
+            "void" => Scheme::Void,
+            "true" => Scheme::Bool(true),
+            "false" => Scheme::Bool(false),
+            _ => self.parse_funcall(elements),
         }
-        panic!("Parse letrec, unexpected eof");
     }
 
-    fn parse_lambda(&mut self) -> Scheme {
-        let _lambda = self.remove_top().unwrap();
-        let _args_left = self.remove_top();
-        let mut args = vec![];
-        while let Some(ref t) = self.top() {
-            if t.token.as_str() != ")" {
-                let arg = self.remove_top().unwrap().token;
-                args.push(arg);
-            } else {
-                let _args_right = self.remove_top();
-                // implictly begin
-                let mut exprs = vec![];
-                while let Some(ref t) = self.top() {
-                    if t.token.as_str() != ")" {
-                        exprs.push(self.parse_expr());
-                    } else {
-                        let _right = self.remove_top();
-                        return Scheme::Lambda(args, Box::new(Begin (exprs)));
-                    }
-                } 
-                panic!("Parse Lambda, unexpected eof");
-            }
-        } 
-        panic!("Parse Lambda, unexpected eof");
+    // ([x e] ...)
+    fn parse_bindings(&mut self, left: &Token, form: &str, operands: &[Datum]) -> Vec<(String, Datum)> {
+        assert!(operands.len() >= 2, "Invalid {} at line {}, col {}", form, left.line, left.col);
+        self.code_list(&operands[0]).iter().map(|b| {
+            let pair = self.code_list(b);
+            assert!(pair.len() == 2, "Invalid binding in {} at line {}, col {}", form, b.token().line, b.token().col);
+            (self.variable(&pair[0]), pair[1].clone())
+        }).collect()
     }
 
+    fn parse_letrec(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        let scope = self.bound.len();
+        let bindings = self.parse_bindings(left, "letrec", operands);
+        self.bound.extend(bindings.iter().map(|(k, _)| k.clone()));
+        let bindings = bindings.iter().map(|(k, v)| (k.clone(), self.parse_expr(v))).collect();
+        let body = self.parse_body(&operands[1..]);
+        self.bound.truncate(scope);
+        return Scheme::Letrec (bindings, Box::new(Begin (body)));
+    }
 
-    fn parse_begin(&mut self) -> Scheme {
-        let _begin = self.remove_top();
-        let mut begin_exprs = vec![];
-        while let Some(ref t) = self.top() {
-            if t.token.as_str() != ")" {
-                let expr = self.parse_expr();
-                begin_exprs.push(expr);
-            } else {
-                assert!(begin_exprs.len() > 0, "begin expr is empty!");
-                let _right = self.remove_top();
-                return Scheme::Begin(begin_exprs);
-            }
-        } 
-        panic!("Parse Begin, unexpected eof");
+    // a rest argument is kept behind a "." in args, so (lambda args e) becomes
+    // Lambda([".", "args"], e) and (lambda (a . rest) e) becomes Lambda(["a", ".", "rest"], e)
+    fn parse_lambda(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() >= 2, "Invalid lambda at line {}, col {}", left.line, left.col);
+        let args = self.parse_formals(&operands[0]);
+        return self.parse_lambda_body(args, &operands[1..]);
     }
 
-    fn parse_empty_list(&mut self) -> Scheme {
-        let _right = self.remove_top();
-        return Quote (Box::new(EmptyList));
+    // formals are a list of symbols, a dotted list, or a single symbol taking all arguments
+    fn parse_formals(&mut self, formals: &Datum) -> Vec<String> {
+        match formals {
+            Datum::List (left, args, rest) => self.parse_formal_list(left, args, rest),
+            var => vec![".".to_string(), self.variable(var)],
+        }
     }
 
-    fn parse_if(&mut self) -> Scheme {
-        let _if = self.remove_top();
-        let cond = self.parse_expr();
-        let b1 = self.parse_expr();
-        let b2 = if self.top().unwrap().token.as_str() == ")" {
-            Void
-        } else { self.parse_expr() };
-        let _right = self.remove_top();
-        return Scheme::If(Box::new(cond), Box::new(b1), Box::new(b2));
+    // formals in a list, in (define (f . formals) ...) there may be only a rest argument
+    fn parse_formal_list(&mut self, left: &Token, args: &[Datum], rest: &Option<Box<Datum>>) -> Vec<String> {
+        let mut formals: Vec<String> = args.iter().map(|a| self.variable(a)).collect();
+        if let Some(rest) = rest {
+            formals.push(".".to_string());
+            formals.push(self.variable(rest));
+        }
+        let names: HashSet<&String> = formals.iter().collect();
+        assert!(names.len() == formals.len(), "Duplicate formals at line {}, col {}", left.line, left.col);
+        return formals;
     }
-    
-    fn parse_let(&mut self) -> Scheme {
-        let _let = self.remove_top();
-        let _binding_left = self.remove_top();
-        let mut bindings = HashMap::new();
-        while let Some(ref t) = self.top() {
-            if t.token.as_str() != ")" {
-                let (var, val) = self.parse_binding();
-                bindings.insert(var, val);
-            } else {
-                let _binding_right = self.remove_top();
-                let mut exprs = vec![];
-                while let Some(ref t) = self.top() {
-                    if t.token.as_str() != ")" {
-                        exprs.push(self.parse_expr());
-                    } else {
-                        let _right = self.remove_top();
-                        return Scheme::Let (bindings, Box::new(Begin (exprs)));
-                    }
-                } 
-                panic!("Parse let, unexpected eof");
+
+    fn parse_lambda_body(&mut self, args: Vec<String>, body: &[Datum]) -> Scheme {
+        let scope = self.bound.len();
+        self.bound.extend(args.iter().filter(|a| a.as_str() != ".").cloned());
+        // implictly begin
+        let exprs = self.parse_body(body);
+        self.bound.truncate(scope);
+        return Scheme::Lambda(args, Box::new(Begin (exprs)));
+    }
+
+    // a body is a sequence of expressions, a record definition scopes over the rest of it
+    fn parse_body(&mut self, body: &[Datum]) -> Vec<Scheme> {
+        let mut exprs = vec![];
+        for (i, e) in body.iter().enumerate() {
+            if let Datum::List (left, elements, None) = e {
+                if elements.first().and_then(|h| h.symbol()) == Some("define-record-type")
+                    && !self.bound.iter().any(|v| v.as_str() == "define-record-type") {
+                    exprs.push(self.parse_define_record_type(left, &elements[1..], &body[i+1..]));
+                    return exprs;
+                }
             }
+            exprs.push(self.parse_expr(e));
         }
-        panic!("Parse let, unexpected eof");
+        return exprs;
     }
 
-    fn parse_binding(&mut self) -> (String, Scheme) {
-        let _left = self.remove_top();
-        let var = self.remove_top().unwrap().token;
-        let val = self.parse_expr();
-        let _right = self.remove_top();
-        return (var, val);
+    fn parse_begin(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() > 0, "begin expr is empty at line {}, col {}", left.line, left.col);
+        return Scheme::Begin(self.parse_body(operands));
     }
 
+    fn parse_empty_list(&mut self) -> Scheme {
+        return Quote (Box::new(EmptyList));
+    }
 
+    fn parse_if(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() == 2 || operands.len() == 3, "Invalid if at line {}, col {}", left.line, left.col);
+        let cond = self.parse_expr(&operands[0]);
+        let b1 = self.parse_expr(&operands[1]);
+        let b2 = if operands.len() == 2 { Void } else { self.parse_expr(&operands[2]) };
+        return Scheme::If(Box::new(cond), Box::new(b1), Box::new(b2));
+    }
 
-    fn parse_funcall(&mut self) -> Scheme {
-        let func = self.parse_expr();
-        let mut args = vec![];
-        while let Some(ref t) = self.top() {
-            if t.token.as_str() != ")" {
-                let expr = self.parse_expr();
-                args.push(expr);
-            } else {
-                let _right = self.remove_top();
-                return Scheme::Funcall (Box::new(func), args);
+    fn parse_let(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        let bindings = self.parse_bindings(left, "let", operands);
+        let bindings: HashMap<String, Scheme> = bindings.iter().map(|(k, v)| (k.clone(), self.parse_expr(v))).collect();
+        let scope = self.bound.len();
+        self.bound.extend(bindings.keys().cloned());
+        let body = self.parse_body(&operands[1..]);
+        self.bound.truncate(scope);
+        return Scheme::Let (bindings, Box::new(Begin (body)));
+    }
+
+    // Temporary names contain ':', which no symbol in a program may contain.
+    fn gen_temp(&mut self, prefix: &str) -> String {
+        let tmp = format!("{}:{}", prefix, self.temps);
+        self.temps += 1;
+        return tmp;
+    }
+
+    // (let-values ([(a b) e1] [c e2]) body) =>
+    //   (let ([t0 (lambda () e1)] [t1 (lambda () e2)])
+    //     (call-with-values t0 (lambda (a b) (call-with-values t1 (lambda c body)))))
+    // the producers are bound first, so that they don't see the other formals.
+    fn parse_let_values(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() >= 2, "Invalid let-values at line {}, col {}", left.line, left.col);
+        let mut bindings = vec![];
+        for b in self.code_list(&operands[0]) {
+            let pair = self.code_list(b);
+            assert!(pair.len() == 2, "Invalid binding in let-values at line {}, col {}", b.token().line, b.token().col);
+            let formals = self.parse_formals(&pair[0]);
+            let producer = self.parse_expr(&pair[1]);
+            bindings.push((formals, producer));
+        }
+        let scope = self.bound.len();
+        for (formals, _) in &bindings {
+            self.bound.extend(formals.iter().filter(|a| a.as_str() != ".").cloned());
+        }
+        let mut body = Begin (self.parse_body(&operands[1..]));
+        self.bound.truncate(scope);
+        if bindings.len() == 1 {
+            let (formals, producer) = bindings.pop().unwrap();
+            let thunk = Lambda (vec![], Box::new(producer));
+            return Prim2 ("call-with-values".to_string(), Box::new(thunk), Box::new(Lambda (formals, Box::new(body))));
+        }
+        let mut thunks = HashMap::default();
+        while let Some((formals, producer)) = bindings.pop() {
+            let tmp = self.gen_temp("let-values");
+            thunks.insert(tmp.clone(), Lambda (vec![], Box::new(producer)));
+            body = Prim2 ("call-with-values".to_string(), Box::new(Symbol (tmp)), Box::new(Lambda (formals, Box::new(body))));
+        }
+        return Scheme::Let (thunks, Box::new(body));
+    }
+
+    // (define-record-type point (make-point x y) point? (x point-x set-point-x!) (y point-y))
+    // is a definition in a body, and it scopes over the rest of the body.
+    fn parse_define_record_type(&mut self, define: &Token, operands: &[Datum], rest: &[Datum]) -> Scheme {
+        let record = self.parse_record_type(define, operands);
+        // the rest of the enclosing body
+        let scope = self.bound.len();
+        self.bound.extend(vec![record.name.clone(), record.ctor.clone(), record.pred.clone()]);
+        for spec in &record.fields {
+            self.bound.extend(spec[1..].iter().cloned());
+        }
+        let exprs = self.parse_body(rest);
+        self.bound.truncate(scope);
+        assert!(exprs.len() > 0, "No expression after record {} at line {}, col {}", record.name, define.line, define.col);
+
+        let rtd = self.gen_temp("record");
+        let (descriptor, procs) = self.record_definitions(&record, &rtd);
+        let mut procs: HashMap<String, Scheme> = procs.into_iter().collect();
+        procs.insert(record.name.clone(), Symbol (rtd.clone()));
+        let inner = Scheme::Let (procs, Box::new(Begin (exprs)));
+        return Scheme::Let (vec![(rtd, descriptor)].into_iter().collect(), Box::new(inner));
+    }
+
+    fn parse_record_type(&self, define: &Token, operands: &[Datum]) -> RecordType {
+        assert!(operands.len() >= 3, "Invalid define-record-type at line {}, col {}", define.line, define.col);
+        let name = self.variable(&operands[0]);
+        let ctor_spec = self.code_list(&operands[1]);
+        assert!(ctor_spec.len() > 0, "Invalid constructor of record {} at line {}, col {}", name, define.line, define.col);
+        let ctor = self.variable(&ctor_spec[0]);
+        let ctor_fields: Vec<String> = ctor_spec[1..].iter().map(|f| self.variable(f)).collect();
+        let pred = self.variable(&operands[2]);
+        let mut fields = vec![];
+        for f in &operands[3..] {
+            let spec: Vec<String> = self.code_list(f).iter().map(|s| self.variable(s)).collect();
+            assert!(spec.len() == 2 || spec.len() == 3, "Invalid field of record {} at line {}, col {}", name, define.line, define.col);
+            fields.push(spec);
+        }
+        for f in &ctor_fields {
+            assert!(fields.iter().any(|spec| &spec[0] == f), "Unknown field {} of record {} at line {}, col {}", f, name, define.line, define.col);
+        }
+        RecordType { name, ctor, ctor_fields, pred, fields }
+    }
+
+    // the descriptor of a record type and its procedures, which refer to the descriptor by rtd.
+    // The record-type descriptor is a vector holding the number of fields and the name in bytes.
+    // A record points to its descriptor in its header.
+    fn record_definitions(&mut self, record: &RecordType, rtd: &str) -> (Scheme, Vec<(String, Scheme)>) {
+        let quote_int = |i: i64| Quote (Box::new(Int64 (i)));
+        let fields = &record.fields;
+        let tmp = self.gen_temp("record");
+        let bytes = record.name.as_bytes();
+        let mut init = vec![Prim3 ("vector-set!".to_string(), Box::new(Symbol (tmp.clone())), Box::new(quote_int(0)), Box::new(quote_int(fields.len() as i64)))];
+        for (i, b) in bytes.iter().enumerate() {
+            init.push(Prim3 ("vector-set!".to_string(), Box::new(Symbol (tmp.clone())), Box::new(quote_int(i as i64 + 1)), Box::new(quote_int(*b as i64))));
+        }
+        init.push(Symbol (tmp.clone()));
+        let descriptor = Scheme::Let (
+            vec![(tmp, Prim1 ("make-vector".to_string(), Box::new(quote_int(bytes.len() as i64 + 1))))].into_iter().collect(),
+            Box::new(Begin (init)));
+
+        let mut procs = vec![];
+        // the constructor, fields not given are void. Its arguments are temporaries, 
+        // a field may be named like rtd
+        let obj = self.gen_temp("record");
+        let args: Vec<String> = record.ctor_fields.iter().map(|_| self.gen_temp("record")).collect();
+        let mut body = vec![];
+        for (i, spec) in fields.iter().enumerate() {
+            let val = match record.ctor_fields.iter().position(|f| f == &spec[0]) {
+                Some (j) => Symbol (args[j].clone()),
+                None => Void,
+            };
+            body.push(Prim3 ("record-set!".to_string(), Box::new(Symbol (obj.clone())), Box::new(quote_int(i as i64)), Box::new(val)));
+        }
+        body.push(Symbol (obj.clone()));
+        let alloc = Prim2 ("make-record".to_string(), Box::new(Symbol (rtd.to_string())), Box::new(quote_int(fields.len() as i64)));
+        let ctor_body = Scheme::Let (vec![(obj, alloc)].into_iter().collect(), Box::new(Begin (body)));
+        procs.push((record.ctor.clone(), Lambda (args, Box::new(ctor_body))));
+        // the predicate
+        let x = self.gen_temp("record");
+        let test = Prim2 ("record-of?".to_string(), Box::new(Symbol (x.clone())), Box::new(Symbol (rtd.to_string())));
+        procs.push((record.pred.clone(), Lambda (vec![x], Box::new(test))));
+        // accessors and modifiers
+        for (i, spec) in fields.iter().enumerate() {
+            let r = self.gen_temp("record");
+            let get = Prim2 ("record-ref".to_string(), Box::new(Symbol (r.clone())), Box::new(quote_int(i as i64)));
+            procs.push((spec[1].clone(), Lambda (vec![r], Box::new(get))));
+            if spec.len() == 3 {
+                let r = self.gen_temp("record");
+                let v = self.gen_temp("record");
+                let set = Prim3 ("record-set!".to_string(), Box::new(Symbol (r.clone())), Box::new(quote_int(i as i64)), Box::new(Symbol (v.clone())));
+                procs.push((spec[2].clone(), Lambda (vec![r, v], Box::new(set))));
             }
-        } 
-        panic!("Parse Funcall, unexpected eof");
+        }
+        (descriptor, procs)
+    }
+
+    fn parse_funcall(&mut self, elements: &[Datum]) -> Scheme {
+        let func = self.parse_expr(&elements[0]);
+        let args = elements[1..].iter().map(|e| self.parse_expr(e)).collect();
+        return Scheme::Funcall (Box::new(func), args);
     }
 
-    fn parse_set(&mut self) -> Scheme {
-        let _set = self.remove_top();
-        let e1 = self.parse_expr();
-        let e2 = self.parse_expr();
-        let _right = self.remove_top();
+    fn parse_set(&mut self, left: &Token, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() == 2, "Invalid set! at line {}, col {}", left.line, left.col);
+        let e1 = self.parse_expr(&operands[0]);
+        let e2 = self.parse_expr(&operands[1]);
         Scheme::Set(Box::new(e1), Box::new(e2))
     }
 
-    fn parse_prim1(&mut self) -> Scheme {
-        let op = self.remove_top();
-        let e1 = self.parse_expr();
-        let _right = self.remove_top();
-        Scheme::Prim1(op.unwrap().token, Box::new(e1))
+    fn parse_prim1(&mut self, left: &Token, op: &str, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() == 1, "{} expects 1 operand at line {}, col {}", op, left.line, left.col);
+        let e1 = self.parse_expr(&operands[0]);
+        Scheme::Prim1(op.to_string(), Box::new(e1))
     }
 
 
-    fn parse_prim2(&mut self) -> Scheme {
-        let op = self.remove_top();
-        let e1 = self.parse_expr();
-        let e2 = self.parse_expr();
-        let _right = self.remove_top();
-        Scheme::Prim2(op.unwrap().token, Box::new(e1), Box::new(e2))
+    fn parse_prim2(&mut self, left: &Token, op: &str, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() == 2, "{} expects 2 operands at line {}, col {}", op, left.line, left.col);
+        let e1 = self.parse_expr(&operands[0]);
+        let e2 = self.parse_expr(&operands[1]);
+        Scheme::Prim2(op.to_string(), Box::new(e1), Box::new(e2))
     }
 
-    fn parse_prim3(&mut self) -> Scheme {
-        let op = self.remove_top();
-        let e1 = self.parse_expr();
-        let e2 = self.parse_expr();
-        let e3 = self.parse_expr();
-        let _right = self.remove_top();
-        Scheme::Prim3(op.unwrap().token, Box::new(e1), Box::new(e2), Box::new(e3))
+    fn parse_prim3(&mut self, left: &Token, op: &str, operands: &[Datum]) -> Scheme {
+        assert!(operands.len() == 3, "{} expects 3 operands at line {}, col {}", op, left.line, left.col);
+        let e1 = self.parse_expr(&operands[0]);
+        let e2 = self.parse_expr(&operands[1]);
+        let e3 = self.parse_expr(&operands[2]);
+        Scheme::Prim3(op.to_string(), Box::new(e1), Box::new(e2), Box::new(e3))
     }
 
-    fn parse_primn(&mut self) -> Scheme {
-        let op = self.remove_top().unwrap().token;
-        let mut exprs = vec![];
-        while let Some(ref t) = self.top() {
-            if t.token.as_str() != ")" {
-                let expr = self.parse_expr();
-                exprs.push(expr);
-            } else {
-                let _right = self.remove_top();
-                return Scheme::PrimN (op, exprs);
-            }
-        } 
-        panic!("Parse Funcall, unexpected eof");
+    fn parse_primn(&mut self, op: &str, operands: &[Datum]) -> Scheme {
+        let exprs = operands.iter().map(|e| self.parse_expr(e)).collect();
+        return Scheme::PrimN (op.to_string(), exprs);
     }
 
-    fn parse_atom(&mut self) -> Scheme {
-        let token = &self.top().unwrap().token;
-        let chars: Vec<char> = token.chars().collect();
-        match chars[0] {
-            '\'' => self.parse_quote(),
-            '0' ..= '9' => Quote (Box::new(self.parse_integer())),
-            '-' => Quote (Box::new(self.parse_integer())),
-            '#' => self.parse_literal(),
-            e => self.parse_symbol(),
+    fn parse_atom(&mut self, t: &Token) -> Scheme {
+        match t.token.as_str() {
+            "#t" => Quote (Box::new(Bool (true))),
+            "#f" => Quote (Box::new(Bool (false))),
+            s if is_number(s) => Quote (Box::new(self.parse_integer(t))),
+            _ => self.parse_symbol(t),
         }
     }
 
-    fn parse_quote(&mut self) -> Scheme {
-        let _quote = self.remove_top();
-        let t = self.top().unwrap();
-        match t.token.as_str() {
-            "(" | "[" => self.parse_quote_list(),
-            "#" => self.parse_literal(),
-            other => self.parse_quote_atom(),
+    fn parse_quote(&mut self, datum: &Datum) -> Scheme {
+        match datum {
+            Datum::List (..) => self.parse_quote_list(datum),
+            Datum::Vector (..) => self.parse_literal(datum),
+            other => self.parse_quote_atom(other),
         }
     }
 
-    fn parse_quote_atom(&mut self) -> Scheme {
-        let atom = self.top().unwrap();
-        let chars: Vec<char> = atom.token.chars().collect();
-        match chars[0] {
-            '0' ..= '9' => Quote (Box::new(self.parse_integer())),
-            '-' => Quote (Box::new(self.parse_integer())),
-            other => panic!("Invalid literal {} at line {}, col {}", atom.token, atom.line, atom.col),
+    fn parse_quote_atom(&mut self, datum: &Datum) -> Scheme {
+        let atom = datum.token();
+        match atom.token.as_str() {
+            "#t" => Quote (Box::new(Bool (true))),
+            "#f" => Quote (Box::new(Bool (false))),
+            s if is_number(s) => Quote (Box::new(self.parse_integer(atom))),
+            other => panic!("Invalid literal {} at line {}, col {}", other, atom.line, atom.col),
         }
     }
-    
-    // right now, we have empty list literal only
-    fn parse_quote_list(&mut self) -> Scheme {
-        let _left = self.remove_top().unwrap();
-        if self.top().unwrap().token.as_str() == ")" { 
-            let _right = self.remove_top();
-            return Quote (Box::new(EmptyList)); 
-        }
 
-        let mut elements = vec![];
-        let mut period = false;
-        while let Some(ref t) = self.top() {
-            match t.token.as_str() {
-                ")" => break,
-                "(" => elements.push(self.parse_quote_list()),
-                "#" => elements.push(self.parse_literal()),
-                "." => { // it means ending is coming, leave of last expr.
-                    self.remove_top(); 
-                    period = true;
-                }
-                other => elements.push(self.parse_quote_atom()),
-            };
+    // right now, we have no symbol literal
+    fn parse_quote_list(&mut self, datum: &Datum) -> Scheme {
+        let (elements, tail) = match datum {
+            Datum::List (_, elements, _) if elements.is_empty() => return Quote (Box::new(EmptyList)),
+            Datum::List (_, elements, tail) => (elements, tail),
+            other => return self.parse_quote(other),
+        };
+        let mut elements: Vec<Scheme> = elements.iter().map(|e| self.parse_quote(e)).collect();
+        match tail {
+            Some(tail) => elements.push(self.parse_quote(tail)),
+            None => elements.push(Quote (Box::new(EmptyList))),
         }
-        if !period { elements.push(Quote (Box::new(EmptyList))); }
-        let _right = self.remove_top();
         return LiteralList (elements);
     }
 
-    fn parse_symbol(&mut self) -> Scheme {
-        let sym = self.remove_top().unwrap();
+    fn parse_symbol(&mut self, sym: &Token) -> Scheme {
         if verify_symbol(&sym.token.as_str()) {
-            return Scheme::Symbol(sym.token);
+            return Scheme::Symbol(sym.token.clone());
         }
         panic!("Invalid Symbol {} at line {} col {}", sym.token, sym.line, sym.col);
     }
 
-    fn parse_integer(&mut self) -> Scheme {
-        let num = self.remove_top().unwrap();
+    fn parse_integer(&mut self, num: &Token) -> Scheme {
         let temp = &num.token.parse();
         match temp {
             Ok(t) => Scheme::Int64(*t),
-            Err(e) => panic!("{} not a valid integer", num.token),
+            Err(_e) => panic!("{} not a valid integer at line {}, col {}", num.token, num.line, num.col),
         }
     }
 
-    fn parse_literal(&mut self) -> Scheme {
-        let _hash = self.remove_top();
-        let t = self.top().unwrap();
-        match t.token.parse::<usize>() {
-            Ok(_len) => self.parse_literal_vector(),
-            Err(e) => match t.token.as_str() {
-                "(" => self.parse_literal_vector(),
-                other => self.parse_literal_atom(),
+    // a vector literal, #n(...) is padded with void up to n elements
+    fn parse_literal(&mut self, datum: &Datum) -> Scheme {
+        let (left, elements) = match datum {
+            Datum::Vector (left, elements) => (left, elements),
+            other => return self.parse_quote(other),
+        };
+        let len = &left.token[1..left.token.len()-1];
+        let mut elements: Vec<Scheme> = elements.iter().map(|e| self.parse_quote(e)).collect();
+        if let Ok(set_len) = len.parse::<usize>() {
+            assert!(set_len >= elements.len(), "Too many elements in vector at line {}, col {}", left.line, left.col);
+            while elements.len() < set_len {
+                elements.push(Void);
             }
         }
+        return LiteralVector (elements);
     }
+}
 
-    fn parse_literal_atom(&mut self) -> Scheme {
-        let atom = self.remove_top().unwrap();
-        match atom.token.as_str() {
-            "t" => Quote (Box::new(Bool (true))),
-            "f" => Quote (Box::new(Bool (false))),
-            other => panic!("Invalid literal atom {} at line {}, col {}", other, atom.line, atom.col),
+
+// Reads back the intermediate languages as PrettyPrinter prints them, so a dump can be
+// edited and fed to a later pass. Unlike Parser, there is no scope, a head is a keyword,
+// a primitive or else a call.
+pub struct IlParser {}
+impl IlParser {
+    pub fn scheme(&self, datum: &Datum) -> Scheme {
+        let (t, items) = match datum {
+            Datum::Atom (t) if is_number(&t.token) => return Int64 (self.integer(t)),
+            Datum::Atom (t) => return Symbol (t.token.clone()),
+            Datum::Quote (..) | Datum::Vector (..) => return Parser::new().parse_expr(datum),
+            Datum::List (_, items, None) if items.is_empty() => return EmptyList,
+            Datum::List (t, items, None) => (t, items),
+            Datum::List (t, _, Some(_)) => panic!("Invalid dotted list at line {}, col {}", t.line, t.col),
+        };
+        let head = match items[0].symbol() {
+            Some(head) => head,
+            None => return Funcall (Box::new(self.scheme(&items[0])), self.schemes(&items[1..])),
+        };
+        let operands = &items[1..];
+        let arity = |n: usize| assert!(operands.len() == n, "{} expects {} operands at line {}, col {}", head, n, t.line, t.col);
+        let body = |n: usize| Box::new(self.scheme(&operands[n]));
+        match head {
+            "letrec" | "let" => {
+                arity(2);
+                let bindings = self.elements(&operands[0]).iter().map(|b| {
+                    let pair = self.elements(b);
+                    assert!(pair.len() == 2, "Invalid binding at line {}, col {}", b.token().line, b.token().col);
+                    (self.name(&pair[0]), self.scheme(&pair[1]))
+                }).collect();
+                if head == "let" { Let (bindings, body(1)) } else { Letrec (bindings, body(1)) }
+            }
+            "locals" => { arity(2); Locals (self.names(&operands[0]).into_iter().collect(), body(1)) }
+            "assigned" => { arity(2); Assigned (self.names(&operands[0]).into_iter().collect(), body(1)) }
+            "free" => { arity(2); Free (self.names(&operands[0]), body(1)) }
+            "bind-free" => { arity(2); Bindfree (self.names(&operands[0]), body(1)) }
+            "lambda" => {
+                arity(2);
+                Lambda (self.formals(&operands[0]), body(1))
+            }
+            "closures" => {
+                arity(2);
+                let clos = self.elements(&operands[0]).iter().map(|c| {
+                    let names = self.names(c);
+                    assert!(names.len() >= 2, "Invalid closure at line {}, col {}", c.token().line, c.token().col);
+                    (names[0].clone(), names[1].clone(), names[2..].to_vec())
+                }).collect();
+                Closures (clos, body(1))
+            }
+            "begin" => Begin (self.schemes(operands)),
+            "if" => { arity(3); If (body(0), body(1), body(2)) }
+            "set!" => { arity(2); Set (body(0), body(1)) }
+            "alloc" => { arity(1); Alloc (body(0)) }
+            "mref" => { arity(2); Mref (body(0), body(1)) }
+            "mset!" => { arity(3); Mset (body(0), body(1), body(2)) }
+            "void" => Void,
+            "nop" => Nop,
+            "true" => Bool (true),
+            "false" => Bool (false),
+            "funcall" => Funcall (body(0), self.schemes(&operands[1..])),
+            "prim1" | "prim2" | "prim3" | "primn" => self.prim(head, self.name(&operands[0]), self.schemes(&operands[1..])),
+            op if PRIMN_OPS.contains(&op) => PrimN (op.to_string(), self.schemes(operands)),
+            op if is_prim(op) => self.prim(&format!("prim{}", operands.len()), op.to_string(), self.schemes(operands)),
+            _ => Funcall (Box::new(self.scheme(&items[0])), self.schemes(operands)),
         }
     }
 
-    fn parse_literal_vector(&mut self) -> Scheme {
-        let len_or_left = self.remove_top().unwrap().token;
-        let mut set_len: usize = 0;
-        // here, test whether a length is explicitly setted.
-        // if it is, set_len catch the length
-        // else length is determined by the elements.
-        match len_or_left.parse::<usize>() {
-            Ok(i) => { set_len = i; self.remove_top(); }
-            Err(e) => (),
+    pub fn expr(&self, datum: &Datum) -> Expr {
+        use Expr::*;
+        let (t, items) = match datum {
+            Datum::Atom (t) if is_number(&t.token) => return Int64 (self.integer(t)),
+            Datum::Atom (t) => return Symbol (t.token.clone()),
+            Datum::List (t, items, None) if items.len() > 0 => (t, items),
+            other => panic!("Invalid expression at line {}, col {}", other.token().line, other.token().col),
         };
-        let mut elements = vec![];
-        while let Some(ref t) = self.top() {
-            match t.token.as_str() {
-                ")" => break,
-                "#" => elements.push(self.parse_literal()),
-                "(" => elements.push(self.parse_quote_list()),
-                other => elements.push(self.parse_quote_atom()),
-            };
-        }
-        let _right = self.remove_top();
-        // before return, check the length
-        if set_len > 0 {
-            for _i in 0..(set_len - elements.len()) {
-                elements.push(Void);
+        let head = match items[0].symbol() {
+            Some(head) => head,
+            None => return Funcall (Box::new(self.expr(&items[0])), self.exprs(&items[1..])),
+        };
+        let operands = &items[1..];
+        let arity = |n: usize| assert!(operands.len() == n, "{} expects {} operands at line {}, col {}", head, n, t.line, t.col);
+        let body = |n: usize| Box::new(self.expr(&operands[n]));
+        let names = || self.names(&operands[0]).into_iter().collect::<HashSet<String>>();
+        let conflicts = || self.elements(&operands[0]).iter().map(|c| {
+            let names = self.names(c);
+            assert!(names.len() >= 1, "Invalid conflict at line {}, col {}", c.token().line, c.token().col);
+            (names[0].clone(), names[1..].iter().cloned().collect())
+        }).collect();
+        match head {
+            "letrec" => { arity(2); Letrec (self.elements(&operands[0]).iter().map(|l| self.lambda(l)).collect(), body(1)) }
+            "locals" => { arity(2); Locals (names(), body(1)) }
+            "ulocals" => { arity(2); Ulocals (names(), body(1)) }
+            "spills" => { arity(2); Spills (names(), body(1)) }
+            "call-live" => { arity(2); CallLive (names(), body(1)) }
+            "locate" => {
+                arity(2);
+                let bindings = self.elements(&operands[0]).iter().map(|b| {
+                    let pair = self.names(b);
+                    assert!(pair.len() == 2, "Invalid location at line {}, col {}", b.token().line, b.token().col);
+                    (pair[0].clone(), pair[1].clone())
+                }).collect();
+                Locate (bindings, body(1))
             }
+            "register-conflict" => { arity(2); RegisterConflict (conflicts(), body(1)) }
+            "frame-conflict" => { arity(2); FrameConflict (conflicts(), body(1)) }
+            "new-frames" => { arity(2); NewFrames (self.elements(&operands[0]).iter().map(|fr| self.names(fr)).collect(), body(1)) }
+            "return-point" => { arity(2); ReturnPoint (self.name(&operands[0]), body(1)) }
+            "begin" => Begin (self.exprs(operands)),
+            "if" if operands.len() == 2 => If1 (body(0), body(1)),
+            "if" => { arity(3); If (body(0), body(1), body(2)) }
+            "set!" => { arity(2); Set (body(0), body(1)) }
+            "alloc" => { arity(1); Alloc (body(0)) }
+            "mref" => { arity(2); Mref (body(0), body(1)) }
+            "mset!" => { arity(3); Mset (body(0), body(1), body(2)) }
+            "nop" => { arity(0); Nop }
+            "true" => { arity(0); Bool (true) }
+            "false" => { arity(0); Bool (false) }
+            "funcall" => Funcall (body(0), self.exprs(&operands[1..])),
+            "prim1" => { arity(3); Prim1 (self.name(&operands[0]), body(1)) }
+            "prim2" => { arity(3); Prim2 (self.name(&operands[0]), body(1), body(2)) }
+            op if is_prim(op) && operands.len() == 1 => Prim1 (op.to_string(), body(0)),
+            op if is_prim(op) && operands.len() == 2 => Prim2 (op.to_string(), body(0), body(1)),
+            _ => Funcall (Box::new(self.expr(&items[0])), self.exprs(operands)),
         }
-        return LiteralVector (elements);
     }
 
-    fn parse_nop(&mut self) -> Scheme {
-        let _nop = self.remove_top();
-        let _right = self.remove_top();
-        return Scheme::Nop;
+    // [label (lambda (args) body)] in a letrec
+    fn lambda(&self, datum: &Datum) -> Expr {
+        let pair = self.elements(datum);
+        let t = datum.token();
+        assert!(pair.len() == 2, "Invalid lambda at line {}, col {}", t.line, t.col);
+        let lambda = self.elements(&pair[1]);
+        assert!(lambda.len() == 3 && lambda[0].symbol() == Some("lambda"), "Invalid lambda at line {}, col {}", t.line, t.col);
+        Expr::Lambda (self.name(&pair[0]), self.formals(&lambda[1]), Box::new(self.expr(&lambda[2])))
     }
 
-    fn parse_bool(&mut self) -> Scheme {
-        let s = self.remove_top().unwrap().token;
-        let e = match s.as_str() {
-            "true" => Scheme::Bool(true),
-            "false" => Scheme::Bool(false),
-            any => panic!("Invalid bool value {}", any),
+    // a rest argument after "." is marked so before UniquifyVariable, a uvar after it is a rest.N
+    fn formals(&self, datum: &Datum) -> Vec<String> {
+        let (mut args, rest) = match datum {
+            Datum::List (_, args, rest) => (args.iter().map(|a| self.name(a)).collect(), rest.as_ref().map(|r| self.name(r))),
+            rest => (vec![], Some (self.name(rest))),
+        };
+        if let Some (rest) = rest {
+            if is_uvar(&rest) {
+                mark_rest(&rest);
+            } else {
+                args.push(".".to_string());
+            }
+            args.push(rest);
+        }
+        args
+    }
+
+    #[cfg(test)]
+    pub fn asm(&self, datum: &Datum) -> Asm {
+        use Asm::*;
+        let (t, items) = match datum {
+            Datum::Atom (t) if t.token.starts_with('%') => return self.register(t),
+            Datum::Atom (t) if t.token.starts_with('$') => return Imm (t.token[1..].parse().unwrap_or_else(|_| panic!("Invalid immediate {} at line {}, col {}", t.token, t.line, t.col))),
+            Datum::Atom (t) => return Label (t.token.clone()),
+            Datum::List (t, items, None) if items.len() > 0 && items[0].symbol().is_some() => (t, items),
+            other => panic!("Invalid instruction at line {}, col {}", other.token().line, other.token().col),
         };
-        let _right = self.remove_top();
-        return e;
+        let head = items[0].symbol().unwrap();
+        let operands = &items[1..];
+        let arity = |n: usize| assert!(operands.len() == n, "{} expects {} operands at line {}, col {}", head, n, t.line, t.col);
+        let operand = |n: usize| Box::new(self.asm(&operands[n]));
+        match head {
+            "deref" => {
+                arity(2);
+                match &operands[1] {
+                    Datum::Atom (n) if is_number(&n.token) => Deref (operand(0), self.integer(n)),
+                    Datum::Atom (r) if r.token.starts_with('%') => DerefRegister (operand(0), operand(1)),
+                    _ => DerefLabel (operand(0), operand(1)),
+                }
+            }
+            "retq" => { arity(0); Retq }
+            "jmp" => { arity(1); Jmp (operand(0)) }
+            "callq" => { arity(1); Callq (operand(0)) }
+            "pushq" => { arity(1); Push (operand(0)) }
+            "popq" => { arity(1); Pop (operand(0)) }
+            "cfg" => Cfg (self.name(&operands[0]), operands[1..].iter().map(|c| self.asm(c)).collect()),
+            "prog" => Prog (operands.iter().map(|c| self.asm(c)).collect()),
+            "code" => Code (operands.iter().map(|c| self.asm(c)).collect()),
+            cc if cc.starts_with('j') && operands.len() == 1 => Jmpif (cc[1..].to_string(), operand(0)),
+            op => { arity(2); Op2 (op.to_string(), operand(0), operand(1)) }
+        }
+    }
+
+    fn prim(&self, kind: &str, op: String, mut operands: Vec<Scheme>) -> Scheme {
+        if kind == "primn" {
+            return PrimN (op, operands);
+        }
+        let n = operands.len();
+        let mut next = || Box::new(operands.remove(0));
+        match (kind, n) {
+            ("prim1", 1) => Prim1 (op, next()),
+            ("prim2", 2) => Prim2 (op, next(), next()),
+            ("prim3", 3) => Prim3 (op, next(), next(), next()),
+            _ => panic!("Invalid operands of {}", op),
+        }
     }
 
-    fn parse_void(&mut self) -> Scheme {
-        let _nop = self.remove_top();
-        let _right = self.remove_top();
-        return Scheme::Void;
+    fn schemes(&self, data: &[Datum]) -> Vec<Scheme> {
+        data.iter().map(|d| self.scheme(d)).collect()
     }
 
-    fn top(&self) -> Option<&Token> {
-        self.top.as_ref()
+    fn exprs(&self, data: &[Datum]) -> Vec<Expr> {
+        data.iter().map(|d| self.expr(d)).collect()
     }
 
-    fn remove_top(&mut self) -> Option<Token> {
-        let new = self.tokens.next();
-        match new {
-            Some(t) => self.top.replace(t),
-            None => self.top.take(),
+    fn elements<'a>(&self, datum: &'a Datum) -> &'a [Datum] {
+        match datum {
+            Datum::List (_, elements, None) => elements,
+            other => panic!("Expect a list at line {}, col {}", other.token().line, other.token().col),
         }
     }
-}
 
+    fn names(&self, datum: &Datum) -> Vec<String> {
+        self.elements(datum).iter().map(|d| self.name(d)).collect()
+    }
+
+    fn name(&self, datum: &Datum) -> String {
+        match datum.symbol() {
+            Some(s) => s.to_string(),
+            None => panic!("Expect a name at line {}, col {}", datum.token().line, datum.token().col),
+        }
+    }
+
+    fn integer(&self, t: &Token) -> i64 {
+        match t.token.parse() {
+            Ok(i) => i,
+            Err(_e) => panic!("{} not a valid integer at line {}, col {}", t.token, t.line, t.col),
+        }
+    }
+
+    #[cfg(test)]
+    fn register(&self, t: &Token) -> Asm {
+        use Asm::*;
+        match t.token.as_str() {
+            "%rax" => RAX, "%rbx" => RBX, "%rcx" => RCX, "%rdx" => RDX,
+            "%rsi" => RSI, "%rdi" => RDI, "%rbp" => RBP, "%rsp" => RSP,
+            "%r8"  => R8,  "%r9"  => R9,  "%r10" => R10, "%r11" => R11,
+            "%r12" => R12, "%r13" => R13, "%r14" => R14, "%r15" => R15,
+            "%rip" => RIP,
+            other => panic!("Invalid register {} at line {}, col {}", other, t.line, t.col),
+        }
+    }
+}
@@ -0,0 +1,406 @@
+
+use crate::syntax::{Scheme, Expr, HashMap, HashSet};
+use crate::compiler::is_rest;
+#[cfg(test)]
+use crate::syntax::Asm;
+
+// ---------------------- documents --------------------------------------
+// A program is turned into an s-expression document before layout. A list keeps
+// the length of its flat form, so that deciding whether it fits is cheap.
+pub enum Doc {
+    Atom (String),
+    // open, close, items, flat length
+    List (String, String, Vec<Doc>, usize),
+}
+
+fn atom(s: &str) -> Doc {
+    Doc::Atom (s.to_string())
+}
+
+fn list_with(open: &str, close: &str, items: Vec<Doc>) -> Doc {
+    let len = open.len() + close.len() + items.iter().map(|d| d.len()).sum::<usize>() + items.len().saturating_sub(1);
+    Doc::List (open.to_string(), close.to_string(), items, len)
+}
+
+fn list(items: Vec<Doc>) -> Doc {
+    list_with("(", ")", items)
+}
+
+fn bracket(items: Vec<Doc>) -> Doc {
+    list_with("[", "]", items)
+}
+
+// the formals as written, a rest argument comes after "." once UniquifyVariable has dropped it
+fn formals(args: &[String]) -> Vec<String> {
+    let mut formals = args.to_vec();
+    if args.last().map_or(false, |a| is_rest(a)) && !args.iter().any(|a| a.as_str() == ".") {
+        formals.insert(args.len() - 1, ".".to_string());
+    }
+    formals
+}
+
+fn sorted<'a>(set: impl Iterator<Item=&'a String>) -> Vec<Doc> {
+    let mut vs: Vec<&String> = set.collect();
+    vs.sort();
+    vs.into_iter().map(|v| atom(v)).collect()
+}
+
+impl Doc {
+    fn len(&self) -> usize {
+        match self {
+            Doc::Atom (s) => s.chars().count(),
+            Doc::List (_, _, _, len) => *len,
+        }
+    }
+
+    fn flat(&self, out: &mut String) {
+        match self {
+            Doc::Atom (s) => out.push_str(s),
+            Doc::List (open, close, items, _) => {
+                out.push_str(open);
+                for (i, d) in items.iter().enumerate() {
+                    if i > 0 { out.push(' '); }
+                    d.flat(out);
+                }
+                out.push_str(close);
+            }
+        }
+    }
+}
+
+// the number of operands kept on the first line of a form, the rest is indented as a body
+fn header(head: &str) -> Option<usize> {
+    match head {
+        "let" | "letrec" | "lambda" | "locals" | "ulocals" | "spills" | "locate" | "assigned" |
+        "free" | "bind-free" | "closures" | "new-frames" | "call-live" | "register-conflict" |
+        "frame-conflict" | "return-point" | "cfg" => Some(1),
+        "begin" | "prog" | "code" => Some(0),
+        _ => None,
+    }
+}
+
+// operators which take any number of operands
+pub const PRIMN_OPS: [&str; 6] = ["and", "or", "apply", "error", "values", "letrec-error"];
+
+// the operators of Prim1, Prim2 and Prim3, the number of operands tells them apart
+pub fn is_prim(op: &str) -> bool {
+    match op {
+        "car" | "cdr" | "make-vector" | "vector-length" | "procedure?" | "boolean?" | "fixnum?" |
+        "null?" | "pair?" | "vector?" | "not" | "box" | "unbox" | "box?" |
+        "call/cc" | "call-with-current-continuation" | "raise" | "raise-continuable" | "apply-values" |
+        "+" | "-" | "*" | "logor" | "logand" | "sra" | "=" | ">" | "<" | ">=" | "<=" | "eq?" |
+        "cons" | "vector-ref" | "set-car!" | "set-cdr!" | "set-box!" |
+        "with-exception-handler" | "call-with-values" | "vector-set!" |
+        "make-procedure" | "procedure-code" | "procedure-ref" | "procedure-set!" |
+        "make-record" | "record-ref" | "record-set!" | "record-of?" => true,
+        _ => PRIMN_OPS.contains(&op),
+    }
+}
+
+// the heads which are not a call when they are read back
+pub fn is_reserved(head: &str) -> bool {
+    is_prim(head) || header(head).is_some() || match head {
+        "if" | "set!" | "alloc" | "mref" | "mset!" | "void" | "nop" | "true" | "false" |
+        "funcall" | "prim1" | "prim2" | "prim3" | "primn" => true,
+        _ => false,
+    }
+}
+
+// ---------------------- printer --------------------------------------
+// Prints Scheme, Expr and Asm as s-expressions within a width. The output reads back
+// with IlParser to the same tree. A form which does not fit is broken over lines,
+// binding forms keep their first operand on the first line and indent the body.
+pub struct PrettyPrinter {
+    width: usize,
+}
+
+impl PrettyPrinter {
+    pub fn new(width: usize) -> Self {
+        Self { width }
+    }
+
+    pub fn scheme(&self, scm: &Scheme) -> String {
+        self.layout_doc(&self.scheme_doc(scm))
+    }
+
+    pub fn expr(&self, expr: &Expr) -> String {
+        self.layout_doc(&self.expr_doc(expr))
+    }
+
+    #[cfg(test)]
+    pub fn asm(&self, asm: &Asm) -> String {
+        self.layout_doc(&self.asm_doc(asm))
+    }
+
+    pub fn layout_doc(&self, doc: &Doc) -> String {
+        let mut out = String::new();
+        self.layout(doc, 0, &mut out);
+        return out;
+    }
+
+    fn layout(&self, doc: &Doc, col: usize, out: &mut String) {
+        let (open, close, items) = match doc {
+            Doc::List (open, close, items, len) if col + len > self.width && (items.len() > 1 || matches!(items.first(), Some(Doc::List (..)))) => (open, close, items),
+            _ => return doc.flat(out),
+        };
+        out.push_str(open);
+        let col = col + open.chars().count();
+        // a set of names fills each line
+        if items.iter().all(|d| matches!(d, Doc::Atom (_))) {
+            for (i, d) in items.iter().enumerate() {
+                if i > 0 {
+                    if self.column(out) + 1 + d.len() + close.len() > self.width { self.newline(col, out); } else { out.push(' '); }
+                }
+                d.flat(out);
+            }
+            out.push_str(close);
+            return;
+        }
+        let (head, rest) = match &items[0] {
+            Doc::Atom (head) => (head, &items[1..]),
+            // a list of bindings, one per line
+            _ => {
+                for (i, d) in items.iter().enumerate() {
+                    if i > 0 { self.newline(col, out); }
+                    self.layout(d, col, out);
+                }
+                out.push_str(close);
+                return;
+            }
+        };
+        out.push_str(head);
+        match header(head) {
+            Some(n) => {
+                for (i, d) in rest.iter().enumerate() {
+                    if i < n {
+                        out.push(' ');
+                        self.layout(d, self.column(out), out);
+                    } else {
+                        self.newline(col + 1, out);
+                        self.layout(d, col + 1, out);
+                    }
+                }
+            }
+            // operands are aligned under the first one
+            None => {
+                let indent = col + head.chars().count() + 1;
+                for (i, d) in rest.iter().enumerate() {
+                    if i == 0 { out.push(' '); } else { self.newline(indent, out); }
+                    self.layout(d, indent, out);
+                }
+            }
+        }
+        out.push_str(close);
+    }
+
+    fn newline(&self, indent: usize, out: &mut String) {
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    }
+
+    fn column(&self, out: &str) -> usize {
+        out.chars().rev().take_while(|c| *c != '\n').count()
+    }
+
+    // a primitive with its operator, marked when reading it back by its operands would give another one
+    fn prim_doc(&self, kind: &str, op: &str, mut items: Vec<Doc>) -> Doc {
+        let default = if PRIMN_OPS.contains(&op) { kind == "primn" } else { is_prim(op) && kind == format!("prim{}", items.len()) };
+        items.insert(0, atom(op));
+        if !default {
+            items.insert(0, atom(kind));
+        }
+        return list(items);
+    }
+
+    // a call whose operator would be read back as a keyword is marked
+    fn call_doc(&self, func: Doc, args: Vec<Doc>) -> Doc {
+        let mut items = vec![];
+        if let Doc::Atom (s) = &func {
+            if is_reserved(s) {
+                items.push(atom("funcall"));
+            }
+        }
+        items.push(func);
+        items.extend(args);
+        return list(items);
+    }
+
+    fn scheme_doc(&self, scm: &Scheme) -> Doc {
+        use Scheme::*;
+        let form = |name: &str, vars: Vec<Doc>, body: &Scheme| list(vec![atom(name), list(vars), self.scheme_doc(body)]);
+        let bindings = |bindings: &HashMap<String, Scheme>| {
+            let mut keys: Vec<&String> = bindings.keys().collect();
+            keys.sort();
+            keys.into_iter().map(|k| bracket(vec![atom(k), self.scheme_doc(&bindings[k])])).collect()
+        };
+        match scm {
+            Letrec (bs, box body) => form("letrec", bindings(bs), body),
+            Let (bs, box body) => form("let", bindings(bs), body),
+            Locals (vars, box body) => form("locals", sorted(vars.iter()), body),
+            Assigned (vars, box body) => form("assigned", sorted(vars.iter()), body),
+            Free (vars, box body) => form("free", vars.iter().map(|v| atom(v)).collect(), body),
+            Bindfree (vars, box body) => form("bind-free", vars.iter().map(|v| atom(v)).collect(), body),
+            Lambda (args, box body) => match formals(args).as_slice() {
+                [dot, rest] if dot.as_str() == "." => list(vec![atom("lambda"), atom(rest), self.scheme_doc(body)]),
+                args => form("lambda", args.iter().map(|v| atom(v)).collect(), body),
+            },
+            Closures (clos, box body) => {
+                let clos = clos.iter().map(|(uvar, label, fvars)| {
+                    let mut items = vec![atom(uvar), atom(label)];
+                    items.extend(fvars.iter().map(|v| atom(v)));
+                    bracket(items)
+                }).collect();
+                list(vec![atom("closures"), list(clos), self.scheme_doc(body)])
+            }
+            Begin (exprs) => {
+                let mut items = vec![atom("begin")];
+                items.extend(exprs.iter().map(|e| self.scheme_doc(e)));
+                list(items)
+            }
+            Prim1 (op, box e) => self.prim_doc("prim1", op, vec![self.scheme_doc(e)]),
+            Prim2 (op, box e1, box e2) => self.prim_doc("prim2", op, vec![self.scheme_doc(e1), self.scheme_doc(e2)]),
+            Prim3 (op, box e1, box e2, box e3) => self.prim_doc("prim3", op, vec![self.scheme_doc(e1), self.scheme_doc(e2), self.scheme_doc(e3)]),
+            PrimN (op, exprs) => self.prim_doc("primn", op, exprs.iter().map(|e| self.scheme_doc(e)).collect()),
+            If (box cond, box b1, box b2) => list(vec![atom("if"), self.scheme_doc(cond), self.scheme_doc(b1), self.scheme_doc(b2)]),
+            Set (box e1, box e2) => list(vec![atom("set!"), self.scheme_doc(e1), self.scheme_doc(e2)]),
+            Alloc (box e) => list(vec![atom("alloc"), self.scheme_doc(e)]),
+            Mref (box base, box offset) => list(vec![atom("mref"), self.scheme_doc(base), self.scheme_doc(offset)]),
+            Mset (box base, box offset, box value) => list(vec![atom("mset!"), self.scheme_doc(base), self.scheme_doc(offset), self.scheme_doc(value)]),
+            Funcall (box func, args) => self.call_doc(self.scheme_doc(func), args.iter().map(|e| self.scheme_doc(e)).collect()),
+            Symbol (s) => atom(s),
+            Int64 (i) => atom(&i.to_string()),
+            Bool (true) => list(vec![atom("true")]),
+            Bool (false) => list(vec![atom("false")]),
+            Quote (box imm) => self.quote(self.datum_doc(imm)),
+            LiteralList (_) | LiteralVector (_) => self.quote(self.datum_doc(scm)),
+            EmptyList => list(vec![]),
+            Void => list(vec![atom("void")]),
+            Nop => list(vec![atom("nop")]),
+        }
+    }
+
+    fn quote(&self, doc: Doc) -> Doc {
+        match doc {
+            Doc::Atom (s) => Doc::Atom (format!("'{}", s)),
+            Doc::List (open, close, items, len) => Doc::List (format!("'{}", open), close, items, len + 1),
+        }
+    }
+
+    // a literal as it is written in the source, a list ends with its tail and a vector
+    // of a given length is padded with void
+    fn datum_doc(&self, scm: &Scheme) -> Doc {
+        use Scheme::*;
+        match scm {
+            Quote (box imm) => self.datum_doc(imm),
+            Int64 (i) => atom(&i.to_string()),
+            Bool (true) => atom("#t"),
+            Bool (false) => atom("#f"),
+            EmptyList => list(vec![]),
+            LiteralList (elements) => {
+                let (tail, elements) = elements.split_last().unwrap();
+                let mut items: Vec<Doc> = elements.iter().map(|e| self.datum_doc(e)).collect();
+                match tail {
+                    Quote (box EmptyList) => (),
+                    tail => {
+                        items.push(atom("."));
+                        items.push(self.datum_doc(tail));
+                    }
+                }
+                list(items)
+            }
+            LiteralVector (elements) => {
+                let n = elements.iter().rev().take_while(|e| matches!(e, Void)).count();
+                let open = if n > 0 { format!("#{}(", elements.len()) } else { "#(".to_string() };
+                let items = elements[..elements.len() - n].iter().map(|e| self.datum_doc(e)).collect();
+                list_with(&open, ")", items)
+            }
+            other => panic!("Invalid literal {:?}", other),
+        }
+    }
+
+    fn expr_doc(&self, expr: &Expr) -> Doc {
+        use Expr::*;
+        let form = |name: &str, vars: Vec<Doc>, body: &Expr| list(vec![atom(name), list(vars), self.expr_doc(body)]);
+        let conflicts = |cg: &HashMap<String, HashSet<String>>| {
+            let mut keys: Vec<&String> = cg.keys().collect();
+            keys.sort();
+            keys.into_iter().map(|k| {
+                let mut items = vec![atom(k)];
+                items.extend(sorted(cg[k].iter()));
+                bracket(items)
+            }).collect()
+        };
+        match expr {
+            Letrec (lambdas, box body) => list(vec![atom("letrec"), list(lambdas.iter().map(|e| self.expr_doc(e)).collect()), self.expr_doc(body)]),
+            Lambda (label, args, box body) => bracket(vec![atom(label), match formals(args).as_slice() {
+                [dot, rest] if dot.as_str() == "." => list(vec![atom("lambda"), atom(rest), self.expr_doc(body)]),
+                args => form("lambda", args.iter().map(|v| atom(v)).collect(), body),
+            }]),
+            Locals (vars, box tail) => form("locals", sorted(vars.iter()), tail),
+            Ulocals (vars, box tail) => form("ulocals", sorted(vars.iter()), tail),
+            Spills (vars, box tail) => form("spills", sorted(vars.iter()), tail),
+            CallLive (vars, box tail) => form("call-live", sorted(vars.iter()), tail),
+            Locate (bindings, box tail) => {
+                let mut keys: Vec<&String> = bindings.keys().collect();
+                keys.sort();
+                form("locate", keys.into_iter().map(|k| bracket(vec![atom(k), atom(&bindings[k])])).collect(), tail)
+            }
+            RegisterConflict (cg, box tail) => form("register-conflict", conflicts(cg), tail),
+            FrameConflict (cg, box tail) => form("frame-conflict", conflicts(cg), tail),
+            NewFrames (frames, box tail) => {
+                let mut frames: Vec<&Vec<String>> = frames.iter().collect();
+                frames.sort();
+                form("new-frames", frames.into_iter().map(|fr| list(fr.iter().map(|v| atom(v)).collect())).collect(), tail)
+            }
+            ReturnPoint (rp, box e) => list(vec![atom("return-point"), atom(rp), self.expr_doc(e)]),
+            Begin (exprs) => {
+                let mut items = vec![atom("begin")];
+                items.extend(exprs.iter().map(|e| self.expr_doc(e)));
+                list(items)
+            }
+            Prim1 (op, box e) => self.prim_doc("prim1", op, vec![self.expr_doc(e)]),
+            Prim2 (op, box e1, box e2) => self.prim_doc("prim2", op, vec![self.expr_doc(e1), self.expr_doc(e2)]),
+            If (box cond, box b1, box b2) => list(vec![atom("if"), self.expr_doc(cond), self.expr_doc(b1), self.expr_doc(b2)]),
+            If1 (box cond, box b) => list(vec![atom("if"), self.expr_doc(cond), self.expr_doc(b)]),
+            Set (box e1, box e2) => list(vec![atom("set!"), self.expr_doc(e1), self.expr_doc(e2)]),
+            Alloc (box e) => list(vec![atom("alloc"), self.expr_doc(e)]),
+            Mref (box base, box offset) => list(vec![atom("mref"), self.expr_doc(base), self.expr_doc(offset)]),
+            Mset (box base, box offset, box value) => list(vec![atom("mset!"), self.expr_doc(base), self.expr_doc(offset), self.expr_doc(value)]),
+            Funcall (box func, args) => self.call_doc(self.expr_doc(func), args.iter().map(|e| self.expr_doc(e)).collect()),
+            Symbol (s) => atom(s),
+            Int64 (i) => atom(&i.to_string()),
+            Bool (true) => list(vec![atom("true")]),
+            Bool (false) => list(vec![atom("false")]),
+            Nop => list(vec![atom("nop")]),
+        }
+    }
+
+    #[cfg(test)]
+    fn asm_doc(&self, asm: &Asm) -> Doc {
+        use Asm::*;
+        let codes = |name: &str, label: Option<&String>, codes: &Vec<Asm>| {
+            let mut items = vec![atom(name)];
+            items.extend(label.map(|l| atom(l)));
+            items.extend(codes.iter().map(|c| self.asm_doc(c)));
+            list(items)
+        };
+        match asm {
+            Imm (n) => atom(&format!("${}", n)),
+            Label (s) => atom(s),
+            Deref (box reg, n) => list(vec![atom("deref"), self.asm_doc(reg), atom(&n.to_string())]),
+            DerefLabel (box reg, box labl) | DerefRegister (box reg, box labl) => list(vec![atom("deref"), self.asm_doc(reg), self.asm_doc(labl)]),
+            Op2 (op, box e1, box e2) => list(vec![atom(op), self.asm_doc(e1), self.asm_doc(e2)]),
+            Retq => list(vec![atom("retq")]),
+            Jmp (box target) => list(vec![atom("jmp"), self.asm_doc(target)]),
+            Jmpif (cc, box target) => list(vec![atom(&format!("j{}", cc)), self.asm_doc(target)]),
+            Callq (box target) => list(vec![atom("callq"), self.asm_doc(target)]),
+            Push (box a) => list(vec![atom("pushq"), self.asm_doc(a)]),
+            Pop (box a) => list(vec![atom("popq"), self.asm_doc(a)]),
+            Cfg (labl, cs) => codes("cfg", Some(labl), cs),
+            Prog (cs) => codes("prog", None, cs),
+            Code (cs) => codes("code", None, cs),
+            // a register
+            reg => atom(&format!("{}", reg)),
+        }
+    }
+}
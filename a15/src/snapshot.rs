@@ -0,0 +1,111 @@
+// Golden snapshots of the passes. The dump of every pass on each program of a corpus is kept
+// next to it, so a pass which changes the code or breaks an invariant shows up as a diff even
+// when the answer stays the same. With A15_BLESS set, the dumps are written instead.
+use std::fs;
+use std::path::PathBuf;
+
+use crate::compiler::{reset_gensym, run_passes, ParseScheme, Program};
+
+pub const SNAPSHOTS: &str = "snapshots";
+
+// lines of context around a change, and the most lines of a diff to show
+const CONTEXT: usize = 2;
+const MAX_DIFF: usize = 80;
+
+pub fn bless_enabled() -> bool {
+    std::env::var_os("A15_BLESS").is_some()
+}
+
+// the dumps of the passes in the order they run, with the names restarted
+pub fn snapshot(s: &str) -> String {
+    reset_gensym();
+    let scm = ParseScheme{}.run(s);
+    let mut text = format!(">>> ParseScheme\n{}\n", scm);
+    run_passes("IntroduceLibrary", Program::Scheme (scm), false, |pass, program| {
+        text.push_str(&format!("\n>>> {}\n{}\n", pass, program));
+    });
+    text
+}
+
+// compares each .ss file of the directory with its .snap file, or writes the .snap files
+// when blessing, and reports the programs whose dumps differ
+pub fn check_snapshots(dir: &str) -> Vec<String> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).expect("failed to read the corpus")
+                                    .filter_map(|e| e.ok().map(|e| e.path()))
+                                    .filter(|p| p.extension().map_or(false, |ext| ext == "ss"))
+                                    .collect();
+    paths.sort();
+    let mut failures = vec![];
+    for path in paths {
+        let s = fs::read_to_string(&path).expect("failed to read the program");
+        let actual = snapshot(&s);
+        let snap = path.with_extension("snap");
+        if bless_enabled() {
+            fs::write(&snap, &actual).expect("failed to write the snapshot");
+            continue;
+        }
+        match fs::read_to_string(&snap) {
+            Ok (expected) if expected == actual => (),
+            Ok (expected) => failures.push(format!("{} changed\n{}", snap.display(), diff(&expected, &actual))),
+            Err (_) => failures.push(format!("{} is missing, set A15_BLESS to write it", snap.display())),
+        }
+    }
+    failures
+}
+
+// a line diff from the longest common subsequence, where each hunk names its pass
+pub fn diff(expected: &str, actual: &str) -> String {
+    let old: Vec<&str> = expected.lines().collect();
+    let new: Vec<&str> = actual.lines().collect();
+    // the common head and tail need no table
+    let head = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let tail = old[head..].iter().rev().zip(new[head..].iter().rev()).take_while(|(a, b)| a == b).count();
+    let (a, b) = (&old[head..old.len() - tail], &new[head..new.len() - tail]);
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    // (mark, line, index of the line in the old text)
+    let mut script: Vec<(char, &str, usize)> = old[..head].iter().enumerate().map(|(i, l)| (' ', *l, i)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            script.push((' ', a[i], head + i));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            script.push(('-', a[i], head + i));
+            i += 1;
+        } else {
+            script.push(('+', b[j], head + i));
+            j += 1;
+        }
+    }
+    script.extend(old[old.len() - tail..].iter().enumerate().map(|(k, l)| (' ', *l, old.len() - tail + k)));
+    let changed: Vec<usize> = (0..script.len()).filter(|&k| script[k].0 != ' ').collect();
+    let mut lines = vec![];
+    let mut shown = 0;
+    for (n, &k) in changed.iter().enumerate() {
+        let start = k.saturating_sub(CONTEXT);
+        if n == 0 || start > changed[n - 1] + CONTEXT {
+            let pass = old[..script[k].2.min(old.len())].iter().rev().find(|l| l.starts_with(">>> "))
+                          .map_or("", |l| &l[4..]);
+            lines.push(format!("@@ {}, line {}", pass, script[k].2 + 1));
+            shown = start;
+        }
+        let end = (k + CONTEXT + 1).min(script.len());
+        let stop = changed.get(n + 1).map_or(end, |&next| end.min(next));
+        for &(mark, line, _) in &script[shown.max(start)..stop] {
+            lines.push(format!("{}{}", mark, line));
+        }
+        shown = stop;
+        if lines.len() > MAX_DIFF {
+            lines.truncate(MAX_DIFF);
+            lines.push("...".to_string());
+            break;
+        }
+    }
+    lines.join("\n")
+}
@@ -1,37 +1,16 @@
 use std::fmt;
-use std::collections::HashMap;
-use std::collections::HashSet;
+use std::hash::BuildHasherDefault;
+use std::collections::hash_map::DefaultHasher;
 
+use crate::pretty::PrettyPrinter;
+
+// maps and sets with a fixed hasher, so that the passes visit them in the same order on every run
+pub type HashMap<K, V> = std::collections::HashMap<K, V, BuildHasherDefault<DefaultHasher>>;
+pub type HashSet<T> = std::collections::HashSet<T, BuildHasherDefault<DefaultHasher>>;
 pub type ConflictGraph = HashMap<String, HashSet<String>>;
 pub type Frame = HashSet<Vec<String>>;
 
 
-// ------------------------------- formatter -------------------------------------
-fn conflict_graph_formatter(form: &str, conflict_graph: &ConflictGraph, tail: &Expr) -> String {
-    let mut cg = vec![];
-    for (v, conflicts) in conflict_graph {
-        let seqs_s = seqs_formatter2(conflicts.iter(), " ");
-        let alist = format!("({} {{{}}})", v, seqs_s);
-        cg.push(alist);
-    }
-    let seqs_s = seqs_formatter2(cg.iter(), " ");
-    format!("({} ({})\n  {})", form, seqs_s, tail)
-}
-
-
-fn seqs_formatter<E: fmt::Display>(form: &str, seqs: impl Iterator<Item=E>, join: &str, tail: impl fmt::Display) -> String {
-    let seqs_s = seqs_formatter2(seqs, join);
-    format!("({} ({})\n  {})", form, seqs_s, tail)
-}
-
-
-fn seqs_formatter2<E: fmt::Display>(seqs: impl Iterator<Item=E>, join: &str) -> String {
-    let seqs: Vec<String> = seqs.map(|e| format!("{}", e)).collect();
-    let seqs_ref: Vec<&str> = seqs.iter().map(|s| s.as_ref()).collect();
-    let seqs_s = seqs_ref.join(join);
-    return seqs_s;
-}
-
 // ---------------------- Scheme / Expr / Asm --------------------------------------
 #[derive(Debug, Eq, PartialEq)]
 pub enum Scheme {
@@ -66,98 +45,17 @@ pub enum Scheme {
 }
 
 
+// laid out within the width of the formatter, 80 by default
 impl fmt::Display for Scheme {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use Scheme::*;
-        match self {
-            Letrec (bindings, box body) => {
-                let seqs: Vec<String> = bindings.iter().map(|(k, v)| format!("[{} {}]", k, v)).collect();
-                let seqs_ref: Vec<&str> = seqs.iter().map(|s| s.as_ref()).collect();
-                let seqs_s = seqs_ref.join("\n");
-                let s = format!("(letrec ({})\n {})", seqs_s, body);
-                write!(f, "{}", s)
-            },
-            Locals (uvars, box tail) => {
-                let s = seqs_formatter("locals", uvars.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Lambda (args, box body) => {
-                let seqs_s = seqs_formatter2(args.iter(), " ");
-                let s = format!("(lambda ({}) {})", seqs_s, body);
-                write!(f, "{}", s)
-            },
-            Free (fvars, box tail) => {
-                let s = seqs_formatter("free", fvars.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Bindfree (fvars, box tail) => {
-                let s = seqs_formatter("bind-free", fvars.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Closures (clos, box tail) => {
-                let seqs: Vec<_> = clos.iter().map(|(uvar, label, fvars)| {
-                    let seqs_fs = seqs_formatter2(fvars.iter(), " ");
-                    format!("[{} {} {}]", uvar, label, seqs_fs)
-                }).collect();
-                let seqs_s = seqs_formatter2(seqs.iter(), " ");
-                let s = format!("(closures ({})\n{})", seqs_s, tail);
-                write!(f, "{}", s)
-            }
-            Let (bindings, box tail) => {
-                let seqs: Vec<String> = bindings.iter().map(|(k, v)| format!("[{} {}]", k, v)).collect();
-                let seqs_ref: Vec<&str> = seqs.iter().map(|s| s.as_ref()).collect();
-                let seqs_s = seqs_ref.join(" ");
-                let s = format!("(let ({})\n {})", seqs_s, tail);
-                write!(f, "{}", s)
-            }
-            Assigned (vars, box body) => {
-                let s = seqs_formatter("assigned", vars.iter(), " ", body);
-                write!(f, "{}", s)
-            }
-            Begin ( exprs ) => {
-                let seqs: Vec<String> = exprs.iter().map(|e| format!("  {}", e)).collect();
-                let seqs_ref: Vec<&str> = seqs.iter().map(|s| s.as_ref()).collect();
-                let seqs_s = seqs_ref.join("\n");
-                write!(f, "(begin \n{})", seqs_s)
-            }
-            Set (box e1, box e2) => write!(f, "(set! {} {})", e1, e2),
-            Prim1 (op, box e) => write!(f, "({} {})", op, e),
-            Prim2 (op, box e1, box e2) => write!(f, "({} {} {})", op, e1, e2),
-            Prim3 (op, box e1, box e2, box e3) => write!(f, "({} {} {} {})", op, e1, e2, e3),
-            PrimN (op, es) => write!(f, "({} {})", op, seqs_formatter2(es.iter(), " ")),
-            If (box cond, box b1, box b2) => write!(f, "(if {} {} {})", cond, b1, b2),
-            Alloc (box e) => write!(f, "(alloc {})", e),
-            Mref (box base, box offset) => write!(f, "(mref {} {})", base, offset),
-            Mset (box base, box offset, box value) => write!(f, "(mset! {} {} {})", base, offset, value),
-            Symbol (s) => write!(f, "{}", s),
-            Funcall (box name, args) => {
-                let seqs_s = seqs_formatter2(args.iter(), " ");
-                write!(f, "({} {})", name, seqs_s)
-            }
-            Quote (box Bool(true)) => write!(f, "'#t"),
-            Quote (box Bool(false)) => write!(f, "'#f"),
-            Quote (box imm) => write!(f, "'{}", imm),
-            LiteralList (list) => {
-                let seqs_s = seqs_formatter2(list.iter(), " ");
-                write!(f, "({})", seqs_s)
-            }
-            LiteralVector (vector) => {
-                let seqs_s = seqs_formatter2(vector.iter(), " ");
-                write!(f, "#({})", seqs_s)
-            }
-            Int64 (i) => write!(f, "{}", i),
-            Bool (true) => write!(f, "(true)"),
-            Bool (false) => write!(f, "(false)"),
-            EmptyList => write!(f, "()"),
-            Nop => write!(f, "(nop)"),
-            Void => write!(f, "(void)"),
-        }
+        let width = f.width().unwrap_or(80);
+        f.write_str(&PrettyPrinter::new(width).scheme(self))
     }
 }
 
 
 
-#[derive(Debug)]
+#[derive(Debug, Eq, PartialEq)]
 pub enum Expr {
     Letrec(Vec<Expr>, Box<Expr>),
     Locals(HashSet<String>, Box<Expr>),
@@ -189,85 +87,8 @@ pub enum Expr {
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use Expr::*;
-        match self {
-            Letrec (lambdas, box body) => {
-                let s = seqs_formatter("letrec", lambdas.iter(), "\n", body);
-                write!(f, "{}", s)
-            },
-            Locals (uvars, box tail) => {
-                let s = seqs_formatter("locals", uvars.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Ulocals (unspills, box tail) => {
-                let s = seqs_formatter("ulocals", unspills.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Spills (spills, box tail) => {
-                let s = seqs_formatter("spills", spills.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Lambda (label, args, box body) => {
-                let seqs_s = seqs_formatter2(args.iter(), " ");
-                let s = format!("({} (lambda ({}) {}))", label, seqs_s, body);
-                write!(f, "{}", s)
-            },
-            Locate (bindings, box tail) => {
-                let seqs: Vec<String> = bindings.iter().map(|(k, v)| format!("({} {})", k, v)).collect();
-                let seqs_ref: Vec<&str> = seqs.iter().map(|s| s.as_ref()).collect();
-                let seqs_s = seqs_ref.join(" ");
-                let s = format!("(locate ({})\n {})", seqs_s, tail);
-                write!(f, "{}", s)
-            }
-            RegisterConflict (conflict_graph, box tail) => {
-                let s = conflict_graph_formatter("register-conflict", conflict_graph, tail);
-                write!(f, "{}", s)
-            }
-            FrameConflict (conflict_graph, box tail) => {
-                let s = conflict_graph_formatter("frame-conflict", conflict_graph, tail);
-                write!(f, "{}", s)
-            }
-            NewFrames (frames, box tail) => {
-                let mut vs = vec![];
-                for lst in frames.iter() {
-                    let seqs_s = seqs_formatter2(lst.iter(), " ");
-                    vs.push(format!("({})", seqs_s));
-                }
-                let vs_s = seqs_formatter2(vs.iter(), " ");
-                let s = format!("(new-frames ({}) {})", vs_s, tail);
-                write!(f, "{}", s)
-            }
-            ReturnPoint (rp, box e) => {
-                let s = format!("(return-point {} {})", rp, e);
-                write!(f, "{}", s)
-            }
-            CallLive (set, box tail) => {
-                let s = seqs_formatter("call-live", set.iter(), " ", tail);
-                write!(f, "{}", s)
-            }
-            Begin ( exprs ) => {
-                let seqs: Vec<String> = exprs.iter().map(|e| format!("  {}", e)).collect();
-                let seqs_ref: Vec<&str> = seqs.iter().map(|s| s.as_ref()).collect();
-                let seqs_s = seqs_ref.join("\n");
-                write!(f, "(begin \n{})", seqs_s)
-            }
-            Set (box e1, box e2) => write!(f, "(set! {} {})", e1, e2),
-            Prim1 (op, box e) => write!(f, "({} {})", op, e),
-            Prim2 (op, box e1, box e2) => write!(f, "({} {} {})", op, e1, e2),
-            If (box cond, box b1, box b2) => write!(f, "(if {} {} {})", cond, b1, b2),
-            If1 (box cond, box b) => write!(f, "(if {} {})", cond, b),
-            Alloc (box e) => write!(f, "(alloc {})", e),
-            Mref (box base, box offset) => write!(f, "(mref {} {})", base, offset),
-            Mset (box base, box offset, box value) => write!(f, "(mset! {} {} {})", base, offset, value),
-            Symbol (s) => write!(f, "{}", s),
-            Funcall (box name, args) => {
-                let seqs_s = seqs_formatter2(args.iter(), " ");
-                write!(f, "({} {})", name, seqs_s)
-            }
-            Int64 (i) => write!(f, "{}", i),
-            Bool (b) => write!(f, "({})", b),
-            Nop => write!(f, "(nop)"),
-        }
+        let width = f.width().unwrap_or(80);
+        f.write_str(&PrettyPrinter::new(width).expr(self))
     }
 }
 
@@ -287,6 +108,7 @@ pub enum Asm {
     Cfg(String, Vec<Asm>),
     Jmp(Box<Asm>),
     Jmpif(String, Box<Asm>),
+    Callq(Box<Asm>),
     Prog(Vec<Asm>),
     Push(Box<Asm>),
     Pop(Box<Asm>),
@@ -294,6 +116,25 @@ pub enum Asm {
 }
 
 
+// A label in the assembly. Letters, digits and '_' are kept, '$' is doubled and any other
+// char becomes $hex$, so distinct labels stay distinct. A label starting with anything
+// but a letter or '_' gets a '.' in front and its first char escaped.
+pub fn mangle(label: &str) -> String {
+    let mut s = String::new();
+    for (i, c) in label.chars().enumerate() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '_' => s.push(c),
+            '0'..='9' if i > 0 => s.push(c),
+            '$' if i > 0 => s.push_str("$$"),
+            c => {
+                if i == 0 { s.push('.'); }
+                s.push_str(&format!("${:x}$", c as u32));
+            }
+        }
+    }
+    return s;
+}
+
 impl fmt::Display for Asm {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Asm::*;
@@ -308,20 +149,21 @@ impl fmt::Display for Asm {
             Deref (box reg, n) => write!(f, "{}({})", n, reg),
             DerefLabel (box reg, s) => write!(f, "{}({})", s, reg),
             DerefRegister (box reg1, box reg2) => write!(f, "({},{})", reg1, reg2),
-            Label (s) => write!(f, "{}", s.replace("-", "_").replace("?", "q").replace("!", "l")),
+            Label (s) => write!(f, "{}", mangle(s)),
             Retq => write!(f, "\tretq\n"),
             Push (box a) => write!(f, "\tpushq {}\n", a),
             Pop (box a) => write!(f, "\tpopq {}\n", a),
-            Jmp (box Label(s)) => write!(f, "\tjmp {}\n", s.replace("-", "_").replace("?", "q").replace("!", "l")),
+            Jmp (box Label(s)) => write!(f, "\tjmp {}\n", mangle(s)),
             Jmp (box other) => write!(f, "\tjmp *{}\n", other),
-            Jmpif (cc, box Label(s)) => write!(f, "\tj{} {}\n", cc, s),
+            Callq (box a) => write!(f, "\tcallq {}\n", a),
+            Jmpif (cc, box Label(s)) => write!(f, "\tj{} {}\n", cc, mangle(s)),
             Jmpif (cc, other) => write!(f, "\tj{} *{}\n", cc, other),
             Cfg (labl, codes) => {
                 let mut codes_str = String::new();
                 for code in codes {
                     codes_str.push_str( &format!("{}", code) );
                 }
-                return write!(f, "{}:\n{}", labl.replace("-", "_").replace("?", "q").replace("!", "l"), codes_str);
+                return write!(f, "{}:\n{}", mangle(labl), codes_str);
             }
             Prog (cfgs) => {
                 let mut codes_str = String::new();
@@ -1,9 +1,19 @@
 use std::process::Command;
-use crate::compiler::compile;
-use crate::syntax::Expr;
-
-
-fn run_helper(filename: &str) -> String {
+use crate::compiler::*;
+use crate::syntax::{Scheme, Asm, mangle, HashMap};
+use crate::parser::{Parser, IlParser, read};
+use crate::interp::{Answer, LETREC_ERROR};
+use crate::pretty::PrettyPrinter;
+use crate::fuzz::{Features, Fuzzer};
+use crate::snapshot::{SNAPSHOTS, check_snapshots, diff};
+use crate::corpus::{CORPUS, Backend, Case, Expect, parse_case, run_case, run_corpus};
+use crate::emulator::Emulator;
+use crate::encoder::Encoder;
+use crate::elf::elf_section;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::jit::{Jit, Value};
+
+fn build_helper(filename: &str) -> String {
     let obj: Vec<&str> = filename.split(".").collect();
     let stem = format!("test_{}", &obj[0]);
     let output = Command::new("gcc")
@@ -15,7 +25,12 @@ fn run_helper(filename: &str) -> String {
                     .output()
                     .expect("failed to execute process");
     println!("{}", String::from_utf8_lossy(&output.stderr));
-    let output = Command::new(stem).output().expect("failed to execute process");
+    return stem;
+}
+
+fn run_helper(filename: &str) -> String {
+    let stem = build_helper(filename);
+    let output = Command::new(format!("./{}", stem)).output().expect("failed to execute process");
     return String::from_utf8_lossy(&output.stdout).to_string();
 }
 
@@ -25,1166 +40,503 @@ fn test_helper(program: &str, filename: &str, expect: &str) {
     assert_eq!(r.as_str().trim(), expect);
 }
 
-
-#[test]
-fn compile1_1() {
-    let s = "'7";
-    test_helper(s, "1-1.s", "7");
-    let s = "'()";
-    test_helper(s, "1-2.s", "()");
-    let s = "'#f";
-    test_helper(s, "1-3.s", "#f");
-    let s = "'(1 2 3 4)";
-    test_helper(s, "1-4.s", "(1 2 3 4)");
-}
-
-#[test]
-fn compile1_2() {
-    let s = "'#5(5 4 3 2 1)";
-    test_helper(s, "1-5.s", "#(5 4 3 2 1)");
-    let s = "'#2((1 2) (3 4))";
-    test_helper(s, "1-6.s", "#((1 2) (3 4))");
-    let s = "'(#2(1 2) #2(3 4))";
-    test_helper(s, "1-7.s", "(#(1 2) #(3 4))");
-    let s = "'(#3(#t #f 1) #3(#f #t 2))";
-    test_helper(s, "1-8.s", "(#(#t #f 1) #(#f #t 2))");
-}
-
-#[test]
-fn compile1_3() {
-    let s = "(let ([t.496 '10]) (if t.496 t.496 '#f))";
-    test_helper(s, "1-9.s", "10");
-    let s = "(if '#t (if '45 '7 '#f) '#f)";
-    test_helper(s, "1-10.s", "7");
-    let s = "(+ '4 '5)";
-    test_helper(s, "1-11.s", "9");
-    let s = "(- '1 '4)";
-    test_helper(s, "1-12.s", "-3");
-    let s = "(* '7 '9)";
-    test_helper(s, "1-13.s", "63");
-}
-
-#[test]
-fn compile1_4() {
-    let s = "(cons '1 '())";
-    test_helper(s, "1-14.s", "(1)");
-    let s = "(car '(1 2))";
-    test_helper(s, "1-15.s", "1");
-    let s = "(cdr '(1 2))";
-    test_helper(s, "1-16.s", "(2)");
-    let s = "(if '#t '1 '2)";
-    test_helper(s, "1-17.s", "1");
-    let s = "(pair? '(1 2))";
-    test_helper(s, "1-18.s", "#t");
-    let s = "(pair? '())";
-    test_helper(s, "1-19.s", "#f");
-    let s = "(vector? '#2(1 2))";
-    test_helper(s, "1-20.s", "#t");
-    let s = "(vector? '(1 2))";
-    test_helper(s, "1-21.s", "#f");
-    let s = "(boolean? '#f)";
-    test_helper(s, "1-22.s", "#t");
-    let s = "(boolean? '7)";
-    test_helper(s, "1-23.s", "#f");
-    let s = "(null? '())";
-    test_helper(s, "1-24.s", "#t");
-    let s = "(null? '(1 2))";
-    test_helper(s, "1-25.s", "#f");
-    let s = "(fixnum? '1234)";
-    test_helper(s, "1-26.s", "#t");
-    let s = "(fixnum? '())";
-    test_helper(s, "1-27.s", "#f");
-    let s = "(procedure? '7)";
-    test_helper(s, "1-29.s", "#f");
-}
-
-#[test]
-fn compile1_42() {
-    let s = "(procedure? (lambda (x) x))";
-    test_helper(s, "1-28.s", "#t");
-}
-
-#[test]
-fn compile1_5() {
-    let s = "(<= '1 '8)";
-    test_helper(s, "1-30.s", "#t");
-    let s = "(<= '8 '1)";
-    test_helper(s, "1-31.s", "#f");
-    let s = "(<= '1 '1)";
-    test_helper(s, "1-32.s", "#t");
-    let s = "(< '8 '1)";
-    test_helper(s, "1-33.s", "#f");
-    let s = "(< '1 '8)";
-    test_helper(s, "1-34.s", "#t");
-    let s = "(= '1 '1)";
-    test_helper(s, "1-35.s", "#t");
-    let s = "(= '1 '0)";
-    test_helper(s, "1-36.s", "#f");
-    let s = "(>= '8 '1)";
-    test_helper(s, "1-37.s", "#t");
-    let s = "(>= '1 '8)";
-    test_helper(s, "1-38.s", "#f");
-    let s = "(>= '1 '1)";
-    test_helper(s, "1-39.s", "#t");
-    let s = "(> '8 '1)";
-    test_helper(s, "1-40.s", "#t");
-    let s = "(> '1 '8)";
-    test_helper(s, "1-41.s", "#f");
-    let s = "(if '#f '#f '#t)";
-    test_helper(s, "1-42.s", "#t");
-    let s = "(if '10 '#f '#t)";
-    test_helper(s, "1-43.s", "#f");
-}
-
-#[test]
-fn compile2() {
-    let s = "
-    (let ([f.1 (lambda () '(1 . 2))])
-        (eq? (f.1) (f.1)))";
-    test_helper(s, "c2.s", "#t");
-}
-
-#[test]
-fn compile3() {
-    let s = "
-    (let ([f.1 (lambda () '#2(1 2))])
-        (eq? (f.1) (f.1)))";
-    test_helper(s, "c3.s", "#t");
-}
-
-#[test]
-fn compile4() {
-    let s = "
-    (let ([f.1 (lambda () '(#2(1 2) #2(1 2) (1 2)))])
-        (eq? (f.1) (f.1)))";
-    test_helper(s, "c4.s", "#t");
-}
-
-#[test]
-fn compile5() {
-    let s = "
-    (letrec ([f.1 (lambda (x.2) 
-                    (begin
-                      (set! f.1 x.2)
-                      f.1))])
-      (f.1 '10))";
-    test_helper(s, "c5.s", "10");
-}
-
-#[test]
-fn compile6() {
-    let s = "    
-    (letrec ([x.1 (lambda () (begin (set! x.1 '2) '1))])
-      (let ([y.2 (x.1)])
-        (let ([z.3 x.1])
-          (cons y.2 z.3))))";
-    test_helper(s, "c6.s", "(1 . 2)");
-}
-
-#[test]
-fn compile7_1() {
-    let s = "
-    (letrec ([x.1 (lambda () (f.2))]
-             [f.2 (lambda () '10)])
-      (x.1))";
-    test_helper(s, "c7-1.s", "10");
-}
-
-
-#[test]
-fn compile7_2() {
-    let s = "
-    (letrec ([x.1 (lambda () (begin (set! f.2 '10) f.2))]
-             [f.2 (lambda () '10)])
-      (x.1))";
-    test_helper(s, "c7-2.s", "10");
-}
-
-#[test]
-fn compile8_useful_for_uncover_assigned() {
-    let s = "
-    (let ([x.3 '10] [y.1 '11] [z.2 '12])
-      (let ([f.9 (lambda (u.7 v.6)
-                    (begin
-                      (set! x.3 u.7)
-                      (+ x.3 v.6)))]
-            [g.8 (lambda (r.5 s.4)
-                    (begin
-                      (set! y.1 (+ z.2 s.4))
-                      y.1))])
-        (* (f.9 '1 '2) (g.8 '3 '4))))";
-    test_helper(s, "c8-1.s", "48");
-    let s = "
-    (let ([x.3 '10] [y.1 '11] [z.2 '12])
-      (let ([f.7 '#f]
-            [g.6 (lambda (r.5 s.4)
-                    (begin
-                      (set! y.1 (+ z.2 s.4))
-                        y.1))])
-        (begin
-          (set! f.7 (lambda (u.9 v.8)
-                        (begin
-                          (set! v.8 u.9)
-                          (+ x.3 v.8))))
-          (* (f.7 '1 '2) (g.6 '3 '4)))))";
-    test_helper(s, "c8-2.s", "176");
-}
-
-#[test]
-fn compile9() {
-    let s = "    
-    (letrec ([filter.1 (lambda (pred?.2 ls.3)
-                         (if (null? ls.3) 
-                             '()
-                             (if (pred?.2 (car ls.3))
-                                 (filter.1 pred?.2 (cdr ls.3))
-                                 (cons (car ls.3) 
-                                       (filter.1 pred?.2 (cdr ls.3))))))])
-      (filter.1 (lambda (x.4) (< x.4 '0)) '(3 -5 91 6 -32 8)))";
-    test_helper(s, "c9.s", "(3 91 6 8)");
-}
-
-#[test]
-fn compile10() {
-    let s = "    
-    (letrec ([add.0 (lambda (n.2)
-                      (lambda (n.3)
-                        (+ n.2 n.3)))]
-             [map.1 (lambda (fn.4 ls.5)
-                      (if (null? ls.5)
-                          '()
-                          (cons (fn.4 (car ls.5)) (map.1 fn.4 (cdr ls.5)))))]
-             [map.9 (lambda (fn.10 fnls.11 ls.12)
-                      (if (null? ls.12)
-                          '()
-                          (cons (fn.10 (car fnls.11) 
-                                       (car ls.12)) 
-                                (map.9 fn.10 (cdr fnls.11) (cdr ls.12)))))])
-      (let ([ls.6 '(1 2 3 4 5 6)])
-        (map.9 (lambda (fn.7 elem.8) (fn.7 elem.8)) 
-            (map.1 add.0 ls.6) ls.6)))";
-    test_helper(s, "c10.s", "(2 4 6 8 10 12)");
-}
-
-
-#[test]
-fn compile11() {
-    let s = "    
-    (let ([x.1 '(4)]
-          [y.2 '(1 2 3)]
-          [v.4 '#3(0)])
-      (letrec ([z.3 (cons y.2 x.1)])
-        (begin
-          (vector-set! v.4 '0 z.3)
-          (set! x.1 '(3))
-          (vector-set! v.4 '1 z.3)
-          (vector-set! v.4 '2 (cons y.2 x.1))
-          v.4)))";
-    test_helper(s, "c11.s", "#(((1 2 3) 4) ((1 2 3) 4) ((1 2 3) 3))");
-}
-
-#[test]
-fn compile11_2() {
-    let s = "
-    (let ([v.4 '#3(0)])
-        v.4)";
-    test_helper(s, "c11-2.s", "#(0 #<void> #<void>)");
-}
-
-#[test]
-fn compile12() {
-    let s = "
-    (let ([x.1 '0])
-      (begin
-        (let ([x.2 '1])
-          (begin 
-            (set! x.1 (+ x.1 '1))
-            (set! x.2 (+ x.2 x.1))
-            x.2))))";
-    test_helper(s, "c12.s", "2");
-}
-
-#[test]
-fn compile13() {
-    let s = "    
-    (cons
-      (let ([f.463 (lambda (h.462 v.461) (* h.462 v.461))])
-        (let ([k.465 (lambda (x.464) (+ x.464 '5))])
-          (letrec ([x.466 '15])
-            (letrec ([g.467 (lambda (x.468) (+ '1 x.468))])
-              (k.465 (g.467 (let ([g.469 '3]) (f.463 g.469 x.466))))))))
-      '())";
-    test_helper(s, "c13.s", "(51)");
-}
-
-#[test]
-fn compile14() {
-    let s = "
-    (letrec ([x.1 '15])
-      x.1)";
-    test_helper(s, "c14.s", "15");
-}
-
-#[test]
-fn compile15() {
-    let s = "(or 10 #f)";
-    test_helper(s, "c15-1.s", "10");
-    let s = "(and #t 45 7)";
-    test_helper(s, "c15-2.s", "7");
-}
-
-#[test]
-fn compile15_3() {
-    let s = "(or () #f)";
-    test_helper(s, "c15-3.s", "()");
-    let s = "(and #t 45 7 '#(1 2 3))";
-    test_helper(s, "c15-4.s", "#(1 2 3)");
-}
-
-
-#[test]
-fn compile16() {
-    let s = "(if (+ 3 5) '7 8)";
-    test_helper(s, "c16-1.s", "7");
-    let s = "(let ([x 5]) (+ 3 x) x)";
-    test_helper(s, "c16-2.s", "5");
-    let s = "(if (cdr (cons #t #f)) 7 8)";
-    test_helper(s, "c16-3.s", "8");
-}
-
-#[test]
-fn compile17() {
-    let s = "    
-    ((letrec ([length (lambda (ptr)
-                        (if (null? ptr) 0 (+ 1 (length (cdr ptr)))))])
-       length)
-     '(5 10 11 5 15))";
-    test_helper(s, "c17.s", "5");
-}
-
-#[test]
-fn compile18() {
-    let s = "    
-    (letrec ([count-leaves (lambda (p)
-                             (if (pair? p)
-                                 (+ (count-leaves (car p))
-                                    (count-leaves (cdr p)))
-                                 1))])
-      (count-leaves 
-        (cons 
-          (cons '0 (cons '0 '0))
-          (cons 
-            (cons (cons (cons '0 (cons '0 '0)) '0) '0)
-            (cons 
-              (cons (cons '0 '0) (cons '0 (cons '0 '0)))
-              (cons (cons '0 '0) '0))))))";
-    test_helper(s, "c18.s", "16");
-}
-
 #[test]
-fn compile19() {
-    let s = "    
-    (letrec ([make-param (lambda (val)
-                           (let ([x val])
-                             (letrec ([param (lambda (set val)
-                                               (if set (set! x val) x))])
-                               param)))])
-      (let ([p (make-param 10)])
-        (p #t 15)
-        (p #f #f)))";
-    test_helper(s, "c19.s", "15");
-}
-
-#[test]
-fn compile20() {
-    let s = "    
-    (let ([x 0])
-      (letrec ([inc (lambda () (set! x (+ x 1)))]
-               [dec (lambda () (set! x (- x 1)))])
-        (inc) (dec) (dec) (inc) (inc) (inc) (dec) (inc) x))";
-    test_helper(s, "c20.s", "2");
-}
-
-#[test]
-fn compile21() {
-    let s = "
-    ((((((lambda (x)
-            (lambda (y)
-              (lambda (z)
-                (lambda (w)
-                  (lambda (u)
-                    (+ x (+ y (+ z (+ w u)))))))))
-         5) 6) 7) 8) 9)";
-    test_helper(s, "c21.s", "35");
-}
-
-#[test]
-fn compile22() {
-    let s = "
-    (letrec ([num-list? (lambda (ls)
-                          (if (null? ls)
-                              #t
-                              (if (fixnum? (car ls))
-                                  (num-list? (cdr ls))
-                                  #f)))]
-             [list-product (lambda (ls)
-                             (if (null? ls)
-                                 1
-                                 (* (car ls) (list-product (cdr ls)))))])
-      (let ([ls '(1 2 3 4 5)])
-        (if (num-list? ls) (list-product ls) #f)))";
-    test_helper(s, "c22.s", "120");
-}
-
-#[test]
-#[should_panic()]
-fn compile23() {
-    let s = "    
-    (let ([quote (lambda (x) x)]
-          [let (lambda (x y) (- y x))]
-          [if (lambda (x y z) (cons x z))]
-          [cons (lambda (x y) (cons y x))]
-          [+ 16])
-      (set! + (* 16 2))
-      (cons (let ((quote (lambda () 0))) +)
-            (if (quote (not #f)) 720000 -1)))";
-    test_helper(s, "c23.s", "???");
-}
-
-#[test]
-#[should_panic()]
-fn compile24() {
-    let s = "    
-    (let ([begin (lambda (x y) (+ x y))]
-          [set! (lambda (x y) (* x y))])
-      (let ([lambda (lambda (x) (begin 1 x))])
-        (let ([lambda (lambda (set! 1 2))])
-          (let ([let (set! lambda lambda)])
-            (begin let (set! lambda (set! 4 (begin 2 3))))))))";
-    test_helper(s, "c24.s", "???");
-}
-
-#[test]
-fn compile25() {
-    let s = "'(#(#t #f 1) #(#f #t 2))";
-    test_helper(s, "c25.s", "(#(#t #f 1) #(#f #t 2))");
-}
-
-#[test]
-fn compile26() {
-    let s = "'#(1 2 3)";
-    test_helper(s, "c26-1.s", "#(1 2 3)");
-    let s = "'#((1 2) 3)";
-    test_helper(s, "c26-2.s", "#((1 2) 3)");
-}
-
-#[test]
-fn compile27() {
-    let s = "(not #f)";
-    test_helper(s, "c27-1.s", "#t");
-    let s = "(not 10)";
-    test_helper(s, "c27-2.s", "#f");
-}
-
-#[test]
-fn compile28_1() {
-    let s = "(let ([v (make-vector 2)]) (vector-length v) 7)";
-    test_helper(s, "28-1.s", "7");
-}
-#[test]
-fn compile28_2() {
-    let s = "(let ([v (make-vector 2)]) (vector-ref v 0) 7)";
-    test_helper(s, "28-2.s", "7");
-}
-
-#[test]
-fn compile28_3() {
-    let s = "(letrec () (= 7 8) 7)";
-    test_helper(s, "28-3.s", "7");
-}
-
-#[test]
-fn compile28_4() {
-    let s = "((lambda (x) (+ 1 2) (+ 1 x)) 10)";
-    test_helper(s, "28-4.s", "11");
-}
-
-#[test]
-fn compile29() {
-    let s = "(let ([x 10]) (begin (+ 1 x)))";
-    test_helper(s, "29.s", "11");
-}
-
-#[test]
-fn compile30() {
-    let s = "(if 10 20)";
-    test_helper(s, "30-1.s", "20");
-    let s = "(if #f 20)";
-    test_helper(s, "30-2.s", "#<void>");
-}
-
-#[test]
-fn compile31() {
-    let s = "
-    (let ([x 10])
-      (let ([x (lambda (x) x)])
-        (x 2)))";
-    test_helper(s, "31-1.s", "2");
-    let s = "
-    (let ([x 10])
-      (letrec ([x (lambda () x)])
-        (x)))";
-    test_helper(s, "31-2.s", "#<procedure>");
-}
-
-
-#[test]
-#[should_panic()]
-fn compile32() {
-    let s = "
-    (let ([x 10])
-      (let ([y (lambda (z) 
-                  (if (< z 0)
-                      1
-                      (+ 1 (y (- z 1)))))])
-        (y x)))";
-    test_helper(s, "c32.s", "12");
-}
-
-#[test]
-fn compile33() {
-    let s = "
-    (let ([x 10])
-      (letrec ([y (lambda (z) 
-                  (if (< z 0)
-                      1
-                      (+ 1 (y (- z 1)))))])
-        (y x)))";
-    test_helper(s, "c33.s", "12");
+fn compile47_prune() {
+    fn labels(program: &str, filename: &str) -> usize {
+        compile(program, filename).unwrap();
+        let asm = std::fs::read_to_string(filename).unwrap();
+        asm.lines().filter(|l| l.ends_with(":")).count()
+    }
+    // a shadowed name does not link the prelude
+    assert_eq!(labels("(let ([map 1]) map)", "c47-6.s"), labels("(let ([x 1]) x)", "c47-7.s"));
+    // member brings equal? along
+    assert!(labels("(member 1 '(1 2))", "c47-8.s") > labels("(memq 1 '(1 2))", "c47-9.s"));
+}
+
+#[test]
+fn compile53() {
+    // labels are assembler safe and distinct
+    let labels = vec!["a-b", "a_b", "a$b", "a$$b", "a$2d$b", "a*b", "a<b>", "a?", "a!", "λ", "5a", ".a", "$5a", "_scheme_entry"];
+    let mangled: Vec<String> = labels.iter().map(|l| mangle(l)).collect();
+    for (i, m) in mangled.iter().enumerate() {
+        assert!(m.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.'), "{}", m);
+        assert!(!m.starts_with(|c: char| c.is_ascii_digit() || c == '$'), "{}", m);
+        assert!(!mangled[..i].contains(m), "{} collides", m);
+    }
+    assert_eq!(mangle("_scheme_entry"), "_scheme_entry");
+}
+
+// a dump reads back to the same tree at any width
+fn roundtrip_helper(scm: Scheme) -> Scheme {
+    let mut back = scm;
+    for width in [20, 80, 1000] {
+        let text = format!("{:width$}", back, width = width);
+        let again = IlParser{}.scheme(&read(&text)[0]);
+        assert_eq!(again, back, "{}", text);
+        back = again;
+    }
+    return back;
+}
+
+#[test]
+fn compile54() {
+    let s = "(letrec ([f (lambda (x . ls) (if (null? ls) x (apply f ls)))]
+                      [v '#3((1 . #t) #(2 3))])
+               (let ([b (box 1)])
+                 (set-box! b (f 1 2 3))
+                 (cons (and 1 (or #f (not 2))) (cons (unbox b) v))))";
+    let scm = roundtrip_helper(ParseScheme{}.run(s));
+    let scm = roundtrip_helper(IntroduceLibrary{}.run(scm));
+    let scm = roundtrip_helper(UniquifyVariable{}.run(scm));
+    let scm = roundtrip_helper(ConvertComplexDatum{}.run(scm));
+    let scm = roundtrip_helper(UncoverAssigned{}.run(scm));
+    let scm = roundtrip_helper(PurifyLetrec{}.run(scm));
+    let scm = roundtrip_helper(ConvertAssignment{}.run(scm));
+    let scm = roundtrip_helper(OptimizeDirectCall{}.run(scm));
+    let scm = roundtrip_helper(RemoveAnonymousLambda{}.run(scm));
+    let scm = roundtrip_helper(SanitizeBindingForms{}.run(scm));
+    let scm = roundtrip_helper(UncoverFree{}.run(scm));
+    let scm = roundtrip_helper(ConvertClosure{}.run(scm));
+    let scm = roundtrip_helper(OptimizeKnownCall{}.run(scm));
+    let scm = roundtrip_helper(IntroduceProceduraPrimitives{}.run(scm));
+    let scm = roundtrip_helper(LiftLetrec{}.run(scm));
+    let scm = roundtrip_helper(NormalizeContext{}.run(scm));
+    let scm = roundtrip_helper(SpecifyRepresentation{}.run(scm));
+    let scm = roundtrip_helper(UncoverLocals{}.run(scm));
+    let scm = roundtrip_helper(RemoveLet{}.run(scm));
+    // lines stay within the width where they can
+    let text = format!("{:40}", scm);
+    assert!(text.lines().count() > 1);
+    // the assembly reads back too
+    let asm = Asm::Prog (vec![Asm::Cfg ("f$1".to_string(), vec![
+        Asm::Op2 ("movq".to_string(), Box::new(Asm::Imm (-8)), Box::new(Asm::RAX)),
+        Asm::Op2 ("movq".to_string(), Box::new(Asm::Deref (Box::new(Asm::RBP), 16)), Box::new(Asm::R8)),
+        Asm::Op2 ("leaq".to_string(), Box::new(Asm::DerefLabel (Box::new(Asm::RIP), Box::new(Asm::Label ("a-b?$2".to_string())))), Box::new(Asm::R15)),
+        Asm::Op2 ("movq".to_string(), Box::new(Asm::DerefRegister (Box::new(Asm::RDX), Box::new(Asm::RCX))), Box::new(Asm::R9)),
+        Asm::Jmpif ("le".to_string(), Box::new(Asm::Label ("g$2".to_string()))),
+        Asm::Push (Box::new(Asm::RBX)),
+        Asm::Pop (Box::new(Asm::RBX)),
+        Asm::Callq (Box::new(Asm::Label ("_scheme_error".to_string()))),
+        Asm::Jmp (Box::new(Asm::R15)),
+        Asm::Retq,
+    ])]);
+    for width in [10, 80] {
+        let text = PrettyPrinter::new(width).asm(&asm);
+        let back = IlParser{}.asm(&read(&text)[0]);
+        assert_eq!(format!("{}", back), format!("{}", asm));
+        assert_eq!(PrettyPrinter::new(width).asm(&back), text);
+    }
+}
+
+// the dump of the program before each pass, the first time it runs
+fn dump_helper(s: &str) -> HashMap<&'static str, String> {
+    let mut dumps = HashMap::default();
+    let program = Program::Scheme (ParseScheme{}.run(s));
+    // the input of a pass is the output of the one before
+    let mut input = format!("{}", program);
+    run_passes("IntroduceLibrary", program, false, |pass, program| {
+        dumps.entry(PASSES[pass_index(pass)]).or_insert(input.clone());
+        input = format!("{}", program);
+        if let Program::Expr (expr) = program {
+            for width in [30, 80] {
+                let text = format!("{:width$}", expr, width = width);
+                assert_eq!(&IlParser{}.expr(&read(&text)[0]), expr, "{}", text);
+            }
+        }
+    });
+    return dumps;
+}
+
+#[test]
+fn compile55() {
+    let s = "(letrec ([spill (lambda (x)
+                                (let ([a (+ x 1)] [b (+ x 2)] [c (+ x 3)] [d (+ x 4)] [e (+ x 5)] [f (+ x 6)] [g (+ x 7)] [h (+ x 8)]
+                                      [i (+ x 9)] [j (+ x 10)] [k (+ x 11)] [l (+ x 12)] [m (+ x 13)] [n (+ x 14)] [o (+ x 15)] [p (+ x 16)])
+                                  (+ a (+ b (+ c (+ d (+ e (+ f (+ g (+ h (+ i (+ j (+ k (+ l (+ m (+ n (+ o p)))))))))))))))))])
+               (let ([v (make-vector 2)])
+                 (vector-set! v 0 (spill 0))
+                 (vector-set! v 1 (lambda (y) (* y (spill y))))
+                 (cons ((vector-ref v 1) 2) v)))";
+    test_helper(s, "c55-0.s", "(336 . #(136 #<procedure>))");
+    let dumps = dump_helper(s);
+    // a spill makes the allocation loop run twice
+    assert!(dumps.contains_key("AssignFrame"));
+    let passes = ["IntroduceLibrary", "ConvertClosure", "CompileToExpr", "ImposeCallingConvention",
+                  "SelectInstructions", "AssignFrame", "FinalizeLocations", "ExposeBasicBlocks", "CompileToAsm"];
+    for (i, pass) in passes.iter().enumerate() {
+        let filename = format!("c55-{}.s", i + 1);
+        compile_from(pass, &dumps[pass], &filename);
+        assert_eq!(run_helper(&filename).trim(), "(336 . #(136 #<procedure>))", "{}", pass);
+    }
+}
+
+// every pass keeps the answer of the program, and the binary prints it too
+fn check_helper(program: &str, filename: &str, expect: &str) {
+    compile_checked(program, filename);
+    let r = run_helper(filename);
+    assert_eq!(r.as_str().trim(), expect);
 }
 
 #[test]
-fn compile34() {
-    let s = "
-    (let ([x 1])
-      (letrec ([x 2]
-               [f (lambda (z) (+ x z))]
-               [even? (lambda (n) (if (= 0 n) #t (odd? (- n 1))))]
-               [odd? (lambda (n) (if (= 1 n) #t (even? (- n 1))))])
-        (let ([c (f 2)])
-          (even? c))))";
-    test_helper(s, "c34.s", "#t");
+fn compile56() {
+    // a continuation entered again after its call/cc returned
+    let s = "(let ([k #f] [n 0])
+               (let ([x (call/cc (lambda (c) (set! k c) 0))])
+                 (set! n (+ n 1))
+                 (if (< x 3) (k (+ x 1)) (cons x n))))";
+    check_helper(s, "c56-1.s", "(3 . 4)");
+    let s = "(letrec ([f (lambda (a . rest) (apply + a rest))])
+               (define-record-type point (make-point x y) point? (x point-x set-point-x!) (y point-y))
+               (let ([p (make-point 1 2)])
+                 (set-point-x! p 10)
+                 (cons (with-exception-handler (lambda (e) (+ e 1)) (lambda () (+ 1 (raise-continuable (point-x p)))))
+                       (cons (call-with-values (lambda () (values 1 2)) (lambda (a b) (f a b)))
+                             (cons p (point? p))))))";
+    check_helper(s, "c56-2.s", "(12 3 #<record point> . #t)");
+    let s = "(letrec ([spill (lambda (x)
+                                (let ([a (+ x 1)] [b (+ x 2)] [c (+ x 3)] [d (+ x 4)] [e (+ x 5)] [f (+ x 6)] [g (+ x 7)] [h (+ x 8)]
+                                      [i (+ x 9)] [j (+ x 10)] [k (+ x 11)] [l (+ x 12)] [m (+ x 13)] [n (+ x 14)] [o (+ x 15)] [p (+ x 16)])
+                                  (+ a (+ b (+ c (+ d (+ e (+ f (+ g (+ h (+ i (+ j (+ k (+ l (+ m (+ n (+ o p)))))))))))))))))])
+               (cons (spill 0) (spill 1)))";
+    check_helper(s, "c56-3.s", "(136 . 152)");
+}
+
+#[test]
+fn compile57() {
+    // the interpreters of all the languages give the error of the runtime
+    let s = "(let ([ls '(2 3)]) (if (pair? ls) (error 0 1 ls) 0))";
+    run_passes("IntroduceLibrary", Program::Scheme (ParseScheme{}.run(s)), false, |pass, program| {
+        if let Some (answer) = interpret(pass, program) {
+            assert_eq!(answer, Answer::Error ("Error: 0 1 (2 3)".to_string()), "{}", pass);
+        }
+    });
+}
+
+#[test]
+fn compile58() {
+    // a record type among the definitions of the prelude, a field may be named like the type
+    let defs = Parser::new().parse_definitions(&read("(define-record-type point (make-point point y) point? (point point-x set-point-x!) (y point-y))"));
+    let names: Vec<&str> = defs.iter().map(|(name, _)| name.as_str()).collect();
+    for name in ["point", "make-point", "point?", "point-x", "set-point-x!", "point-y"] {
+        assert!(names.contains(&name), "{} is not defined", name);
+    }
+    let body = Parser::new().parse(&read("(let ([p (make-point 1 2)]) (set-point-x! p 3) (cons p (cons (point-x p) (point? p))))")[0]);
+    let program = Program::Scheme (Scheme::Letrec (defs.into_iter().collect(), Box::new(body)));
+    match run_passes("UniquifyVariable", program, true, |_, _| ()) {
+        Program::Asm (asm) => assert_eq!(Emulator{}.run(&asm), Answer::Value ("(#<record point> 3 . #t)".to_string())),
+        program => panic!("expect assembly, found {}", program),
+    }
+}
+
+#[test]
+fn compile59() {
+    // the interpreter binds a letrec like PurifyLetrec, the lambdas first and the others once all are done
+    check_helper("(letrec ([x ((lambda () (f)))] [f (lambda () 1)]) x)", "c59-1.s", "1");
+    check_helper("(letrec ([f (lambda () 2)] [x ((lambda () (f)))]) (begin (set! f (lambda () 3)) (cons x (f))))", "c59-2.s", "(2 . 3)");
+    let errors = [
+        "(letrec ([a 1] [b ((lambda () a))]) b)",
+        "(letrec ([a (cons 1 2)] [b ((lambda () (set! a 2) 0))]) (cons b a))",
+        "(letrec ([f (lambda () x)] [x ((lambda () (f)))]) x)",
+    ];
+    for s in errors {
+        let program = Program::Scheme (ParseScheme{}.run(s));
+        assert_eq!(interpret("IntroduceLibrary", &program), Some (Answer::Error (LETREC_ERROR.to_string())), "{}", s);
+        compile_checked(s, "c59-3.s");
+    }
 }
 
-
 // invalid tests
-#[test]
-#[should_panic()]
-fn invalid1() {
-    let s = "'(#(a b c)";
-    test_helper(s, "i1.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid2() {
-    let s = "5.5";
-    test_helper(s, "i2.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid3() {
-    let s = "#\\a";
-    test_helper(s, "i3.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid4() {
-    let s = "\"test\"";
-    test_helper(s, "i4.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid5() {
-    let s = "quote";
-    test_helper(s, "i5.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid6() {
-    let s = "(quote)";
-    test_helper(s, "i6.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid7() {
-    let s = "(quote 1 2)";
-    test_helper(s, "i7.s", "!");
-}
-
-
-#[test]
-#[should_panic()]
-fn invalid8() {
-    let s = "foo";
-    test_helper(s, "i8.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid9() {
-    let s = "set!";
-    test_helper(s, "i9.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid10() {
-    let s = "(set! set! 3)";
-    test_helper(s, "i10.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid11() {
-    let s = "(set! 1 2)";
-    test_helper(s, "i11.s", "!");
-}
-
 
 #[test]
-#[should_panic()]
-fn invalid12() {
-    let s = "(set! foo 1)";
-    test_helper(s, "i12.s", "!");
+#[should_panic(expected = "Unknown pass AssignRegisters")]
+fn invalid98() {
+    let s = "(letrec () (locals () (begin (set! rax 8) (r15 rbp rax))))";
+    compile_from("AssignRegisters", s, "i98.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid13() {
-    let s = "(let ((foo 0)) (set! foo))";
-    test_helper(s, "i13.s", "!");
+#[should_panic(expected = "mset! expects 3 operands at line 1, col 33")]
+fn invalid99() {
+    let s = "(letrec () (locals (x.1) (begin (mset! x.1 8) (r15 rbp rax))))";
+    compile_from("RemoveComplexOpera", s, "i99.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid14() {
-    let s = "(let ((foo 0)) (set! foo 1 2))";
-    test_helper(s, "i14.s", "!");
+#[should_panic(expected = "FlattenSet changed the answer from 3 to 2")]
+fn invalid100() {
+    let s = "(letrec () (locals () (+ 8 8)))";
+    let program = Program::Expr (IlParser{}.expr(&read(s)[0]));
+    check_pass("FlattenSet", &program, &Answer::Value ("3".to_string()));
 }
 
 #[test]
-#[should_panic()]
-fn invalid15() {
-    let s = "(if 1)";
-    test_helper(s, "i15.s", "!");
+#[should_panic(expected = "CompileToExpr produced an invalid program: in the body, x.1 is not declared in locals")]
+fn invalid101() {
+    let s = "(letrec () (locals () (+ x.1 8)))";
+    compile_from("RemoveComplexOpera", s, "i101.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid16() {
-    let s = "(if 1 2 3 4)";
-    test_helper(s, "i16.s", "!");
+#[should_panic(expected = "NormalizeContext produced an invalid program: in the body, g$2 is unbound")]
+fn invalid102() {
+    let s = "(letrec ([f$1 (lambda (x.1) (+ x.1 '1))]) (g$2 '2))";
+    compile_from("SpecifyRepresentation", s, "i102.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid17() {
-    let s = "(begin)";
-    test_helper(s, "i17.s", "!");
+#[should_panic(expected = "ImposeCallingConvention produced an invalid program: in the body, it is not wrapped in (locals (new-frames ...))")]
+fn invalid103() {
+    let s = "(letrec () (locals () (begin (set! rax 8) (r15 rbp rax))))";
+    compile_from("UncoverFrameConflict", s, "i103.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid18() {
-    let s = "(let (foo 3) foo)";
-    test_helper(s, "i18.s", "!");
+#[should_panic(expected = "SelectInstructions produced an invalid program: in the body, (set! fv0 fv1) moves memory to memory")]
+fn invalid104() {
+    let s = "(letrec () (locals () (ulocals () (locate () (frame-conflict () (begin (set! fv0 fv1) (r15 rbp rax)))))))";
+    compile_from("UncoverRegisterConflict", s, "i104.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid19() {
-    let s = "(let ([foo 3 4]) foo)";
-    test_helper(s, "i19.s", "!");
+#[should_panic(expected = "FlattenProgram produced an invalid program: in f$1, the last block falls off the end")]
+fn invalid105() {
+    let s = "(letrec ([f$1 (lambda () (begin (set! rax 8) (nop)))]) (begin (set! rax 8) (f$1)))";
+    compile_from("CompileToAsm", s, "i105.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid20() {
-    let s = "(let ([foo 3]))";
-    test_helper(s, "i20.s", "!");
+#[should_panic(expected = "UncoverAssigned produced an invalid program: set! x.1 but x.1 is not marked assigned")]
+fn invalid106() {
+    let s = "(letrec ([f.1 (lambda (x.1) (assigned () (begin (set! x.1 '2) x.1)))]) (f.1 '2))";
+    compile_from("PurifyLetrec", s, "i106.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid21() {
-    let s = "(letrec (foo (lambda (x) x)) foo)";
-    test_helper(s, "i21.s", "!");
+#[should_panic(expected = "AssignFrame produced an unsound allocation: y.2 and x.1 are both in rbx, but x.1 is live at (set! y.2 16) in the body")]
+fn invalid107() {
+    let s = "(letrec () (locate ([x.1 rbx] [y.2 rbx]) (begin (set! x.1 8) (set! y.2 16) (set! x.1 (+ x.1 y.2)) (set! rax x.1) (r15 rbp rax))))";
+    compile_from("DiscardCallLive", s, "i107.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid22() {
-    let s = "(letrec ([foo (lambda (x) x) (lambda (x) x)]) foo)";
-    test_helper(s, "i22.s", "!");
+#[should_panic(expected = "AssignFrame produced an unsound allocation: x.1 is live across the call at rp$2 in the body, but it is in the caller-saved register rbx")]
+fn invalid108() {
+    let s = "(letrec ([f$1 (lambda () (locate () (begin (set! rax 8) (r15 rbp rax))))])
+               (locate ([x.1 rbx] [y.2 fv0])
+                 (begin
+                   (set! y.2 r15)
+                   (set! x.1 8)
+                   (return-point rp$2 (begin (set! r15 rp$2) (f$1 rbp r15)))
+                   (set! rax (+ rax x.1))
+                   (y.2 rbp rax))))";
+    compile_from("DiscardCallLive", s, "i108.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid23() {
-    let s = "(letrec ([foo (lambda (x) x)]))";
-    test_helper(s, "i23.s", "!");
+#[should_panic(expected = "AssignNewFrame produced an unsound allocation: x.1 is live across the call at rp$2 in the body, but fv1 is in the new frame")]
+fn invalid109() {
+    let s = "(letrec ([f$1 (lambda () (locals () (ulocals () (locate () (frame-conflict () (begin (set! rax fv0) (r15 rbp rax)))))))])
+               (locals (x.1 nfv.2)
+                 (ulocals ()
+                   (locate ([x.1 fv1] [nfv.2 fv1])
+                     (frame-conflict ()
+                       (begin
+                         (set! x.1 8)
+                         (return-point rp$2 (begin (set! nfv.2 16) (set! r15 rp$2) (f$1 rbp r15 nfv.2)))
+                         (set! rax x.1)
+                         (r15 rbp rax)))))))";
+    compile_from("FinalizeFrameLocations", s, "i109.s");
 }
 
 #[test]
-#[should_panic()]
-fn invalid24() {
-    let s = "(lambda)";
-    test_helper(s, "i24.s", "!");
+fn fuzz1() {
+    let fuzzer = Fuzzer { features: Features::all(), stem: "fuzz1".to_string() };
+    assert_eq!(fuzzer.generate(7), fuzzer.generate(7));
+    if let Some (report) = fuzzer.run(1, 20) {
+        panic!("{}", report);
+    }
 }
 
 #[test]
-#[should_panic()]
-fn invalid25() {
-    let s = "(lambda (x))";
-    test_helper(s, "i25.s", "!");
+fn fuzz2() {
+    // only the back end is exercised without closures, vectors and assignments
+    let features = Features { closures: false, assignments: false, vectors: false, max_args: 8, ..Features::all() };
+    let fuzzer = Fuzzer { features, stem: "fuzz2".to_string() };
+    if let Some (report) = fuzzer.run(100, 20) {
+        panic!("{}", report);
+    }
 }
 
 #[test]
-#[should_panic()]
-fn invalid26() {
-    let s = "(lambda (x x) x)";
-    test_helper(s, "i26.s", "!");
+fn snapshot1() {
+    // set A15_BLESS to write the snapshots after a deliberate change
+    let failures = check_snapshots(SNAPSHOTS);
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
 }
 
 #[test]
-#[should_panic()]
-fn invalid27() {
-    let s = "(lambda (x 1) x)";
-    test_helper(s, "i27.s", "!");
+fn snapshot2() {
+    let expected = ">>> A\n(f 1)\n\n>>> B\n(f 1)\n(g 2)\n(h 3)\n(i 4)";
+    let actual = ">>> A\n(f 1)\n\n>>> B\n(f 1)\n(g 5)\n(h 3)\n(i 4)";
+    assert_eq!(diff(expected, actual), "@@ B, line 6\n >>> B\n (f 1)\n-(g 2)\n+(g 5)\n (h 3)\n (i 4)");
+    assert_eq!(diff(expected, expected), "");
 }
 
 #[test]
-#[should_panic()]
-fn invalid28() {
-    let s = "(cons 1)";
-    test_helper(s, "i28.s", "!");
+fn corpus1() {
+    // the programs of corpus/, with what each should do in its header, on every backend:
+    // linked by gcc, linked from the objects of the encoder, without gcc, and in the process
+    let backends = [Backend::Native, Backend::Object, Backend::Emulator,
+                    #[cfg(all(target_os = "linux", target_arch = "x86_64"))] Backend::Jit];
+    for backend in backends {
+        let failures = run_corpus(CORPUS, backend);
+        assert!(failures.is_empty(), "{:?}\n{}", backend, failures.join("\n\n"));
+    }
+}
+
+#[test]
+fn corpus2() {
+    let case = parse_case("c", ";; a comment\n;; expect: (1\n;; expect: 2)\n(display 1)").unwrap();
+    assert_eq!(case.expect, Expect::Output ("(1\n2)".to_string()));
+    assert_eq!(case.program, "(display 1)");
+    let case = parse_case("c", ";; compile-error:\n(x)\n").unwrap();
+    assert_eq!(case.expect, Expect::CompileError ("".to_string()));
+    assert!(parse_case("c", ";; expect: 1\n;; error: 2\n1").is_err());
+    assert!(parse_case("c", "1").is_err());
+    // more output than a pipe holds, which is read while the program runs
+    let program = "(letrec ([ones (lambda (n) (if (= n 0) '() (cons 1 (ones (- n 1)))))]
+                            [rows (lambda (n row) (if (= n 0) '() (cons row (rows (- n 1) row))))])
+                     (rows 100 (ones 1000)))";
+    let row = format!("({})", vec!["1"; 1000].join(" "));
+    let expect = Expect::Output (format!("({})", vec![row; 100].join(" ")));
+    let case = Case { name: "output".to_string(), expect, program: program.to_string() };
+    let dir = std::env::temp_dir().join(format!("a15-output-{}", std::process::id()));
+    let result = run_case(&case, &dir, Backend::Native);
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(result, Ok (()));
+}
+
+// the assembly of a program
+fn asm_helper(s: &str) -> Asm {
+    match run_passes("IntroduceLibrary", Program::Scheme (ParseScheme{}.run(s)), false, |_, _| ()) {
+        Program::Asm (asm) => asm,
+        program => panic!("expect assembly, found {}", program),
+    }
+}
+
+fn emulate_helper(s: &str) -> Answer {
+    Emulator{}.run(&asm_helper(s))
+}
+
+#[test]
+fn emulate2() {
+    use Asm::*;
+    assert_eq!(emulate_helper("(cons 1 (cons #t '()))"), Answer::Value ("(1 #t)".to_string()));
+    assert_eq!(emulate_helper("(error 1 2 3)"), Answer::Error ("Error: 1 2 3".to_string()));
+    // the second vector ends past the heap, where a native program writes into another mapping
+    let s = "(let ([v (make-vector 99000)]) (let ([w (make-vector 3000)]) (vector-set! w 2500 5) (vector-ref w 2500)))";
+    match emulate_helper(s) {
+        Answer::Error (msg) => assert!(msg.starts_with("an invalid access 9208 bytes above the heap"), "{}", msg),
+        answer => panic!("expect an invalid access, found {}", answer),
+    }
+    let op2 = |op: &str, src, dst| Op2 (op.to_string(), Box::new(src), Box::new(dst));
+    let prog = |codes| Prog (vec![Cfg ("_scheme_entry".to_string(), codes)]);
+    assert_eq!(Emulator{}.run(&prog(vec![op2("movq", Imm (16), RAX), Retq])), Answer::Value ("2".to_string()));
+    let answer = Emulator{}.run(&prog(vec![op2("movq", Deref (Box::new(RSP), 0), Deref (Box::new(RBP), 0)), Retq]));
+    assert_eq!(answer, Answer::Stuck ("the assembler rejects `movq 0(%rsp), 0(%rbp)` in _scheme_entry".to_string()));
+    let answer = Emulator{}.run(&prog(vec![op2("movq", Imm (0), RBX), Retq]));
+    assert_eq!(answer, Answer::Error ("a return to main without restoring %rbx, by `retq` at _scheme_entry+1".to_string()));
+}
+
+// the .text of the assembly by `as`, and by the encoder
+fn encode_helper(asm: Asm, name: &str) -> (Vec<u8>, Vec<u8>) {
+    let dir = std::env::temp_dir();
+    let file = dir.join(format!("a15-{}-{}.s", name, std::process::id()));
+    let object = file.with_extension("o");
+    let text = Encoder{}.run(&asm);
+    GenerateAsm{}.run(asm, file.to_str().unwrap()).unwrap();
+    let output = Command::new("as").arg("--64").arg("-o").arg(&object).arg(&file).output().expect("failed to execute process");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let bytes = std::fs::read(&object).unwrap();
+    let _ = std::fs::remove_file(&file);
+    let _ = std::fs::remove_file(&object);
+    (elf_section(&bytes, ".text").unwrap().to_vec(), text.bytes)
+}
+
+#[test]
+fn object2() {
+    for name in ["callcc", "closure", "frame", "vector"] {
+        let s = std::fs::read_to_string(format!("{}/{}.ss", SNAPSHOTS, name)).unwrap();
+        let (expected, actual) = encode_helper(asm_helper(&s), name);
+        assert!(expected == actual, "the encoding of {} differs from as", name);
+    }
+    // the operands the compiler seldom emits
+    use Asm::*;
+    let op2 = |op: &str, src, dst| Op2 (op.to_string(), Box::new(src), Box::new(dst));
+    let deref = |reg, n| Deref (Box::new(reg), n);
+    let index = |reg1, reg2| DerefRegister (Box::new(reg1), Box::new(reg2));
+    let mut codes = vec![];
+    let bases = || vec![deref(RSP, 0), deref(RBP, 0), deref(R12, 8), deref(R13, 0), deref(RAX, -8), deref(R15, 4096),
+                    index(RAX, RBX), index(RBP, R8), index(R13, R14), index(R12, RSI)];
+    codes.extend(bases().into_iter().map(|mem| op2("movq", mem, R9)));
+    codes.extend(bases().into_iter().map(|mem| op2("movq", RBX, mem)));
+    codes.extend(bases().into_iter().map(|mem| op2("addq", Imm (7), mem)));
+    for n in [0, 1, -128, 128, 1 << 31, -(1 << 31), 1 << 40] {
+        codes.push(op2("movq", Imm (n), R11));
+        if n as i32 as i64 == n {
+            codes.push(op2("addq", Imm (n), RAX));
+            codes.push(op2("cmpq", Imm (n), R8));
+            codes.push(op2("imulq", Imm (n), R14));
+            codes.push(Push (Box::new(Imm (n))));
+        }
+    }
+    for op in ["addq", "subq", "andq", "orq", "cmpq", "imulq"] {
+        codes.push(op2(op, R12, RDX));
+        codes.push(op2(op, deref(RBP, 16), R9));
+    }
+    codes.extend(vec![
+        op2("sarq", Imm (1), RAX), op2("sarq", Imm (3), R10), op2("leaq", index(RAX, R9), RDI),
+        Push (Box::new(R15)), Pop (Box::new(R15)), Push (Box::new(deref(RBP, 8))), Pop (Box::new(deref(R12, 0))),
+        Jmp (Box::new(R15)), Jmp (Box::new(deref(RBP, 0))), Jmp (Box::new(deref(R8, -2))),
+        Jmpif ("le".to_string(), Box::new(Label ("far".to_string()))),
+        Jmpif ("e".to_string(), Box::new(Label ("_scheme_entry".to_string()))),
+        Callq (Box::new(Label ("_scheme_error".to_string()))),
+    ]);
+    // a jump over more than 127 bytes is long
+    let far: Vec<Asm> = (0..50).map(|_| op2("movq", R13, deref(R14, 8))).collect();
+    let asm = Prog (vec![
+        Cfg ("_scheme_entry".to_string(), codes),
+        Cfg ("near".to_string(), far),
+        Cfg ("far".to_string(), vec![Jmp (Box::new(Label ("near".to_string()))), Retq]),
+    ]);
+    let (expected, actual) = encode_helper(asm, "operands");
+    assert_eq!(expected, actual);
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn jit_helper(s: &str) -> Result<Value, String> {
+    Jit{}.run(asm_helper(s))
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn jit2() {
+    let fixnum = |n| Box::new(Value::Fixnum (n));
+    assert_eq!(jit_helper("(cons 1 (cons #t '()))"),
+               Ok (Value::Pair (fixnum(1), Box::new(Value::Pair (Box::new(Value::Boolean (true)), Box::new(Value::Nil))))));
+    assert_eq!(jit_helper("(let ([v (make-vector 2)]) (vector-set! v 0 0) (vector-set! v 1 (box (void))) v)"),
+               Ok (Value::Vector (vec![Value::Fixnum (0), Value::Box (Box::new(Value::Void))])));
+    assert_eq!(jit_helper("(lambda (x) x)"), Ok (Value::Procedure));
+    // an error goes back to Rust, and the next program runs as well
+    assert_eq!(jit_helper("(error 1 2 3)"), Err ("Error: 1 2 3".to_string()));
+    assert_eq!(jit_helper("(raise 5)"), Err ("Exception: uncaught 5".to_string()));
+    assert_eq!(jit_helper("(+ 1 2)"), Ok (Value::Fixnum (3)));
+    // a fault on a guard page goes back to Rust too
+    let s = "(letrec ([f (lambda (n) (if (= n 0) 0 (+ 1 (f (- n 1)))))]) (f 1000000))";
+    assert_eq!(jit_helper(s), Err ("invalid access just above the stack".to_string()));
+    assert_eq!(jit_helper("(+ 1 2)"), Ok (Value::Fixnum (3)));
+    // a circular list prints like print1, cut at its length
+    let s = "(let ([p (cons 1 '())]) (set-cdr! p p) p)";
+    let value = jit_helper(s).unwrap();
+    assert_eq!(value.to_string(), format!("({} ...)", vec!["1"; 1000].join(" ")));
 }
-
-#[test]
-#[should_panic()]
-fn invalid29() {
-    let s = "(foo 1)";
-    test_helper(s, "i29.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid30() {
-    let s = "(quote . 3)";
-    test_helper(s, "i30.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid31() {
-    let s = "(lambda (x) . y)";
-    test_helper(s, "i31.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid32() {
-    let s = "((lambda (x) x) . 3)";
-    test_helper(s, "i32.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid33() {
-    let s = "(if (true) 3 4)";
-    test_helper(s, "i33.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid34() {
-    let s = "(if (false) 3 4)";
-    test_helper(s, "i34.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid35() {
-    let s = "(let ([x 5] [x 10]) (+ x x))";
-    test_helper(s, "i35.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid36() {
-    let s = "(letrec ([x (lambda () 5)] [x (lambda () 10)]) (+ (x) (x)))";
-    test_helper(s, "i36.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid37() {
-    let s = "((lambda (x x) (+ x x)) 5 10)";
-    test_helper(s, "i37.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid38() {
-    let s = "(letrec () (let ([x (alloc 8)]) (mset! x 0 10) (mref x 0)))";
-    test_helper(s, "i38.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid39() {
-    let s = "(letrec () (void 1))";
-    test_helper(s, "i39.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid40() {
-    let s = "(letrec () (car))";
-    test_helper(s, "i40.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid41() {
-    let s = "(letrec () (cdr))";
-    test_helper(s, "i41.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid42() {
-    let s = "(letrec () (make-vector))";
-    test_helper(s, "i42.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid43() {
-    let s = "(letrec () (vector-length))";
-    test_helper(s, "i43.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid44() {
-    let s = "(letrec () (boolean?))";
-    test_helper(s, "i44.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid45() {
-    let s = "(letrec () (fixnum?))";
-    test_helper(s, "i45.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid46() {
-    let s = "(letrec () (null?))";
-    test_helper(s, "i46.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid47() {
-    let s = "(letrec () (pair?))";
-    test_helper(s, "i48.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid49() {
-    let s = "(letrec () (vector?))";
-    test_helper(s, "i49.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid50() {
-    let s = "(letrec () (let ([x (cons 1 2)]) (car x (cons 3 4))))";
-    test_helper(s, "i50.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid51() {
-    let s = "(letrec () (let ([x (cons 1 2)] [y (cons 3 4)]) (cdr x y)))";
-    test_helper(s, "i51.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid52() {
-    let s = "(letrec () (make-vector 5 6))";
-    test_helper(s, "i52.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid53() {
-    let s = "(letrec () (vector-length (make-vector 7) 1))";
-    test_helper(s, "i53.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid54() {
-    let s = "(letrec () (boolean? #t #f))";
-    test_helper(s, "i54.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid55() {
-    let s = "(letrec () (fixnum? 7 8))";
-    test_helper(s, "i55.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid56() {
-    let s = "(letrec () (null? '() '()))";
-    test_helper(s, "i56.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid57() {
-    let s = "(letrec () (pair? (cons 1 2) (cons 3 4)))";
-    test_helper(s, "i57.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid58() {
-    let s = "(letrec () (vector? (make-vector 1) (make-vector 2)))";
-    test_helper(s, "i58.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid59() {
-    let s = "(letrec () (* 1))";
-    test_helper(s, "i59.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid60() {
-    let s = "(letrec () (+ 2))";
-    test_helper(s, "i60.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid61() {
-    let s = "(letrec () (- 3))";
-    test_helper(s, "i61.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid62() {
-    let s = "(letrec () (cons 4))";
-    test_helper(s, "i62.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid63() {
-    let s = "(letrec () (vector-ref (make-vector 5)))";
-    test_helper(s, "i63.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid64() {
-    let s = "(letrec () (< 6))";
-    test_helper(s, "i64.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid65() {
-    let s = "(letrec () (<= 7))";
-    test_helper(s, "i65.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid66() {
-    let s = "(letrec () (= 8))";
-    test_helper(s, "i66.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid67() {
-    let s = "(letrec () (>= 9))";
-    test_helper(s, "i67.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid68() {
-    let s = "(letrec () (> 10))";
-    test_helper(s, "i68.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid69() {
-    let s = "(letrec () (eq? 11))";
-    test_helper(s, "i69.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid70() {
-    let s = "(letrec () (let ([x (cons (void) (void))]) (begin (set-car! x) x)))";
-    test_helper(s, "i70.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid71() {
-    let s = "(letrec () (let ([x (cons (void) (void))]) (begin (set-car! x) x)))";
-    test_helper(s, "i71.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid72() {
-    let s = "(letrec () (* 1 2 3))";
-    test_helper(s, "i72.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid73() {
-    let s = "(letrec () (+ 2 3 4))";
-    test_helper(s, "i73.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid74() {
-    let s = "(letrec () (- 3 5 6))";
-    test_helper(s, "i74.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid75() {
-    let s = "(letrec () (cons 4 5 6))";
-    test_helper(s, "i75.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid76() {
-    let s = "(letrec () (vector-ref (make-vector 5) 0 10))";
-    test_helper(s, "i76.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid77() {
-    let s = "(letrec () (< 6 7 8))";
-    test_helper(s, "i77.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid78() {
-    let s = "(letrec () (<= 7 8 9))";
-    test_helper(s, "i78.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid79() {
-    let s = "(letrec () (= 8 9 10))";
-    test_helper(s, "i79.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid80() {
-    let s = "(letrec () (>= 9 10 11))";
-    test_helper(s, "i80.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid81() {
-    let s = "(letrec () (> 10 11 12))";
-    test_helper(s, "i81.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid82() {
-    let s = "(letrec () (eq? 11 12 13))";
-    test_helper(s, "i82.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid83() {
-    let s = "(letrec () (let ([x (cons (void) (void))]) (begin (set-car! x 0 1) x)))";
-    test_helper(s, "i83.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid84() {
-    let s = "(letrec () (let ([x (cons (void) (void))]) (begin (set-car! x 2 3) x)))";
-    test_helper(s, "i84.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid85() {
-    let s = "(letrec () (let ([x (make-vector 2)]) (begin (vector-set! x 0) x)))";
-    test_helper(s, "i85.s", "!");
-}
-
-#[test]
-#[should_panic()]
-fn invalid86() {
-    let s = "(letrec () (let ([x (make-vector 2)]) (begin (vector-set! x 0 3 1) x)))";
-    test_helper(s, "i86.s", "!");
-}
-
-#[test]
-#[should_panic("variable y unbound")]
-fn invalid87() {
-    let s = "(let ([x 5]) (+ x y))";
-    test_helper(s, "i87.s", "!");
-}
-
-#[test]
-#[should_panic("variable f unbound")]
-fn invalid88() {
-    let s = "(let ([f (lambda (x) (if (= x 0) 1 (* x (f (- x 1)))))]) (f 10))";
-    test_helper(s, "i88.s", "!");
-}
\ No newline at end of file
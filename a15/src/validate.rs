@@ -0,0 +1,227 @@
+// A validator for the register and frame allocators. It recomputes liveness on a body
+// whose uvars have locations, without the conflict graphs the allocators used, and proves
+// that no location holds two values at once and that no uvar lives across a call in a
+// register, since a call clobbers them all.
+use crate::syntax::{Expr, HashMap, HashSet};
+use crate::compiler::{is_reg, is_uvar};
+use crate::verify::{brief, is_frame_var};
+
+type Check = Result<(), String>;
+type Live = HashSet<String>;
+
+// the passes whose locate forms hold new locations. FinalizeFrameLocations and FinalizeLocations
+// are not here: they put the locations already checked in place of the uvars, and drop only the
+// moves from a location to itself, so no uvar they leave has a location to share
+pub const ALLOCATORS: [&str; 4] = ["PreAssignFrame", "AssignNewFrame", "AssignRegister", "AssignFrame"];
+
+pub fn validate_allocation(expr: &Expr) -> Check {
+    match expr {
+        Expr::Letrec (lambdas, box body) => {
+            for lambda in lambdas {
+                if let Expr::Lambda (label, _, box body) = lambda {
+                    validate_body(label, body)?;
+                }
+            }
+            validate_body("the body", body)
+        }
+        _ => Ok (()),
+    }
+}
+
+fn validate_body(name: &str, body: &Expr) -> Check {
+    use Expr::*;
+    let mut locations = HashMap::default();
+    let mut tail = body;
+    loop {
+        tail = match tail {
+            Locate (bindings, box e) => {
+                locations.extend(bindings.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+                e
+            }
+            Locals (_, box e) | Ulocals (_, box e) | Spills (_, box e) | NewFrames (_, box e) |
+            FrameConflict (_, box e) | RegisterConflict (_, box e) | CallLive (_, box e) => e,
+            _ => break,
+        };
+    }
+    let validator = AllocationValidator { body: name.to_string(), locations };
+    validator.tail(tail).map(|_| ())
+}
+
+// a uvar, a register or a frame variable, which holds a value
+fn variable(expr: &Expr) -> Option<&String> {
+    match expr {
+        Expr::Symbol (x) if is_uvar(x) || is_reg(x) || is_frame_var(x) => Some (x),
+        _ => None,
+    }
+}
+
+fn sorted(live: &Live) -> Vec<&String> {
+    let mut vars: Vec<&String> = live.iter().collect();
+    vars.sort();
+    vars
+}
+
+struct AllocationValidator {
+    body: String,
+    locations: HashMap<String, String>,
+}
+
+impl AllocationValidator {
+    fn location<'a>(&'a self, x: &'a str) -> Option<&'a str> {
+        if is_reg(x) || is_frame_var(x) {
+            return Some (x);
+        }
+        self.locations.get(x).map(|loc| loc.as_str())
+    }
+
+    // x is written at the point, so no other variable live after it can share its location
+    fn write(&self, x: &str, source: Option<&String>, after: &Live, point: &Expr) -> Check {
+        let loc = match self.location(x) {
+            Some (loc) => loc,
+            None => return Ok (()),
+        };
+        for v in sorted(after) {
+            if v == x || Some (v) == source || self.location(v) != Some (loc) {
+                continue;
+            }
+            if is_uvar(x) {
+                return Err (format!("{} and {} are both in {}, but {} is live at {} in {}", x, v, loc, v, brief(point), self.body));
+            }
+            return Err (format!("{} is in {}, but it is live at {} in {}", v, loc, brief(point), self.body));
+        }
+        Ok (())
+    }
+
+    fn jumps<'a>(&self, tail: &'a Expr, jumps: &mut Vec<&'a Expr>) {
+        match tail {
+            Expr::If (_, box b1, box b2) => {
+                self.jumps(b1, jumps);
+                self.jumps(b2, jumps);
+            }
+            Expr::Begin (exprs) => exprs.last().into_iter().for_each(|e| self.jumps(e, jumps)),
+            e => jumps.push(e),
+        }
+    }
+
+    // the uvars live after a call survive it only in the frame, below the new frame of the call
+    fn call(&self, label: &str, tail: &Expr, after: &Live) -> Check {
+        let mut jumps = vec![];
+        self.jumps(tail, &mut jumps);
+        let new_frame = jumps.iter().filter_map(|jump| match jump {
+            Expr::Funcall (_, args) => args.iter().filter_map(|a| variable(a)).filter_map(|a| self.location(a)).filter(|loc| is_frame_var(loc)).map(|loc| loc[2..].parse::<i64>().unwrap()).min(),
+            _ => None,
+        }).min();
+        for v in sorted(after).into_iter().filter(|v| is_uvar(v)) {
+            match self.location(v) {
+                Some (loc) if is_reg(loc) => {
+                    return Err (format!("{} is live across the call at {} in {}, but it is in the caller-saved register {}", v, label, self.body, loc));
+                }
+                Some (loc) if is_frame_var(loc) && new_frame.map_or(false, |i| loc[2..].parse::<i64>().unwrap() >= i) => {
+                    return Err (format!("{} is live across the call at {} in {}, but {} is in the new frame", v, label, self.body, loc));
+                }
+                _ => (),
+            }
+        }
+        Ok (())
+    }
+
+    fn tail(&self, tail: &Expr) -> Result<Live, String> {
+        use Expr::*;
+        match tail {
+            // like the allocators, skip the branch which is never taken
+            If (box Bool (true), box b1, _) => self.tail(b1),
+            If (box Bool (false), _, box b2) => self.tail(b2),
+            If (box pred, box b1, box b2) => {
+                let live1 = self.tail(b1)?;
+                let live2 = self.tail(b2)?;
+                self.pred(pred, &live1, &live2)
+            }
+            Begin (exprs) => match exprs.split_last() {
+                Some ((last, effects)) => {
+                    let live = self.tail(last)?;
+                    self.effects(effects, live)
+                }
+                None => Ok (Live::default()),
+            },
+            Funcall (box target, args) => {
+                Ok (std::iter::once(target).chain(args.iter()).filter_map(|e| variable(e)).cloned().collect())
+            }
+            _ => Ok (Live::default()),
+        }
+    }
+
+    fn pred(&self, pred: &Expr, live1: &Live, live2: &Live) -> Result<Live, String> {
+        use Expr::*;
+        match pred {
+            Bool (true) => Ok (live1.clone()),
+            Bool (false) => Ok (live2.clone()),
+            If (box Bool (true), box b1, _) => self.pred(b1, live1, live2),
+            If (box Bool (false), _, box b2) => self.pred(b2, live1, live2),
+            If (box pred, box b1, box b2) => {
+                let new_live1 = self.pred(b1, live1, live2)?;
+                let new_live2 = self.pred(b2, live1, live2)?;
+                self.pred(pred, &new_live1, &new_live2)
+            }
+            Begin (exprs) => match exprs.split_last() {
+                Some ((last, effects)) => {
+                    let live = self.pred(last, live1, live2)?;
+                    self.effects(effects, live)
+                }
+                None => Ok (live1.union(live2).cloned().collect()),
+            },
+            Prim2 (_, box a, box b) => {
+                let mut live: Live = live1.union(live2).cloned().collect();
+                live.extend([a, b].iter().filter_map(|e| variable(e)).cloned());
+                Ok (live)
+            }
+            _ => Ok (live1.union(live2).cloned().collect()),
+        }
+    }
+
+    fn effects(&self, effects: &[Expr], mut live: Live) -> Result<Live, String> {
+        for effect in effects.iter().rev() {
+            live = self.effect(effect, live)?;
+        }
+        Ok (live)
+    }
+
+    fn effect(&self, effect: &Expr, after: Live) -> Result<Live, String> {
+        use Expr::*;
+        match effect {
+            Set (box Symbol (x), box value) => {
+                let uses: Vec<&String> = match value {
+                    Prim2 (_, box a, box b) | Mref (box a, box b) => [a, b].iter().filter_map(|e| variable(e)).collect(),
+                    Alloc (box e) => variable(e).into_iter().collect(),
+                    e => variable(e).into_iter().collect(),
+                };
+                let source = if let Symbol (_) = value { variable(value) } else { None };
+                self.write(x, source, &after, effect)?;
+                let mut live = after;
+                live.remove(x);
+                live.extend(uses.into_iter().cloned());
+                Ok (live)
+            }
+            Mset (box a, box b, box c) => {
+                let mut live = after;
+                live.extend([a, b, c].iter().filter_map(|e| variable(e)).cloned());
+                Ok (live)
+            }
+            If (box Bool (true), box b1, _) => self.effect(b1, after),
+            If (box Bool (false), _, box b2) => self.effect(b2, after),
+            If (box pred, box b1, box b2) => {
+                let live1 = self.effect(b1, after.clone())?;
+                let live2 = self.effect(b2, after)?;
+                self.pred(pred, &live1, &live2)
+            }
+            Begin (exprs) => self.effects(exprs, after),
+            ReturnPoint (label, box tail) => {
+                self.call(label, tail, &after)?;
+                // the call clobbers every register
+                let mut live = self.tail(tail)?;
+                live.extend(after.into_iter().filter(|v| !is_reg(v)));
+                Ok (live)
+            }
+            _ => Ok (after),
+        }
+    }
+}
@@ -0,0 +1,1326 @@
+// Verifiers for the languages between the passes. A pass assumes the grammar of its input
+// and fails deep inside when it is broken, so in debug builds the output of every pass is
+// checked here, before the next pass runs on it.
+use crate::syntax::{Scheme, Expr, Asm, HashMap, HashSet};
+use crate::compiler::{
+    Program, pass_index, runtime_label, primitive_arity, is_reg, is_uvar, is_label,
+    is_value_prim, is_pred_prim, is_effect_prim,
+    APPLY_LABEL, COLLECT_REST_LABEL, CALLCC_LABEL, CONTINUATION_LABEL, HANDLER_LABEL, RAISE_LABEL,
+    RAISE_CONTINUABLE_LABEL, ERROR_LABEL, VALUES_LABEL, APPLY_VALUES_LABEL, CALL_WITH_VALUES_LABEL, LETREC_ERROR_LABEL,
+};
+
+type Check = Result<(), String>;
+
+const RUNTIME_LABELS: [&str; 12] = [
+    APPLY_LABEL, COLLECT_REST_LABEL, CALLCC_LABEL, CONTINUATION_LABEL, HANDLER_LABEL, RAISE_LABEL,
+    RAISE_CONTINUABLE_LABEL, ERROR_LABEL, VALUES_LABEL, APPLY_VALUES_LABEL, CALL_WITH_VALUES_LABEL, LETREC_ERROR_LABEL,
+];
+
+const BINOPS: [&str; 6] = ["+", "-", "*", "logand", "logor", "sra"];
+const RELOPS: [&str; 5] = ["<", "<=", "=", ">=", ">"];
+
+// checks the output of the named pass against its language
+pub fn verify(pass: &str, program: &Program) -> Check {
+    let i = pass_index(pass);
+    match program {
+        Program::Scheme (scm) if i < pass_index("NormalizeContext") => verify_scheme(i, scm),
+        Program::Scheme (scm) => verify_context(i, scm),
+        Program::Expr (expr) if i < pass_index("ImposeCallingConvention") => verify_uil(i, expr),
+        Program::Expr (expr) if i < pass_index("ExposeBasicBlocks") => verify_conventions(i, expr),
+        Program::Expr (expr) => verify_blocks(i, expr),
+        Program::Asm (asm) => verify_asm(asm),
+    }
+}
+
+// the first line of an expression, enough to find it in a dump
+pub fn brief<T: std::fmt::Display>(e: &T) -> String {
+    let s = format!("{:60}", e);
+    match s.split_once('\n') {
+        Some ((line, _)) => format!("{} ...", line.trim_end()),
+        None => s,
+    }
+}
+
+pub fn is_frame_var(s: &str) -> bool {
+    s.starts_with("fv") && s[2..].parse::<i64>().is_ok()
+}
+
+fn sorted<'a, T>(map: &'a HashMap<String, T>) -> Vec<(&'a String, &'a T)> {
+    let mut pairs: Vec<(&String, &T)> = map.iter().collect();
+    pairs.sort_by(|a, b| a.0.cmp(b.0));
+    pairs
+}
+
+// ---------------------- Scheme, IntroduceLibrary to LiftLetrec -----------------------
+pub fn verify_scheme(pass: usize, scm: &Scheme) -> Check {
+    let mut labels = HashSet::default();
+    collect_letrec_labels(scm, &mut labels);
+    let mut verifier = SchemeVerifier { pass, labels, bound: HashMap::default(), binders: HashSet::default(), assigned: vec![] };
+    if verifier.after("LiftLetrec") && !matches!(scm, Scheme::Letrec (..)) {
+        return Err (format!("the program is not a letrec of lambdas: {}", brief(scm)));
+    }
+    verifier.scheme(scm, true)
+}
+
+fn collect_letrec_labels(scm: &Scheme, labels: &mut HashSet<String>) {
+    if let Scheme::Letrec (bindings, _) = scm {
+        labels.extend(bindings.keys().filter(|k| is_label(k)).cloned());
+    }
+    scheme_children(scm).into_iter().for_each(|e| collect_letrec_labels(e, labels));
+}
+
+fn scheme_children(scm: &Scheme) -> Vec<&Scheme> {
+    use Scheme::*;
+    match scm {
+        Letrec (bindings, box body) | Let (bindings, box body) => bindings.values().chain(std::iter::once(body)).collect(),
+        Locals (_, box e) | Assigned (_, box e) | Lambda (_, box e) | Free (_, box e) | Bindfree (_, box e) |
+        Closures (_, box e) | Prim1 (_, box e) | Alloc (box e) | Quote (box e) => vec![e],
+        Prim2 (_, box a, box b) | Set (box a, box b) | Mref (box a, box b) => vec![a, b],
+        Prim3 (_, box a, box b, box c) | If (box a, box b, box c) | Mset (box a, box b, box c) => vec![a, b, c],
+        Begin (exprs) | PrimN (_, exprs) | LiteralVector (exprs) | LiteralList (exprs) => exprs.iter().collect(),
+        Funcall (box func, args) => std::iter::once(func).chain(args.iter()).collect(),
+        Symbol (_) | Int64 (_) | Bool (_) | EmptyList | Void | Nop => vec![],
+    }
+}
+
+struct SchemeVerifier {
+    pass: usize,
+    labels: HashSet<String>,
+    // uvars in scope, with how many times they are bound
+    bound: HashMap<String, usize>,
+    // every uvar bound so far, a uvar is bound only once from UniquifyVariable to ConvertClosure
+    binders: HashSet<String>,
+    assigned: Vec<HashSet<String>>,
+}
+
+impl SchemeVerifier {
+    fn after(&self, pass: &str) -> bool {
+        self.pass >= pass_index(pass)
+    }
+
+    fn between(&self, from: &str, to: &str) -> bool {
+        self.after(from) && !self.after(to)
+    }
+
+    fn bind(&mut self, x: &str) -> Check {
+        if self.after("UniquifyVariable") {
+            if !is_uvar(x) {
+                return Err (format!("{} is bound but it is not a uvar", x));
+            }
+            if !self.after("ConvertClosure") && !self.binders.insert(x.to_string()) {
+                return Err (format!("{} is bound twice", x));
+            }
+        }
+        *self.bound.entry(x.to_string()).or_insert(0) += 1;
+        Ok (())
+    }
+
+    fn unbind(&mut self, x: &str) {
+        if let Some (n) = self.bound.get_mut(x) {
+            *n -= 1;
+            if *n == 0 {
+                self.bound.remove(x);
+            }
+        }
+    }
+
+    fn reference(&self, x: &str) -> Check {
+        if !self.after("UniquifyVariable") || self.bound.contains_key(x) {
+            return Ok (());
+        }
+        if self.after("ConvertClosure") && (self.labels.contains(x) || RUNTIME_LABELS.contains(&x)) {
+            return Ok (());
+        }
+        Err (format!("{} is unbound", x))
+    }
+
+    fn prim(&self, op: &str) -> Check {
+        if op == "not" && self.after("ConvertComplexDatum") {
+            return Err ("not is left after ConvertComplexDatum".to_string());
+        }
+        if runtime_label(op).is_some() && self.after("ConvertClosure") {
+            return Err (format!("{} is not a call to the runtime after ConvertClosure", op));
+        }
+        if is_value_prim(op) || is_pred_prim(op) || is_effect_prim(op) || primitive_arity(op).is_some() || runtime_label(op).is_some() {
+            return Ok (());
+        }
+        Err (format!("unknown primitive {}", op))
+    }
+
+    fn scheme(&mut self, scm: &Scheme, top: bool) -> Check {
+        use Scheme::*;
+        match scm {
+            Letrec (bindings, box body) => {
+                if self.after("LiftLetrec") && !top {
+                    return Err (format!("a letrec is left in the body: {}", brief(scm)));
+                }
+                let bindings = sorted(bindings);
+                for (x, _) in bindings.iter() {
+                    if !self.after("ConvertClosure") {
+                        self.bind(x)?;
+                    } else if !is_label(x) {
+                        return Err (format!("letrec binds {} which is not a label", x));
+                    }
+                }
+                let names: Vec<String> = bindings.iter().map(|(x, _)| x.to_string()).collect();
+                // the lambdas see the assigned variables of the letrec too
+                let assigned = match body {
+                    Assigned (set, _) => set.clone(),
+                    _ => HashSet::default(),
+                };
+                self.assigned.push(assigned);
+                for (x, value) in bindings.iter() {
+                    match value {
+                        Lambda (args, box body) => self.lambda(args, body)?,
+                        _ if self.after("PurifyLetrec") => return Err (format!("letrec binds {} to {}", x, brief(*value))),
+                        value => self.scheme(value, false)?,
+                    }
+                }
+                self.assigned.pop();
+                if self.between("ConvertClosure", "IntroduceProceduraPrimitives") {
+                    self.closures(&names, body)?;
+                } else if matches!(body, Assigned (..)) {
+                    self.body(&names, body)?;
+                } else {
+                    // PurifyLetrec moves the assigned variables of a letrec to a let
+                    self.scheme(body, false)?;
+                }
+                if !self.after("ConvertClosure") {
+                    names.iter().for_each(|x| self.unbind(x));
+                }
+                Ok (())
+            }
+            Let (bindings, box body) => {
+                let bindings = sorted(bindings);
+                for (_, value) in bindings.iter() {
+                    match value {
+                        // a let binds lambdas until SanitizeBindingForms moves them to a letrec
+                        Lambda (args, box body) if !self.after("SanitizeBindingForms") => self.lambda(args, body)?,
+                        value => self.scheme(value, false)?,
+                    }
+                }
+                for (x, _) in bindings.iter() {
+                    self.bind(x)?;
+                }
+                let names: Vec<String> = bindings.iter().map(|(x, _)| x.to_string()).collect();
+                self.body(&names, body)?;
+                names.iter().for_each(|x| self.unbind(x));
+                Ok (())
+            }
+            Lambda (args, box body) => {
+                if self.after("RemoveAnonymousLambda") {
+                    return Err (format!("an anonymous lambda is left: {}", brief(scm)));
+                }
+                self.lambda(args, body)
+            }
+            Set (box Symbol (x), box value) => {
+                if self.after("ConvertAssignment") {
+                    return Err (format!("set! {} is left after ConvertAssignment", x));
+                }
+                if self.after("UncoverAssigned") && !self.assigned.iter().any(|set| set.contains(x)) {
+                    return Err (format!("set! {} but {} is not marked assigned", x, x));
+                }
+                self.reference(x)?;
+                self.scheme(value, false)
+            }
+            Prim1 (op, box e) => {
+                self.prim(op)?;
+                self.scheme(e, false)
+            }
+            Prim2 (op, box e1, box e2) => {
+                self.prim(op)?;
+                self.scheme(e1, false)?;
+                self.scheme(e2, false)
+            }
+            Prim3 (op, box e1, box e2, box e3) => {
+                self.prim(op)?;
+                self.scheme(e1, false)?;
+                self.scheme(e2, false)?;
+                self.scheme(e3, false)
+            }
+            PrimN (op, exprs) => {
+                let allowed = match op.as_str() {
+                    "and" | "or" | "error" | "apply" => !self.after("ConvertComplexDatum"),
+                    "values" | "letrec-error" => !self.after("ConvertClosure"),
+                    _ => false,
+                };
+                if !allowed {
+                    return Err (format!("unexpected {} with {} operands", op, exprs.len()));
+                }
+                exprs.iter().try_for_each(|e| self.scheme(e, false))
+            }
+            Quote (box imm) => {
+                match imm {
+                    Int64 (_) | Bool (_) | EmptyList => Ok (()),
+                    _ if self.after("ConvertComplexDatum") => Err (format!("a complex datum is left: {}", brief(scm))),
+                    _ => Ok (()),
+                }
+            }
+            LiteralList (_) | LiteralVector (_) if self.after("ConvertComplexDatum") => {
+                Err (format!("a complex datum is left: {}", brief(scm)))
+            }
+            LiteralList (_) | LiteralVector (_) => Ok (()),
+            Begin (exprs) if exprs.is_empty() => Err ("an empty begin".to_string()),
+            Begin (exprs) => exprs.iter().try_for_each(|e| self.scheme(e, false)),
+            If (box pred, box b1, box b2) => {
+                self.scheme(pred, false)?;
+                self.scheme(b1, false)?;
+                self.scheme(b2, false)
+            }
+            Funcall (box func, args) => {
+                self.scheme(func, false)?;
+                args.iter().try_for_each(|e| self.scheme(e, false))
+            }
+            Symbol (x) => self.reference(x),
+            Int64 (_) | Bool (_) | EmptyList | Void => Ok (()),
+            other => Err (format!("unexpected {}", brief(other))),
+        }
+    }
+
+    fn lambda(&mut self, args: &Vec<String>, body: &Scheme) -> Check {
+        use Scheme::*;
+        for x in args {
+            self.bind(x)?;
+        }
+        if self.between("UncoverFree", "ConvertClosure") {
+            match body {
+                Free (vars, box body) => {
+                    vars.iter().try_for_each(|x| self.reference(x))?;
+                    self.scheme(body, false)?;
+                }
+                _ => return Err (format!("a lambda body misses its free variables: {}", brief(body))),
+            }
+        } else if self.between("ConvertClosure", "IntroduceProceduraPrimitives") {
+            match body {
+                Bindfree (vars, box body) if args.first().map_or(false, |cp| vars.contains(cp)) => {
+                    // the closure pointer is bound as the first argument
+                    let free: Vec<&String> = vars.iter().filter(|x| Some (*x) != args.first()).collect();
+                    free.iter().try_for_each(|x| self.bind(x))?;
+                    self.scheme(body, false)?;
+                    free.iter().for_each(|x| self.unbind(x));
+                }
+                _ => return Err (format!("a lambda body does not bind its free variables: {}", brief(body))),
+            }
+        } else {
+            self.body(args, body)?;
+        }
+        args.iter().for_each(|x| self.unbind(x));
+        Ok (())
+    }
+
+    // the body of a binding form, where the assigned ones of its variables are marked
+    fn body(&mut self, names: &[String], body: &Scheme) -> Check {
+        if !self.between("UncoverAssigned", "ConvertAssignment") {
+            return self.scheme(body, false);
+        }
+        match body {
+            Scheme::Assigned (set, box body) => {
+                if let Some (x) = set.iter().find(|x| !names.contains(x)) {
+                    return Err (format!("{} is marked assigned where it is not bound", x));
+                }
+                self.assigned.push(set.clone());
+                self.scheme(body, false)?;
+                self.assigned.pop();
+                Ok (())
+            }
+            _ => Err (format!("a body misses its assigned variables: {}", brief(body))),
+        }
+    }
+
+    // the body of a letrec of labels, which makes a closure for each label
+    fn closures(&mut self, labels: &[String], body: &Scheme) -> Check {
+        match body {
+            Scheme::Closures (closures, box body) => {
+                for (name, _, _) in closures {
+                    self.bind(name)?;
+                }
+                for (name, label, free) in closures {
+                    if !labels.contains(label) {
+                        return Err (format!("the closure {} has the code {} which the letrec does not bind", name, label));
+                    }
+                    free.iter().try_for_each(|x| self.reference(x))?;
+                }
+                self.scheme(body, false)?;
+                closures.iter().for_each(|(name, _, _)| self.unbind(name));
+                Ok (())
+            }
+            _ => Err (format!("a letrec body misses its closures: {}", brief(body))),
+        }
+    }
+}
+
+// ------------- Scheme in value, pred and effect context, NormalizeContext to RemoveLet -------------
+// From SpecifyRepresentation on the primitives are machine words, and from UncoverLocals on
+// every uvar is declared in the locals of its body.
+pub fn verify_context(pass: usize, scm: &Scheme) -> Check {
+    use Scheme::*;
+    let mut verifier = ContextVerifier { pass, labels: HashSet::default(), bound: HashMap::default(), body: String::new() };
+    match scm {
+        Letrec (bindings, box body) => {
+            verifier.labels = bindings.keys().cloned().collect();
+            for (label, lambda) in sorted(bindings) {
+                verifier.body = label.to_string();
+                match lambda {
+                    Lambda (args, box body) if is_label(label) => verifier.lambda(args, body)?,
+                    _ => return Err (format!("letrec binds {} to {}", label, brief(lambda))),
+                }
+            }
+            verifier.body = "the body".to_string();
+            verifier.lambda(&vec![], body)
+        }
+        _ => Err (format!("the program is not a letrec of lambdas: {}", brief(scm))),
+    }
+}
+
+struct ContextVerifier {
+    pass: usize,
+    labels: HashSet<String>,
+    bound: HashMap<String, usize>,
+    // the lambda being verified
+    body: String,
+}
+
+impl ContextVerifier {
+    fn after(&self, pass: &str) -> bool {
+        self.pass >= pass_index(pass)
+    }
+
+    fn word(&self) -> bool {
+        self.after("SpecifyRepresentation")
+    }
+
+    fn error(&self, msg: String) -> Check {
+        Err (format!("in {}, {}", self.body, msg))
+    }
+
+    fn lambda(&mut self, args: &Vec<String>, body: &Scheme) -> Check {
+        self.bound.clear();
+        for x in args {
+            self.bind(x)?;
+        }
+        if !self.after("UncoverLocals") {
+            return self.value(body);
+        }
+        match body {
+            Scheme::Locals (locals, box body) => {
+                locals.iter().try_for_each(|x| self.bind(x))?;
+                self.value(body)
+            }
+            _ => self.error(format!("the body misses its locals: {}", brief(body))),
+        }
+    }
+
+    fn bind(&mut self, x: &str) -> Check {
+        if !is_uvar(x) {
+            return self.error(format!("{} is bound but it is not a uvar", x));
+        }
+        *self.bound.entry(x.to_string()).or_insert(0) += 1;
+        Ok (())
+    }
+
+    fn unbind(&mut self, x: &str) {
+        if let Some (n) = self.bound.get_mut(x) {
+            *n -= 1;
+            if *n == 0 {
+                self.bound.remove(x);
+            }
+        }
+    }
+
+    fn reference(&self, x: &str) -> Check {
+        if self.bound.contains_key(x) || self.labels.contains(x) || RUNTIME_LABELS.contains(&x) {
+            return Ok (());
+        }
+        if is_uvar(x) && self.after("UncoverLocals") {
+            return self.error(format!("{} is not declared in locals", x));
+        }
+        self.error(format!("{} is unbound", x))
+    }
+
+    // a let binds its variables, or they are already declared in locals
+    fn bindings(&mut self, bindings: &HashMap<String, Scheme>) -> Check {
+        if self.after("RemoveLet") {
+            return self.error("a let is left after RemoveLet".to_string());
+        }
+        let bindings = sorted(bindings);
+        for (_, value) in bindings.iter() {
+            self.value(value)?;
+        }
+        for (x, _) in bindings.iter() {
+            if self.after("UncoverLocals") {
+                self.reference(x)?;
+            } else {
+                self.bind(x)?;
+            }
+        }
+        Ok (())
+    }
+
+    fn unbind_all(&mut self, bindings: &HashMap<String, Scheme>) {
+        if !self.after("UncoverLocals") {
+            bindings.keys().for_each(|x| self.unbind(x));
+        }
+    }
+
+    fn sequence<F>(&mut self, exprs: &Vec<Scheme>, last: F) -> Check where F: FnOnce(&mut Self, &Scheme) -> Check {
+        match exprs.split_last() {
+            Some ((tail, effects)) => {
+                effects.iter().try_for_each(|e| self.effect(e))?;
+                last(self, tail)
+            }
+            None => self.error("an empty begin".to_string()),
+        }
+    }
+
+    fn value(&mut self, scm: &Scheme) -> Check {
+        use Scheme::*;
+        match scm {
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.value(b1)?;
+                self.value(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.value(e)),
+            Let (bindings, box body) => {
+                self.bindings(bindings)?;
+                self.value(body)?;
+                self.unbind_all(bindings);
+                Ok (())
+            }
+            Funcall (box func, args) => {
+                self.value(func)?;
+                args.iter().try_for_each(|e| self.value(e))
+            }
+            Symbol (x) => self.reference(x),
+            Prim2 (op, box e1, box e2) if self.word() && BINOPS.contains(&op.as_str()) => {
+                self.value(e1)?;
+                self.value(e2)
+            }
+            Alloc (box e) if self.word() => self.value(e),
+            Mref (box e1, box e2) if self.word() => {
+                self.value(e1)?;
+                self.value(e2)
+            }
+            Int64 (_) if self.word() => Ok (()),
+            Prim1 (op, box e) if !self.word() && is_value_prim(op) => self.value(e),
+            Prim2 (op, box e1, box e2) if !self.word() && is_value_prim(op) => {
+                self.value(e1)?;
+                self.value(e2)
+            }
+            Prim3 (op, box e1, box e2, box e3) if !self.word() && is_value_prim(op) => {
+                self.value(e1)?;
+                self.value(e2)?;
+                self.value(e3)
+            }
+            Quote (box Int64 (_)) | Quote (box Bool (_)) | Quote (box EmptyList) | Void if !self.word() => Ok (()),
+            other => self.error(format!("{} is not a value", brief(other))),
+        }
+    }
+
+    fn pred(&mut self, scm: &Scheme) -> Check {
+        use Scheme::*;
+        match scm {
+            Bool (_) => Ok (()),
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.pred(b1)?;
+                self.pred(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.pred(e)),
+            Let (bindings, box body) => {
+                self.bindings(bindings)?;
+                self.pred(body)?;
+                self.unbind_all(bindings);
+                Ok (())
+            }
+            Prim2 (op, box e1, box e2) if self.word() && RELOPS.contains(&op.as_str()) => {
+                self.value(e1)?;
+                self.value(e2)
+            }
+            Prim1 (op, box e) if !self.word() && is_pred_prim(op) => self.value(e),
+            Prim2 (op, box e1, box e2) if !self.word() && is_pred_prim(op) => {
+                self.value(e1)?;
+                self.value(e2)
+            }
+            Prim3 (op, box e1, box e2, box e3) if !self.word() && is_pred_prim(op) => {
+                self.value(e1)?;
+                self.value(e2)?;
+                self.value(e3)
+            }
+            other => self.error(format!("{} is not a predicate", brief(other))),
+        }
+    }
+
+    fn effect(&mut self, scm: &Scheme) -> Check {
+        use Scheme::*;
+        match scm {
+            Nop => Ok (()),
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.effect(b1)?;
+                self.effect(b2)
+            }
+            Begin (exprs) => exprs.iter().try_for_each(|e| self.effect(e)),
+            Let (bindings, box body) => {
+                self.bindings(bindings)?;
+                self.effect(body)?;
+                self.unbind_all(bindings);
+                Ok (())
+            }
+            Funcall (box func, args) => {
+                self.value(func)?;
+                args.iter().try_for_each(|e| self.value(e))
+            }
+            Set (box Symbol (x), box value) if self.after("RemoveLet") && is_uvar(x) => {
+                self.reference(x)?;
+                self.value(value)
+            }
+            Mset (box e1, box e2, box e3) if self.word() => {
+                self.value(e1)?;
+                self.value(e2)?;
+                self.value(e3)
+            }
+            Prim1 (op, box e) if !self.word() && is_effect_prim(op) => self.value(e),
+            Prim2 (op, box e1, box e2) if !self.word() && is_effect_prim(op) => {
+                self.value(e1)?;
+                self.value(e2)
+            }
+            Prim3 (op, box e1, box e2, box e3) if !self.word() && is_effect_prim(op) => {
+                self.value(e1)?;
+                self.value(e2)?;
+                self.value(e3)
+            }
+            other => self.error(format!("{} is not an effect", brief(other))),
+        }
+    }
+}
+
+// ---------------------- Expr, CompileToExpr to FlattenSet --------------------------------
+// After RemoveComplexOpera the operands are trivial, and after FlattenSet so is a set! but
+// for one operation.
+pub fn verify_uil(pass: usize, expr: &Expr) -> Check {
+    use Expr::*;
+    match expr {
+        Letrec (lambdas, box body) => {
+            let mut labels: HashSet<String> = RUNTIME_LABELS.iter().map(|l| l.to_string()).collect();
+            for lambda in lambdas {
+                match lambda {
+                    Lambda (label, _, _) if is_label(label) => {
+                        if !labels.insert(label.to_string()) {
+                            return Err (format!("{} is bound twice", label));
+                        }
+                    }
+                    e => return Err (format!("the letrec binds {}", brief(e))),
+                }
+            }
+            let mut verifier = UilVerifier { pass, labels, declared: HashSet::default(), body: String::new() };
+            for lambda in lambdas {
+                if let Lambda (label, args, box body) = lambda {
+                    verifier.body = label.to_string();
+                    verifier.locals(args, body)?;
+                }
+            }
+            verifier.body = "the body".to_string();
+            verifier.locals(&vec![], body)
+        }
+        _ => Err (format!("the program is not a letrec of lambdas: {}", brief(expr))),
+    }
+}
+
+struct UilVerifier {
+    pass: usize,
+    labels: HashSet<String>,
+    declared: HashSet<String>,
+    body: String,
+}
+
+impl UilVerifier {
+    fn after(&self, pass: &str) -> bool {
+        self.pass >= pass_index(pass)
+    }
+
+    fn error(&self, msg: String) -> Check {
+        Err (format!("in {}, {}", self.body, msg))
+    }
+
+    fn locals(&mut self, args: &Vec<String>, body: &Expr) -> Check {
+        match body {
+            Expr::Locals (locals, box tail) => {
+                self.declared = args.iter().chain(locals.iter()).cloned().collect();
+                self.tail(tail)
+            }
+            _ => self.error(format!("the body misses its locals: {}", brief(body))),
+        }
+    }
+
+    fn symbol(&self, x: &str) -> Check {
+        if is_uvar(x) && !self.declared.contains(x) {
+            return self.error(format!("{} is not declared in locals", x));
+        }
+        if is_label(x) && !self.labels.contains(x) {
+            return self.error(format!("label {} is unbound", x));
+        }
+        if !is_uvar(x) && !is_label(x) {
+            return self.error(format!("{} is neither a uvar nor a label", x));
+        }
+        Ok (())
+    }
+
+    fn triv(&self, expr: &Expr) -> Check {
+        match expr {
+            Expr::Int64 (_) => Ok (()),
+            Expr::Symbol (x) => self.symbol(x),
+            e => self.error(format!("{} is not trivial", brief(e))),
+        }
+    }
+
+    // an operand, trivial after RemoveComplexOpera
+    fn operand(&self, expr: &Expr) -> Check {
+        if self.after("RemoveComplexOpera") { self.triv(expr) } else { self.value(expr) }
+    }
+
+    fn sequence<F>(&self, exprs: &Vec<Expr>, last: F) -> Check where F: FnOnce(&Self, &Expr) -> Check {
+        match exprs.split_last() {
+            Some ((tail, effects)) => {
+                effects.iter().try_for_each(|e| self.effect(e))?;
+                last(self, tail)
+            }
+            None => self.error("an empty begin".to_string()),
+        }
+    }
+
+    // a single operation on operands
+    fn simple(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Prim2 (op, box e1, box e2) if BINOPS.contains(&op.as_str()) => {
+                self.operand(e1)?;
+                self.operand(e2)
+            }
+            Alloc (box e) => self.operand(e),
+            Mref (box e1, box e2) => {
+                self.operand(e1)?;
+                self.operand(e2)
+            }
+            Funcall (box func, args) => {
+                self.operand(func)?;
+                args.iter().try_for_each(|e| self.operand(e))
+            }
+            e => self.triv(e),
+        }
+    }
+
+    fn tail(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.tail(b1)?;
+                self.tail(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.tail(e)),
+            e => self.simple(e),
+        }
+    }
+
+    fn value(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.value(b1)?;
+                self.value(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.value(e)),
+            e => self.simple(e),
+        }
+    }
+
+    fn pred(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Bool (_) => Ok (()),
+            Prim2 (op, box e1, box e2) if RELOPS.contains(&op.as_str()) => {
+                self.operand(e1)?;
+                self.operand(e2)
+            }
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.pred(b1)?;
+                self.pred(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.pred(e)),
+            e => self.error(format!("{} is not a predicate", brief(e))),
+        }
+    }
+
+    fn effect(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Nop => Ok (()),
+            Set (box Symbol (x), box value) if is_uvar(x) => {
+                self.symbol(x)?;
+                if self.after("FlattenSet") {
+                    match value {
+                        If (..) | Begin (..) => return self.error(format!("set! {} to {}", x, brief(value))),
+                        value => return self.simple(value),
+                    }
+                }
+                self.value(value)
+            }
+            Mset (box e1, box e2, box e3) => {
+                self.operand(e1)?;
+                self.operand(e2)?;
+                self.operand(e3)
+            }
+            Funcall (box func, args) => {
+                self.operand(func)?;
+                args.iter().try_for_each(|e| self.operand(e))
+            }
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.effect(b1)?;
+                self.effect(b2)
+            }
+            Begin (exprs) => exprs.iter().try_for_each(|e| self.effect(e)),
+            e => self.error(format!("{} is not an effect", brief(e))),
+        }
+    }
+}
+
+// ------------- Expr with the calling convention, ImposeCallingConvention to UpdateFrameLocations -------------
+// Each pass wraps the bodies in a precise nest of forms, which the next pass takes apart.
+// From SelectInstructions on every instruction is one that x86-64 has.
+pub fn verify_conventions(pass: usize, expr: &Expr) -> Check {
+    use Expr::*;
+    match expr {
+        Letrec (lambdas, box body) => {
+            let mut labels: HashSet<String> = RUNTIME_LABELS.iter().map(|l| l.to_string()).collect();
+            for lambda in lambdas {
+                match lambda {
+                    Lambda (label, args, _) if is_label(label) && args.is_empty() => {
+                        if !labels.insert(label.to_string()) {
+                            return Err (format!("{} is bound twice", label));
+                        }
+                    }
+                    e => return Err (format!("the letrec binds {}", brief(e))),
+                }
+            }
+            collect_return_points(expr, &mut labels);
+            let mut verifier = MachineVerifier { pass, labels, declared: HashSet::default(), body: String::new() };
+            for lambda in lambdas {
+                if let Lambda (label, _, box body) = lambda {
+                    verifier.body = label.to_string();
+                    verifier.wrapped(body)?;
+                }
+            }
+            verifier.body = "the body".to_string();
+            verifier.wrapped(body)
+        }
+        _ => Err (format!("the program is not a letrec of lambdas: {}", brief(expr))),
+    }
+}
+
+fn collect_return_points(expr: &Expr, labels: &mut HashSet<String>) {
+    use Expr::*;
+    match expr {
+        ReturnPoint (label, box tail) => {
+            labels.insert(label.to_string());
+            collect_return_points(tail, labels);
+        }
+        Letrec (exprs, box e) => {
+            exprs.iter().for_each(|e| collect_return_points(e, labels));
+            collect_return_points(e, labels);
+        }
+        Locals (_, box e) | Ulocals (_, box e) | Spills (_, box e) | Locate (_, box e) | Lambda (_, _, box e) |
+        RegisterConflict (_, box e) | FrameConflict (_, box e) | NewFrames (_, box e) | CallLive (_, box e) => {
+            collect_return_points(e, labels);
+        }
+        Begin (exprs) => exprs.iter().for_each(|e| collect_return_points(e, labels)),
+        If (box e1, box e2, box e3) => {
+            collect_return_points(e1, labels);
+            collect_return_points(e2, labels);
+            collect_return_points(e3, labels);
+        }
+        _ => (),
+    }
+}
+
+// the nests of forms around a body, outermost first
+fn wrappers(pass: usize) -> Vec<Vec<&'static str>> {
+    let after = |p: &str| pass >= pass_index(p);
+    let frames = vec!["locals", "ulocals", "locate", "frame-conflict"];
+    if after("FinalizeLocations") {
+        vec![vec![]]
+    } else if after("DiscardCallLive") {
+        vec![vec!["locate"]]
+    } else if after("AssignFrame") {
+        vec![frames, vec!["locate"]]
+    } else if after("AssignRegister") {
+        vec![vec!["locals", "ulocals", "spills", "locate", "frame-conflict"], vec!["locate"]]
+    } else if after("UncoverRegisterConflict") {
+        vec![vec!["locals", "ulocals", "locate", "frame-conflict", "register-conflict"], vec!["locate"]]
+    } else if after("FinalizeFrameLocations") {
+        vec![frames, vec!["locate"]]
+    } else if after("AssignNewFrame") {
+        vec![frames]
+    } else if after("PreAssignFrame") {
+        vec![vec!["locals", "new-frames", "locate", "frame-conflict", "call-live"]]
+    } else if after("UncoverFrameConflict") {
+        vec![vec!["locals", "new-frames", "spills", "frame-conflict", "call-live"]]
+    } else {
+        vec![vec!["locals", "new-frames"]]
+    }
+}
+
+// takes the nest of forms off a body, and collects the uvars they declare
+fn unwrap<'a>(mut expr: &'a Expr, nest: &[&str], declared: &mut HashSet<String>) -> Option<&'a Expr> {
+    use Expr::*;
+    for wrapper in nest {
+        expr = match (*wrapper, expr) {
+            ("locals", Locals (set, box e)) | ("ulocals", Ulocals (set, box e)) | ("spills", Spills (set, box e)) => {
+                declared.extend(set.iter().cloned());
+                e
+            }
+            ("locate", Locate (bindings, box e)) => {
+                declared.extend(bindings.keys().cloned());
+                e
+            }
+            ("new-frames", NewFrames (frames, box e)) => {
+                frames.iter().for_each(|frame| declared.extend(frame.iter().cloned()));
+                e
+            }
+            ("frame-conflict", FrameConflict (_, box e)) | ("register-conflict", RegisterConflict (_, box e)) |
+            ("call-live", CallLive (_, box e)) => e,
+            _ => return None,
+        };
+    }
+    Some (expr)
+}
+
+struct MachineVerifier {
+    pass: usize,
+    labels: HashSet<String>,
+    declared: HashSet<String>,
+    body: String,
+}
+
+impl MachineVerifier {
+    fn after(&self, pass: &str) -> bool {
+        self.pass >= pass_index(pass)
+    }
+
+    fn error(&self, msg: String) -> Check {
+        Err (format!("in {}, {}", self.body, msg))
+    }
+
+    fn wrapped(&mut self, body: &Expr) -> Check {
+        let nests = wrappers(self.pass);
+        for nest in nests.iter() {
+            let mut declared = HashSet::default();
+            if let Some (tail) = unwrap(body, nest, &mut declared) {
+                if !self.is_wrapper(tail) {
+                    self.declared = declared;
+                    return self.tail(tail);
+                }
+            }
+        }
+        let nests: Vec<String> = nests.iter().map(|nest| format!("({} ...{}", nest.join(" ("), ")".repeat(nest.len()))).collect();
+        self.error(format!("it is not wrapped in {}: {}", nests.join(" or "), brief(body)))
+    }
+
+    fn is_wrapper(&self, expr: &Expr) -> bool {
+        use Expr::*;
+        matches!(expr, Locals (..) | Ulocals (..) | Spills (..) | Locate (..) | NewFrames (..) |
+                       FrameConflict (..) | RegisterConflict (..) | CallLive (..))
+    }
+
+    fn location(&self, x: &str) -> Check {
+        if is_reg(x) || is_frame_var(x) {
+            return Ok (());
+        }
+        if is_uvar(x) {
+            if self.after("FinalizeLocations") {
+                return self.error(format!("{} is left after FinalizeLocations", x));
+            }
+            if !self.declared.contains(x) {
+                return self.error(format!("{} is not declared", x));
+            }
+            return Ok (());
+        }
+        self.error(format!("{} is not a location", x))
+    }
+
+    fn triv(&self, expr: &Expr) -> Check {
+        match expr {
+            Expr::Int64 (_) => Ok (()),
+            Expr::Symbol (x) if is_label(x) && !is_uvar(x) => {
+                if self.labels.contains(x) { Ok (()) } else { self.error(format!("label {} is unbound", x)) }
+            }
+            Expr::Symbol (x) => self.location(x),
+            e => self.error(format!("{} is not trivial", brief(e))),
+        }
+    }
+
+    fn sequence<F>(&self, exprs: &Vec<Expr>, last: F) -> Check where F: FnOnce(&Self, &Expr) -> Check {
+        match exprs.split_last() {
+            Some ((tail, effects)) => {
+                effects.iter().try_for_each(|e| self.effect(e))?;
+                last(self, tail)
+            }
+            None => self.error("an empty begin".to_string()),
+        }
+    }
+
+    fn tail(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.tail(b1)?;
+                self.tail(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.tail(e)),
+            Funcall (box target, live) => {
+                self.triv(target)?;
+                if self.after("DiscardCallLive") && !live.is_empty() {
+                    return self.error(format!("the jump {} keeps its live locations", brief(expr)));
+                }
+                live.iter().try_for_each(|e| match e {
+                    Symbol (x) => self.location(x),
+                    e => self.error(format!("{} is not a location", brief(e))),
+                })
+            }
+            e => self.error(format!("{} is not a tail", brief(e))),
+        }
+    }
+
+    fn pred(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Bool (_) => Ok (()),
+            Prim2 (op, box e1, box e2) if RELOPS.contains(&op.as_str()) => {
+                self.triv(e1)?;
+                self.triv(e2)?;
+                if self.after("SelectInstructions") {
+                    return instruction(expr).or_else(|msg| self.error(msg));
+                }
+                Ok (())
+            }
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.pred(b1)?;
+                self.pred(b2)
+            }
+            Begin (exprs) => self.sequence(exprs, |v, e| v.pred(e)),
+            e => self.error(format!("{} is not a predicate", brief(e))),
+        }
+    }
+
+    fn effect(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Nop => Ok (()),
+            Set (box Symbol (x), box value) => {
+                self.location(x)?;
+                match value {
+                    Prim2 (op, box e1, box e2) if BINOPS.contains(&op.as_str()) => {
+                        self.triv(e1)?;
+                        self.triv(e2)?;
+                    }
+                    Alloc (box e) if !self.after("SelectInstructions") => self.triv(e)?,
+                    Mref (box e1, box e2) => {
+                        self.triv(e1)?;
+                        self.triv(e2)?;
+                    }
+                    e => self.triv(e)?,
+                }
+                if self.after("SelectInstructions") {
+                    return instruction(expr).or_else(|msg| self.error(msg));
+                }
+                Ok (())
+            }
+            Mset (box e1, box e2, box e3) => {
+                self.triv(e1)?;
+                self.triv(e2)?;
+                self.triv(e3)?;
+                if self.after("SelectInstructions") {
+                    return instruction(expr).or_else(|msg| self.error(msg));
+                }
+                Ok (())
+            }
+            If (box pred, box b1, box b2) => {
+                self.pred(pred)?;
+                self.effect(b1)?;
+                self.effect(b2)
+            }
+            Begin (exprs) => exprs.iter().try_for_each(|e| self.effect(e)),
+            ReturnPoint (label, box tail) => {
+                if !is_label(label) {
+                    return self.error(format!("the return point {} is not a label", label));
+                }
+                self.tail(tail)
+            }
+            e => self.error(format!("{} is not an effect", brief(e))),
+        }
+    }
+}
+
+// an instruction selected for x86-64, with at most one memory operand
+fn instruction(expr: &Expr) -> Check {
+    use Expr::*;
+    let memory = |e: &Expr| matches!(e, Symbol (x) if is_frame_var(x));
+    let label = |e: &Expr| matches!(e, Symbol (x) if is_label(x) && !is_uvar(x));
+    match expr {
+        Set (box a, box Prim2 (op, box b, box c)) => {
+            if a != b {
+                return Err (format!("{} does not operate on its target", brief(expr)));
+            }
+            if memory(a) && (memory(c) || op.as_str() == "*") {
+                return Err (format!("{} can not write to memory", brief(expr)));
+            }
+        }
+        Set (box a, box Mref (box b, box c)) => {
+            if memory(a) || memory(b) || memory(c) || matches!((b, c), (Int64 (_), Int64 (_))) {
+                return Err (format!("{} has more than one memory operand", brief(expr)));
+            }
+        }
+        Set (_, box Alloc (_)) => return Err (format!("{} is not an instruction", brief(expr))),
+        Set (box a, box b) => {
+            if memory(a) && (memory(b) || label(b)) {
+                return Err (format!("{} moves memory to memory", brief(expr)));
+            }
+        }
+        Mset (box base, box offset, box value) => {
+            if [base, offset, value].iter().any(|e| memory(e) || label(e)) || matches!((base, offset), (Int64 (_), Int64 (_))) {
+                return Err (format!("{} has more than one memory operand", brief(expr)));
+            }
+        }
+        Prim2 (_, box a, box b) => {
+            if matches!(a, Int64 (_)) {
+                return Err (format!("{} compares an integer to something", brief(expr)));
+            }
+            if memory(a) && memory(b) {
+                return Err (format!("{} compares memory to memory", brief(expr)));
+            }
+        }
+        _ => (),
+    }
+    Ok (())
+}
+
+// ---------------------- blocks, ExposeBasicBlocks to FlattenProgram ----------------------
+// Every block ends with a jump. From OptimizeJump on, a block may fall through to the next
+// one, and after FlattenProgram its body is a single begin.
+pub fn verify_blocks(pass: usize, expr: &Expr) -> Check {
+    use Expr::*;
+    match expr {
+        Letrec (lambdas, box body) => {
+            let mut labels: HashSet<String> = RUNTIME_LABELS.iter().map(|l| l.to_string()).collect();
+            for lambda in lambdas {
+                match lambda {
+                    Lambda (label, args, _) if is_label(label) && args.is_empty() => {
+                        if !labels.insert(label.to_string()) {
+                            return Err (format!("{} is bound twice", label));
+                        }
+                    }
+                    e => return Err (format!("the letrec binds {}", brief(e))),
+                }
+            }
+            let mut verifier = BlockVerifier { pass, labels, body: "the body".to_string(), last: lambdas.is_empty() };
+            verifier.block(body)?;
+            for (i, lambda) in lambdas.iter().enumerate() {
+                if let Lambda (label, _, box body) = lambda {
+                    verifier.body = label.to_string();
+                    verifier.last = i + 1 == lambdas.len();
+                    verifier.block(body)?;
+                }
+            }
+            Ok (())
+        }
+        _ => Err (format!("the program is not a letrec of blocks: {}", brief(expr))),
+    }
+}
+
+struct BlockVerifier {
+    pass: usize,
+    labels: HashSet<String>,
+    body: String,
+    // the last block has no block to fall through
+    last: bool,
+}
+
+impl BlockVerifier {
+    fn after(&self, pass: &str) -> bool {
+        self.pass >= pass_index(pass)
+    }
+
+    fn error(&self, msg: String) -> Check {
+        Err (format!("in {}, {}", self.body, msg))
+    }
+
+    fn block(&self, expr: &Expr) -> Check {
+        if let Expr::Begin (exprs) = expr {
+            if self.after("FlattenProgram") && exprs.iter().any(|e| matches!(e, Expr::Begin (..))) {
+                return self.error("a begin is left in a flat block".to_string());
+            }
+        }
+        self.tail(expr)
+    }
+
+    fn triv(&self, expr: &Expr) -> Check {
+        match expr {
+            Expr::Int64 (_) => Ok (()),
+            Expr::Symbol (x) if is_reg(x) || is_frame_var(x) => Ok (()),
+            Expr::Symbol (x) if is_label(x) && !is_uvar(x) => {
+                if self.labels.contains(x) { Ok (()) } else { self.error(format!("label {} is unbound", x)) }
+            }
+            e => self.error(format!("{} is neither a register, a frame variable nor a label", brief(e))),
+        }
+    }
+
+    fn jump(&self, expr: &Expr) -> Check {
+        match expr {
+            Expr::Funcall (box target, args) if args.is_empty() => self.triv(target),
+            e => self.error(format!("{} is not a jump", brief(e))),
+        }
+    }
+
+    fn fall_through(&self) -> Check {
+        if !self.after("OptimizeJump") {
+            return self.error("the block falls through before OptimizeJump".to_string());
+        }
+        if self.last {
+            return self.error("the last block falls off the end".to_string());
+        }
+        Ok (())
+    }
+
+    fn tail(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Begin (exprs) => match exprs.split_last() {
+                Some ((tail, effects)) => {
+                    effects.iter().try_for_each(|e| self.effect(e))?;
+                    self.tail(tail)
+                }
+                None => self.error("an empty begin".to_string()),
+            },
+            If (box pred, box b1, box b2) if !self.after("OptimizeJump") => {
+                self.pred(pred)?;
+                self.jump(b1)?;
+                self.jump(b2)
+            }
+            If1 (box pred, box jump) => {
+                self.pred(pred)?;
+                self.jump(jump)?;
+                self.fall_through()
+            }
+            Nop => self.fall_through(),
+            e => self.jump(e),
+        }
+    }
+
+    fn pred(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Prim1 (op, box relop) if op.as_str() == "not" && self.after("OptimizeJump") => self.pred(relop),
+            Prim2 (op, box e1, box e2) if RELOPS.contains(&op.as_str()) => {
+                self.triv(e1)?;
+                self.triv(e2)?;
+                instruction(expr).or_else(|msg| self.error(msg))
+            }
+            e => self.error(format!("{} is not a comparison", brief(e))),
+        }
+    }
+
+    fn effect(&self, expr: &Expr) -> Check {
+        use Expr::*;
+        match expr {
+            Set (box Symbol (x), box value) if is_reg(x) || is_frame_var(x) => {
+                match value {
+                    Prim2 (op, box e1, box e2) if BINOPS.contains(&op.as_str()) => {
+                        self.triv(e1)?;
+                        self.triv(e2)?;
+                    }
+                    Mref (box e1, box e2) => {
+                        self.triv(e1)?;
+                        self.triv(e2)?;
+                    }
+                    e => self.triv(e)?,
+                }
+                instruction(expr).or_else(|msg| self.error(msg))
+            }
+            Mset (box e1, box e2, box e3) => {
+                self.triv(e1)?;
+                self.triv(e2)?;
+                self.triv(e3)?;
+                instruction(expr).or_else(|msg| self.error(msg))
+            }
+            If1 (box pred, box jump) if self.after("OptimizeJump") => {
+                self.pred(pred)?;
+                self.jump(jump)
+            }
+            e => self.error(format!("{} is not an effect", brief(e))),
+        }
+    }
+}
+
+// ---------------------- Asm, CompileToAsm ------------------------------------------------
+pub fn verify_asm(asm: &Asm) -> Check {
+    use Asm::*;
+    match asm {
+        Prog (blocks) => {
+            let mut labels = HashSet::default();
+            for block in blocks {
+                match block {
+                    Cfg (label, _) => {
+                        if !labels.insert(label.as_str()) {
+                            return Err (format!("the block {} is defined twice", label));
+                        }
+                    }
+                    e => return Err (format!("{:?} is not a block", e)),
+                }
+            }
+            for block in blocks {
+                if let Cfg (label, codes) = block {
+                    for code in codes {
+                        asm_instruction(code, &labels).map_err(|msg| format!("in {}, {}", label, msg))?;
+                    }
+                }
+            }
+            Ok (())
+        }
+        e => Err (format!("{:?} is not a program", e)),
+    }
+}
+
+fn asm_instruction(code: &Asm, labels: &HashSet<&str>) -> Check {
+    use Asm::*;
+    let memory = |e: &Asm| matches!(e, Deref (..) | DerefLabel (..) | DerefRegister (..));
+    let label = |e: &Asm| match e {
+        Label (l) if !labels.contains(l.as_str()) => Err (format!("the label {} has no block", l)),
+        _ => Ok (()),
+    };
+    match code {
+        Op2 (op, box src, box dst) => {
+            if memory(src) && memory(dst) {
+                return Err (format!("{} has two memory operands", op));
+            }
+            if matches!(dst, Imm (_)) {
+                return Err (format!("{} writes to an immediate", op));
+            }
+            if let Imm (i) = src {
+                if (*i < i32::MIN as i64 || *i > i32::MAX as i64) && (op.as_str() != "movq" || memory(dst)) {
+                    return Err (format!("{} takes the 64-bit immediate {}", op, i));
+                }
+            }
+            if let DerefLabel (_, box l) = src {
+                label(l)?;
+            }
+            Ok (())
+        }
+        Jmp (box target) | Jmpif (_, box target) => label(target),
+        _ => Ok (()),
+    }
+}